@@ -0,0 +1,101 @@
+extern crate argparse;
+extern crate rustis;
+
+use std::cmp::max;
+use std::collections::BTreeMap;
+use std::fs;
+use std::process;
+use argparse::{ArgumentParser, List, Store};
+use rustis::rustis::binary::bytes_to_string;
+use rustis::rustis::snapshot::{self, Item, SnapshotReader, SNAPSHOT_MIN_VERSION, TRIGGERS_AUX};
+use rustis::rustis::value::Value;
+
+const USAGE:&'static str = "usage: rustis-rdb check FILE | rustis-rdb convert FILE OUT [--rdb-version N]";
+
+// the name TYPE gives a value
+fn type_name(value:&Value) -> String {
+    return match value {
+        &Value::IntValue(_) | &Value::StrValue(_) => "string".to_string(),
+        &Value::ListValue(_) => "list".to_string(),
+        &Value::SetValue(_) => "set".to_string(),
+        &Value::SortedSetValue(_) => "zset".to_string(),
+        &Value::HashValue(_) => "hash".to_string(),
+        &Value::StreamValue(_) => "stream".to_string(),
+        &Value::ModuleValue(ref v) => v.type_name().to_string(),
+        _ => "none".to_string(),
+    };
+}
+
+fn read(path:&str) -> Result<Vec<u8>, String> {
+    return fs::read(path).map_err(|e| format!("Can't open {}: {}", path, e));
+}
+
+// reads the whole file, verifying its checksum, and prints what it holds
+fn check(data:&[u8]) -> Result<(), String> {
+    let mut reader = SnapshotReader::new(data)?;
+    println!("RDB format version {}", reader.version());
+    let mut functions = 0;
+    // the keys of each database by type, and how many have an expire
+    let mut dbs:BTreeMap<usize, (BTreeMap<String, usize>, usize)> = BTreeMap::new();
+    let mut db = 0;
+    while let Some(item) = reader.next()? {
+        match item {
+            Item::Aux(ref name, ref value) if name == TRIGGERS_AUX => println!("aux {}: {} bytes", name, value.len()),
+            Item::Aux(name, value) => println!("aux {}: {}", name, bytes_to_string(&value)),
+            Item::Function(_) => functions += 1,
+            Item::SelectDb(index) => db = index,
+            Item::Entry(_, value, expire_at) => {
+                let counts = dbs.entry(db).or_insert((BTreeMap::new(), 0));
+                *counts.0.entry(type_name(&value)).or_insert(0) += 1;
+                if expire_at.is_some() {
+                    counts.1 += 1;
+                }
+            }
+        }
+    }
+    if functions > 0 {
+        println!("function libraries: {}", functions);
+    }
+    for (index, &(ref types, expires)) in dbs.iter() {
+        let keys = types.values().sum::<usize>();
+        let types = types.iter().map(|(t, n)| format!("{} {}", t, n)).collect::<Vec<String>>().join(", ");
+        println!("db {}: {} keys, {} with an expire ({})", index, keys, expires, types);
+    }
+    println!("RDB looks OK");
+    return Ok(());
+}
+
+// writes the file again in `version`, or in its own version if that's 0
+fn convert(data:&[u8], out:&str, version:u16) -> Result<(), String> {
+    let version = match version {
+        0 => max(SnapshotReader::new(data)?.version(), SNAPSHOT_MIN_VERSION),
+        v => v,
+    };
+    let converted = snapshot::convert(data, version)?;
+    fs::write(out, converted).map_err(|e| format!("Can't write {}: {}", out, e))?;
+    println!("Wrote {} in RDB format version {}", out, version);
+    return Ok(());
+}
+
+fn main() {
+    let mut command = String::new();
+    let mut files:Vec<String> = Vec::new();
+    let mut version:u16 = 0;
+    {
+        let mut parser = ArgumentParser::new();
+        parser.set_description("Checks RDB files written by redis or rustis, and converts them to a version another redis can load.");
+        parser.refer(&mut command).required().add_argument("command", Store, "check or convert");
+        parser.refer(&mut files).add_argument("files", List, "the file to read, then the file convert writes");
+        parser.refer(&mut version).add_option(&["--rdb-version"], Store, "RDB format version convert writes, from 9 (redis 5) to 12 (redis 7.4); defaults to the input's");
+        parser.parse_args_or_exit();
+    }
+    let result = match (command.as_str(), files.len()) {
+        ("check", 1) => read(&files[0]).and_then(|data| check(&data)),
+        ("convert", 2) => read(&files[0]).and_then(|data| convert(&data, &files[1], version)),
+        _ => Err(USAGE.to_string()),
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,14 @@
+#![recursion_limit = "256"]
+
+pub mod rustis;
+
+#[macro_use]
+extern crate nom;
+extern crate indexmap;
+extern crate libc;
+extern crate mio;
+extern crate mlua;
+extern crate sha1_smol;
+extern crate wasmi;
+#[cfg(test)]
+extern crate wat;
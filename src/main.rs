@@ -1,26 +1,77 @@
-pub mod rustis;
-
-#[macro_use]
-extern crate nom;
 extern crate argparse;
-extern crate libc;
-extern crate mio;
+extern crate rustis;
 
-use argparse::{ArgumentParser, Store};
-use rustis::server::RustisServer;
+use argparse::{ArgumentParser, Collect, Store, StoreTrue};
+use rustis::rustis::config::{Config, EnableCommand};
+use rustis::rustis::server::RustisServer;
 
 fn main() {
     // parse CLI args
     let mut src = "localhost:6379".to_string();
     let mut db_count = 16;
+    let mut config = Config::new();
+    let mut notify_keyspace_events = String::new();
+    let mut dbfilename = config.dbfilename.clone();
+    let mut save = config.get("save").unwrap();
+    let mut stop_writes_on_bgsave_error = config.get("stop-writes-on-bgsave-error").unwrap();
+    let mut appendonly = config.get("appendonly").unwrap();
+    let mut appendfilename = config.appendfilename.clone();
+    let mut appendfsync = config.get("appendfsync").unwrap();
+    let mut aof_load_truncated = config.get("aof-load-truncated").unwrap();
+    let mut auto_aof_rewrite_min_size = config.get("auto-aof-rewrite-min-size").unwrap();
+    let mut modules:Vec<String> = Vec::new();
+    let mut enable_module_command = config.get("enable-module-command").unwrap();
+    let mut import_rdb = String::new();
     {
         let mut parser = ArgumentParser::new();
         parser.refer(&mut src).add_argument("address", Store, "host:port to listen on");
         parser.refer(&mut db_count).add_option(&["-d", "--db-count"], Store, "number of separate redis DBs to run");
+        parser.refer(&mut config.lazyfree_lazy_user_del).add_option(&["--lazyfree-lazy-user-del"], StoreTrue, "free values removed by DEL in the background");
+        parser.refer(&mut config.lazyfree_lazy_expire).add_option(&["--lazyfree-lazy-expire"], StoreTrue, "free expired values in the background");
+        parser.refer(&mut notify_keyspace_events).add_option(&["--notify-keyspace-events"], Store, "keyspace event classes to publish, e.g. KEA");
+        parser.refer(&mut config.dir).add_option(&["--dir"], Store, "directory the snapshot is saved in and loaded from");
+        parser.refer(&mut dbfilename).add_option(&["--dbfilename"], Store, "file name of the snapshot");
+        parser.refer(&mut save).add_option(&["--save"], Store, "save rules as pairs of seconds and changes, e.g. \"3600 1 300 100\", or \"\" to disable");
+        parser.refer(&mut stop_writes_on_bgsave_error).add_option(&["--stop-writes-on-bgsave-error"], Store, "refuse writes while background saves fail: yes or no");
+        parser.refer(&mut appendonly).add_option(&["--appendonly"], Store, "log every write to the append only file and replay it at startup: yes or no");
+        parser.refer(&mut appendfilename).add_option(&["--appendfilename"], Store, "name the append only file's parts and manifest are given");
+        parser.refer(&mut config.appenddirname).add_option(&["--appenddirname"], Store, "directory under dir the append only file's parts are kept in");
+        parser.refer(&mut appendfsync).add_option(&["--appendfsync"], Store, "when the append only file is synced to disk: always, everysec or no");
+        parser.refer(&mut aof_load_truncated).add_option(&["--aof-load-truncated"], Store, "load an append only file cut short by a crash up to its last command: yes or no");
+        parser.refer(&mut config.auto_aof_rewrite_percentage).add_option(&["--auto-aof-rewrite-percentage"], Store, "rewrite the append only file once it grew by this percentage since the last rewrite, or 0 to disable");
+        parser.refer(&mut auto_aof_rewrite_min_size).add_option(&["--auto-aof-rewrite-min-size"], Store, "smallest append only file size rewritten automatically, e.g. 64mb");
+        parser.refer(&mut import_rdb).add_option(&["--import-rdb"], Store, "RDB file from redis to start with instead of the saved data, which it replaces");
+        parser.refer(&mut enable_module_command).add_option(&["--enable-module-command"], Store, "who may run MODULE LOAD and UNLOAD: no, yes or local clients");
+        parser.refer(&mut modules).add_option(&["--loadmodule"], Collect, "native module to load at startup, with its arguments, e.g. \"./mod.so 10\"");
 
         parser.parse_args_or_exit();
     }
+    for &(name, ref value) in [("notify-keyspace-events", &notify_keyspace_events), ("dbfilename", &dbfilename), ("save", &save), ("stop-writes-on-bgsave-error", &stop_writes_on_bgsave_error),
+                               ("appendonly", &appendonly), ("appendfilename", &appendfilename), ("appendfsync", &appendfsync), ("aof-load-truncated", &aof_load_truncated),
+                               ("auto-aof-rewrite-min-size", &auto_aof_rewrite_min_size)].iter() {
+        if let Err(e) = config.set(name, value) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    // protected, so it can't be set with CONFIG SET
+    config.enable_module_command = match EnableCommand::parse(&enable_module_command) {
+        Some(enable) => enable,
+        None => {
+            eprintln!("argument must be one of the following: no, yes, local");
+            std::process::exit(1);
+        }
+    };
 
-    let mut server = RustisServer::new(db_count);
-    server.run(src);
+    let mut server = RustisServer::new(db_count, config);
+    // modules go first, since the snapshot may hold their values
+    for module in modules {
+        let mut words = module.split_whitespace().map(|w| w.to_string());
+        let path = words.next().unwrap_or(String::new());
+        if let Err(e) = server.load_module(&path, words.collect()) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    server.run(src, if import_rdb.is_empty() {None} else {Some(import_rdb)});
 }
@@ -0,0 +1,410 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use nom::IResult;
+use rustis::binary::{bytes_to_string, string_to_bytes};
+use rustis::command::Command;
+use rustis::key::now_ms;
+use rustis::parse::{ParseResult, resp_array_parser};
+
+// how often the append only file is flushed to disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AppendFsync {
+    Always,
+    EverySec,
+    No,
+}
+
+impl AppendFsync {
+    pub fn parse(value:&str) -> Option<AppendFsync> {
+        return match value.to_lowercase().as_str() {
+            "always" => Some(AppendFsync::Always),
+            "everysec" => Some(AppendFsync::EverySec),
+            "no" => Some(AppendFsync::No),
+            _ => None,
+        };
+    }
+
+    pub fn name(&self) -> &'static str {
+        return match self {
+            &AppendFsync::Always => "always",
+            &AppendFsync::EverySec => "everysec",
+            &AppendFsync::No => "no",
+        };
+    }
+}
+
+// a command as RESP, the way clients send it
+pub fn encode(args:&[String]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        let bytes = string_to_bytes(arg);
+        out.extend_from_slice(format!("${}\r\n", bytes.len()).as_bytes());
+        out.extend_from_slice(&bytes);
+        out.extend_from_slice(b"\r\n");
+    }
+    return out;
+}
+
+// the log of writes, buffered until the event loop flushes it before
+// replying to the clients that made them
+pub struct Aof {
+    file:File,
+    buf:Vec<u8>,
+    // where the last whole write ended
+    size:u64,
+    // the database the commands in the file apply to
+    selected_db:Option<usize>,
+    fsync:AppendFsync,
+    // written since the last fsync, and whether an everysec fsync is running
+    unsynced:bool,
+    syncing:Arc<AtomicBool>,
+    last_fsync:u64,
+}
+
+impl Aof {
+    // opens the log to append to it
+    pub fn open(path:&Path, fsync:AppendFsync) -> io::Result<Aof> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        return Ok(Aof::new(file, fsync));
+    }
+
+    fn new(file:File, fsync:AppendFsync) -> Aof {
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        return Aof {
+            file: file,
+            buf: Vec::new(),
+            size: size,
+            selected_db: None,
+            fsync: fsync,
+            unsynced: false,
+            syncing: Arc::new(AtomicBool::new(false)),
+            last_fsync: now_ms(),
+        };
+    }
+
+    pub fn set_fsync(&mut self, fsync:AppendFsync) {
+        self.fsync = fsync;
+    }
+
+    // adds a command run on a database, selecting it first if needed
+    pub fn feed(&mut self, db:usize, args:&[String]) {
+        if self.selected_db != Some(db) {
+            self.buf.extend(encode(&["SELECT".to_string(), db.to_string()]));
+            self.selected_db = Some(db);
+        }
+        self.buf.extend(encode(args));
+    }
+
+    // writes out what was fed, syncing it right away with appendfsync always,
+    // and returns how many bytes were written. After a failed write what
+    // was fed stays buffered for the next flush, and a partly written
+    // command is cut off the file, or, if that fails too, not written again
+    pub fn flush(&mut self) -> io::Result<usize> {
+        if self.buf.is_empty() {
+            return Ok(0);
+        }
+        let (written, result) = write_partial(&mut self.file, &self.buf);
+        if let Err(e) = result {
+            if written > 0 && self.file.set_len(self.size).is_err() {
+                self.buf.drain(0..written);
+                self.size += written as u64;
+            }
+            return Err(e);
+        }
+        self.buf.clear();
+        self.size += written as u64;
+        self.unsynced = true;
+        if self.fsync == AppendFsync::Always {
+            self.file.sync_data()?;
+            self.unsynced = false;
+            self.last_fsync = now_ms();
+        }
+        return Ok(written);
+    }
+
+    // with appendfsync everysec, syncs once a second from a background
+    // thread, so a slow disk doesn't stall the event loop
+    pub fn cron(&mut self) {
+        let now = now_ms();
+        if self.fsync != AppendFsync::EverySec || !self.unsynced || now < self.last_fsync + 1000 {
+            return;
+        }
+        if self.syncing.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        let file = match self.file.try_clone() {
+            Ok(file) => file,
+            Err(_) => {
+                self.syncing.store(false, Ordering::SeqCst);
+                return;
+            }
+        };
+        let syncing = self.syncing.clone();
+        thread::spawn(move || {
+            if let Err(e) = file.sync_data() {
+                eprintln!("Can't persist the append only file: {}", e);
+            }
+            syncing.store(false, Ordering::SeqCst);
+        });
+        self.unsynced = false;
+        self.last_fsync = now;
+    }
+
+    // how long until the next everysec fsync is due
+    pub fn next_fsync(&self) -> Option<u64> {
+        if self.fsync != AppendFsync::EverySec || !self.unsynced {
+            return None;
+        }
+        return Some((self.last_fsync + 1000).saturating_sub(now_ms()));
+    }
+
+    // syncs what was written before the log is closed
+    pub fn close(mut self) -> io::Result<()> {
+        self.flush()?;
+        return self.file.sync_data();
+    }
+}
+
+// writes as much of `buf` as `out` takes, returning how much that was along
+// with the error that stopped it
+fn write_partial<W:Write>(out:&mut W, buf:&[u8]) -> (usize, io::Result<()>) {
+    let mut written = 0;
+    while written < buf.len() {
+        match out.write(&buf[written..]) {
+            Ok(0) => return (written, Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole buffer"))),
+            Ok(n) => written += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return (written, Err(e)),
+        }
+    }
+    return (written, Ok(()));
+}
+
+// the files the append only file is made of, as in redis 7: a base file with
+// a snapshot of the data, and incremental files logging the writes since.
+// Rewriting starts a new incremental file and, once the new base is
+// written, the old files become history and are removed
+#[derive(Clone, Debug, PartialEq)]
+pub struct Manifest {
+    // the appendfilename the files are named after
+    name:String,
+    pub base:Option<String>,
+    pub incrs:Vec<String>,
+    pub history:Vec<String>,
+    base_seq:u64,
+    incr_seq:u64,
+}
+
+impl Manifest {
+    pub fn new(name:&str) -> Manifest {
+        return Manifest {
+            name: name.to_string(),
+            base: None,
+            incrs: Vec::new(),
+            history: Vec::new(),
+            base_seq: 0,
+            incr_seq: 0,
+        };
+    }
+
+    // the manifest's name for an appendfilename
+    pub fn file_name(name:&str) -> String {
+        return format!("{}.manifest", name);
+    }
+
+    // lines of `file <name> seq <n> type <b|i|h>`
+    pub fn parse(name:&str, text:&str) -> Result<Manifest, String> {
+        let mut manifest = Manifest::new(name);
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words = line.split_whitespace().collect::<Vec<&str>>();
+            if words.len() % 2 != 0 {
+                return Err("Invalid AOF manifest file format".to_string());
+            }
+            let (mut file, mut seq, mut kind) = (None, None, None);
+            for pair in words.chunks(2) {
+                match pair[0] {
+                    "file" => file = Some(pair[1].to_string()),
+                    "seq" => seq = pair[1].parse::<u64>().ok(),
+                    "type" => kind = Some(pair[1]),
+                    // fields added by later versions are ignored
+                    _ => {}
+                }
+            }
+            let (file, seq) = match (file, seq) {
+                (Some(file), Some(seq)) if !file.contains('/') => (file, seq),
+                _ => return Err("Invalid AOF manifest file format".to_string()),
+            };
+            match kind {
+                Some("b") => {
+                    if manifest.base.is_some() {
+                        return Err("Found duplicate base file information".to_string());
+                    }
+                    manifest.base = Some(file);
+                    manifest.base_seq = seq;
+                }
+                Some("i") => {
+                    if seq <= manifest.incr_seq {
+                        return Err("Found a non-monotonic sequence number".to_string());
+                    }
+                    manifest.incrs.push(file);
+                    manifest.incr_seq = seq;
+                }
+                Some("h") => manifest.history.push(file),
+                _ => return Err("Unknown AOF file type".to_string()),
+            }
+        }
+        return Ok(manifest);
+    }
+
+    pub fn to_string(&self) -> String {
+        let mut text = String::new();
+        if let Some(ref base) = self.base {
+            text.push_str(&format!("file {} seq {} type b\n", base, self.base_seq));
+        }
+        for file in self.history.iter() {
+            text.push_str(&format!("file {} seq 0 type h\n", file));
+        }
+        let first = self.incr_seq + 1 - self.incrs.len() as u64;
+        for (i, file) in self.incrs.iter().enumerate() {
+            text.push_str(&format!("file {} seq {} type i\n", file, first + i as u64));
+        }
+        return text;
+    }
+
+    // adds an incremental file for the writes from now on, returning its name
+    pub fn new_incr(&mut self) -> String {
+        self.incr_seq += 1;
+        let file = format!("{}.{}.incr.aof", self.name, self.incr_seq);
+        self.incrs.push(file.clone());
+        return file;
+    }
+
+    // the name the next base file gets
+    pub fn next_base(&self) -> String {
+        return format!("{}.{}.base.rdb", self.name, self.base_seq + 1);
+    }
+
+    // makes a rewritten base current; the incremental files from before the
+    // rewrite started, all but the last `kept`, are history along with the
+    // old base
+    pub fn rewritten(&mut self, base:String, kept:usize) {
+        if let Some(old) = self.base.take() {
+            self.history.push(old);
+        }
+        self.base = Some(base);
+        self.base_seq += 1;
+        let old = self.incrs.len().saturating_sub(kept);
+        self.history.extend(self.incrs.drain(..old));
+    }
+
+    // every file the data is loaded from, in order
+    pub fn files(&self) -> Vec<String> {
+        return self.base.iter().chain(self.incrs.iter()).cloned().collect();
+    }
+}
+
+// the commands of a log with the offset each ends at, and the length of its
+// valid part if it ends with a command cut short
+pub fn parse_log(data:&[u8]) -> Result<(Vec<(Command, usize)>, Option<usize>), String> {
+    let s = bytes_to_string(data);
+    let mut commands = Vec::new();
+    let mut rest = s.as_str();
+    let mut offset = 0;
+    while !rest.is_empty() {
+        let len = match resp_array_parser(rest) {
+            IResult::Done(r, _) => rest.len() - r.len(),
+            IResult::Incomplete(_) => return Ok((commands, Some(offset))),
+            IResult::Error(_) => return Err("Bad file format reading the append only file".to_string()),
+        };
+        let ParseResult(_, parsed) = Command::parse(&rest[..len]);
+        offset += rest[..len].chars().count();
+        for cmd in parsed {
+            commands.push((cmd?, offset));
+        }
+        rest = &rest[len..];
+    }
+    return Ok((commands, None));
+}
+
+#[test]
+fn test_parse_log() {
+    let mut data = encode(&["SET".to_string(), "k".to_string(), "\u{e9}t\u{e9}".to_string()]);
+    assert_eq!(data, b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$3\r\n\xe9t\xe9\r\n".to_vec());
+    data.extend(encode(&["PEXPIREAT".to_string(), "k".to_string(), "1700000000000".to_string()]));
+    let (commands, truncated) = parse_log(&data).unwrap();
+    assert_eq!(commands.len(), 2);
+    assert_eq!(commands[1], (Command::PexpireAt {key: "k".to_string(), timestamp: 1700000000000}, data.len()));
+    assert_eq!(truncated, None);
+
+    let valid = data.len();
+    data.extend_from_slice(b"*2\r\n$3\r\nDEL\r\n$1");
+    let (commands, truncated) = parse_log(&data).unwrap();
+    assert_eq!(commands.len(), 2);
+    assert_eq!(truncated, Some(valid));
+
+    data.truncate(valid);
+    data.extend_from_slice(b"garbage");
+    assert!(parse_log(&data).is_err());
+}
+
+#[test]
+fn test_write_partial() {
+    // a disk with room for 10 more bytes
+    struct Full(Vec<u8>);
+    impl Write for Full {
+        fn write(&mut self, buf:&[u8]) -> io::Result<usize> {
+            let room = 10 - self.0.len();
+            if room == 0 {
+                return Err(io::Error::new(io::ErrorKind::Other, "No space left on device"));
+            }
+            let n = room.min(buf.len()).min(4);
+            self.0.extend_from_slice(&buf[..n]);
+            return Ok(n);
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+    let mut out = Full(Vec::new());
+    let (written, result) = write_partial(&mut out, b"0123456789abcdef");
+    assert_eq!(written, 10);
+    assert!(result.is_err());
+    assert_eq!(out.0, b"0123456789".to_vec());
+    let mut out = Full(Vec::new());
+    let (written, result) = write_partial(&mut out, b"01234");
+    assert_eq!(written, 5);
+    assert!(result.is_ok());
+}
+
+#[test]
+fn test_manifest() {
+    let mut manifest = Manifest::new("appendonly.aof");
+    manifest.rewritten(manifest.next_base(), 0);
+    assert_eq!(manifest.new_incr(), "appendonly.aof.1.incr.aof");
+    assert_eq!(manifest.to_string(), "file appendonly.aof.1.base.rdb seq 1 type b\nfile appendonly.aof.1.incr.aof seq 1 type i\n");
+
+    // a rewrite starts a new incremental file, and the old ones go once the
+    // new base is written
+    manifest.new_incr();
+    let base = manifest.next_base();
+    assert_eq!(base, "appendonly.aof.2.base.rdb");
+    manifest.rewritten(base, 1);
+    assert_eq!(manifest.history, vec!["appendonly.aof.1.base.rdb".to_string(), "appendonly.aof.1.incr.aof".to_string()]);
+    assert_eq!(manifest.files(), vec!["appendonly.aof.2.base.rdb".to_string(), "appendonly.aof.2.incr.aof".to_string()]);
+    let parsed = Manifest::parse("appendonly.aof", &manifest.to_string()).unwrap();
+    assert_eq!(parsed, manifest);
+    manifest.history.clear();
+    assert_eq!(Manifest::parse("appendonly.aof", "file a.aof seq 1 type b\nfile b.aof seq 2 type i\nfile c.aof seq 3 type i\n").unwrap().files(), vec!["a.aof", "b.aof", "c.aof"]);
+
+    assert!(Manifest::parse("appendonly.aof", "file a seq 1 type b\nfile b seq 1 type b\n").is_err());
+    assert!(Manifest::parse("appendonly.aof", "file a seq 2 type i\nfile b seq 1 type i\n").is_err());
+    assert!(Manifest::parse("appendonly.aof", "file a seq\n").is_err());
+}
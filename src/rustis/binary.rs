@@ -0,0 +1,35 @@
+// rustis stores binary data in Strings one byte per char, so that any byte
+// sequence a client sends survives the round trip unchanged. ASCII text is
+// unaffected; other bytes map to the chars U+0080..U+00FF.
+
+pub fn bytes_to_string(bytes:&[u8]) -> String {
+    return bytes.iter().map(|&b| b as char).collect();
+}
+
+pub fn string_to_bytes(s:&str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(s.len());
+    for c in s.chars() {
+        if (c as u32) < 0x100 {
+            bytes.push(c as u8);
+        } else {
+            // not produced from client data, so fall back to utf-8
+            let mut utf8 = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+        }
+    }
+    return bytes;
+}
+
+// length in bytes of a binary string
+pub fn byte_len(s:&str) -> usize {
+    return s.chars().count();
+}
+
+#[test]
+fn test_round_trip() {
+    let bytes = (0..256).map(|b| b as u8).collect::<Vec<u8>>();
+    let s = bytes_to_string(&bytes);
+    assert_eq!(byte_len(&s), 256);
+    assert_eq!(string_to_bytes(&s), bytes);
+    assert_eq!(bytes_to_string(b"abc"), "abc");
+}
@@ -5,7 +5,89 @@ use rustis::key::Key;
 use rustis::value::Value;
 use rustis::parse::{ParseResult, resp_array_parser, command_parser};
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
+pub struct SortOptions {
+    pub by:Option<String>,
+    pub limit:Option<(i64, i64)>,
+    pub get:Vec<String>,
+    pub desc:bool,
+    pub alpha:bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum GeoFrom {
+    Member(String),
+    LonLat(f64, f64),
+}
+
+// search areas, in meters
+#[derive(Clone, Debug, PartialEq)]
+pub enum GeoShape {
+    Radius(f64),
+    Box(f64, f64),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GeoSearchOptions {
+    pub from:Option<GeoFrom>,
+    pub by:Option<GeoShape>,
+    // meters per unit of the distances in the reply
+    pub unit:f64,
+    pub desc:Option<bool>,
+    pub count:Option<(usize, bool)>,
+    pub withcoord:bool,
+    pub withdist:bool,
+    pub withhash:bool,
+}
+
+// MAXLEN or MINID trimming for XADD and XTRIM, checked when it runs
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamTrim {
+    pub minid:bool,
+    pub threshold:String,
+    pub approx:bool,
+    pub limit:Option<i64>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClaimOptions {
+    pub idle:Option<i64>,
+    pub time:Option<i64>,
+    pub retrycount:Option<i64>,
+    pub force:bool,
+    pub justid:bool,
+    pub lastid:Option<String>,
+}
+
+// what FUNCTION RESTORE and TRIGGER RESTORE do with what's already there
+#[derive(Clone, Debug, PartialEq)]
+pub enum RestorePolicy {
+    Append,
+    Replace,
+    Flush,
+}
+
+impl RestorePolicy {
+    pub fn name(&self) -> String {
+        return match self {
+            &RestorePolicy::Append => "APPEND",
+            &RestorePolicy::Replace => "REPLACE",
+            &RestorePolicy::Flush => "FLUSH",
+        }.to_string();
+    }
+}
+
+impl StreamTrim {
+    pub fn args(&self) -> Vec<String> {
+        let mut args = vec![(if self.minid {"MINID"} else {"MAXLEN"}).to_string(), (if self.approx {"~"} else {"="}).to_string(), self.threshold.clone()];
+        if let Some(limit) = self.limit {
+            args.extend(vec!["LIMIT".to_string(), limit.to_string()]);
+        }
+        return args;
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Command {
     // strings
     Set {key:Key, value:Value, exp:Option<u64>},
@@ -29,37 +111,571 @@ pub enum Command {
     Scard {key:Key},
     Sismember {key:Key, member:String},
     Srem {key:Key, members:Vec<String>},
+    // geo
+    GeoAdd {key:Key, nx:bool, xx:bool, ch:bool, items:Vec<(f64, f64, String)>},
+    GeoPos {key:Key, members:Vec<String>},
+    GeoDist {key:Key, member1:String, member2:String, unit:f64},
+    GeoHash {key:Key, members:Vec<String>},
+    GeoSearch {key:Key, options:GeoSearchOptions},
+    GeoSearchStore {destination:Key, source:Key, options:GeoSearchOptions, storedist:bool},
+    // streams
+    XAdd {key:Key, nomkstream:bool, trim:Option<StreamTrim>, id:String, fields:Vec<(String, String)>},
+    XRange {key:Key, start:String, end:String, count:Option<i64>},
+    XRevRange {key:Key, end:String, start:String, count:Option<i64>},
+    XLen {key:Key},
+    XTrim {key:Key, trim:StreamTrim},
+    XDel {key:Key, ids:Vec<String>},
+    XInfoStream {key:Key},
+    XInfoGroups {key:Key},
+    XInfoConsumers {key:Key, group:String},
+    XGroupCreate {key:Key, group:String, id:String, mkstream:bool, entries_read:Option<i64>},
+    XGroupSetId {key:Key, group:String, id:String, entries_read:Option<i64>},
+    XGroupDestroy {key:Key, group:String},
+    XGroupCreateConsumer {key:Key, group:String, consumer:String},
+    XGroupDelConsumer {key:Key, group:String, consumer:String},
+    XRead {count:Option<i64>, block:Option<i64>, keys:Vec<Key>, ids:Vec<String>},
+    XReadGroup {group:String, consumer:String, count:Option<i64>, block:Option<i64>, noack:bool, keys:Vec<Key>, ids:Vec<String>},
+    XAck {key:Key, group:String, ids:Vec<String>},
+    XPending {key:Key, group:String},
+    XPendingRange {key:Key, group:String, idle:Option<i64>, start:String, end:String, count:i64, consumer:Option<String>},
+    XClaim {key:Key, group:String, consumer:String, min_idle:i64, ids:Vec<String>, options:ClaimOptions},
+    XAutoClaim {key:Key, group:String, consumer:String, min_idle:i64, start:String, count:Option<i64>, justid:bool},
+    // hyperloglog
+    Pfadd {key:Key, elements:Vec<String>},
+    Pfcount {keys:Vec<Key>},
+    Pfmerge {destkey:Key, sourcekeys:Vec<Key>},
     // all
     Del {keys:Vec<Key>},
+    Unlink {keys:Vec<Key>},
     Exists {key:Key},
     Type {key:Key},
+    Rename {key:Key, newkey:Key},
+    RenameNx {key:Key, newkey:Key},
+    Copy {source:Key, destination:Key, db:Option<usize>, replace:bool},
+    Move {key:Key, db:usize},
+    Expire {key:Key, seconds:i64},
+    Pexpire {key:Key, milliseconds:i64},
+    PexpireAt {key:Key, timestamp:i64},
+    Ttl {key:Key},
+    Pttl {key:Key},
+    Touch {keys:Vec<Key>},
+    Sort {key:Key, options:SortOptions, store:Option<Key>},
+    SortRo {key:Key, options:SortOptions},
+    Dump {key:Key},
+    Restore {key:Key, ttl:i64, payload:String, replace:bool, absttl:bool, idletime:Option<i64>, freq:Option<i64>},
+    RandomKey,
+    ObjectEncoding {key:Key},
+    ObjectFreq {key:Key},
+    ObjectIdleTime {key:Key},
+    ObjectRefCount {key:Key},
     // misc
     DbSize,
     Select(usize),
     FlushDb,
+    FlushDbAsync,
     FlushAll,
+    FlushAllAsync,
     SwapDb(usize, usize),
-    Ping {message:String},
+    Ping {message:Option<String>},
     Echo {message:String},
     Time,
+    ClientNoTouch(bool),
+    ConfigGet {parameter:String},
+    ConfigSet {parameter:String, value:String},
+    // pub/sub
+    Subscribe {channels:Vec<String>},
+    Unsubscribe {channels:Vec<String>},
+    Psubscribe {patterns:Vec<String>},
+    Punsubscribe {patterns:Vec<String>},
+    Publish {channel:String, message:String},
+    PubsubChannels {pattern:Option<String>},
+    PubsubNumSub {channels:Vec<String>},
+    PubsubNumPat,
+    // transactions
+    Multi,
+    Exec,
+    Discard,
+    Watch {keys:Vec<Key>},
+    Unwatch,
+    // scripting
+    Eval {script:String, numkeys:i64, args:Vec<String>, readonly:bool},
+    EvalSha {sha:String, numkeys:i64, args:Vec<String>, readonly:bool},
+    ScriptLoad {script:String},
+    ScriptExists {shas:Vec<String>},
+    ScriptFlush,
+    ScriptKill,
+    // functions
+    FunctionLoad {code:String, replace:bool},
+    FunctionDelete {library:String},
+    FunctionFlush,
+    FunctionList {pattern:Option<String>, withcode:bool},
+    FunctionDump,
+    FunctionRestore {payload:String, policy:RestorePolicy},
+    FunctionKill,
+    FCall {function:String, numkeys:i64, args:Vec<String>, readonly:bool},
+    // triggers
+    TriggerCreate {name:String, pattern:String, event:String, commands:Vec<String>, replace:bool},
+    TriggerDelete {name:String},
+    TriggerList,
+    TriggerDump,
+    TriggerRestore {payload:String, policy:RestorePolicy},
+    // wasm modules
+    WasmLoad {path:String},
+    WasmUnload {name:String},
+    WasmList,
+    // native modules
+    ModuleLoad {path:String, args:Vec<String>},
+    ModuleUnload {name:String},
+    ModuleList,
+    // persistence
+    Save,
+    BgSave {schedule:bool},
+    BgRewriteAof,
+    LastSave,
+    Info {section:Option<String>},
+    // a command no built in parser recognized, which a module may implement
+    Custom {args:Vec<String>},
 }
 
 #[derive(Debug, PartialEq)]
 pub enum Return {
     Ok,
+    Queued,
+    // a simple string reply other than OK
+    Status(String),
     Error(String),
     ValueReturn(Value),
 }
 
+// the arity of each command as in redis' command table: how many words a
+// call has, name included, or at least -arity of them when negative
+const ARITIES:[(&'static str, i64); 99] = [
+    ("select", 2), ("flushdb", -1), ("flushall", -1), ("swapdb", 3), ("dbsize", 1),
+    ("get", 2), ("set", -3), ("append", 3), ("incr", 2), ("incrby", 3),
+    ("incrbyfloat", 3), ("decr", 2), ("decrby", 3),
+    ("lindex", 3), ("llen", 2), ("lpop", -2), ("rpop", -2), ("lpush", -3), ("rpush", -3), ("lset", 4),
+    ("sadd", -3), ("scard", 2), ("sismember", 3), ("srem", -3),
+    ("pfadd", -2), ("pfcount", -2), ("pfmerge", -2),
+    ("geoadd", -5), ("geopos", -2), ("geodist", -4), ("geohash", -2), ("geosearch", -7), ("geosearchstore", -8),
+    ("xadd", -5), ("xrange", -4), ("xrevrange", -4), ("xlen", 2), ("xtrim", -4), ("xdel", -3),
+    ("xinfo", -2), ("xgroup", -2), ("xread", -4), ("xreadgroup", -7), ("xack", -4), ("xpending", -3),
+    ("xclaim", -6), ("xautoclaim", -6),
+    ("del", -2), ("unlink", -2), ("exists", -2), ("type", 2), ("rename", 3), ("renamenx", 3),
+    ("copy", -3), ("move", 3), ("expire", -3), ("pexpire", -3), ("ttl", 2), ("pttl", 2), ("pexpireat", -3),
+    ("touch", -2), ("randomkey", 1), ("object", -2), ("dump", 2), ("restore", -4), ("sort", -2), ("sort_ro", -2),
+    ("client", -2), ("config", -2), ("echo", 2), ("ping", -1), ("time", 1), ("info", -1),
+    ("subscribe", -2), ("unsubscribe", -1), ("psubscribe", -2), ("punsubscribe", -1), ("publish", 3), ("pubsub", -2),
+    ("multi", 1), ("exec", 1), ("discard", 1), ("watch", -2), ("unwatch", 1),
+    ("eval", -3), ("eval_ro", -3), ("evalsha", -3), ("evalsha_ro", -3), ("script", -2),
+    ("function", -2), ("fcall", -3), ("fcall_ro", -3),
+    ("wasm", -2), ("module", -2), ("trigger", -2),
+    ("save", 1), ("bgsave", -1), ("lastsave", 1), ("bgrewriteaof", 1),
+];
+
+const SUBCOMMAND_ARITIES:[(&'static str, i64); 40] = [
+    ("object|encoding", 3), ("object|freq", 3), ("object|idletime", 3), ("object|refcount", 3),
+    ("client|no-touch", 3), ("config|get", -3), ("config|set", -4),
+    ("xinfo|stream", -3), ("xinfo|groups", 3), ("xinfo|consumers", 4),
+    ("xgroup|create", -5), ("xgroup|setid", -5), ("xgroup|destroy", 4), ("xgroup|createconsumer", 5), ("xgroup|delconsumer", 5),
+    ("pubsub|channels", -2), ("pubsub|numsub", -2), ("pubsub|numpat", 2),
+    ("script|load", 3), ("script|exists", -3), ("script|flush", -2), ("script|kill", 2),
+    ("function|load", -3), ("function|delete", 3), ("function|flush", -2), ("function|list", -2),
+    ("function|dump", 2), ("function|restore", -3), ("function|kill", 2),
+    ("wasm|load", 3), ("wasm|unload", 3), ("wasm|list", 2),
+    ("module|load", -3), ("module|unload", 3), ("module|list", 2),
+    ("trigger|create", -6), ("trigger|delete", 3), ("trigger|list", 2), ("trigger|dump", 2), ("trigger|restore", -3),
+];
+
 impl Command {
     pub fn quote(s:&str) -> String {
-        return if s.len() == 0 || s.contains(" ") {
-            format!("\"{}\"", s)
+        return if s.len() == 0 || s.contains(|c| c == ' ' || c == '"' || c == '\t' || c == '\r' || c == '\n') {
+            format!("\"{}\"", s.replace("\\", "\\\\").replace("\"", "\\\""))
         } else {
             s.to_string()
         };
     }
 
+    // the lowercase command name, for error messages
+    pub fn name(&self) -> String {
+        return format!("{:?}", self).chars().take_while(|c| c.is_alphanumeric()).collect::<String>().to_lowercase();
+    }
+
+    // the keys a command reads or writes in the selected database
+    pub fn keys(&self) -> Vec<&Key> {
+        match self {
+            &Command::Set {ref key, ..} |
+            &Command::Append {ref key, ..} |
+            &Command::Get {ref key} |
+            &Command::Incr {ref key} |
+            &Command::IncrBy {ref key, ..} |
+            &Command::IncrByFloat {ref key, ..} |
+            &Command::Decr {ref key} |
+            &Command::DecrBy {ref key, ..} |
+            &Command::Lindex {ref key, ..} |
+            &Command::Llen {ref key} |
+            &Command::Lpop {ref key} |
+            &Command::Rpop {ref key} |
+            &Command::Lpush {ref key, ..} |
+            &Command::Rpush {ref key, ..} |
+            &Command::Lset {ref key, ..} |
+            &Command::Sadd {ref key, ..} |
+            &Command::Scard {ref key} |
+            &Command::Sismember {ref key, ..} |
+            &Command::Srem {ref key, ..} |
+            &Command::Exists {ref key} |
+            &Command::Type {ref key} |
+            &Command::Move {ref key, ..} |
+            &Command::Expire {ref key, ..} |
+            &Command::Pexpire {ref key, ..} |
+            &Command::Ttl {ref key} |
+            &Command::Pttl {ref key} |
+            &Command::ObjectEncoding {ref key} |
+            &Command::ObjectFreq {ref key} |
+            &Command::ObjectIdleTime {ref key} |
+            &Command::ObjectRefCount {ref key} |
+            &Command::Dump {ref key} |
+            &Command::Restore {ref key, ..} |
+            &Command::SortRo {ref key, ..} |
+            &Command::Sort {ref key, store: None, ..} => vec![key],
+            &Command::Sort {ref key, store: Some(ref store), ..} => vec![key, store],
+            &Command::Rename {ref key, ref newkey} |
+            &Command::RenameNx {ref key, ref newkey} => vec![key, newkey],
+            &Command::Copy {ref source, ref destination, ..} => vec![source, destination],
+            &Command::Del {ref keys} |
+            &Command::Unlink {ref keys} |
+            &Command::Touch {ref keys} |
+            &Command::Pfcount {ref keys} => keys.iter().collect(),
+            &Command::Pfadd {ref key, ..} |
+            &Command::GeoAdd {ref key, ..} |
+            &Command::GeoPos {ref key, ..} |
+            &Command::GeoDist {ref key, ..} |
+            &Command::GeoHash {ref key, ..} |
+            &Command::GeoSearch {ref key, ..} |
+            &Command::XAdd {ref key, ..} |
+            &Command::XRange {ref key, ..} |
+            &Command::XRevRange {ref key, ..} |
+            &Command::XLen {ref key} |
+            &Command::XTrim {ref key, ..} |
+            &Command::XDel {ref key, ..} |
+            &Command::XInfoStream {ref key} |
+            &Command::XInfoGroups {ref key} |
+            &Command::XInfoConsumers {ref key, ..} |
+            &Command::XGroupCreate {ref key, ..} |
+            &Command::XGroupSetId {ref key, ..} |
+            &Command::XGroupDestroy {ref key, ..} |
+            &Command::XGroupCreateConsumer {ref key, ..} |
+            &Command::XGroupDelConsumer {ref key, ..} |
+            &Command::XAck {ref key, ..} |
+            &Command::XPending {ref key, ..} |
+            &Command::XPendingRange {ref key, ..} |
+            &Command::XClaim {ref key, ..} |
+            &Command::XAutoClaim {ref key, ..} => vec![key],
+            &Command::XRead {ref keys, ..} |
+            &Command::XReadGroup {ref keys, ..} => keys.iter().collect(),
+            &Command::GeoSearchStore {ref destination, ref source, ..} => vec![destination, source],
+            &Command::Pfmerge {ref destkey, ref sourcekeys} => Some(destkey).into_iter().chain(sourcekeys.iter()).collect(),
+            _ => vec![],
+        }
+    }
+
+    // whether the command may modify the dataset
+    pub fn is_write(&self) -> bool {
+        return match self {
+            &Command::Set {..} | &Command::Append {..} | &Command::Incr {..} | &Command::IncrBy {..} |
+            &Command::IncrByFloat {..} | &Command::Decr {..} | &Command::DecrBy {..} |
+            &Command::Lpop {..} | &Command::Rpop {..} | &Command::Lpush {..} | &Command::Rpush {..} |
+            &Command::Lset {..} | &Command::Sadd {..} | &Command::Srem {..} |
+            &Command::GeoAdd {..} | &Command::GeoSearchStore {..} |
+            &Command::XAdd {..} | &Command::XTrim {..} | &Command::XDel {..} |
+            &Command::XGroupCreate {..} | &Command::XGroupSetId {..} | &Command::XGroupDestroy {..} |
+            &Command::XGroupCreateConsumer {..} | &Command::XGroupDelConsumer {..} |
+            &Command::XReadGroup {..} | &Command::XAck {..} | &Command::XClaim {..} | &Command::XAutoClaim {..} |
+            &Command::Pfadd {..} | &Command::Pfcount {..} | &Command::Pfmerge {..} |
+            &Command::Del {..} | &Command::Unlink {..} | &Command::Rename {..} | &Command::RenameNx {..} |
+            &Command::Copy {..} | &Command::Move {..} | &Command::Expire {..} | &Command::Pexpire {..} | &Command::PexpireAt {..} |
+            &Command::Sort {..} | &Command::Restore {..} |
+            &Command::FlushDb | &Command::FlushDbAsync | &Command::FlushAll | &Command::FlushAllAsync |
+            &Command::SwapDb(..) | &Command::FunctionLoad {..} | &Command::FunctionDelete {..} |
+            &Command::FunctionFlush | &Command::FunctionRestore {..} |
+            &Command::TriggerCreate {..} | &Command::TriggerDelete {..} | &Command::TriggerRestore {..} => true,
+            _ => false,
+        };
+    }
+
+    // the arguments of a command that changes data, the way a client would
+    // send it, for the append only file
+    pub fn args(&self) -> Option<Vec<String>> {
+        let s = |s:&str| s.to_string();
+        let mut args = match self {
+            &Command::Set {ref key, ref value, exp} => {
+                let mut args = vec![s("SET"), key.clone(), match value {
+                    &Value::IntValue(i) => i.to_string(),
+                    &Value::StrValue(ref v) => v.clone(),
+                    _ => return None,
+                }];
+                if let Some(ms) = exp {
+                    args.extend(vec![s("PX"), ms.to_string()]);
+                }
+                args
+            }
+            &Command::Append {ref key, ref value} => vec![s("APPEND"), key.clone(), value.clone()],
+            &Command::Incr {ref key} => vec![s("INCR"), key.clone()],
+            &Command::IncrBy {ref key, increment} => vec![s("INCRBY"), key.clone(), increment.to_string()],
+            &Command::IncrByFloat {ref key, increment} => vec![s("INCRBYFLOAT"), key.clone(), increment.to_string()],
+            &Command::Decr {ref key} => vec![s("DECR"), key.clone()],
+            &Command::DecrBy {ref key, decrement} => vec![s("DECRBY"), key.clone(), decrement.to_string()],
+            &Command::Lpop {ref key} => vec![s("LPOP"), key.clone()],
+            &Command::Rpop {ref key} => vec![s("RPOP"), key.clone()],
+            &Command::Lpush {ref key, ref values} => [s("LPUSH"), key.clone()].iter().chain(values).cloned().collect(),
+            &Command::Rpush {ref key, ref values} => [s("RPUSH"), key.clone()].iter().chain(values).cloned().collect(),
+            &Command::Lset {ref key, index, ref value} => vec![s("LSET"), key.clone(), index.to_string(), value.clone()],
+            &Command::Sadd {ref key, ref members} => [s("SADD"), key.clone()].iter().chain(members).cloned().collect(),
+            &Command::Srem {ref key, ref members} => [s("SREM"), key.clone()].iter().chain(members).cloned().collect(),
+            &Command::GeoAdd {ref key, nx, xx, ch, ref items} => {
+                let mut args = vec![s("GEOADD"), key.clone()];
+                for &(flag, name) in [(nx, "NX"), (xx, "XX"), (ch, "CH")].iter() {
+                    if flag {
+                        args.push(s(name));
+                    }
+                }
+                for &(lon, lat, ref member) in items {
+                    args.extend(vec![lon.to_string(), lat.to_string(), member.clone()]);
+                }
+                args
+            }
+            &Command::GeoSearchStore {ref destination, ref source, ref options, storedist} => {
+                let mut args = vec![s("GEOSEARCHSTORE"), destination.clone(), source.clone()];
+                match options.from {
+                    Some(GeoFrom::Member(ref member)) => args.extend(vec![s("FROMMEMBER"), member.clone()]),
+                    Some(GeoFrom::LonLat(lon, lat)) => args.extend(vec![s("FROMLONLAT"), lon.to_string(), lat.to_string()]),
+                    None => {}
+                }
+                // the unit matters for STOREDIST, so areas are given in it
+                let unit = match options.unit {
+                    u if u == 1000.0 => "km",
+                    u if u == 1609.34 => "mi",
+                    u if u == 0.3048 => "ft",
+                    _ => "m",
+                };
+                match options.by {
+                    Some(GeoShape::Radius(r)) => args.extend(vec![s("BYRADIUS"), (r / options.unit).to_string(), s(unit)]),
+                    Some(GeoShape::Box(w, h)) => args.extend(vec![s("BYBOX"), (w / options.unit).to_string(), (h / options.unit).to_string(), s(unit)]),
+                    None => {}
+                }
+                match options.desc {
+                    Some(true) => args.push(s("DESC")),
+                    Some(false) => args.push(s("ASC")),
+                    None => {}
+                }
+                if let Some((count, any)) = options.count {
+                    args.extend(vec![s("COUNT"), count.to_string()]);
+                    if any {
+                        args.push(s("ANY"));
+                    }
+                }
+                if storedist {
+                    args.push(s("STOREDIST"));
+                }
+                args
+            }
+            &Command::XAdd {ref key, nomkstream, ref trim, ref id, ref fields} => {
+                let mut args = vec![s("XADD"), key.clone()];
+                if nomkstream {
+                    args.push(s("NOMKSTREAM"));
+                }
+                if let Some(ref trim) = *trim {
+                    args.extend(trim.args());
+                }
+                args.push(id.clone());
+                for &(ref field, ref value) in fields {
+                    args.extend(vec![field.clone(), value.clone()]);
+                }
+                args
+            }
+            &Command::XTrim {ref key, ref trim} => [s("XTRIM"), key.clone()].iter().cloned().chain(trim.args()).collect(),
+            &Command::XDel {ref key, ref ids} => [s("XDEL"), key.clone()].iter().chain(ids).cloned().collect(),
+            &Command::XGroupCreate {ref key, ref group, ref id, mkstream, entries_read} => {
+                let mut args = vec![s("XGROUP"), s("CREATE"), key.clone(), group.clone(), id.clone()];
+                if mkstream {
+                    args.push(s("MKSTREAM"));
+                }
+                if let Some(n) = entries_read {
+                    args.extend(vec![s("ENTRIESREAD"), n.to_string()]);
+                }
+                args
+            }
+            &Command::XGroupSetId {ref key, ref group, ref id, entries_read} => {
+                let mut args = vec![s("XGROUP"), s("SETID"), key.clone(), group.clone(), id.clone()];
+                if let Some(n) = entries_read {
+                    args.extend(vec![s("ENTRIESREAD"), n.to_string()]);
+                }
+                args
+            }
+            &Command::XGroupDestroy {ref key, ref group} => vec![s("XGROUP"), s("DESTROY"), key.clone(), group.clone()],
+            &Command::XGroupCreateConsumer {ref key, ref group, ref consumer} => vec![s("XGROUP"), s("CREATECONSUMER"), key.clone(), group.clone(), consumer.clone()],
+            &Command::XGroupDelConsumer {ref key, ref group, ref consumer} => vec![s("XGROUP"), s("DELCONSUMER"), key.clone(), group.clone(), consumer.clone()],
+            // replayed without blocking
+            &Command::XReadGroup {ref group, ref consumer, count, noack, ref keys, ref ids, ..} => {
+                let mut args = vec![s("XREADGROUP"), s("GROUP"), group.clone(), consumer.clone()];
+                if let Some(count) = count {
+                    args.extend(vec![s("COUNT"), count.to_string()]);
+                }
+                if noack {
+                    args.push(s("NOACK"));
+                }
+                args.push(s("STREAMS"));
+                args.extend(keys.iter().chain(ids).cloned());
+                args
+            }
+            &Command::XAck {ref key, ref group, ref ids} => [s("XACK"), key.clone(), group.clone()].iter().chain(ids).cloned().collect(),
+            &Command::XClaim {ref key, ref group, ref consumer, min_idle, ref ids, ref options} => {
+                let mut args = [s("XCLAIM"), key.clone(), group.clone(), consumer.clone(), min_idle.to_string()].iter().chain(ids).cloned().collect::<Vec<String>>();
+                for &(value, name) in [(options.idle, "IDLE"), (options.time, "TIME"), (options.retrycount, "RETRYCOUNT")].iter() {
+                    if let Some(value) = value {
+                        args.extend(vec![s(name), value.to_string()]);
+                    }
+                }
+                if options.force {
+                    args.push(s("FORCE"));
+                }
+                if options.justid {
+                    args.push(s("JUSTID"));
+                }
+                if let Some(ref id) = options.lastid {
+                    args.extend(vec![s("LASTID"), id.clone()]);
+                }
+                args
+            }
+            &Command::XAutoClaim {ref key, ref group, ref consumer, min_idle, ref start, count, justid} => {
+                let mut args = vec![s("XAUTOCLAIM"), key.clone(), group.clone(), consumer.clone(), min_idle.to_string(), start.clone()];
+                if let Some(count) = count {
+                    args.extend(vec![s("COUNT"), count.to_string()]);
+                }
+                if justid {
+                    args.push(s("JUSTID"));
+                }
+                args
+            }
+            &Command::Pfadd {ref key, ref elements} => [s("PFADD"), key.clone()].iter().chain(elements).cloned().collect(),
+            &Command::Pfcount {ref keys} => [s("PFCOUNT")].iter().chain(keys).cloned().collect(),
+            &Command::Pfmerge {ref destkey, ref sourcekeys} => [s("PFMERGE"), destkey.clone()].iter().chain(sourcekeys).cloned().collect(),
+            &Command::Del {ref keys} => [s("DEL")].iter().chain(keys).cloned().collect(),
+            &Command::Unlink {ref keys} => [s("UNLINK")].iter().chain(keys).cloned().collect(),
+            &Command::Rename {ref key, ref newkey} => vec![s("RENAME"), key.clone(), newkey.clone()],
+            &Command::RenameNx {ref key, ref newkey} => vec![s("RENAMENX"), key.clone(), newkey.clone()],
+            &Command::Copy {ref source, ref destination, db, replace} => {
+                let mut args = vec![s("COPY"), source.clone(), destination.clone()];
+                if let Some(db) = db {
+                    args.extend(vec![s("DB"), db.to_string()]);
+                }
+                if replace {
+                    args.push(s("REPLACE"));
+                }
+                args
+            }
+            &Command::Move {ref key, db} => vec![s("MOVE"), key.clone(), db.to_string()],
+            &Command::Expire {ref key, seconds} => vec![s("EXPIRE"), key.clone(), seconds.to_string()],
+            &Command::Pexpire {ref key, milliseconds} => vec![s("PEXPIRE"), key.clone(), milliseconds.to_string()],
+            &Command::PexpireAt {ref key, timestamp} => vec![s("PEXPIREAT"), key.clone(), timestamp.to_string()],
+            &Command::Sort {ref key, ref options, store: Some(ref store)} => {
+                let mut args = vec![s("SORT"), key.clone()];
+                if let Some(ref pattern) = options.by {
+                    args.extend(vec![s("BY"), pattern.clone()]);
+                }
+                if let Some((offset, count)) = options.limit {
+                    args.extend(vec![s("LIMIT"), offset.to_string(), count.to_string()]);
+                }
+                for pattern in options.get.iter() {
+                    args.extend(vec![s("GET"), pattern.clone()]);
+                }
+                if options.desc {
+                    args.push(s("DESC"));
+                }
+                if options.alpha {
+                    args.push(s("ALPHA"));
+                }
+                args.extend(vec![s("STORE"), store.clone()]);
+                args
+            }
+            &Command::Restore {ref key, ttl, ref payload, replace, absttl, idletime, freq} => {
+                let mut args = vec![s("RESTORE"), key.clone(), ttl.to_string(), payload.clone()];
+                if replace {
+                    args.push(s("REPLACE"));
+                }
+                if absttl {
+                    args.push(s("ABSTTL"));
+                }
+                if let Some(seconds) = idletime {
+                    args.extend(vec![s("IDLETIME"), seconds.to_string()]);
+                }
+                if let Some(freq) = freq {
+                    args.extend(vec![s("FREQ"), freq.to_string()]);
+                }
+                args
+            }
+            &Command::FlushDb | &Command::FlushDbAsync => vec![s("FLUSHDB")],
+            &Command::FlushAll | &Command::FlushAllAsync => vec![s("FLUSHALL")],
+            &Command::SwapDb(a, b) => vec![s("SWAPDB"), a.to_string(), b.to_string()],
+            &Command::Eval {ref script, numkeys, ref args, ..} => [s("EVAL"), script.clone(), numkeys.to_string()].iter().chain(args).cloned().collect(),
+            &Command::EvalSha {ref sha, numkeys, ref args, ..} => [s("EVALSHA"), sha.clone(), numkeys.to_string()].iter().chain(args).cloned().collect(),
+            &Command::FCall {ref function, numkeys, ref args, ..} => [s("FCALL"), function.clone(), numkeys.to_string()].iter().chain(args).cloned().collect(),
+            &Command::FunctionLoad {ref code, replace} => {
+                let mut args = vec![s("FUNCTION"), s("LOAD")];
+                if replace {
+                    args.push(s("REPLACE"));
+                }
+                args.push(code.clone());
+                args
+            }
+            &Command::FunctionDelete {ref library} => vec![s("FUNCTION"), s("DELETE"), library.clone()],
+            &Command::FunctionFlush => vec![s("FUNCTION"), s("FLUSH")],
+            &Command::FunctionRestore {ref payload, ref policy} => vec![s("FUNCTION"), s("RESTORE"), payload.clone(), policy.name()],
+            &Command::TriggerCreate {ref name, ref pattern, ref event, ref commands, replace} => {
+                let mut args = vec![s("TRIGGER"), s("CREATE")];
+                if replace {
+                    args.push(s("REPLACE"));
+                }
+                args.extend(vec![name.clone(), pattern.clone(), event.clone()]);
+                args.extend(commands.iter().cloned());
+                args
+            }
+            &Command::TriggerDelete {ref name} => vec![s("TRIGGER"), s("DELETE"), name.clone()],
+            &Command::TriggerRestore {ref payload, ref policy} => vec![s("TRIGGER"), s("RESTORE"), payload.clone(), policy.name()],
+            &Command::Custom {ref args} => args.clone(),
+            _ => return None,
+        };
+        args.shrink_to_fit();
+        return Some(args);
+    }
+
+    // the error redis gives for a known command or subcommand called with
+    // the wrong number of arguments, if that's what `parts` is
+    pub fn arity_error<S:AsRef<str>>(parts:&[S]) -> Option<String> {
+        let name = match parts.first() {
+            Some(name) => name.as_ref().to_lowercase(),
+            None => return None,
+        };
+        let full_name = match parts.get(1) {
+            Some(sub) if SUBCOMMAND_ARITIES.iter().any(|&(n, _)| n.starts_with(&format!("{}|", name))) => format!("{}|{}", name, sub.as_ref().to_lowercase()),
+            _ => name.clone(),
+        };
+        let (name, arity) = match SUBCOMMAND_ARITIES.iter().chain(ARITIES.iter()).find(|&&(n, _)| n == full_name || n == name) {
+            Some(&(name, arity)) => (name, arity),
+            None => return None,
+        };
+        let len = parts.len() as i64;
+        if (arity >= 0 && len != arity) || len < -arity {
+            return Some(format!("ERR wrong number of arguments for '{}' command", name));
+        }
+        return None;
+    }
+
+    pub fn unknown_command_error<S:AsRef<str>>(parts:&[S]) -> String {
+        let name = parts.first().map(|name| name.as_ref()).unwrap_or("");
+        let args = parts.iter().skip(1).map(|arg| format!("'{}' ", arg.as_ref())).collect::<String>();
+        return format!("ERR unknown command '{}', with args beginning with: {}", name, args);
+    }
+
     pub fn parse(s:&str) -> ParseResult {
         let mut remaining = s;
         let mut parsed_chars = 0;
@@ -74,10 +690,15 @@ impl Command {
                     let cmd = command_parser(&joined);
                     match cmd {
                         IResult::Done("", c) => {
-                            commands.push(c);
+                            commands.push(Ok(c));
                         }
+                        // left for the server, which knows the commands modules
+                        // added
                         _ => {
-                            break;
+                            commands.push(match Command::arity_error(&x) {
+                                Some(e) => Err(e),
+                                None => Ok(Command::Custom {args: x.iter().map(|arg| arg.to_string()).collect()}),
+                            });
                         }
                     }
                 }
@@ -94,6 +715,8 @@ impl Display for Return {
     fn fmt(&self, f: &mut Formatter) -> Result {
         match self {
             &Return::Ok => write!(f, "+OK\r\n"),
+            &Return::Queued => write!(f, "+QUEUED\r\n"),
+            &Return::Status(ref s) => write!(f, "+{}\r\n", s),
             &Return::Error(ref s) => write!(f, "-{}\r\n", s),
             &Return::ValueReturn(ref v) => {
                 return v.fmt(f);
@@ -112,14 +735,46 @@ fn test_error() {
     assert_eq!(format!("{}", Return::Error("ERR there was an error".to_string())), "-ERR there was an error\r\n");
 }
 
+#[test]
+fn test_name() {
+    assert_eq!(Command::DbSize.name(), "dbsize");
+    assert_eq!(Command::XAdd {key: "s".to_string(), nomkstream: false, trim: None, id: "*".to_string(), fields: vec![]}.name(), "xadd");
+}
+
+#[test]
+fn test_arity_error() {
+    let arity = |parts:&[&str]| Command::arity_error(parts);
+    assert_eq!(arity(&["GET"]), Some("ERR wrong number of arguments for 'get' command".to_string()));
+    assert_eq!(arity(&["get", "a", "b"]), Some("ERR wrong number of arguments for 'get' command".to_string()));
+    assert_eq!(arity(&["SET", "a"]), Some("ERR wrong number of arguments for 'set' command".to_string()));
+    assert_eq!(arity(&["set", "a", "b", "NX", "GET"]), None);
+    assert_eq!(arity(&["CONFIG", "GET"]), Some("ERR wrong number of arguments for 'config|get' command".to_string()));
+    assert_eq!(arity(&["config"]), Some("ERR wrong number of arguments for 'config' command".to_string()));
+    assert_eq!(arity(&["config", "nosuch"]), None);
+    assert_eq!(arity(&["nosuch"]), None);
+    assert_eq!(Command::parse("*1\r\n$4\r\nLLEN\r\n"), ParseResult(14, vec![Err("ERR wrong number of arguments for 'llen' command".to_string())]));
+}
+
+#[test]
+fn test_is_write() {
+    assert!(Command::Set {key: "k".to_string(), value: Value::IntValue(1), exp: None}.is_write());
+    assert!(Command::FlushAll.is_write());
+    assert!(!Command::Get {key: "k".to_string()}.is_write());
+    assert!(!Command::XRead {count: None, block: None, keys: vec![], ids: vec![]}.is_write());
+}
+
 #[test]
 fn test_parse() {
     assert_eq!(
         Command::parse("*1\r\n$6\r\nDBSIZE\r\n"),
-        ParseResult(16, vec![Command::DbSize])
+        ParseResult(16, vec![Ok(Command::DbSize)])
     );
     assert_eq!(
         Command::parse("*1\r\n$6\r\nDBSIZE\r\n*2\r\n$3\r\nGET\r\n$4\r\nabcd\r\n"),
-        ParseResult(39, vec![Command::DbSize, Command::Get {key: "abcd".to_string()}])
+        ParseResult(39, vec![Ok(Command::DbSize), Ok(Command::Get {key: "abcd".to_string()})])
+    );
+    assert_eq!(
+        Command::parse("*2\r\n$3\r\nFOO\r\n$1\r\nx\r\n*1\r\n$5\r\nMULTI\r\n"),
+        ParseResult(35, vec![Ok(Command::Custom {args: vec!["FOO".to_string(), "x".to_string()]}), Ok(Command::Multi)])
     );
 }
@@ -0,0 +1,275 @@
+use std::str::FromStr;
+use rustis::aof::AppendFsync;
+use rustis::notify;
+
+// who may run a command that can take over the server: no client, any, or
+// only those connected from the same host
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EnableCommand {
+    No,
+    Yes,
+    Local,
+}
+
+impl EnableCommand {
+    pub fn parse(s:&str) -> Option<EnableCommand> {
+        return match s.to_lowercase().as_str() {
+            "no" => Some(EnableCommand::No),
+            "yes" => Some(EnableCommand::Yes),
+            "local" => Some(EnableCommand::Local),
+            _ => None,
+        };
+    }
+
+    pub fn name(&self) -> &'static str {
+        return match *self {
+            EnableCommand::No => "no",
+            EnableCommand::Yes => "yes",
+            EnableCommand::Local => "local",
+        };
+    }
+}
+
+// runtime configuration, settable from the command line and with CONFIG SET
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub lazyfree_lazy_user_del:bool,
+    pub lazyfree_lazy_expire:bool,
+    pub notify_keyspace_events:u32,
+    // milliseconds a script runs before other clients get -BUSY
+    pub busy_reply_threshold:u64,
+    // the budget of each call into a wasm module: instructions, milliseconds
+    // and bytes of linear memory
+    pub wasm_fuel_limit:u64,
+    pub wasm_time_limit:u64,
+    pub wasm_memory_limit:usize,
+    // where SAVE and BGSAVE write the snapshot, which is loaded at startup
+    pub dir:String,
+    pub dbfilename:String,
+    // a background save starts once a rule's changes were made in its seconds
+    pub save_params:Vec<(u64, u64)>,
+    pub stop_writes_on_bgsave_error:bool,
+    // every write is logged to the append only file, whose parts are kept in
+    // appenddirname under dir, and which is replayed at startup instead of
+    // loading the snapshot
+    pub appendonly:bool,
+    pub appendfilename:String,
+    pub appenddirname:String,
+    pub appendfsync:AppendFsync,
+    // whether a log cut short by a crash loads up to its last whole command
+    pub aof_load_truncated:bool,
+    // the append only file is rewritten once it grew by the percentage since
+    // the last rewrite, and is at least the minimum size in bytes
+    pub auto_aof_rewrite_percentage:u64,
+    pub auto_aof_rewrite_min_size:u64,
+    // MODULE LOAD and UNLOAD run native code in the server, so they're only
+    // allowed when enabled at startup
+    pub enable_module_command:EnableCommand,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        return Config {
+            lazyfree_lazy_user_del: false,
+            lazyfree_lazy_expire: false,
+            notify_keyspace_events: 0,
+            busy_reply_threshold: 5000,
+            wasm_fuel_limit: 100000000,
+            wasm_time_limit: 1000,
+            wasm_memory_limit: 64 << 20,
+            dir: ".".to_string(),
+            dbfilename: "dump.rdb".to_string(),
+            save_params: vec![(3600, 1), (300, 100), (60, 10000)],
+            stop_writes_on_bgsave_error: true,
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appenddirname: "appendonlydir".to_string(),
+            appendfsync: AppendFsync::EverySec,
+            aof_load_truncated: true,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 << 20,
+            enable_module_command: EnableCommand::No,
+        };
+    }
+
+    pub fn get(&self, name:&str) -> Option<String> {
+        return match name.to_lowercase().as_str() {
+            "lazyfree-lazy-user-del" => Some(Config::yes_no(self.lazyfree_lazy_user_del)),
+            "lazyfree-lazy-expire" => Some(Config::yes_no(self.lazyfree_lazy_expire)),
+            "notify-keyspace-events" => Some(notify::flags_to_string(self.notify_keyspace_events)),
+            "busy-reply-threshold" | "lua-time-limit" => Some(self.busy_reply_threshold.to_string()),
+            "wasm-fuel-limit" => Some(self.wasm_fuel_limit.to_string()),
+            "wasm-time-limit" => Some(self.wasm_time_limit.to_string()),
+            "wasm-memory-limit" => Some(self.wasm_memory_limit.to_string()),
+            "dir" => Some(self.dir.clone()),
+            "dbfilename" => Some(self.dbfilename.clone()),
+            "save" => Some(self.save_params.iter().map(|&(seconds, changes)| format!("{} {}", seconds, changes)).collect::<Vec<String>>().join(" ")),
+            "stop-writes-on-bgsave-error" => Some(Config::yes_no(self.stop_writes_on_bgsave_error)),
+            "appendonly" => Some(Config::yes_no(self.appendonly)),
+            "appendfilename" => Some(self.appendfilename.clone()),
+            "appenddirname" => Some(self.appenddirname.clone()),
+            "appendfsync" => Some(self.appendfsync.name().to_string()),
+            "aof-load-truncated" => Some(Config::yes_no(self.aof_load_truncated)),
+            "auto-aof-rewrite-percentage" => Some(self.auto_aof_rewrite_percentage.to_string()),
+            "auto-aof-rewrite-min-size" => Some(self.auto_aof_rewrite_min_size.to_string()),
+            "enable-module-command" => Some(self.enable_module_command.name().to_string()),
+            _ => None,
+        };
+    }
+
+    pub fn set(&mut self, name:&str, value:&str) -> Result<(), String> {
+        match name.to_lowercase().as_str() {
+            "lazyfree-lazy-user-del" => self.lazyfree_lazy_user_del = Config::parse_yes_no(value)?,
+            "lazyfree-lazy-expire" => self.lazyfree_lazy_expire = Config::parse_yes_no(value)?,
+            "notify-keyspace-events" => {
+                self.notify_keyspace_events = match notify::parse_flags(value) {
+                    Some(flags) => flags,
+                    None => return Err("ERR Invalid event class character. Use 'Ag$lshzxeKEtmdn'.".to_string()),
+                };
+            }
+            "busy-reply-threshold" | "lua-time-limit" => self.busy_reply_threshold = Config::parse_number(value)?,
+            "wasm-fuel-limit" => self.wasm_fuel_limit = Config::parse_number(value)?,
+            "wasm-time-limit" => self.wasm_time_limit = Config::parse_number(value)?,
+            "wasm-memory-limit" => self.wasm_memory_limit = Config::parse_number(value)?,
+            // with dbfilename and SAVE it would write files anywhere
+            "dir" => return Err("ERR CONFIG SET failed (possibly related to argument 'dir') - can't set protected config".to_string()),
+            "dbfilename" => {
+                if value.contains('/') {
+                    return Err("ERR dbfilename can't be a path, just a filename".to_string());
+                }
+                self.dbfilename = value.to_string();
+            }
+            "save" => self.save_params = Config::parse_save_params(value)?,
+            "stop-writes-on-bgsave-error" => self.stop_writes_on_bgsave_error = Config::parse_yes_no(value)?,
+            "appendonly" => self.appendonly = Config::parse_yes_no(value)?,
+            "appendfilename" => {
+                if value.contains('/') {
+                    return Err("ERR appendfilename can't be a path, just a filename".to_string());
+                }
+                self.appendfilename = value.to_string();
+            }
+            "appendfsync" => {
+                self.appendfsync = match AppendFsync::parse(value) {
+                    Some(fsync) => fsync,
+                    None => return Err("ERR argument(s) must be one of the following: always, everysec, no".to_string()),
+                };
+            }
+            "aof-load-truncated" => self.aof_load_truncated = Config::parse_yes_no(value)?,
+            // the files already written are where they are
+            "appenddirname" => return Err("ERR CONFIG SET failed (possibly related to argument 'appenddirname') - can't set immutable config".to_string()),
+            "auto-aof-rewrite-percentage" => self.auto_aof_rewrite_percentage = Config::parse_number(value)?,
+            "auto-aof-rewrite-min-size" => self.auto_aof_rewrite_min_size = Config::parse_memory(value)?,
+            "enable-module-command" => return Err("ERR CONFIG SET failed (possibly related to argument 'enable-module-command') - can't set protected config".to_string()),
+            _ => return Err(format!("ERR Unknown option or number of arguments for CONFIG SET - '{}'", name)),
+        }
+        return Ok(());
+    }
+
+    // whether a client, connected locally or not, may load and unload modules
+    pub fn check_module_command(&self, local:bool) -> Result<(), String> {
+        return match self.enable_module_command {
+            EnableCommand::Yes => Ok(()),
+            EnableCommand::Local if local => Ok(()),
+            _ => Err("ERR MODULE command not allowed. If the enable-module-command option is set to \"local\", you can run it from a local connection, otherwise you need to set this option in the configuration file, and then restart the server.".to_string()),
+        };
+    }
+
+    fn yes_no(b:bool) -> String {
+        return (if b {"yes"} else {"no"}).to_string();
+    }
+
+    fn parse_number<T:FromStr>(value:&str) -> Result<T, String> {
+        return value.parse().map_err(|_| "ERR argument couldn't be parsed into an integer".to_string());
+    }
+
+    // a number of bytes, with an optional unit: k, m and g are powers of
+    // 1000, kb, mb and gb powers of 1024
+    fn parse_memory(value:&str) -> Result<u64, String> {
+        let lower = value.to_lowercase();
+        let digits = lower.trim_end_matches(|c:char| c.is_alphabetic());
+        let unit = match &lower[digits.len()..] {
+            "" | "b" => 1,
+            "k" => 1000,
+            "kb" => 1024,
+            "m" => 1000 * 1000,
+            "mb" => 1024 * 1024,
+            "g" => 1000 * 1000 * 1000,
+            "gb" => 1024 * 1024 * 1024,
+            _ => return Err("ERR argument must be a memory value".to_string()),
+        };
+        return match digits.parse::<u64>().ok().and_then(|n| n.checked_mul(unit)) {
+            Some(n) => Ok(n),
+            None => Err("ERR argument must be a memory value".to_string()),
+        };
+    }
+
+    // pairs of seconds and changes, or nothing to disable saving
+    fn parse_save_params(value:&str) -> Result<Vec<(u64, u64)>, String> {
+        let numbers = value.split_whitespace().map(|n| n.parse::<u64>()).collect::<Result<Vec<u64>, _>>();
+        return match numbers {
+            Ok(ref numbers) if numbers.len() % 2 == 0 => Ok(numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect()),
+            _ => Err("ERR Invalid save parameters".to_string()),
+        };
+    }
+
+    fn parse_yes_no(value:&str) -> Result<bool, String> {
+        return match value.to_lowercase().as_str() {
+            "yes" => Ok(true),
+            "no" => Ok(false),
+            _ => Err("ERR argument must be 'yes' or 'no'".to_string()),
+        };
+    }
+}
+
+#[test]
+fn test_config() {
+    let mut config = Config::new();
+    assert_eq!(config.get("lazyfree-lazy-expire"), Some("no".to_string()));
+    assert_eq!(config.set("lazyfree-lazy-expire", "yes"), Ok(()));
+    assert_eq!(config.get("LAZYFREE-LAZY-EXPIRE"), Some("yes".to_string()));
+    assert!(config.set("lazyfree-lazy-expire", "maybe").is_err());
+    assert!(config.set("no-such-option", "yes").is_err());
+    assert_eq!(config.get("notify-keyspace-events"), Some("".to_string()));
+    assert_eq!(config.set("notify-keyspace-events", "Elx"), Ok(()));
+    assert_eq!(config.get("notify-keyspace-events"), Some("lxE".to_string()));
+    assert!(config.set("notify-keyspace-events", "Q").is_err());
+    assert_eq!(config.set("lua-time-limit", "100"), Ok(()));
+    assert_eq!(config.get("busy-reply-threshold"), Some("100".to_string()));
+    assert!(config.set("busy-reply-threshold", "soon").is_err());
+    assert_eq!(config.set("wasm-memory-limit", "1048576"), Ok(()));
+    assert_eq!(config.wasm_memory_limit, 1 << 20);
+    assert_eq!(config.get("dbfilename"), Some("dump.rdb".to_string()));
+    assert!(config.set("dbfilename", "/tmp/x.rdb").is_err());
+    assert!(config.set("dir", "/tmp").is_err());
+    assert_eq!(config.dir, ".");
+    assert_eq!(config.get("save"), Some("3600 1 300 100 60 10000".to_string()));
+    assert_eq!(config.set("save", "900 1 60 5"), Ok(()));
+    assert_eq!(config.save_params, vec![(900, 1), (60, 5)]);
+    assert!(config.set("save", "900").is_err());
+    assert_eq!(config.set("save", ""), Ok(()));
+    assert_eq!(config.get("save"), Some("".to_string()));
+    assert_eq!(config.get("appendfsync"), Some("everysec".to_string()));
+    assert_eq!(config.set("appendfsync", "ALWAYS"), Ok(()));
+    assert_eq!(config.appendfsync, AppendFsync::Always);
+    assert!(config.set("appendfsync", "sometimes").is_err());
+    assert!(config.set("appendfilename", "../log.aof").is_err());
+    assert_eq!(config.get("aof-load-truncated"), Some("yes".to_string()));
+    assert!(config.set("appenddirname", "elsewhere").is_err());
+    assert_eq!(config.get("auto-aof-rewrite-min-size"), Some("67108864".to_string()));
+    assert_eq!(config.set("auto-aof-rewrite-min-size", "1kb"), Ok(()));
+    assert_eq!(config.auto_aof_rewrite_min_size, 1024);
+    assert_eq!(config.set("auto-aof-rewrite-min-size", "2M"), Ok(()));
+    assert_eq!(config.auto_aof_rewrite_min_size, 2000000);
+    assert!(config.set("auto-aof-rewrite-min-size", "lots").is_err());
+    assert!(config.set("auto-aof-rewrite-percentage", "-1").is_err());
+    // modules can only be loaded by clients once that's enabled at startup
+    assert_eq!(config.get("enable-module-command"), Some("no".to_string()));
+    assert!(config.check_module_command(true).unwrap_err().starts_with("ERR MODULE command not allowed."));
+    assert!(config.set("enable-module-command", "yes").is_err());
+    config.enable_module_command = EnableCommand::parse("local").unwrap();
+    assert_eq!(config.check_module_command(true), Ok(()));
+    assert!(config.check_module_command(false).is_err());
+    config.enable_module_command = EnableCommand::Yes;
+    assert_eq!(config.check_module_command(false), Ok(()));
+    assert_eq!(EnableCommand::parse("sometimes"), None);
+}
@@ -0,0 +1,42 @@
+// CRC-64/Jones as used by redis for DUMP payloads and RDB files: reflected
+// polynomial 0xad93d23594c935a9, no initial value or final xor
+
+const POLY:u64 = 0x95ac9329ac4bc9b5;
+
+pub struct Crc64 {
+    table:[u64; 256],
+}
+
+impl Crc64 {
+    pub fn new() -> Crc64 {
+        let mut table = [0u64; 256];
+        for i in 0..256 {
+            let mut crc = i as u64;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {(crc >> 1) ^ POLY} else {crc >> 1};
+            }
+            table[i] = crc;
+        }
+        return Crc64 {
+            table: table,
+        };
+    }
+
+    pub fn update(&self, crc:u64, data:&[u8]) -> u64 {
+        let mut crc = crc;
+        for &b in data {
+            crc = self.table[((crc ^ b as u64) & 0xff) as usize] ^ (crc >> 8);
+        }
+        return crc;
+    }
+}
+
+pub fn crc64(data:&[u8]) -> u64 {
+    return Crc64::new().update(0, data);
+}
+
+#[test]
+fn test_crc64() {
+    assert_eq!(crc64(b"123456789"), 0xe9c6d914c4b8d9ca);
+    assert_eq!(crc64(b""), 0);
+}
@@ -1,28 +1,505 @@
+use std::cmp::Ordering;
 use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::mem;
 use std::ptr::null_mut;
+use indexmap::IndexMap;
 use libc::{timeval, gettimeofday, time_t, suseconds_t};
-use rustis::command::{Command, Return};
-use rustis::key::{ExpireTime, Key};
+use rustis::binary::{byte_len, bytes_to_string, string_to_bytes};
+use rustis::command::{ClaimOptions, Command, GeoFrom, GeoSearchOptions, GeoShape, RestorePolicy, Return, SortOptions, StreamTrim};
+use rustis::geo;
+use rustis::stream::{self, Claim, ClaimResult, ConsumerGroup, Stream, StreamId, TrimTo, STREAM_NODE_MAX_ENTRIES};
+use rustis::config::Config;
+use rustis::key::{ExpireTime, Key, KeyMeta, now_ms};
+use rustis::hyperloglog::{self, HyperLogLog};
+use rustis::rdb;
+use rustis::snapshot::{SnapshotWriter, TRIGGERS_AUX};
+use rustis::lazyfree::{LazyFree, LAZYFREE_THRESHOLD, free_effort};
+use rustis::notify;
+use rustis::trigger::{Trigger, Triggers};
 use rustis::value::Value;
+use rustis::zset::SortedSet;
 
 pub struct RustisDb {
-    values:HashMap<Key, Value>,
+    // indexed so RANDOMKEY can pick a key in constant time
+    values:IndexMap<Key, Value>,
     exp:BinaryHeap<ExpireTime>,
+    // absolute expiration time in ms for each volatile key; entries in `exp`
+    // that don't match this map are stale and skipped
+    expires:HashMap<Key, u64>,
+    meta:HashMap<Key, KeyMeta>,
+    rng:u64,
+    lazyfree:Option<LazyFree>,
+    lazy_user_del:bool,
+    lazy_expire:bool,
+    notify_flags:u32,
+    // classes native modules subscribed to, recorded whatever the config
+    module_event_classes:u32,
+    // keyspace events not yet published, as (class, event, key)
+    events:Vec<(u32, String, Key)>,
+    // how many clients WATCH each key, and the version of each watched key,
+    // which changes whenever the key does
+    watched:HashMap<Key, usize>,
+    versions:HashMap<Key, u64>,
+    version:u64,
+    triggers:Triggers,
+    // set while trigger commands run, which don't fire triggers themselves
+    firing:bool,
+    // how many changes were made, for the save rules
+    dirty:u64,
+    // with the append only file on, the commands to log for the changes
+    // made, as their arguments
+    propagate:bool,
+    propagated:Vec<Vec<String>>,
 }
 
 impl RustisDb {
     pub fn new() -> RustisDb {
         return RustisDb {
-            values: HashMap::with_capacity(1024),
+            values: IndexMap::with_capacity(1024),
             exp: BinaryHeap::with_capacity(1024),
+            expires: HashMap::new(),
+            meta: HashMap::with_capacity(1024),
+            rng: now_ms() | 1,
+            lazyfree: None,
+            lazy_user_del: false,
+            lazy_expire: false,
+            notify_flags: 0,
+            module_event_classes: 0,
+            events: Vec::new(),
+            watched: HashMap::new(),
+            versions: HashMap::new(),
+            version: 0,
+            triggers: Triggers::new(),
+            firing: false,
+            dirty: 0,
+            propagate: false,
+            propagated: Vec::new(),
         };
     }
 
+    pub fn configure(&mut self, config:&Config) {
+        self.lazy_user_del = config.lazyfree_lazy_user_del;
+        self.lazy_expire = config.lazyfree_lazy_expire;
+        self.notify_flags = config.notify_keyspace_events;
+        self.propagate = config.appendonly;
+    }
+
+    // records a keyspace event if its class is enabled; every event but a
+    // miss is a change to its key
+    pub fn notify(&mut self, class:u32, event:&str, key:&Key) {
+        if class != notify::KEY_MISS {
+            self.modified(key);
+            self.dirty += 1;
+        }
+        if self.records(class) {
+            self.events.push((class, event.to_string(), key.clone()));
+        }
+    }
+
+    fn records(&self, class:u32) -> bool {
+        return notify::enabled(self.notify_flags, class) || self.module_event_classes & class != 0 || !self.triggers.is_empty();
+    }
+
+    // the changes made since the database was created
+    pub fn changes(&self) -> u64 {
+        return self.dirty;
+    }
+
+    // the commands to log since the last call, for the append only file
+    pub fn take_propagated(&mut self) -> Vec<Vec<String>> {
+        return mem::replace(&mut self.propagated, Vec::new());
+    }
+
+    pub fn set_module_event_classes(&mut self, classes:u32) {
+        self.module_event_classes = classes;
+    }
+
+    fn modified(&mut self, key:&Key) {
+        if self.watched.contains_key(key) {
+            self.version += 1;
+            self.versions.insert(key.clone(), self.version);
+        }
+    }
+
+    // starts tracking a key for a WATCHing client, returning its version
+    pub fn watch(&mut self, key:&Key) -> u64 {
+        *self.watched.entry(key.clone()).or_insert(0) += 1;
+        return self.key_version(key);
+    }
+
+    pub fn unwatch(&mut self, key:&Key) {
+        let last = match self.watched.get_mut(key) {
+            Some(n) => {
+                *n -= 1;
+                *n == 0
+            }
+            None => false,
+        };
+        if last {
+            self.watched.remove(key);
+            self.versions.remove(key);
+        }
+    }
+
+    // expires keys first, since a watched key expiring counts as a change
+    pub fn key_version(&mut self, key:&Key) -> u64 {
+        self.gc();
+        return self.versions.get(key).cloned().unwrap_or(0);
+    }
+
+    // SWAPDB exchanges the data of two databases; watches stay with their
+    // database, and a watched key changes if it exists on either side
+    pub fn swap_data(a:&mut RustisDb, b:&mut RustisDb) {
+        a.gc();
+        b.gc();
+        let in_either = |db:&RustisDb, other:&RustisDb| db.watched.keys().filter(|key| {
+            db.values.contains_key(*key) || other.values.contains_key(*key)
+        }).cloned().collect::<Vec<Key>>();
+        for key in in_either(a, b) {
+            a.modified(&key);
+        }
+        for key in in_either(b, a) {
+            b.modified(&key);
+        }
+        mem::swap(&mut a.values, &mut b.values);
+        mem::swap(&mut a.exp, &mut b.exp);
+        mem::swap(&mut a.expires, &mut b.expires);
+        mem::swap(&mut a.meta, &mut b.meta);
+        a.dirty += 1;
+    }
+
+    // flushing changes every watched key that exists
+    fn flushed(&mut self) {
+        let changed = self.watched.keys().filter(|key| self.values.contains_key(*key)).cloned().collect::<Vec<Key>>();
+        for key in changed {
+            self.modified(&key);
+        }
+        self.dirty += self.values.len() as u64;
+    }
+
+    // the events recorded since the last call, for the server to publish
+    pub fn take_events(&mut self) -> Vec<(u32, String, Key)> {
+        return mem::replace(&mut self.events, Vec::new());
+    }
+
+    // without a background thread, values are always freed inline
+    pub fn set_lazyfree(&mut self, lazyfree:LazyFree) {
+        self.lazyfree = Some(lazyfree);
+    }
+
+    fn free(&self, value:Value, lazy:bool) {
+        match self.lazyfree {
+            Some(ref lazyfree) if lazy && free_effort(&value) > LAZYFREE_THRESHOLD => lazyfree.free(value),
+            _ => drop(value),
+        }
+    }
+
     pub fn gc(&mut self) {
-        // TODO: remove any keys that have expired
+        let now = now_ms();
+        loop {
+            match self.exp.peek() {
+                Some(e) if e.expire_at <= now => {}
+                _ => break,
+            }
+            let e = self.exp.pop().unwrap();
+            if self.expires.get(&e.key) == Some(&e.expire_at) {
+                self.expires.remove(&e.key);
+                self.meta.remove(&e.key);
+                if let Some(value) = self.values.swap_remove(&e.key) {
+                    let lazy = self.lazy_expire;
+                    self.free(value, lazy);
+                    self.notify(notify::EXPIRED, "expired", &e.key);
+                    if self.propagate {
+                        self.propagated.push(vec!["DEL".to_string(), e.key.clone()]);
+                    }
+                }
+            }
+        }
+    }
+
+    // expires the keys whose time has passed without waiting for a command
+    // to look them up, firing the triggers their events match
+    pub fn active_expire(&mut self) {
+        let first_event = self.events.len();
+        self.gc();
+        self.fire_triggers(first_event);
+    }
+
+    // when the next volatile key expires, or an earlier time if its TTL
+    // changed since
+    pub fn next_expire(&self) -> Option<u64> {
+        return self.exp.peek().map(|e| e.expire_at);
+    }
+
+    pub fn contains_key(&mut self, key:&Key) -> bool {
+        self.gc();
+        return self.values.contains_key(key);
+    }
+
+    // returns a copy of a key's value along with its absolute expiration time
+    pub fn get_entry(&mut self, key:&Key) -> Result<Option<(Value, Option<u64>)>, String> {
+        self.gc();
+        return match self.values.get(key) {
+            Some(v) => Ok(Some((v.try_clone()?, self.expires.get(key).cloned()))),
+            None => Ok(None),
+        };
+    }
+
+    pub fn remove_entry(&mut self, key:&Key) -> Option<(Value, Option<u64>)> {
+        self.gc();
+        self.meta.remove(key);
+        return match self.values.swap_remove(key) {
+            Some(v) => Some((v, self.expires.remove(key))),
+            None => None,
+        };
+    }
+
+    pub fn get_value(&mut self, key:&Key) -> Option<&Value> {
+        self.gc();
+        return self.values.get(key);
+    }
+
+    // a value for a native module to change in place, which counts as a
+    // change to its key
+    pub fn get_value_mut(&mut self, key:&Key) -> Option<&mut Value> {
+        self.gc();
+        if self.values.contains_key(key) {
+            self.modified(key);
+            self.dirty += 1;
+        }
+        return self.values.get_mut(key);
+    }
+
+    // replaces a key's value for a native module, dropping its TTL like SET
+    pub fn store_value(&mut self, key:Key, value:Value) {
+        self.gc();
+        self.modified(&key);
+        self.dirty += 1;
+        self.set_expire(&key, None);
+        if !self.meta.contains_key(&key) {
+            self.meta.insert(key.clone(), KeyMeta::new(now_ms()));
+        }
+        if let Some(old) = self.values.insert(key, value) {
+            self.free(old, false);
+        }
+    }
+
+    pub fn insert_entry(&mut self, key:Key, value:Value, expire_at:Option<u64>) {
+        self.set_expire(&key, expire_at);
+        self.values.insert(key, value);
+    }
+
+    // writes the database's live keys and its triggers, if it has any
+    pub fn snapshot(&self, index:usize, writer:&mut SnapshotWriter) -> Result<(), String> {
+        let now = now_ms();
+        let live = |key:&Key| self.expires.get(key).map_or(true, |&at| at > now);
+        let size = self.values.keys().filter(|key| live(key)).count();
+        if size == 0 && self.triggers.is_empty() {
+            return Ok(());
+        }
+        let expires = self.expires.values().filter(|&&at| at > now).count();
+        writer.select_db(index, size, expires);
+        if !self.triggers.is_empty() {
+            writer.aux(TRIGGERS_AUX, &self.triggers.dump());
+        }
+        for (key, value) in self.values.iter() {
+            if live(key) {
+                writer.entry(key, value, self.expires.get(key).cloned())?;
+            }
+        }
+        return Ok(());
+    }
+
+    // restores the triggers saved in a snapshot
+    pub fn load_triggers(&mut self, payload:&[u8]) -> Result<(), String> {
+        return self.triggers.restore(payload, RestorePolicy::Flush);
+    }
+
+    fn set_expire(&mut self, key:&Key, expire_at:Option<u64>) {
+        match expire_at {
+            Some(at) => {
+                self.expires.insert(key.clone(), at);
+                self.exp.push(ExpireTime::new(key.clone(), at));
+            }
+            None => {
+                self.expires.remove(key);
+            }
+        }
+    }
+
+    // xorshift64*, good enough for sampling keys and LFU increments
+    fn random(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        return self.rng.wrapping_mul(0x2545F4914F6CDD1D);
+    }
+
+    fn touch(&mut self, key:&Key, now:u64) {
+        if !self.values.contains_key(key) {
+            return;
+        }
+        let r = (self.random() >> 11) as f64 / (1u64 << 53) as f64;
+        if let Some(meta) = self.meta.get_mut(key) {
+            meta.access(now, r);
+            return;
+        }
+        let mut meta = KeyMeta::new(now);
+        meta.access(now, r);
+        self.meta.insert(key.clone(), meta);
     }
 
     pub fn run_command(&mut self, cmd:Command) -> Return {
+        return self.run_command_touching(cmd, true);
+    }
+
+    // runs a command without updating the access time and frequency of the
+    // keys it looks up, for clients in CLIENT NO-TOUCH mode
+    pub fn run_command_untouched(&mut self, cmd:Command) -> Return {
+        return self.run_command_touching(cmd, false);
+    }
+
+    fn run_command_touching(&mut self, cmd:Command, touch:bool) -> Return {
+        // keys expiring now fire triggers too
+        let first_event = self.events.len();
+        self.gc();
+        let now = now_ms();
+        let keys = cmd.keys().into_iter().cloned().collect::<Vec<Key>>();
+        let touch = match cmd {
+            Command::Touch {..} => true,
+            Command::Exists {..} | Command::Type {..} | Command::Ttl {..} | Command::Pttl {..} |
+            Command::ObjectEncoding {..} | Command::ObjectFreq {..} |
+            Command::ObjectIdleTime {..} | Command::ObjectRefCount {..} => false,
+            _ => touch,
+        };
+        if touch {
+            for key in keys.iter() {
+                self.touch(key, now);
+            }
+        }
+        let missing = keys.iter().filter(|key| !self.values.contains_key(*key)).cloned().collect::<Vec<Key>>();
+        let read_only = !cmd.is_write() && !match cmd {Command::Exists {..} => true, _ => false};
+        let mark = self.events.len();
+        // commands run by triggers are logged as part of the one firing them
+        let propagated = if self.propagate && !self.firing && cmd.is_write() {Some((cmd.clone(), self.dirty))} else {None};
+        let result = self.execute(cmd);
+        if let Some((cmd, dirty)) = propagated {
+            if self.dirty != dirty {
+                self.propagate_command(cmd, &result);
+            }
+        }
+        // keys created by the command start out with fresh metadata
+        for key in keys {
+            if self.values.contains_key(&key) && !self.meta.contains_key(&key) {
+                self.meta.insert(key, KeyMeta::new(now));
+            }
+        }
+        // a key's `new` event comes before the event of the command adding it
+        let mut created = Vec::new();
+        for key in missing {
+            if self.values.contains_key(&key) {
+                if self.records(notify::NEW) {
+                    created.push((notify::NEW, "new".to_string(), key));
+                }
+            } else if read_only {
+                self.notify(notify::KEY_MISS, "keymiss", &key);
+            }
+        }
+        let after = self.events.split_off(mark);
+        self.events.extend(created);
+        self.events.extend(after);
+        self.fire_triggers(first_event);
+        return result;
+    }
+
+    // logs a command that changed data, rewritten so that replaying it has
+    // the same effect later: TTLs become absolute and generated stream ids,
+    // claimed entries and delivery times are spelled out
+    fn propagate_command(&mut self, cmd:Command, result:&Return) {
+        let commands = match cmd {
+            Command::Set {key, value, exp: Some(_)} => {
+                let at = self.expires.get(&key).cloned();
+                let mut commands = vec![Command::Set {key: key.clone(), value: value, exp: None}];
+                if let Some(at) = at {
+                    commands.push(Command::PexpireAt {key: key, timestamp: at as i64});
+                }
+                commands
+            }
+            Command::Expire {key, ..} | Command::Pexpire {key, ..} | Command::PexpireAt {key, ..} => {
+                match self.expires.get(&key) {
+                    Some(&at) if self.values.contains_key(&key) => vec![Command::PexpireAt {key: key, timestamp: at as i64}],
+                    _ => vec![Command::Del {keys: vec![key]}],
+                }
+            }
+            Command::Restore {key, payload, replace, idletime, freq, ..} => {
+                if self.values.contains_key(&key) {
+                    let ttl = self.expires.get(&key).cloned().unwrap_or(0) as i64;
+                    vec![Command::Restore {key: key, ttl: ttl, payload: payload, replace: replace, absttl: true, idletime: idletime, freq: freq}]
+                } else {
+                    vec![Command::Del {keys: vec![key]}]
+                }
+            }
+            Command::XAdd {key, nomkstream, trim, fields, ..} => match result {
+                &Return::ValueReturn(Value::StrValue(ref id)) => vec![Command::XAdd {key: key, nomkstream: nomkstream, trim: trim, id: id.clone(), fields: fields}],
+                _ => vec![],
+            },
+            Command::XClaim {key, group, consumer, options, ..} => {
+                let ids = match result {
+                    &Return::ValueReturn(Value::ArrayValue(ref claimed)) => RustisDb::entry_ids(claimed),
+                    _ => vec![],
+                };
+                let options = ClaimOptions {idle: None, time: Some(now_ms() as i64), retrycount: None, force: false, justid: options.justid, lastid: options.lastid};
+                vec![Command::XClaim {key: key, group: group, consumer: consumer, min_idle: 0, ids: ids, options: options}]
+            }
+            Command::XAutoClaim {key, group, consumer, justid, ..} => {
+                let ids = match result {
+                    &Return::ValueReturn(Value::ArrayValue(ref reply)) if reply.len() == 3 => match (&reply[1], &reply[2]) {
+                        (&Value::ArrayValue(ref claimed), &Value::ArrayValue(ref deleted)) => {
+                            RustisDb::entry_ids(claimed).into_iter().chain(RustisDb::entry_ids(deleted)).collect()
+                        }
+                        _ => vec![],
+                    },
+                    _ => vec![],
+                };
+                let options = ClaimOptions {idle: None, time: Some(now_ms() as i64), retrycount: None, force: false, justid: justid, lastid: None};
+                vec![Command::XClaim {key: key, group: group, consumer: consumer, min_idle: 0, ids: ids, options: options}]
+            }
+            cmd => vec![cmd],
+        };
+        for cmd in commands {
+            if let Some(args) = cmd.args() {
+                self.propagated.push(args);
+            }
+        }
+    }
+
+    // the ids of stream entries in a reply, which are either ids or entries
+    fn entry_ids(reply:&[Value]) -> Vec<String> {
+        return reply.iter().filter_map(|v| match v {
+            &Value::StrValue(ref id) => Some(id.clone()),
+            &Value::ArrayValue(ref entry) => match entry.first() {
+                Some(&Value::StrValue(ref id)) => Some(id.clone()),
+                _ => None,
+            },
+            _ => None,
+        }).collect();
+    }
+
+    // runs the commands of the triggers the events since `first` fire, as
+    // part of the command that caused them
+    fn fire_triggers(&mut self, first:usize) {
+        if self.firing || self.triggers.is_empty() {
+            return;
+        }
+        let commands = self.events[first..].iter().flat_map(|&(_, ref event, ref key)| self.triggers.fired(event, key)).collect::<Vec<Command>>();
+        self.firing = true;
+        for cmd in commands {
+            self.run_command(cmd);
+        }
+        self.firing = false;
+    }
+
+    fn execute(&mut self, cmd:Command) -> Return {
         match cmd {
             Command::Get {key} => {
                 let value:Value = match self.values.get_mut(&key) {
@@ -32,14 +509,17 @@ impl RustisDb {
                 return Return::ValueReturn(value);
             }
             Command::Set {key, value, exp} => {
-                // TODO: remove expiration if it exists
-                self.values.insert(key, value);
-                match exp {
-                    Some(x) => {
-                        // TODO: add expiration time
-                    }
-                    None => {}
+                // expiration times have to fit in a signed 64 bit number
+                let expire_at = match exp.map(|ms| now_ms().checked_add(ms)) {
+                    Some(Some(at)) if at <= i64::max_value() as u64 => Some(at),
+                    Some(_) => return Return::Error("ERR invalid expire time in 'set' command".to_string()),
+                    None => None,
+                };
+                self.notify(notify::STRING, "set", &key);
+                if expire_at.is_some() {
+                    self.notify(notify::GENERIC, "expire", &key);
                 }
+                self.insert_entry(key, value, expire_at);
                 return Return::Ok;
             }
             Command::Append {key, value} => {
@@ -55,18 +535,19 @@ impl RustisDb {
                         value
                     }
                 };
-                let return_value = Return::ValueReturn(Value::IntValue(new_value.len() as i64));
+                let return_value = Return::ValueReturn(Value::IntValue(byte_len(&new_value) as i64));
+                self.notify(notify::STRING, "append", &key);
                 self.values.insert(key, Value::StrValue(new_value));
                 return return_value;
             }
             Command::Incr {key} => {
-                return self.run_command(Command::IncrBy {key: key, increment: 1});
+                return self.execute(Command::IncrBy {key: key, increment: 1});
             }
             Command::Decr {key} => {
-                return self.run_command(Command::IncrBy {key: key, increment: -1});
+                return self.execute(Command::IncrBy {key: key, increment: -1});
             }
             Command::DecrBy {key, decrement} => {
-                return self.run_command(Command::IncrBy {key: key, increment: -decrement});
+                return self.execute(Command::IncrBy {key: key, increment: -decrement});
             }
             Command::IncrBy {key, increment} => {
                 let new_value = match self.values.get(&key) {
@@ -86,6 +567,7 @@ impl RustisDb {
                     }
                 };
                 let return_value = new_value.clone();
+                self.notify(notify::STRING, "incrby", &key);
                 self.values.insert(key, new_value);
                 return Return::ValueReturn(return_value);
             }
@@ -107,6 +589,7 @@ impl RustisDb {
                     }
                 };
                 let return_value = new_value.clone();
+                self.notify(notify::STRING, "incrbyfloat", &key);
                 self.values.insert(key, new_value);
                 return Return::ValueReturn(return_value);
             }
@@ -138,72 +621,74 @@ impl RustisDb {
                 }
             }
             Command::Lpop {key} => {
-                match self.values.get_mut(&key) {
-                    Some(&mut Value::ListValue(ref mut l)) => {
-                        let popped = l.pop_front();
-                        match popped {
-                            Some(x) => {
-                                return Return::ValueReturn(Value::StrValue(x));
-                            }
-                            None => {
-                                return Return::Error("ERR list is empty".to_string());
-                            }
-                        }
-                    }
+                let popped = match self.values.get_mut(&key) {
+                    Some(&mut Value::ListValue(ref mut l)) => l.pop_front(),
                     _ => {
                         return Return::Error("WRONGTYPE not a list".to_string());
                     }
+                };
+                match popped {
+                    Some(x) => {
+                        self.notify(notify::LIST, "lpop", &key);
+                        return Return::ValueReturn(Value::StrValue(x));
+                    }
+                    None => {
+                        return Return::Error("ERR list is empty".to_string());
+                    }
                 }
             }
             Command::Rpop {key} => {
-                match self.values.get_mut(&key) {
-                    Some(&mut Value::ListValue(ref mut l)) => {
-                        let popped = l.pop_back();
-                        match popped {
-                            Some(x) => {
-                                return Return::ValueReturn(Value::StrValue(x));
-                            }
-                            None => {
-                                return Return::Error("ERR list is empty".to_string());
-                            }
-                        }
-                    }
+                let popped = match self.values.get_mut(&key) {
+                    Some(&mut Value::ListValue(ref mut l)) => l.pop_back(),
                     _ => {
                         return Return::Error("WRONGTYPE not a list".to_string());
                     }
+                };
+                match popped {
+                    Some(x) => {
+                        self.notify(notify::LIST, "rpop", &key);
+                        return Return::ValueReturn(Value::StrValue(x));
+                    }
+                    None => {
+                        return Return::Error("ERR list is empty".to_string());
+                    }
                 }
             }
             Command::Lpush {key, values} => {
                 if !self.values.contains_key(&key) {
                     self.values.insert(key.clone(), Value::ListValue(VecDeque::new()));
                 }
-                match self.values.get_mut(&key) {
+                let len = match self.values.get_mut(&key) {
                     Some(&mut Value::ListValue(ref mut l)) => {
                         for val in values {
                             l.push_front(val);
                         }
-                        return Return::ValueReturn(Value::IntValue(l.len() as i64));
+                        l.len()
                     }
                     _ => {
                         return Return::Error("WRONGTYPE not a list".to_string());
                     }
-                }
+                };
+                self.notify(notify::LIST, "lpush", &key);
+                return Return::ValueReturn(Value::IntValue(len as i64));
             }
             Command::Rpush {key, values} => {
                 if !self.values.contains_key(&key) {
                     self.values.insert(key.clone(), Value::ListValue(VecDeque::new()));
                 }
-                match self.values.get_mut(&key) {
+                let len = match self.values.get_mut(&key) {
                     Some(&mut Value::ListValue(ref mut l)) => {
                         for val in values {
                             l.push_back(val);
                         }
-                        return Return::ValueReturn(Value::IntValue(l.len() as i64));
+                        l.len()
                     }
                     _ => {
                         return Return::Error("WRONGTYPE not a list".to_string());
                     }
-                }
+                };
+                self.notify(notify::LIST, "rpush", &key);
+                return Return::ValueReturn(Value::IntValue(len as i64));
             }
             Command::Lset {key, index, value} => {
                 match self.values.get_mut(&key) {
@@ -211,7 +696,6 @@ impl RustisDb {
                         match RustisDb::list_index(&l, index) {
                             Some(i) => {
                                 l[i] = value;
-                                return Return::Ok;
                             }
                             None => {
                                 return Return::Error("ERR index out of range".to_string());
@@ -222,12 +706,14 @@ impl RustisDb {
                         return Return::Error("WRONGTYPE not a list".to_string());
                     }
                 }
+                self.notify(notify::LIST, "lset", &key);
+                return Return::Ok;
             }
             Command::Sadd {key, members} => {
                 if !self.values.contains_key(&key) {
                     self.values.insert(key.clone(), Value::SetValue(HashSet::new()));
                 }
-                match self.values.get_mut(&key) {
+                let added = match self.values.get_mut(&key) {
                     Some(&mut Value::SetValue(ref mut s)) => {
                         let mut added = 0;
                         for member in members {
@@ -236,12 +722,16 @@ impl RustisDb {
                                 added += 1;
                             }
                         }
-                        return Return::ValueReturn(Value::IntValue(added));
+                        added
                     }
                     _ => {
                         return Return::Error("WRONGTYPE not a set".to_string());
                     }
+                };
+                if added > 0 {
+                    self.notify(notify::SET, "sadd", &key);
                 }
+                return Return::ValueReturn(Value::IntValue(added));
             }
             Command::Scard {key} => {
                 match self.values.get(&key) {
@@ -264,7 +754,7 @@ impl RustisDb {
                 }
             }
             Command::Srem {key, members} => {
-                match self.values.get_mut(&key) {
+                let removed = match self.values.get_mut(&key) {
                     Some(&mut Value::SetValue(ref mut s)) => {
                         let mut removed = 0;
                         for member in members {
@@ -273,22 +763,43 @@ impl RustisDb {
                                 removed += 1;
                             }
                         }
-                        return Return::ValueReturn(Value::IntValue(removed));
+                        removed
                     }
                     _ => {
                         return Return::Error("WRONGTYPE not a set".to_string());
                     }
+                };
+                if removed > 0 {
+                    self.notify(notify::SET, "srem", &key);
                 }
+                return Return::ValueReturn(Value::IntValue(removed));
             }
             Command::DbSize => {
                 return Return::ValueReturn(Value::IntValue(self.values.len() as i64));
             }
             Command::Del {keys} => {
-                // TODO: remove expiration if it exists
+                if self.lazy_user_del {
+                    return self.execute(Command::Unlink {keys: keys});
+                }
                 let mut i = 0;
                 for key in keys.iter() {
-                    match self.values.remove(key) {
+                    match self.remove_entry(key) {
                         Some(_) => {
+                            self.notify(notify::GENERIC, "del", key);
+                            i += 1;
+                        }
+                        None => {}
+                    }
+                }
+                return Return::ValueReturn(Value::IntValue(i));
+            }
+            Command::Unlink {keys} => {
+                let mut i = 0;
+                for key in keys.iter() {
+                    match self.remove_entry(key) {
+                        Some((value, _)) => {
+                            self.free(value, true);
+                            self.notify(notify::GENERIC, "del", key);
                             i += 1;
                         }
                         None => {}
@@ -297,7 +808,7 @@ impl RustisDb {
                 return Return::ValueReturn(Value::IntValue(i));
             }
             Command::Ping {message} => {
-                return self.run_command(Command::Echo {message: message});
+                return self.execute(Command::Echo {message: message.unwrap_or("PONG".to_string())});
             }
             Command::Echo {message} => {
                 return Return::ValueReturn(Value::StrValue(message));
@@ -313,81 +824,1334 @@ impl RustisDb {
                     Some(&Value::ListValue(_)) => Return::ValueReturn(Value::StrValue("list".to_string())),
                     Some(&Value::SortedSetValue(_)) => Return::ValueReturn(Value::StrValue("zset".to_string())),
                     Some(&Value::HashValue(_)) => Return::ValueReturn(Value::StrValue("hash".to_string())),
+                    Some(&Value::StreamValue(_)) => Return::ValueReturn(Value::StrValue("stream".to_string())),
+                    Some(&Value::ModuleValue(ref v)) => Return::ValueReturn(Value::StrValue(v.type_name().to_string())),
                     _ => Return::ValueReturn(Value::Nil),
                 }
             }
-            Command::FlushDb => {
-                self.values.clear();
-                return Return::Ok;
+            Command::Rename {key, newkey} => {
+                // the value keeps its TTL, access time and frequency
+                let meta = self.meta.remove(&key);
+                match self.remove_entry(&key) {
+                    Some((value, expire_at)) => {
+                        self.remove_entry(&newkey);
+                        self.notify(notify::GENERIC, "rename_from", &key);
+                        self.notify(notify::GENERIC, "rename_to", &newkey);
+                        if let Some(meta) = meta {
+                            self.meta.insert(newkey.clone(), meta);
+                        }
+                        self.insert_entry(newkey, value, expire_at);
+                        return Return::Ok;
+                    }
+                    None => {
+                        return Return::Error("ERR no such key".to_string());
+                    }
+                }
             }
-            Command::Time => {
-                let mut t = timeval {tv_sec: 0 as time_t, tv_usec: 0 as suseconds_t};
-                unsafe {
-                    gettimeofday(&mut t, null_mut());
+            Command::RenameNx {key, newkey} => {
+                if !self.values.contains_key(&key) {
+                    return Return::Error("ERR no such key".to_string());
                 }
-                let r = Value::ArrayValue(vec![
-                    Value::IntValue(t.tv_sec as i64),
-                    Value::IntValue(t.tv_usec as i64),
-                ]);
-                return Return::ValueReturn(r);
+                if self.values.contains_key(&newkey) {
+                    return Return::ValueReturn(Value::IntValue(0));
+                }
+                return match self.execute(Command::Rename {key: key, newkey: newkey}) {
+                    Return::Ok => Return::ValueReturn(Value::IntValue(1)),
+                    r => r,
+                };
             }
-            _ => {
-                return Return::Ok;
+            Command::Copy {source, destination, db: _, replace} => {
+                if source == destination {
+                    return Return::Error("ERR source and destination objects are the same".to_string());
+                }
+                if !replace && self.values.contains_key(&destination) {
+                    return Return::ValueReturn(Value::IntValue(0));
+                }
+                match self.get_entry(&source) {
+                    Ok(Some((value, expire_at))) => {
+                        self.notify(notify::GENERIC, "copy_to", &destination);
+                        self.insert_entry(destination, value, expire_at);
+                        return Return::ValueReturn(Value::IntValue(1));
+                    }
+                    Ok(None) => {
+                        return Return::ValueReturn(Value::IntValue(0));
+                    }
+                    Err(e) => return Return::Error(e),
+                }
             }
-        }
-    }
-
-    fn list_index<T>(list:&VecDeque<T>, i:i64) -> Option<usize> {
-        let len = list.len() as i64;
-        let mut index = i;
-        if index < 0 {
-            index = len + index;
-        }
-        if index >= 0 && i < len {
-            return Some(index as usize);
-        } else {
-            return None;
-        }
-    }
-}
-
-#[test]
-fn test_dbsize() {
-    let mut db = RustisDb::new();
-    assert_eq!(db.run_command(Command::DbSize), Return::ValueReturn(Value::IntValue(0)));
-    let result = db.run_command(Command::Set {key: "test_key123".to_string(), value: Value::StrValue("abc".to_string()), exp: None});
-    assert_eq!(result, Return::Ok);
-    assert_eq!(db.run_command(Command::DbSize), Return::ValueReturn(Value::IntValue(1)));
-    let result = db.run_command(Command::Set {key: "test_key123".to_string(), value: Value::StrValue("def".to_string()), exp: None});
-    assert_eq!(result, Return::Ok);
-    assert_eq!(db.run_command(Command::DbSize), Return::ValueReturn(Value::IntValue(1)));
-    let result = db.run_command(Command::Set {key: "test_key456".to_string(), value: Value::StrValue("abc".to_string()), exp: None});
-    assert_eq!(result, Return::Ok);
-    assert_eq!(db.run_command(Command::DbSize), Return::ValueReturn(Value::IntValue(2)));
-    let result = db.run_command(Command::Del {keys: vec!["test_key456".to_string()]});
-    assert_eq!(result, Return::ValueReturn(Value::IntValue(1)));
-    assert_eq!(db.run_command(Command::DbSize), Return::ValueReturn(Value::IntValue(1)));
-}
-
-#[test]
-fn test_get_set() {
-    let mut db = RustisDb::new();
-    assert_eq!(db.run_command(Command::Get {key: "test_key123".to_string()}), Return::ValueReturn(Value::Nil));
-    let result = db.run_command(Command::Set {key: "test_key123".to_string(), value: Value::StrValue("def".to_string()), exp: None});
-    assert_eq!(result, Return::Ok);
-    assert_eq!(db.run_command(Command::Get {key: "test_key123".to_string()}), Return::ValueReturn(Value::StrValue("def".to_string())));
-}
-
-#[test]
-fn test_incr() {
-    let mut db = RustisDb::new();
-    assert_eq!(db.run_command(Command::Incr {key: "abc".to_string()}), Return::ValueReturn(Value::IntValue(1)));
-    assert_eq!(db.run_command(Command::Incr {key: "abc".to_string()}), Return::ValueReturn(Value::IntValue(2)));
-    assert_eq!(db.run_command(Command::IncrBy {key: "abc".to_string(), increment: 10}), Return::ValueReturn(Value::IntValue(12)));
-    assert_eq!(db.run_command(Command::IncrByFloat {key: "abc".to_string(), increment: 0.1}), Return::ValueReturn(Value::StrValue("12.1".to_string())));
-    db.run_command(Command::Set {key: "abc".to_string(), value: Value::StrValue("defg".to_string()), exp: None});
-    assert!(match db.run_command(Command::IncrBy {key: "abc".to_string(), increment: 10}) {
-        Return::Error(_) => true,
+            Command::Expire {key, seconds} => {
+                return match seconds.checked_mul(1000).and_then(|ms| (now_ms() as i64).checked_add(ms)) {
+                    Some(timestamp) => self.execute(Command::PexpireAt {key: key, timestamp: timestamp}),
+                    None => Return::Error("ERR invalid expire time in 'expire' command".to_string()),
+                };
+            }
+            Command::Pexpire {key, milliseconds} => {
+                return match (now_ms() as i64).checked_add(milliseconds) {
+                    Some(timestamp) => self.execute(Command::PexpireAt {key: key, timestamp: timestamp}),
+                    None => Return::Error("ERR invalid expire time in 'pexpire' command".to_string()),
+                };
+            }
+            Command::PexpireAt {key, timestamp} => {
+                if !self.values.contains_key(&key) {
+                    return Return::ValueReturn(Value::IntValue(0));
+                }
+                if timestamp <= now_ms() as i64 {
+                    self.remove_entry(&key);
+                    self.notify(notify::GENERIC, "del", &key);
+                } else {
+                    self.set_expire(&key, Some(timestamp as u64));
+                    self.notify(notify::GENERIC, "expire", &key);
+                }
+                return Return::ValueReturn(Value::IntValue(1));
+            }
+            Command::Ttl {key} => {
+                return match self.execute(Command::Pttl {key: key}) {
+                    Return::ValueReturn(Value::IntValue(ms)) if ms >= 0 => Return::ValueReturn(Value::IntValue((ms + 500) / 1000)),
+                    r => r,
+                };
+            }
+            Command::Pttl {key} => {
+                if !self.values.contains_key(&key) {
+                    return Return::ValueReturn(Value::IntValue(-2));
+                }
+                return match self.expires.get(&key) {
+                    Some(&at) => Return::ValueReturn(Value::IntValue(at.saturating_sub(now_ms()) as i64)),
+                    None => Return::ValueReturn(Value::IntValue(-1)),
+                };
+            }
+            Command::Touch {keys} => {
+                let touched = keys.iter().filter(|key| self.values.contains_key(*key)).count();
+                return Return::ValueReturn(Value::IntValue(touched as i64));
+            }
+            Command::RandomKey => {
+                if self.values.is_empty() {
+                    return Return::ValueReturn(Value::Nil);
+                }
+                let n = (self.random() % self.values.len() as u64) as usize;
+                let key = self.values.get_index(n).unwrap().0.clone();
+                return Return::ValueReturn(Value::StrValue(key));
+            }
+            Command::ObjectEncoding {key} => {
+                return match self.values.get(&key) {
+                    Some(v) => Return::ValueReturn(Value::StrValue(v.encoding().to_string())),
+                    None => Return::ValueReturn(Value::Nil),
+                };
+            }
+            Command::ObjectFreq {key} => {
+                if !self.values.contains_key(&key) {
+                    return Return::ValueReturn(Value::Nil);
+                }
+                let now = now_ms();
+                let freq = self.meta.get(&key).map(|m| m.freq(now)).unwrap_or(0);
+                return Return::ValueReturn(Value::IntValue(freq as i64));
+            }
+            Command::ObjectIdleTime {key} => {
+                if !self.values.contains_key(&key) {
+                    return Return::ValueReturn(Value::Nil);
+                }
+                let now = now_ms();
+                let idle = self.meta.get(&key).map(|m| m.idle_seconds(now)).unwrap_or(0);
+                return Return::ValueReturn(Value::IntValue(idle as i64));
+            }
+            Command::ObjectRefCount {key} => {
+                return match self.values.get(&key) {
+                    // small integers are shared objects in redis
+                    Some(&Value::IntValue(i)) if i >= 0 && i < 10000 => Return::ValueReturn(Value::IntValue(2147483647)),
+                    Some(_) => Return::ValueReturn(Value::IntValue(1)),
+                    None => Return::ValueReturn(Value::Nil),
+                };
+            }
+            Command::GeoAdd {key, nx, xx, ch, items} => {
+                if nx && xx {
+                    return Return::Error("ERR XX and NX options at the same time are not compatible".to_string());
+                }
+                for &(lon, lat, _) in items.iter() {
+                    if !geo::valid_lon_lat(lon, lat) {
+                        return Return::Error(format!("ERR invalid longitude,latitude pair {:.6},{:.6}", lon, lat));
+                    }
+                }
+                if !self.values.contains_key(&key) {
+                    if xx {
+                        return Return::ValueReturn(Value::IntValue(0));
+                    }
+                    self.values.insert(key.clone(), Value::SortedSetValue(SortedSet::new()));
+                }
+                let (added, changed) = match self.values.get_mut(&key) {
+                    Some(&mut Value::SortedSetValue(ref mut z)) => {
+                        let (mut added, mut changed) = (0, 0);
+                        for (lon, lat, member) in items {
+                            let score = geo::encode_score(lon, lat);
+                            match z.get(&member).cloned() {
+                                Some(old) => {
+                                    if nx {
+                                        continue;
+                                    }
+                                    if old != score {
+                                        changed += 1;
+                                    }
+                                }
+                                None => {
+                                    if xx {
+                                        continue;
+                                    }
+                                    added += 1;
+                                }
+                            }
+                            z.insert(member, score);
+                        }
+                        (added, changed)
+                    }
+                    _ => {
+                        return Return::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string());
+                    }
+                };
+                if added + changed > 0 {
+                    self.notify(notify::ZSET, "zadd", &key);
+                }
+                return Return::ValueReturn(Value::IntValue(if ch {added + changed} else {added}));
+            }
+            Command::GeoPos {key, members} => {
+                let z = match self.get_zset(&key) {
+                    Ok(z) => z,
+                    Err(e) => return Return::Error(e),
+                };
+                let positions = members.iter().map(|member| match z.and_then(|z| z.get(member)) {
+                    Some(&score) => {
+                        let (lon, lat) = geo::decode_score(score);
+                        Value::ArrayValue(vec![Value::StrValue(geo::format_coordinate(lon)), Value::StrValue(geo::format_coordinate(lat))])
+                    }
+                    None => Value::Nil,
+                }).collect();
+                return Return::ValueReturn(Value::ArrayValue(positions));
+            }
+            Command::GeoDist {key, member1, member2, unit} => {
+                let z = match self.get_zset(&key) {
+                    Ok(Some(z)) => z,
+                    Ok(None) => return Return::ValueReturn(Value::Nil),
+                    Err(e) => return Return::Error(e),
+                };
+                match (z.get(&member1), z.get(&member2)) {
+                    (Some(&score1), Some(&score2)) => {
+                        let (lon1, lat1) = geo::decode_score(score1);
+                        let (lon2, lat2) = geo::decode_score(score2);
+                        let d = geo::distance(lon1, lat1, lon2, lat2) / unit;
+                        return Return::ValueReturn(Value::StrValue(geo::format_distance(d)));
+                    }
+                    _ => {
+                        return Return::ValueReturn(Value::Nil);
+                    }
+                }
+            }
+            Command::GeoHash {key, members} => {
+                let z = match self.get_zset(&key) {
+                    Ok(z) => z,
+                    Err(e) => return Return::Error(e),
+                };
+                let hashes = members.iter().map(|member| match z.and_then(|z| z.get(member)) {
+                    Some(&score) => Value::StrValue(geo::geohash_string(score)),
+                    None => Value::Nil,
+                }).collect();
+                return Return::ValueReturn(Value::ArrayValue(hashes));
+            }
+            Command::GeoSearch {key, options} => {
+                let results = match self.geo_search(&key, &options) {
+                    Ok(results) => results,
+                    Err(e) => return Return::Error(e),
+                };
+                let reply = results.into_iter().map(|(member, score, d)| {
+                    if !options.withdist && !options.withhash && !options.withcoord {
+                        return Value::StrValue(member);
+                    }
+                    let mut item = vec![Value::StrValue(member)];
+                    if options.withdist {
+                        item.push(Value::StrValue(geo::format_distance(d / options.unit)));
+                    }
+                    if options.withhash {
+                        item.push(Value::IntValue(score as i64));
+                    }
+                    if options.withcoord {
+                        let (lon, lat) = geo::decode_score(score);
+                        item.push(Value::ArrayValue(vec![Value::StrValue(geo::format_coordinate(lon)), Value::StrValue(geo::format_coordinate(lat))]));
+                    }
+                    Value::ArrayValue(item)
+                }).collect();
+                return Return::ValueReturn(Value::ArrayValue(reply));
+            }
+            Command::GeoSearchStore {destination, source, options, storedist} => {
+                let results = match self.geo_search(&source, &options) {
+                    Ok(results) => results,
+                    Err(e) => return Return::Error(e),
+                };
+                let stored = results.into_iter().map(|(member, score, d)| {
+                    (member, if storedist {d / options.unit} else {score})
+                }).collect::<SortedSet>();
+                let len = stored.len();
+                let removed = self.remove_entry(&destination).is_some();
+                if len > 0 {
+                    self.notify(notify::ZSET, "geosearchstore", &destination);
+                    self.insert_entry(destination, Value::SortedSetValue(stored), None);
+                } else if removed {
+                    self.notify(notify::GENERIC, "del", &destination);
+                }
+                return Return::ValueReturn(Value::IntValue(len as i64));
+            }
+            Command::XAdd {key, nomkstream, trim, id, fields} => {
+                let trim = match trim {
+                    Some(ref trim) => match RustisDb::stream_trim(trim) {
+                        Ok(trim) => Some(trim),
+                        Err(e) => return Return::Error(e),
+                    },
+                    None => None,
+                };
+                let created = !self.values.contains_key(&key);
+                if created {
+                    if nomkstream {
+                        return Return::ValueReturn(Value::Nil);
+                    }
+                    self.values.insert(key.clone(), Value::StreamValue(Stream::new()));
+                }
+                let mut trimmed = 0;
+                let result = match self.values.get_mut(&key) {
+                    Some(&mut Value::StreamValue(ref mut s)) => match s.next_id(&id) {
+                        Ok(id) => {
+                            s.add(id, fields);
+                            if let Some((to, approx, limit)) = trim {
+                                trimmed = s.trim(to, approx, limit);
+                            }
+                            Return::ValueReturn(Value::StrValue(id.to_string()))
+                        }
+                        Err(e) => Return::Error(e),
+                    },
+                    _ => Return::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()),
+                };
+                // a rejected id must not leave an empty stream behind
+                match result {
+                    Return::Error(_) if created => {
+                        self.values.swap_remove(&key);
+                    }
+                    Return::Error(_) => {}
+                    _ => {
+                        self.notify(notify::STREAM, "xadd", &key);
+                        if trimmed > 0 {
+                            self.notify(notify::STREAM, "xtrim", &key);
+                        }
+                    }
+                }
+                return result;
+            }
+            Command::XRange {key, start, end, count} => {
+                return self.stream_range(&key, &start, &end, count, false);
+            }
+            Command::XRevRange {key, end, start, count} => {
+                return self.stream_range(&key, &start, &end, count, true);
+            }
+            Command::XLen {key} => {
+                return match self.get_stream(&key) {
+                    Ok(s) => Return::ValueReturn(Value::IntValue(s.map_or(0, |s| s.len()) as i64)),
+                    Err(e) => Return::Error(e),
+                };
+            }
+            Command::XTrim {key, trim} => {
+                let (to, approx, limit) = match RustisDb::stream_trim(&trim) {
+                    Ok(trim) => trim,
+                    Err(e) => return Return::Error(e),
+                };
+                let trimmed = match self.values.get_mut(&key) {
+                    Some(&mut Value::StreamValue(ref mut s)) => s.trim(to, approx, limit),
+                    Some(_) => return Return::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()),
+                    None => 0,
+                };
+                if trimmed > 0 {
+                    self.notify(notify::STREAM, "xtrim", &key);
+                }
+                return Return::ValueReturn(Value::IntValue(trimmed as i64));
+            }
+            Command::XDel {key, ids} => {
+                let mut parsed = Vec::with_capacity(ids.len());
+                for id in ids.iter() {
+                    match StreamId::parse(id, 0) {
+                        Some(id) => parsed.push(id),
+                        None => return Return::Error(stream::INVALID_ID_ERR.to_string()),
+                    }
+                }
+                let deleted = match self.values.get_mut(&key) {
+                    Some(&mut Value::StreamValue(ref mut s)) => parsed.iter().filter(|id| s.delete(id)).count(),
+                    Some(_) => return Return::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()),
+                    None => 0,
+                };
+                if deleted > 0 {
+                    self.notify(notify::STREAM, "xdel", &key);
+                }
+                return Return::ValueReturn(Value::IntValue(deleted as i64));
+            }
+            Command::XInfoStream {key} => {
+                let s = match self.get_stream(&key) {
+                    Ok(Some(s)) => s,
+                    Ok(None) => return Return::Error("ERR no such key".to_string()),
+                    Err(e) => return Return::Error(e),
+                };
+                let entry = |e:Option<(&StreamId, &Vec<(String, String)>)>| e.map_or(Value::Nil, |(id, fields)| stream::entry_value(id, fields));
+                // rustis keeps entries in a single tree, so report the node
+                // counts redis' radix tree of listpacks would have
+                let nodes = (s.len() + STREAM_NODE_MAX_ENTRIES - 1) / STREAM_NODE_MAX_ENTRIES;
+                return Return::ValueReturn(Value::ArrayValue(vec![
+                    Value::StrValue("length".to_string()),
+                    Value::IntValue(s.len() as i64),
+                    Value::StrValue("radix-tree-keys".to_string()),
+                    Value::IntValue(nodes as i64),
+                    Value::StrValue("radix-tree-nodes".to_string()),
+                    Value::IntValue(nodes as i64 + 1),
+                    Value::StrValue("last-generated-id".to_string()),
+                    Value::StrValue(s.last_id.to_string()),
+                    Value::StrValue("max-deleted-entry-id".to_string()),
+                    Value::StrValue(s.max_deleted_id.to_string()),
+                    Value::StrValue("entries-added".to_string()),
+                    Value::IntValue(s.entries_added as i64),
+                    Value::StrValue("recorded-first-entry-id".to_string()),
+                    Value::StrValue(s.first_id().to_string()),
+                    Value::StrValue("groups".to_string()),
+                    Value::IntValue(s.groups.len() as i64),
+                    Value::StrValue("first-entry".to_string()),
+                    entry(s.entries.iter().next()),
+                    Value::StrValue("last-entry".to_string()),
+                    entry(s.entries.iter().next_back()),
+                ]));
+            }
+            Command::XInfoGroups {key} => {
+                let s = match self.get_stream(&key) {
+                    Ok(Some(s)) => s,
+                    Ok(None) => return Return::Error("ERR no such key".to_string()),
+                    Err(e) => return Return::Error(e),
+                };
+                return Return::ValueReturn(Value::ArrayValue(s.groups.iter().map(|(name, g)| {
+                    let lag = s.group_lag(g);
+                    let entries_read = g.entries_read.or(lag.map(|lag| s.entries_added - lag));
+                    Value::ArrayValue(vec![
+                        Value::StrValue("name".to_string()),
+                        Value::StrValue(name.clone()),
+                        Value::StrValue("consumers".to_string()),
+                        Value::IntValue(g.consumers.len() as i64),
+                        Value::StrValue("pending".to_string()),
+                        Value::IntValue(g.pending.len() as i64),
+                        Value::StrValue("last-delivered-id".to_string()),
+                        Value::StrValue(g.last_id.to_string()),
+                        Value::StrValue("entries-read".to_string()),
+                        entries_read.map_or(Value::Nil, |n| Value::IntValue(n as i64)),
+                        Value::StrValue("lag".to_string()),
+                        lag.map_or(Value::Nil, |n| Value::IntValue(n as i64)),
+                    ])
+                }).collect()));
+            }
+            Command::XInfoConsumers {key, group} => {
+                let nogroup = format!("NOGROUP No such consumer group '{}' for key name '{}'", group, key);
+                let s = match self.get_stream(&key) {
+                    Ok(Some(s)) => s,
+                    Ok(None) => return Return::Error("ERR no such key".to_string()),
+                    Err(e) => return Return::Error(e),
+                };
+                let g = match s.groups.get(&group) {
+                    Some(g) => g,
+                    None => return Return::Error(nogroup),
+                };
+                let now = now_ms();
+                return Return::ValueReturn(Value::ArrayValue(g.consumers.iter().map(|(name, c)| {
+                    Value::ArrayValue(vec![
+                        Value::StrValue("name".to_string()),
+                        Value::StrValue(name.clone()),
+                        Value::StrValue("pending".to_string()),
+                        Value::IntValue(c.pending.len() as i64),
+                        Value::StrValue("idle".to_string()),
+                        Value::IntValue(now.saturating_sub(c.seen_time) as i64),
+                        Value::StrValue("inactive".to_string()),
+                        Value::IntValue(c.active_time.map_or(-1, |t| now.saturating_sub(t) as i64)),
+                    ])
+                }).collect()));
+            }
+            Command::XGroupCreate {key, group, id, mkstream, entries_read} => {
+                let last_id = match (id.as_str(), StreamId::parse(&id, 0)) {
+                    ("$", _) => None,
+                    (_, Some(id)) => Some(id),
+                    (_, None) => return Return::Error(stream::INVALID_ID_ERR.to_string()),
+                };
+                match self.values.get(&key) {
+                    Some(&Value::StreamValue(_)) => {}
+                    Some(_) => return Return::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()),
+                    None if mkstream => {
+                        self.values.insert(key.clone(), Value::StreamValue(Stream::new()));
+                    }
+                    None => {
+                        return Return::Error("ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.".to_string());
+                    }
+                }
+                if let Some(&mut Value::StreamValue(ref mut s)) = self.values.get_mut(&key) {
+                    if s.groups.contains_key(&group) {
+                        return Return::Error("BUSYGROUP Consumer Group name already exists".to_string());
+                    }
+                    let entries_read = match (entries_read, last_id) {
+                        (Some(n), _) if n >= 0 => Some(n as u64),
+                        (_, None) => Some(s.entries_added),
+                        _ => None,
+                    };
+                    s.groups.insert(group, ConsumerGroup::new(last_id.unwrap_or(s.last_id), entries_read));
+                }
+                self.notify(notify::STREAM, "xgroup-create", &key);
+                return Return::Ok;
+            }
+            Command::XGroupSetId {key, group, id, entries_read} => {
+                let nogroup = format!("NOGROUP No such consumer group '{}' for key name '{}'", group, key);
+                let s = match self.stream_with_group(&key, &group, nogroup) {
+                    Ok(s) => s,
+                    Err(e) => return Return::Error(e),
+                };
+                let (last_id, entries_added) = (s.last_id, s.entries_added);
+                let g = s.groups.get_mut(&group).unwrap();
+                if id == "$" {
+                    g.last_id = last_id;
+                    g.entries_read = Some(entries_added);
+                } else {
+                    match StreamId::parse(&id, 0) {
+                        Some(id) => g.last_id = id,
+                        None => return Return::Error(stream::INVALID_ID_ERR.to_string()),
+                    }
+                    g.entries_read = None;
+                }
+                if let Some(n) = entries_read {
+                    g.entries_read = if n >= 0 {Some(n as u64)} else {None};
+                }
+                self.notify(notify::STREAM, "xgroup-setid", &key);
+                return Return::Ok;
+            }
+            Command::XGroupDestroy {key, group} => {
+                let destroyed = match self.values.get_mut(&key) {
+                    Some(&mut Value::StreamValue(ref mut s)) => s.groups.remove(&group).is_some(),
+                    Some(_) => return Return::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()),
+                    None => return Return::Error("ERR The XGROUP subcommand requires the key to exist.".to_string()),
+                };
+                if destroyed {
+                    self.notify(notify::STREAM, "xgroup-destroy", &key);
+                }
+                return Return::ValueReturn(Value::IntValue(if destroyed {1} else {0}));
+            }
+            Command::XGroupCreateConsumer {key, group, consumer} => {
+                let nogroup = format!("NOGROUP No such consumer group '{}' for key name '{}'", group, key);
+                let created = match self.stream_with_group(&key, &group, nogroup) {
+                    Ok(s) => {
+                        let g = s.groups.get_mut(&group).unwrap();
+                        let created = !g.consumers.contains_key(&consumer);
+                        g.consumer(&consumer, now_ms());
+                        created
+                    }
+                    Err(e) => return Return::Error(e),
+                };
+                if created {
+                    self.notify(notify::STREAM, "xgroup-createconsumer", &key);
+                }
+                return Return::ValueReturn(Value::IntValue(if created {1} else {0}));
+            }
+            Command::XGroupDelConsumer {key, group, consumer} => {
+                let nogroup = format!("NOGROUP No such consumer group '{}' for key name '{}'", group, key);
+                let pending = match self.stream_with_group(&key, &group, nogroup) {
+                    Ok(s) => s.groups.get_mut(&group).unwrap().delete_consumer(&consumer),
+                    Err(e) => return Return::Error(e),
+                };
+                if pending.is_some() {
+                    self.notify(notify::STREAM, "xgroup-delconsumer", &key);
+                }
+                return Return::ValueReturn(Value::IntValue(pending.unwrap_or(0) as i64));
+            }
+            Command::XRead {count, block: _, keys, ids} => {
+                if keys.len() != ids.len() {
+                    return Return::Error("ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified.".to_string());
+                }
+                let mut starts = Vec::with_capacity(ids.len());
+                for (key, id) in keys.iter().zip(ids.iter()) {
+                    let s = match self.get_stream(key) {
+                        Ok(s) => s,
+                        Err(e) => return Return::Error(e),
+                    };
+                    if id == "$" {
+                        starts.push(s.map_or(stream::MIN_ID, |s| s.last_id));
+                    } else {
+                        match StreamId::parse(id, 0) {
+                            Some(id) => starts.push(id),
+                            None => return Return::Error(stream::INVALID_ID_ERR.to_string()),
+                        }
+                    }
+                }
+                let count = count.and_then(|count| if count > 0 {Some(count as usize)} else {None});
+                let mut reply = vec![];
+                for (key, start) in keys.into_iter().zip(starts) {
+                    if let Ok(Some(s)) = self.get_stream(&key) {
+                        let entries = match start.next() {
+                            Some(start) => s.range(start, stream::MAX_ID, count, false),
+                            None => vec![],
+                        };
+                        if !entries.is_empty() {
+                            let entries = entries.into_iter().map(|(id, fields)| stream::entry_value(id, fields)).collect();
+                            reply.push(Value::ArrayValue(vec![Value::StrValue(key.clone()), Value::ArrayValue(entries)]));
+                        }
+                    }
+                }
+                return Return::ValueReturn(if reply.is_empty() {Value::Nil} else {Value::ArrayValue(reply)});
+            }
+            Command::XReadGroup {group, consumer, count, block: _, noack, keys, ids} => {
+                if keys.len() != ids.len() {
+                    return Return::Error("ERR Unbalanced 'xreadgroup' list of streams: for each stream key an ID or '>' must be specified.".to_string());
+                }
+                // every stream is checked before anything gets delivered
+                let mut starts = Vec::with_capacity(ids.len());
+                for (key, id) in keys.iter().zip(ids.iter()) {
+                    let nogroup = format!("NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option", key, group);
+                    if let Err(e) = self.stream_with_group(key, &group, nogroup) {
+                        return Return::Error(e);
+                    }
+                    if id == ">" {
+                        starts.push(None);
+                    } else {
+                        match StreamId::parse(id, 0) {
+                            Some(id) => starts.push(Some(id)),
+                            None => return Return::Error(stream::INVALID_ID_ERR.to_string()),
+                        }
+                    }
+                }
+                let count = count.and_then(|count| if count > 0 {Some(count as usize)} else {None});
+                let now = now_ms();
+                let mut reply = vec![];
+                let mut new_consumer = vec![];
+                for (key, start) in keys.into_iter().zip(starts) {
+                    if let Some(&mut Value::StreamValue(ref mut s)) = self.values.get_mut(&key) {
+                        if !s.groups[&group].consumers.contains_key(&consumer) {
+                            new_consumer.push(key.clone());
+                        }
+                        let entries = match start {
+                            None => {
+                                let ids = s.read_group(&group, &consumer, count, noack, now);
+                                if ids.is_empty() {
+                                    continue;
+                                }
+                                ids.iter().map(|id| stream::entry_value(id, &s.entries[id])).collect()
+                            }
+                            // entries deleted since they were delivered come back without fields
+                            Some(after) => s.read_history(&group, &consumer, after, count, now).iter().map(|id| match s.entries.get(id) {
+                                Some(fields) => stream::entry_value(id, fields),
+                                None => Value::ArrayValue(vec![Value::StrValue(id.to_string()), Value::Nil]),
+                            }).collect(),
+                        };
+                        reply.push(Value::ArrayValue(vec![Value::StrValue(key), Value::ArrayValue(entries)]));
+                    }
+                }
+                for key in new_consumer {
+                    self.notify(notify::STREAM, "xgroup-createconsumer", &key);
+                }
+                if reply.is_empty() {
+                    return Return::ValueReturn(Value::Nil);
+                }
+                // deliveries change the group's pending entries
+                self.dirty += 1;
+                return Return::ValueReturn(Value::ArrayValue(reply));
+            }
+            Command::XAck {key, group, ids} => {
+                let mut parsed = Vec::with_capacity(ids.len());
+                for id in ids.iter() {
+                    match StreamId::parse(id, 0) {
+                        Some(id) => parsed.push(id),
+                        None => return Return::Error(stream::INVALID_ID_ERR.to_string()),
+                    }
+                }
+                let acked = match self.values.get_mut(&key) {
+                    Some(&mut Value::StreamValue(ref mut s)) => match s.groups.get_mut(&group) {
+                        Some(g) => parsed.iter().filter(|id| g.ack(id)).count(),
+                        None => 0,
+                    },
+                    Some(_) => return Return::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()),
+                    None => 0,
+                };
+                self.dirty += acked as u64;
+                return Return::ValueReturn(Value::IntValue(acked as i64));
+            }
+            Command::XPending {key, group} => {
+                let nogroup = format!("NOGROUP No such key '{}' or consumer group '{}'", key, group);
+                let g = match self.stream_with_group(&key, &group, nogroup) {
+                    Ok(s) => &s.groups[&group],
+                    Err(e) => return Return::Error(e),
+                };
+                if g.pending.is_empty() {
+                    return Return::ValueReturn(Value::ArrayValue(vec![Value::IntValue(0), Value::Nil, Value::Nil, Value::Nil]));
+                }
+                let consumers = g.consumers.iter().filter(|&(_, c)| !c.pending.is_empty()).map(|(name, c)| {
+                    Value::ArrayValue(vec![Value::StrValue(name.clone()), Value::StrValue(c.pending.len().to_string())])
+                }).collect();
+                return Return::ValueReturn(Value::ArrayValue(vec![
+                    Value::IntValue(g.pending.len() as i64),
+                    Value::StrValue(g.pending.keys().next().unwrap().to_string()),
+                    Value::StrValue(g.pending.keys().next_back().unwrap().to_string()),
+                    Value::ArrayValue(consumers),
+                ]));
+            }
+            Command::XPendingRange {key, group, idle, start, end, count, consumer} => {
+                let start = match stream::parse_range_bound(&start, true) {
+                    Ok(id) => id,
+                    Err(e) => return Return::Error(e),
+                };
+                let end = match stream::parse_range_bound(&end, false) {
+                    Ok(id) => id,
+                    Err(e) => return Return::Error(e),
+                };
+                let nogroup = format!("NOGROUP No such key '{}' or consumer group '{}'", key, group);
+                let g = match self.stream_with_group(&key, &group, nogroup) {
+                    Ok(s) => &s.groups[&group],
+                    Err(e) => return Return::Error(e),
+                };
+                if start > end || count <= 0 {
+                    return Return::ValueReturn(Value::ArrayValue(vec![]));
+                }
+                let now = now_ms();
+                let min_idle = idle.unwrap_or(0).max(0) as u64;
+                let pending = g.pending.range(start..=end).filter(|&(_, p)| {
+                    consumer.as_ref().map_or(true, |c| *c == p.consumer) && now.saturating_sub(p.delivery_time) >= min_idle
+                }).take(count as usize).map(|(id, p)| Value::ArrayValue(vec![
+                    Value::StrValue(id.to_string()),
+                    Value::StrValue(p.consumer.clone()),
+                    Value::IntValue(now.saturating_sub(p.delivery_time) as i64),
+                    Value::IntValue(p.delivery_count as i64),
+                ])).collect();
+                return Return::ValueReturn(Value::ArrayValue(pending));
+            }
+            Command::XClaim {key, group, consumer, min_idle, ids, options} => {
+                let mut parsed = Vec::with_capacity(ids.len());
+                for id in ids.iter() {
+                    match StreamId::parse(id, 0) {
+                        Some(id) => parsed.push(id),
+                        None => return Return::Error(stream::INVALID_ID_ERR.to_string()),
+                    }
+                }
+                let lastid = match options.lastid {
+                    Some(ref id) => match StreamId::parse(id, 0) {
+                        Some(id) => Some(id),
+                        None => return Return::Error(stream::INVALID_ID_ERR.to_string()),
+                    },
+                    None => None,
+                };
+                let now = now_ms();
+                let claim = Claim {
+                    min_idle: min_idle.max(0) as u64,
+                    delivery_time: match (options.time, options.idle) {
+                        (Some(time), _) => time.max(0) as u64,
+                        (None, Some(idle)) => now.saturating_sub(idle.max(0) as u64),
+                        (None, None) => now,
+                    },
+                    retrycount: options.retrycount.map(|n| n.max(0) as u64),
+                    force: options.force,
+                    justid: options.justid,
+                };
+                let nogroup = format!("NOGROUP No such key '{}' or consumer group '{}'", key, group);
+                let s = match self.stream_with_group(&key, &group, nogroup) {
+                    Ok(s) => s,
+                    Err(e) => return Return::Error(e),
+                };
+                let new_consumer = !parsed.is_empty() && !s.groups[&group].consumers.contains_key(&consumer);
+                let mut changed = false;
+                if let Some(lastid) = lastid {
+                    let g = s.groups.get_mut(&group).unwrap();
+                    if lastid > g.last_id {
+                        g.last_id = lastid;
+                        changed = true;
+                    }
+                }
+                let claimed = parsed.into_iter().filter(|id| s.claim(&group, &consumer, *id, &claim, now) == ClaimResult::Claimed).collect::<Vec<StreamId>>();
+                let reply = Value::ArrayValue(claimed.iter().map(|id| {
+                    if options.justid {Value::StrValue(id.to_string())} else {stream::entry_value(id, &s.entries[id])}
+                }).collect());
+                if new_consumer {
+                    self.notify(notify::STREAM, "xgroup-createconsumer", &key);
+                }
+                if changed || !claimed.is_empty() {
+                    self.dirty += 1;
+                }
+                return Return::ValueReturn(reply);
+            }
+            Command::XAutoClaim {key, group, consumer, min_idle, start, count, justid} => {
+                let start = match stream::parse_range_bound(&start, true) {
+                    Ok(id) => id,
+                    Err(e) => return Return::Error(e),
+                };
+                let count = match count {
+                    Some(count) if count < 1 => return Return::Error("ERR COUNT must be > 0".to_string()),
+                    Some(count) => count as usize,
+                    None => 100,
+                };
+                let now = now_ms();
+                let claim = Claim {min_idle: min_idle.max(0) as u64, delivery_time: now, retrycount: None, force: false, justid: justid};
+                let nogroup = format!("NOGROUP No such key '{}' or consumer group '{}'", key, group);
+                let s = match self.stream_with_group(&key, &group, nogroup) {
+                    Ok(s) => s,
+                    Err(e) => return Return::Error(e),
+                };
+                let existed = s.groups[&group].consumers.contains_key(&consumer);
+                let (claimed, deleted, cursor) = s.autoclaim(&group, &consumer, start, count, &claim, now);
+                let new_consumer = !existed && s.groups[&group].consumers.contains_key(&consumer);
+                let reply = Value::ArrayValue(vec![
+                    Value::StrValue(cursor.to_string()),
+                    Value::ArrayValue(claimed.iter().map(|id| {
+                        if justid {Value::StrValue(id.to_string())} else {stream::entry_value(id, &s.entries[id])}
+                    }).collect()),
+                    Value::ArrayValue(deleted.iter().map(|id| Value::StrValue(id.to_string())).collect()),
+                ]);
+                if new_consumer {
+                    self.notify(notify::STREAM, "xgroup-createconsumer", &key);
+                }
+                if !claimed.is_empty() || !deleted.is_empty() {
+                    self.dirty += 1;
+                }
+                return Return::ValueReturn(reply);
+            }
+            Command::Pfadd {key, elements} => {
+                let (mut hll, mut changed) = match self.get_hll(&key) {
+                    Ok(Some(hll)) => (hll, false),
+                    Ok(None) => (HyperLogLog::new(), true),
+                    Err(e) => return Return::Error(e),
+                };
+                let sparse = match self.values.get(&key) {
+                    Some(&Value::StrValue(ref s)) => hyperloglog::is_sparse(&string_to_bytes(s)),
+                    _ => true,
+                };
+                for element in elements.iter() {
+                    changed |= hll.add(&string_to_bytes(element));
+                }
+                if changed {
+                    let bytes = hll.to_bytes(sparse, None);
+                    self.notify(notify::STRING, "pfadd", &key);
+                    self.set_value(key, Value::StrValue(bytes_to_string(&bytes)));
+                }
+                return Return::ValueReturn(Value::IntValue(if changed {1} else {0}));
+            }
+            Command::Pfcount {keys} => {
+                if keys.len() == 1 {
+                    let key = &keys[0];
+                    let bytes = match self.values.get(key) {
+                        Some(&Value::StrValue(ref s)) => string_to_bytes(s),
+                        Some(_) => return Return::Error(hyperloglog::INVALID_HLL_ERR.to_string()),
+                        None => return Return::ValueReturn(Value::IntValue(0)),
+                    };
+                    if let Some(count) = hyperloglog::cached_count(&bytes) {
+                        if HyperLogLog::is_hll(&bytes) {
+                            return Return::ValueReturn(Value::IntValue(count as i64));
+                        }
+                    }
+                    let hll = match HyperLogLog::from_bytes(&bytes) {
+                        Ok(hll) => hll,
+                        Err(e) => return Return::Error(e.to_string()),
+                    };
+                    // cache the cardinality in the value's header
+                    let count = hll.count();
+                    let cached = hll.to_bytes(hyperloglog::is_sparse(&bytes), Some(count));
+                    self.set_value(key.clone(), Value::StrValue(bytes_to_string(&cached)));
+                    return Return::ValueReturn(Value::IntValue(count as i64));
+                }
+                let mut merged = HyperLogLog::new();
+                for key in keys.iter() {
+                    match self.get_hll(key) {
+                        Ok(Some(hll)) => merged.merge(&hll),
+                        Ok(None) => {}
+                        Err(e) => return Return::Error(e),
+                    }
+                }
+                return Return::ValueReturn(Value::IntValue(merged.count() as i64));
+            }
+            Command::Pfmerge {destkey, sourcekeys} => {
+                let mut merged = match self.get_hll(&destkey) {
+                    Ok(Some(hll)) => hll,
+                    Ok(None) => HyperLogLog::new(),
+                    Err(e) => return Return::Error(e),
+                };
+                for key in sourcekeys.iter() {
+                    match self.get_hll(key) {
+                        Ok(Some(hll)) => merged.merge(&hll),
+                        Ok(None) => {}
+                        Err(e) => return Return::Error(e),
+                    }
+                }
+                let bytes = merged.to_bytes(false, None);
+                self.notify(notify::STRING, "pfadd", &destkey);
+                self.set_value(destkey, Value::StrValue(bytes_to_string(&bytes)));
+                return Return::Ok;
+            }
+            Command::Sort {key, options, store} => {
+                let sorted = match self.sort(&key, &options, store.is_some()) {
+                    Ok(sorted) => sorted,
+                    Err(e) => return Return::Error(e),
+                };
+                match store {
+                    Some(destination) => {
+                        let list = sorted.into_iter().map(|v| match v {
+                            Value::StrValue(s) => s,
+                            _ => "".to_string(),
+                        }).collect::<VecDeque<String>>();
+                        let len = list.len();
+                        let removed = self.remove_entry(&destination).is_some();
+                        if len > 0 {
+                            self.notify(notify::LIST, "sortstore", &destination);
+                            self.insert_entry(destination, Value::ListValue(list), None);
+                        } else if removed {
+                            self.notify(notify::GENERIC, "del", &destination);
+                        }
+                        return Return::ValueReturn(Value::IntValue(len as i64));
+                    }
+                    None => {
+                        return Return::ValueReturn(Value::ArrayValue(sorted));
+                    }
+                }
+            }
+            Command::SortRo {key, options} => {
+                return self.execute(Command::Sort {key: key, options: options, store: None});
+            }
+            Command::Dump {key} => {
+                return match self.values.get(&key) {
+                    Some(v) => match rdb::dump(v) {
+                        Ok(payload) => Return::ValueReturn(Value::StrValue(bytes_to_string(&payload))),
+                        Err(e) => Return::Error(e),
+                    },
+                    None => Return::ValueReturn(Value::Nil),
+                };
+            }
+            Command::Restore {key, ttl, payload, replace, absttl, idletime, freq} => {
+                if ttl < 0 {
+                    return Return::Error("ERR Invalid TTL value, must be >= 0".to_string());
+                }
+                if idletime.map_or(false, |i| i < 0) {
+                    return Return::Error("ERR Invalid IDLETIME value, must be >= 0".to_string());
+                }
+                if freq.map_or(false, |f| f < 0 || f > 255) {
+                    return Return::Error("ERR Invalid FREQ value, must be >= 0 and <= 255".to_string());
+                }
+                if !replace && self.values.contains_key(&key) {
+                    return Return::Error("BUSYKEY Target key name already exists.".to_string());
+                }
+                let value = match rdb::restore(&string_to_bytes(&payload)) {
+                    Ok(v) => v,
+                    Err(e) => return Return::Error(e),
+                };
+                let now = now_ms();
+                let expire_at = if ttl == 0 {
+                    None
+                } else if absttl {
+                    Some(ttl as u64)
+                } else {
+                    match (now as i64).checked_add(ttl) {
+                        Some(at) => Some(at as u64),
+                        None => return Return::Error("ERR invalid expire time in 'restore' command".to_string()),
+                    }
+                };
+                if self.remove_entry(&key).is_some() {
+                    self.notify(notify::GENERIC, "del", &key);
+                }
+                if expire_at.map_or(false, |at| at <= now) {
+                    // already expired, so there is nothing to restore
+                    return Return::Ok;
+                }
+                let mut meta = KeyMeta::new(now);
+                if let Some(idle) = idletime {
+                    meta.last_access = now.saturating_sub((idle as u64).saturating_mul(1000));
+                }
+                if let Some(f) = freq {
+                    meta.set_freq(now, f as u8);
+                }
+                self.meta.insert(key.clone(), meta);
+                self.notify(notify::GENERIC, "restore", &key);
+                self.insert_entry(key, value, expire_at);
+                return Return::Ok;
+            }
+            Command::FlushDb => {
+                self.flushed();
+                self.values.clear();
+                self.meta.clear();
+                self.expires.clear();
+                self.exp.clear();
+                return Return::Ok;
+            }
+            Command::FlushDbAsync => {
+                self.flushed();
+                let mut values = mem::replace(&mut self.values, IndexMap::with_capacity(1024));
+                let meta = mem::replace(&mut self.meta, HashMap::with_capacity(1024));
+                let expires = mem::replace(&mut self.expires, HashMap::new());
+                let exp = mem::replace(&mut self.exp, BinaryHeap::with_capacity(1024));
+                // a module's free may not be safe to call from another thread
+                values.retain(|_, value| match value {
+                    &mut Value::ModuleValue(_) => false,
+                    _ => true,
+                });
+                match self.lazyfree {
+                    Some(ref lazyfree) => lazyfree.free((values, meta, expires, exp)),
+                    None => drop((values, meta, expires, exp)),
+                }
+                return Return::Ok;
+            }
+            Command::TriggerCreate {name, pattern, event, commands, replace} => {
+                return match Trigger::new(name, pattern, event, &commands).and_then(|trigger| self.triggers.create(trigger, replace)) {
+                    Ok(()) => {
+                        self.dirty += 1;
+                        Return::Ok
+                    }
+                    Err(e) => Return::Error(e),
+                };
+            }
+            Command::TriggerDelete {name} => {
+                return match self.triggers.delete(&name) {
+                    Ok(()) => {
+                        self.dirty += 1;
+                        Return::Ok
+                    }
+                    Err(e) => Return::Error(e),
+                };
+            }
+            Command::TriggerList => {
+                return Return::ValueReturn(self.triggers.list());
+            }
+            Command::TriggerDump => {
+                return Return::ValueReturn(Value::StrValue(bytes_to_string(&self.triggers.dump())));
+            }
+            Command::TriggerRestore {payload, policy} => {
+                return match self.triggers.restore(&string_to_bytes(&payload), policy) {
+                    Ok(()) => {
+                        self.dirty += 1;
+                        Return::Ok
+                    }
+                    Err(e) => Return::Error(e),
+                };
+            }
+            Command::Time => {
+                let mut t = timeval {tv_sec: 0 as time_t, tv_usec: 0 as suseconds_t};
+                unsafe {
+                    gettimeofday(&mut t, null_mut());
+                }
+                let r = Value::ArrayValue(vec![
+                    Value::IntValue(t.tv_sec as i64),
+                    Value::IntValue(t.tv_usec as i64),
+                ]);
+                return Return::ValueReturn(r);
+            }
+            _ => {
+                return Return::Ok;
+            }
+        }
+    }
+
+    // replaces a key's value, keeping its expiration time
+    fn set_value(&mut self, key:Key, value:Value) {
+        self.values.insert(key, value);
+    }
+
+    fn get_zset(&self, key:&Key) -> Result<Option<&SortedSet>, String> {
+        return match self.values.get(key) {
+            Some(&Value::SortedSetValue(ref z)) => Ok(Some(z)),
+            Some(_) => Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()),
+            None => Ok(None),
+        };
+    }
+
+    fn get_stream(&self, key:&Key) -> Result<Option<&Stream>, String> {
+        return match self.values.get(key) {
+            Some(&Value::StreamValue(ref s)) => Ok(Some(s)),
+            Some(_) => Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()),
+            None => Ok(None),
+        };
+    }
+
+    // replaces XREAD's `$` ids with the current last ids, so that a blocked
+    // read waits for entries added after it was issued
+    pub fn resolve_last_ids(&self, cmd:Command) -> Command {
+        return match cmd {
+            Command::XRead {count, block, keys, ids} => {
+                let ids = keys.iter().zip(ids.into_iter()).map(|(key, id)| {
+                    if id != "$" {
+                        return id;
+                    }
+                    match self.get_stream(key) {
+                        Ok(Some(s)) => s.last_id.to_string(),
+                        _ => stream::MIN_ID.to_string(),
+                    }
+                }).collect();
+                Command::XRead {count: count, block: block, keys: keys, ids: ids}
+            }
+            cmd => cmd,
+        };
+    }
+
+    // the stream at key if it has the group, or else the given NOGROUP error
+    fn stream_with_group(&mut self, key:&Key, group:&str, nogroup:String) -> Result<&mut Stream, String> {
+        return match self.values.get_mut(key) {
+            Some(&mut Value::StreamValue(ref mut s)) if s.groups.contains_key(group) => Ok(s),
+            Some(&mut Value::StreamValue(_)) | None => Err(nogroup),
+            Some(_) => Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()),
+        };
+    }
+
+    // validates XADD and XTRIM trimming arguments
+    fn stream_trim(trim:&StreamTrim) -> Result<(TrimTo, bool, Option<usize>), String> {
+        let to = if trim.minid {
+            match StreamId::parse(&trim.threshold, 0) {
+                Some(id) => TrimTo::MinId(id),
+                None => return Err(stream::INVALID_ID_ERR.to_string()),
+            }
+        } else {
+            match trim.threshold.parse::<i64>() {
+                Ok(len) if len >= 0 => TrimTo::MaxLen(len as usize),
+                Ok(_) => return Err("ERR The MAXLEN argument must be >= 0.".to_string()),
+                Err(_) => return Err("ERR value is not an integer or out of range".to_string()),
+            }
+        };
+        let limit = match trim.limit {
+            Some(_) if !trim.approx => return Err("ERR syntax error, LIMIT cannot be used without the special ~ option".to_string()),
+            Some(limit) if limit < 0 => return Err("ERR The LIMIT argument must be >= 0.".to_string()),
+            Some(0) => None,
+            Some(limit) => Some(limit as usize),
+            None if trim.approx => Some(100 * STREAM_NODE_MAX_ENTRIES),
+            None => None,
+        };
+        return Ok((to, trim.approx, limit));
+    }
+
+    fn stream_range(&self, key:&Key, start:&str, end:&str, count:Option<i64>, rev:bool) -> Return {
+        let start = match stream::parse_range_bound(start, true) {
+            Ok(id) => id,
+            Err(e) => return Return::Error(e),
+        };
+        let end = match stream::parse_range_bound(end, false) {
+            Ok(id) => id,
+            Err(e) => return Return::Error(e),
+        };
+        let s = match self.get_stream(key) {
+            Ok(Some(s)) => s,
+            Ok(None) => return Return::ValueReturn(Value::ArrayValue(vec![])),
+            Err(e) => return Return::Error(e),
+        };
+        if count.map_or(false, |count| count <= 0) {
+            return Return::ValueReturn(Value::ArrayValue(vec![]));
+        }
+        let entries = s.range(start, end, count.map(|count| count as usize), rev);
+        return Return::ValueReturn(Value::ArrayValue(entries.into_iter().map(|(id, fields)| stream::entry_value(id, fields)).collect()));
+    }
+
+    // returns the matching members with their scores and distances in meters
+    fn geo_search(&self, key:&Key, options:&GeoSearchOptions) -> Result<Vec<(String, f64, f64)>, String> {
+        let shape = match options.by {
+            Some(ref shape) => shape,
+            None => return Err("ERR exactly one of BYRADIUS and BYBOX can be specified for GEOSEARCH".to_string()),
+        };
+        let z = match self.get_zset(key)? {
+            Some(z) => z,
+            None => return Ok(vec![]),
+        };
+        let (lon, lat) = match options.from {
+            Some(GeoFrom::Member(ref member)) => match z.get(member) {
+                Some(&score) => geo::decode_score(score),
+                None => return Err("ERR could not decode requested zset member".to_string()),
+            },
+            Some(GeoFrom::LonLat(lon, lat)) => {
+                if !geo::valid_lon_lat(lon, lat) {
+                    return Err(format!("ERR invalid longitude,latitude pair {:.6},{:.6}", lon, lat));
+                }
+                (lon, lat)
+            }
+            None => return Err("ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for GEOSEARCH".to_string()),
+        };
+        let any = options.count.map_or(false, |(_, any)| any);
+        let ranges = match shape {
+            &GeoShape::Radius(radius) => geo::search_ranges(lon, lat, radius, radius * 2.0, radius * 2.0),
+            &GeoShape::Box(width, height) => geo::search_ranges(lon, lat, (width * width + height * height).sqrt() / 2.0, width, height),
+        };
+        let mut results = Vec::new();
+        // only the members in the cells around the center can match
+        for (member, score) in ranges.into_iter().flat_map(|(min, max)| z.range_by_score(min, max)) {
+            let (point_lon, point_lat) = geo::decode_score(score);
+            let d = match shape {
+                &GeoShape::Radius(radius) => {
+                    let d = geo::distance(lon, lat, point_lon, point_lat);
+                    if d > radius {
+                        continue;
+                    }
+                    d
+                }
+                &GeoShape::Box(width, height) => match geo::distance_in_box(lon, lat, width, height, point_lon, point_lat) {
+                    Some(d) => d,
+                    None => continue,
+                },
+            };
+            results.push((member.clone(), score, d));
+            if any && Some(results.len()) == options.count.map(|(count, _)| count) {
+                break;
+            }
+        }
+        // a COUNT without ANY returns the closest matches
+        let desc = match options.desc {
+            Some(desc) => Some(desc),
+            None if options.count.is_some() && !any => Some(false),
+            None => None,
+        };
+        if let Some(desc) = desc {
+            results.sort_by(|a, b| {
+                let cmp = a.2.partial_cmp(&b.2).unwrap_or(Ordering::Equal);
+                if desc {cmp.reverse()} else {cmp}
+            });
+        }
+        if let Some((count, _)) = options.count {
+            results.truncate(count);
+        }
+        return Ok(results);
+    }
+
+    fn get_hll(&self, key:&Key) -> Result<Option<HyperLogLog>, String> {
+        return match self.values.get(key) {
+            Some(&Value::StrValue(ref s)) => HyperLogLog::from_bytes(&string_to_bytes(s)).map(Some).map_err(|e| e.to_string()),
+            Some(_) => Err(hyperloglog::INVALID_HLL_ERR.to_string()),
+            None => Ok(None),
+        };
+    }
+
+    // looks up a SORT BY or GET pattern with its first `*` replaced by
+    // `element`; `key*->field` patterns read a field from a hash
+    fn lookup_pattern(&self, pattern:&str, element:&str) -> Option<String> {
+        if pattern == "#" {
+            return Some(element.to_string());
+        }
+        let star = match pattern.find('*') {
+            Some(i) => i,
+            None => return None,
+        };
+        let (key_pattern, field) = match pattern[star + 1..].find("->") {
+            Some(i) if star + i + 3 < pattern.len() => (&pattern[..star + 1 + i], Some(&pattern[star + i + 3..])),
+            _ => (pattern, None),
+        };
+        let key = key_pattern.replacen('*', element, 1);
+        return match (self.values.get(&key), field) {
+            (Some(&Value::StrValue(ref s)), None) => Some(s.clone()),
+            (Some(&Value::IntValue(i)), None) => Some(i.to_string()),
+            (Some(&Value::HashValue(ref h)), Some(f)) => h.get(f).cloned(),
+            _ => None,
+        };
+    }
+
+    fn sort(&self, key:&Key, options:&SortOptions, store:bool) -> Result<Vec<Value>, String> {
+        let (mut elements, is_set, is_zset) = match self.values.get(key) {
+            Some(&Value::ListValue(ref l)) => (l.iter().cloned().collect::<Vec<String>>(), false, false),
+            Some(&Value::SetValue(ref s)) => (s.iter().cloned().collect(), true, false),
+            Some(&Value::SortedSetValue(ref z)) => {
+                let mut members = z.iter().collect::<Vec<(&String, &f64)>>();
+                members.sort_by(|a, b| a.1.partial_cmp(b.1).unwrap_or(Ordering::Equal).then(a.0.cmp(b.0)));
+                (members.into_iter().map(|(m, _)| m.clone()).collect(), false, true)
+            }
+            None => (vec![], false, false),
+            _ => return Err("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()),
+        };
+        // a BY pattern without `*` skips sorting, except that stored sets are
+        // still sorted so the result is deterministic
+        let dontsort = options.by.as_ref().map_or(false, |by| !by.contains('*'));
+        if !dontsort || (is_set && store) {
+            let by = if dontsort {None} else {options.by.as_ref()};
+            let alpha = options.alpha || dontsort;
+            let mut weighted = Vec::with_capacity(elements.len());
+            for element in elements {
+                let weight = match by {
+                    Some(pattern) => self.lookup_pattern(pattern, &element),
+                    None => Some(element.clone()),
+                };
+                let score = if alpha {
+                    0.0
+                } else {
+                    match weight {
+                        Some(ref w) => match w.parse::<f64>() {
+                            Ok(f) if !f.is_nan() => f,
+                            _ => return Err("ERR One or more scores can't be converted into double".to_string()),
+                        },
+                        None => 0.0,
+                    }
+                };
+                weighted.push((element, weight, score));
+            }
+            weighted.sort_by(|a, b| {
+                let cmp = if !alpha {
+                    a.2.partial_cmp(&b.2).unwrap_or(Ordering::Equal).then_with(|| a.0.cmp(&b.0))
+                } else if by.is_some() {
+                    a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0))
+                } else {
+                    a.0.cmp(&b.0)
+                };
+                if options.desc {cmp.reverse()} else {cmp}
+            });
+            elements = weighted.into_iter().map(|(e, _, _)| e).collect();
+        } else if is_zset && options.desc {
+            elements.reverse();
+        }
+        let (start, count) = match options.limit {
+            Some((start, count)) => (if start < 0 {0} else {start as usize}, if count < 0 {elements.len()} else {count as usize}),
+            None => (0, elements.len()),
+        };
+        let mut result = Vec::new();
+        for element in elements.iter().skip(start).take(count) {
+            if options.get.is_empty() {
+                result.push(Value::StrValue(element.clone()));
+            }
+            for pattern in options.get.iter() {
+                result.push(match self.lookup_pattern(pattern, element) {
+                    Some(s) => Value::StrValue(s),
+                    None => Value::Nil,
+                });
+            }
+        }
+        return Ok(result);
+    }
+
+    fn list_index<T>(list:&VecDeque<T>, i:i64) -> Option<usize> {
+        let len = list.len() as i64;
+        let mut index = i;
+        if index < 0 {
+            index = len + index;
+        }
+        if index >= 0 && i < len {
+            return Some(index as usize);
+        } else {
+            return None;
+        }
+    }
+}
+
+#[test]
+fn test_dbsize() {
+    let mut db = RustisDb::new();
+    assert_eq!(db.run_command(Command::DbSize), Return::ValueReturn(Value::IntValue(0)));
+    let result = db.run_command(Command::Set {key: "test_key123".to_string(), value: Value::StrValue("abc".to_string()), exp: None});
+    assert_eq!(result, Return::Ok);
+    assert_eq!(db.run_command(Command::DbSize), Return::ValueReturn(Value::IntValue(1)));
+    let result = db.run_command(Command::Set {key: "test_key123".to_string(), value: Value::StrValue("def".to_string()), exp: None});
+    assert_eq!(result, Return::Ok);
+    assert_eq!(db.run_command(Command::DbSize), Return::ValueReturn(Value::IntValue(1)));
+    let result = db.run_command(Command::Set {key: "test_key456".to_string(), value: Value::StrValue("abc".to_string()), exp: None});
+    assert_eq!(result, Return::Ok);
+    assert_eq!(db.run_command(Command::DbSize), Return::ValueReturn(Value::IntValue(2)));
+    let result = db.run_command(Command::Del {keys: vec!["test_key456".to_string()]});
+    assert_eq!(result, Return::ValueReturn(Value::IntValue(1)));
+    assert_eq!(db.run_command(Command::DbSize), Return::ValueReturn(Value::IntValue(1)));
+}
+
+#[test]
+fn test_get_set() {
+    let mut db = RustisDb::new();
+    assert_eq!(db.run_command(Command::Get {key: "test_key123".to_string()}), Return::ValueReturn(Value::Nil));
+    let result = db.run_command(Command::Set {key: "test_key123".to_string(), value: Value::StrValue("def".to_string()), exp: None});
+    assert_eq!(result, Return::Ok);
+    assert_eq!(db.run_command(Command::Get {key: "test_key123".to_string()}), Return::ValueReturn(Value::StrValue("def".to_string())));
+}
+
+#[test]
+fn test_incr() {
+    let mut db = RustisDb::new();
+    assert_eq!(db.run_command(Command::Incr {key: "abc".to_string()}), Return::ValueReturn(Value::IntValue(1)));
+    assert_eq!(db.run_command(Command::Incr {key: "abc".to_string()}), Return::ValueReturn(Value::IntValue(2)));
+    assert_eq!(db.run_command(Command::IncrBy {key: "abc".to_string(), increment: 10}), Return::ValueReturn(Value::IntValue(12)));
+    assert_eq!(db.run_command(Command::IncrByFloat {key: "abc".to_string(), increment: 0.1}), Return::ValueReturn(Value::StrValue("12.1".to_string())));
+    db.run_command(Command::Set {key: "abc".to_string(), value: Value::StrValue("defg".to_string()), exp: None});
+    assert!(match db.run_command(Command::IncrBy {key: "abc".to_string(), increment: 10}) {
+        Return::Error(_) => true,
         _ => false,
     });
 }
@@ -413,3 +2177,422 @@ fn test_set() {
     assert_eq!(db.run_command(Command::Sismember {key: "abc".to_string(), member: "one".to_string()}), Return::ValueReturn(Value::IntValue(0)));
     assert_eq!(db.run_command(Command::Sismember {key: "abc".to_string(), member: "three".to_string()}), Return::ValueReturn(Value::IntValue(1)));
 }
+
+#[test]
+fn test_rename_copy() {
+    let mut db = RustisDb::new();
+    db.run_command(Command::Set {key: "abc".to_string(), value: Value::StrValue("one".to_string()), exp: Some(100000)});
+    db.run_command(Command::Set {key: "def".to_string(), value: Value::StrValue("two".to_string()), exp: None});
+    assert_eq!(db.run_command(Command::RenameNx {key: "abc".to_string(), newkey: "def".to_string()}), Return::ValueReturn(Value::IntValue(0)));
+    assert_eq!(db.run_command(Command::ObjectFreq {key: "abc".to_string()}), Return::ValueReturn(Value::IntValue(6)));
+    // past the first access the counter only grows by chance, so the key
+    // isn't touched again
+    assert_eq!(db.run_command_untouched(Command::Rename {key: "abc".to_string(), newkey: "ghi".to_string()}), Return::Ok);
+    assert_eq!(db.run_command(Command::Exists {key: "abc".to_string()}), Return::ValueReturn(Value::IntValue(0)));
+    assert_eq!(db.run_command_untouched(Command::Ttl {key: "ghi".to_string()}), Return::ValueReturn(Value::IntValue(100)));
+    assert_eq!(db.run_command(Command::ObjectFreq {key: "ghi".to_string()}), Return::ValueReturn(Value::IntValue(6)));
+    assert_eq!(db.run_command(Command::Copy {source: "ghi".to_string(), destination: "def".to_string(), db: None, replace: false}), Return::ValueReturn(Value::IntValue(0)));
+    assert_eq!(db.run_command(Command::Copy {source: "ghi".to_string(), destination: "def".to_string(), db: None, replace: true}), Return::ValueReturn(Value::IntValue(1)));
+    assert_eq!(db.run_command(Command::Get {key: "def".to_string()}), Return::ValueReturn(Value::StrValue("one".to_string())));
+    assert_eq!(db.run_command(Command::Ttl {key: "def".to_string()}), Return::ValueReturn(Value::IntValue(100)));
+    assert!(match db.run_command(Command::Rename {key: "missing".to_string(), newkey: "x".to_string()}) {
+        Return::Error(_) => true,
+        _ => false,
+    });
+}
+
+#[test]
+fn test_expire() {
+    let mut db = RustisDb::new();
+    db.run_command(Command::Set {key: "abc".to_string(), value: Value::StrValue("one".to_string()), exp: None});
+    assert_eq!(db.run_command(Command::Ttl {key: "abc".to_string()}), Return::ValueReturn(Value::IntValue(-1)));
+    assert_eq!(db.run_command(Command::Ttl {key: "def".to_string()}), Return::ValueReturn(Value::IntValue(-2)));
+    assert_eq!(db.run_command(Command::Expire {key: "abc".to_string(), seconds: i64::max_value()}), Return::Error("ERR invalid expire time in 'expire' command".to_string()));
+    assert_eq!(db.run_command(Command::Expire {key: "abc".to_string(), seconds: i64::min_value()}), Return::Error("ERR invalid expire time in 'expire' command".to_string()));
+    assert_eq!(db.run_command(Command::Set {key: "def".to_string(), value: Value::IntValue(1), exp: Some(u64::max_value())}), Return::Error("ERR invalid expire time in 'set' command".to_string()));
+    assert_eq!(db.run_command(Command::Exists {key: "def".to_string()}), Return::ValueReturn(Value::IntValue(0)));
+    assert_eq!(db.run_command(Command::Pexpire {key: "abc".to_string(), milliseconds: i64::max_value()}), Return::Error("ERR invalid expire time in 'pexpire' command".to_string()));
+    assert_eq!(db.run_command(Command::Ttl {key: "abc".to_string()}), Return::ValueReturn(Value::IntValue(-1)));
+    assert_eq!(db.run_command(Command::Pexpire {key: "abc".to_string(), milliseconds: 0}), Return::ValueReturn(Value::IntValue(1)));
+    assert_eq!(db.run_command(Command::Exists {key: "abc".to_string()}), Return::ValueReturn(Value::IntValue(0)));
+}
+
+#[test]
+fn test_object() {
+    let mut db = RustisDb::new();
+    db.run_command(Command::Set {key: "abc".to_string(), value: Value::IntValue(12), exp: None});
+    db.run_command(Command::Rpush {key: "def".to_string(), values: vec!["a".to_string()]});
+    assert_eq!(db.run_command(Command::ObjectEncoding {key: "abc".to_string()}), Return::ValueReturn(Value::StrValue("int".to_string())));
+    assert_eq!(db.run_command(Command::ObjectEncoding {key: "def".to_string()}), Return::ValueReturn(Value::StrValue("listpack".to_string())));
+    assert_eq!(db.run_command(Command::ObjectFreq {key: "abc".to_string()}), Return::ValueReturn(Value::IntValue(5)));
+    // the first access past the initial value always increments the counter
+    db.run_command(Command::Get {key: "abc".to_string()});
+    assert_eq!(db.run_command(Command::ObjectFreq {key: "abc".to_string()}), Return::ValueReturn(Value::IntValue(6)));
+    db.run_command_untouched(Command::Get {key: "abc".to_string()});
+    assert_eq!(db.run_command(Command::ObjectFreq {key: "abc".to_string()}), Return::ValueReturn(Value::IntValue(6)));
+    assert_eq!(db.run_command(Command::ObjectIdleTime {key: "abc".to_string()}), Return::ValueReturn(Value::IntValue(0)));
+    assert_eq!(db.run_command(Command::ObjectFreq {key: "ghi".to_string()}), Return::ValueReturn(Value::Nil));
+    assert_eq!(db.run_command(Command::Touch {keys: vec!["abc".to_string(), "def".to_string(), "ghi".to_string()]}), Return::ValueReturn(Value::IntValue(2)));
+    match db.run_command(Command::RandomKey) {
+        Return::ValueReturn(Value::StrValue(ref k)) => assert!(k == "abc" || k == "def"),
+        r => panic!("unexpected {:?}", r),
+    }
+    // every key can come up, including after others were deleted
+    for i in 0..10 {
+        db.run_command(Command::Set {key: i.to_string(), value: Value::IntValue(i), exp: None});
+    }
+    db.run_command(Command::Del {keys: vec!["abc".to_string(), "3".to_string()]});
+    let mut seen = HashSet::new();
+    for _ in 0..1000 {
+        match db.run_command(Command::RandomKey) {
+            Return::ValueReturn(Value::StrValue(k)) => seen.insert(k),
+            r => panic!("unexpected {:?}", r),
+        };
+    }
+    assert_eq!(seen.len(), 10);
+    assert!(!seen.contains("abc") && !seen.contains("3"));
+}
+
+#[test]
+fn test_unlink() {
+    let mut db = RustisDb::new();
+    db.set_lazyfree(LazyFree::new());
+    db.run_command(Command::Rpush {key: "abc".to_string(), values: (0..1000).map(|i| i.to_string()).collect()});
+    db.run_command(Command::Set {key: "def".to_string(), value: Value::IntValue(1), exp: None});
+    assert_eq!(db.run_command(Command::Unlink {keys: vec!["abc".to_string(), "ghi".to_string()]}), Return::ValueReturn(Value::IntValue(1)));
+    assert_eq!(db.run_command(Command::Exists {key: "abc".to_string()}), Return::ValueReturn(Value::IntValue(0)));
+    assert_eq!(db.run_command(Command::FlushDbAsync), Return::Ok);
+    assert_eq!(db.run_command(Command::DbSize), Return::ValueReturn(Value::IntValue(0)));
+}
+
+#[test]
+fn test_dump_restore() {
+    let mut db = RustisDb::new();
+    db.run_command(Command::Rpush {key: "abc".to_string(), values: vec!["a".to_string(), "\u{0}\u{ff}".to_string()]});
+    let payload = match db.run_command(Command::Dump {key: "abc".to_string()}) {
+        Return::ValueReturn(Value::StrValue(p)) => p,
+        r => panic!("unexpected {:?}", r),
+    };
+    assert_eq!(db.run_command(Command::Dump {key: "def".to_string()}), Return::ValueReturn(Value::Nil));
+    let restore = |key:&str, replace:bool| Command::Restore {key: key.to_string(), ttl: 5000, payload: payload.clone(), replace: replace, absttl: false, idletime: Some(60), freq: Some(20)};
+    assert!(match db.run_command(restore("abc", false)) {
+        Return::Error(ref e) => e.starts_with("BUSYKEY"),
+        _ => false,
+    });
+    assert_eq!(db.run_command(restore("abc", true)), Return::Ok);
+    assert_eq!(db.run_command(restore("def", false)), Return::Ok);
+    assert_eq!(db.run_command(Command::Lindex {key: "def".to_string(), index: 1}), Return::ValueReturn(Value::StrValue("\u{0}\u{ff}".to_string())));
+    assert_eq!(db.run_command(Command::Ttl {key: "def".to_string()}), Return::ValueReturn(Value::IntValue(5)));
+    assert_eq!(db.run_command(Command::ObjectIdleTime {key: "abc".to_string()}), Return::ValueReturn(Value::IntValue(60)));
+    assert_eq!(db.run_command(Command::ObjectFreq {key: "abc".to_string()}), Return::ValueReturn(Value::IntValue(20)));
+    assert_eq!(db.run_command(Command::Restore {key: "ghi".to_string(), ttl: i64::max_value(), payload: payload.clone(), replace: false, absttl: false, idletime: None, freq: None}),
+               Return::Error("ERR invalid expire time in 'restore' command".to_string()));
+    assert_eq!(db.run_command(Command::Restore {key: "ghi".to_string(), ttl: 0, payload: payload.clone(), replace: false, absttl: false, idletime: Some(i64::max_value()), freq: None}), Return::Ok);
+    assert_eq!(db.run_command(Command::Del {keys: vec!["ghi".to_string()]}), Return::ValueReturn(Value::IntValue(1)));
+    let mut bad = payload.clone();
+    bad.pop();
+    assert!(match db.run_command(Command::Restore {key: "ghi".to_string(), ttl: 0, payload: bad, replace: false, absttl: false, idletime: None, freq: None}) {
+        Return::Error(_) => true,
+        _ => false,
+    });
+}
+
+#[test]
+fn test_sort() {
+    let mut db = RustisDb::new();
+    let options = |by:Option<&str>, get:Vec<&str>, desc:bool, alpha:bool| SortOptions {
+        by: by.map(|s| s.to_string()),
+        limit: None,
+        get: get.into_iter().map(|s| s.to_string()).collect(),
+        desc: desc,
+        alpha: alpha,
+    };
+    let strs = |v:Vec<&str>| Value::ArrayValue(v.into_iter().map(|s| Value::StrValue(s.to_string())).collect());
+    db.run_command(Command::Rpush {key: "ids".to_string(), values: vec!["3".to_string(), "1".to_string(), "2".to_string(), "10".to_string()]});
+    assert_eq!(db.run_command(Command::SortRo {key: "ids".to_string(), options: options(None, vec![], false, false)}), Return::ValueReturn(strs(vec!["1", "2", "3", "10"])));
+    assert_eq!(db.run_command(Command::SortRo {key: "ids".to_string(), options: options(None, vec![], true, true)}), Return::ValueReturn(strs(vec!["3", "2", "10", "1"])));
+    for (id, weight) in vec![("1", 30), ("2", 20), ("3", 10)] {
+        db.run_command(Command::Set {key: format!("weight_{}", id), value: Value::IntValue(weight), exp: None});
+        let mut h = HashMap::new();
+        h.insert("name".to_string(), format!("user{}", id));
+        db.run_command(Command::Set {key: format!("user:{}", id), value: Value::HashValue(h), exp: None});
+    }
+    assert_eq!(db.run_command(Command::SortRo {key: "ids".to_string(), options: options(Some("weight_*"), vec![], false, false)}), Return::ValueReturn(strs(vec!["10", "3", "2", "1"])));
+    // ties between weights are broken by the elements themselves
+    for (id, tag) in vec![("1", "b"), ("2", "a"), ("3", "a")] {
+        db.run_command(Command::Set {key: format!("tag_{}", id), value: Value::StrValue(tag.to_string()), exp: None});
+    }
+    assert_eq!(db.run_command(Command::SortRo {key: "ids".to_string(), options: options(Some("tag_*"), vec![], false, true)}), Return::ValueReturn(strs(vec!["10", "2", "3", "1"])));
+    assert_eq!(db.run_command(Command::SortRo {key: "ids".to_string(), options: options(Some("tag_*"), vec![], true, true)}), Return::ValueReturn(strs(vec!["1", "3", "2", "10"])));
+    db.run_command(Command::Set {key: "weight_10".to_string(), value: Value::IntValue(20), exp: None});
+    assert_eq!(db.run_command(Command::SortRo {key: "ids".to_string(), options: options(Some("weight_*"), vec![], false, false)}), Return::ValueReturn(strs(vec!["3", "10", "2", "1"])));
+    db.run_command(Command::Del {keys: vec!["weight_10".to_string()]});
+    assert_eq!(db.run_command(Command::SortRo {key: "ids".to_string(), options: options(Some("nosort"), vec!["#", "user:*->name"], false, false)}), Return::ValueReturn(Value::ArrayValue(vec![
+        Value::StrValue("3".to_string()), Value::StrValue("user3".to_string()),
+        Value::StrValue("1".to_string()), Value::StrValue("user1".to_string()),
+        Value::StrValue("2".to_string()), Value::StrValue("user2".to_string()),
+        Value::StrValue("10".to_string()), Value::Nil,
+    ])));
+    let mut limited = options(None, vec![], false, false);
+    limited.limit = Some((1, 2));
+    assert_eq!(db.run_command(Command::Sort {key: "ids".to_string(), options: limited, store: Some("out".to_string())}), Return::ValueReturn(Value::IntValue(2)));
+    assert_eq!(db.run_command(Command::Lindex {key: "out".to_string(), index: 0}), Return::ValueReturn(Value::StrValue("2".to_string())));
+    db.run_command(Command::Rpush {key: "names".to_string(), values: vec!["b".to_string(), "a".to_string()]});
+    assert!(match db.run_command(Command::SortRo {key: "names".to_string(), options: options(None, vec![], false, false)}) {
+        Return::Error(_) => true,
+        _ => false,
+    });
+}
+
+#[test]
+fn test_hyperloglog() {
+    let mut db = RustisDb::new();
+    let elements = |range: ::std::ops::Range<i32>| range.map(|i| i.to_string()).collect::<Vec<String>>();
+    assert_eq!(db.run_command(Command::Pfadd {key: "a".to_string(), elements: elements(0..100)}), Return::ValueReturn(Value::IntValue(1)));
+    assert_eq!(db.run_command(Command::Pfadd {key: "a".to_string(), elements: elements(0..100)}), Return::ValueReturn(Value::IntValue(0)));
+    assert_eq!(db.run_command(Command::Pfcount {keys: vec!["a".to_string()]}), Return::ValueReturn(Value::IntValue(100)));
+    db.run_command(Command::Pfadd {key: "b".to_string(), elements: elements(50..150)});
+    let union = db.run_command(Command::Pfcount {keys: vec!["a".to_string(), "b".to_string()]});
+    assert!(match union {
+        Return::ValueReturn(Value::IntValue(n)) => n >= 145 && n <= 155,
+        _ => false,
+    });
+    assert_eq!(db.run_command(Command::Pfmerge {destkey: "c".to_string(), sourcekeys: vec!["a".to_string(), "b".to_string()]}), Return::Ok);
+    assert_eq!(db.run_command(Command::Pfcount {keys: vec!["c".to_string()]}), union);
+    // the value is a plain string, so it survives a DUMP/RESTORE round trip
+    let payload = match db.run_command(Command::Dump {key: "c".to_string()}) {
+        Return::ValueReturn(Value::StrValue(p)) => p,
+        r => panic!("unexpected {:?}", r),
+    };
+    db.run_command(Command::Restore {key: "d".to_string(), ttl: 0, payload: payload, replace: false, absttl: false, idletime: None, freq: None});
+    assert_eq!(db.run_command(Command::Pfcount {keys: vec!["d".to_string()]}), union);
+    db.run_command(Command::Set {key: "e".to_string(), value: Value::StrValue("abc".to_string()), exp: None});
+    assert!(match db.run_command(Command::Pfadd {key: "e".to_string(), elements: elements(0..1)}) {
+        Return::Error(_) => true,
+        _ => false,
+    });
+}
+
+#[test]
+fn test_geo() {
+    let mut db = RustisDb::new();
+    let items = vec![(13.361389, 38.115556, "Palermo".to_string()), (15.087269, 37.502669, "Catania".to_string())];
+    assert_eq!(db.run_command(Command::GeoAdd {key: "Sicily".to_string(), nx: false, xx: false, ch: false, items: items}), Return::ValueReturn(Value::IntValue(2)));
+    assert_eq!(db.run_command(Command::GeoDist {key: "Sicily".to_string(), member1: "Palermo".to_string(), member2: "Catania".to_string(), unit: 1000.0}), Return::ValueReturn(Value::StrValue("166.2742".to_string())));
+    assert_eq!(db.run_command(Command::GeoHash {key: "Sicily".to_string(), members: vec!["Palermo".to_string(), "NonExisting".to_string()]}), Return::ValueReturn(Value::ArrayValue(vec![Value::StrValue("sqc8b49rny0".to_string()), Value::Nil])));
+    let search = |from:GeoFrom, by:GeoShape, desc:Option<bool>, withdist:bool| GeoSearchOptions {
+        from: Some(from), by: Some(by), unit: 1000.0, desc: desc, count: None, withcoord: false, withdist: withdist, withhash: false,
+    };
+    assert_eq!(db.run_command(Command::GeoSearch {key: "Sicily".to_string(), options: search(GeoFrom::LonLat(15.0, 37.0), GeoShape::Radius(200000.0), Some(false), true)}), Return::ValueReturn(Value::ArrayValue(vec![
+        Value::ArrayValue(vec![Value::StrValue("Catania".to_string()), Value::StrValue("56.4413".to_string())]),
+        Value::ArrayValue(vec![Value::StrValue("Palermo".to_string()), Value::StrValue("190.4424".to_string())]),
+    ])));
+    assert_eq!(db.run_command(Command::GeoSearch {key: "Sicily".to_string(), options: search(GeoFrom::Member("Palermo".to_string()), GeoShape::Box(100000.0, 100000.0), None, false)}), Return::ValueReturn(Value::ArrayValue(vec![
+        Value::StrValue("Palermo".to_string()),
+    ])));
+    assert_eq!(db.run_command(Command::GeoSearchStore {destination: "near".to_string(), source: "Sicily".to_string(), options: search(GeoFrom::LonLat(15.0, 37.0), GeoShape::Radius(100000.0), None, false), storedist: false}), Return::ValueReturn(Value::IntValue(1)));
+    assert_eq!(db.run_command(Command::GeoPos {key: "near".to_string(), members: vec!["Catania".to_string()]}), db.run_command(Command::GeoPos {key: "Sicily".to_string(), members: vec!["Catania".to_string()]}));
+    // the cells searched wrap around the 180th meridian
+    let items = vec![(179.99, 0.0, "east".to_string()), (-179.99, 0.0, "west".to_string()), (179.0, 0.0, "far east".to_string()), (-179.0, 0.0, "far west".to_string()), (0.0, 0.0, "null island".to_string())];
+    db.run_command(Command::GeoAdd {key: "dateline".to_string(), nx: false, xx: false, ch: false, items: items});
+    let names = |names:&[&str]| Return::ValueReturn(Value::ArrayValue(names.iter().map(|name| Value::StrValue(name.to_string())).collect()));
+    assert_eq!(db.run_command(Command::GeoSearch {key: "dateline".to_string(), options: search(GeoFrom::LonLat(179.999, 0.0), GeoShape::Radius(10000.0), Some(false), false)}), names(&["east", "west"]));
+    assert_eq!(db.run_command(Command::GeoSearch {key: "dateline".to_string(), options: search(GeoFrom::LonLat(-179.999, 0.0), GeoShape::Box(20000.0, 20000.0), Some(false), false)}), names(&["west", "east"]));
+    assert_eq!(db.run_command(Command::GeoSearch {key: "dateline".to_string(), options: search(GeoFrom::Member("far east".to_string()), GeoShape::Radius(250000.0), Some(false), false)}), names(&["far east", "east", "west", "far west"]));
+}
+
+#[test]
+fn test_stream() {
+    let mut db = RustisDb::new();
+    let xadd = |id:&str, n:&str| Command::XAdd {key: "s".to_string(), nomkstream: false, trim: None, id: id.to_string(), fields: vec![("n".to_string(), n.to_string())]};
+    assert_eq!(db.run_command(Command::XAdd {key: "s".to_string(), nomkstream: true, trim: None, id: "*".to_string(), fields: vec![]}), Return::ValueReturn(Value::Nil));
+    assert_eq!(db.run_command(xadd("1-1", "a")), Return::ValueReturn(Value::StrValue("1-1".to_string())));
+    assert_eq!(db.run_command(xadd("1-*", "b")), Return::ValueReturn(Value::StrValue("1-2".to_string())));
+    assert_eq!(db.run_command(xadd("3", "c")), Return::ValueReturn(Value::StrValue("3-0".to_string())));
+    assert_eq!(db.run_command(xadd("2-0", "d")), Return::Error("ERR The ID specified in XADD is equal or smaller than the target stream top item".to_string()));
+    assert_eq!(db.run_command(Command::Type {key: "s".to_string()}), Return::ValueReturn(Value::StrValue("stream".to_string())));
+    let entry = |id:&str, n:&str| Value::ArrayValue(vec![Value::StrValue(id.to_string()), Value::ArrayValue(vec![Value::StrValue("n".to_string()), Value::StrValue(n.to_string())])]);
+    assert_eq!(db.run_command(Command::XRange {key: "s".to_string(), start: "(1-1".to_string(), end: "+".to_string(), count: None}), Return::ValueReturn(Value::ArrayValue(vec![entry("1-2", "b"), entry("3-0", "c")])));
+    assert_eq!(db.run_command(Command::XRevRange {key: "s".to_string(), end: "+".to_string(), start: "-".to_string(), count: Some(1)}), Return::ValueReturn(Value::ArrayValue(vec![entry("3-0", "c")])));
+    assert_eq!(db.run_command(Command::XDel {key: "s".to_string(), ids: vec!["1-2".to_string(), "9-9".to_string()]}), Return::ValueReturn(Value::IntValue(1)));
+    assert_eq!(db.run_command(Command::XTrim {key: "s".to_string(), trim: StreamTrim {minid: false, threshold: "1".to_string(), approx: false, limit: None}}), Return::ValueReturn(Value::IntValue(1)));
+    assert_eq!(db.run_command(Command::XLen {key: "s".to_string()}), Return::ValueReturn(Value::IntValue(1)));
+    let xread = |id:&str| Command::XRead {count: None, block: None, keys: vec!["s".to_string()], ids: vec![id.to_string()]};
+    assert_eq!(db.run_command(xread("$")), Return::ValueReturn(Value::Nil));
+    assert_eq!(db.run_command(xread("1-1")), Return::ValueReturn(Value::ArrayValue(vec![
+        Value::ArrayValue(vec![Value::StrValue("s".to_string()), Value::ArrayValue(vec![entry("3-0", "c")])]),
+    ])));
+    assert_eq!(db.resolve_last_ids(xread("$")), xread("3-0"));
+    match db.run_command(Command::XInfoStream {key: "s".to_string()}) {
+        Return::ValueReturn(Value::ArrayValue(info)) => {
+            assert_eq!(info[7], Value::StrValue("3-0".to_string()));
+            assert_eq!(info[9], Value::StrValue("1-2".to_string()));
+            assert_eq!(info[11], Value::IntValue(3));
+            assert_eq!(info[17], entry("3-0", "c"));
+        }
+        r => panic!("unexpected {:?}", r),
+    }
+}
+
+#[test]
+fn test_stream_groups() {
+    use rustis::command::ClaimOptions;
+    let mut db = RustisDb::new();
+    let s = |v:&str| Value::StrValue(v.to_string());
+    assert_eq!(db.run_command(Command::XGroupCreate {key: "s".to_string(), group: "g".to_string(), id: "$".to_string(), mkstream: false, entries_read: None}), Return::Error("ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.".to_string()));
+    assert_eq!(db.run_command(Command::XGroupCreate {key: "s".to_string(), group: "g".to_string(), id: "$".to_string(), mkstream: true, entries_read: None}), Return::Ok);
+    assert_eq!(db.run_command(Command::XGroupCreate {key: "s".to_string(), group: "g".to_string(), id: "0".to_string(), mkstream: false, entries_read: None}), Return::Error("BUSYGROUP Consumer Group name already exists".to_string()));
+    for id in vec!["1-0", "2-0"] {
+        db.run_command(Command::XAdd {key: "s".to_string(), nomkstream: false, trim: None, id: id.to_string(), fields: vec![("f".to_string(), "v".to_string())]});
+    }
+    let read = |id:&str| Command::XReadGroup {group: "g".to_string(), consumer: "alice".to_string(), count: None, block: None, noack: false, keys: vec!["s".to_string()], ids: vec![id.to_string()]};
+    let entry = |id:&str| Value::ArrayValue(vec![s(id), Value::ArrayValue(vec![s("f"), s("v")])]);
+    assert_eq!(db.run_command(read(">")), Return::ValueReturn(Value::ArrayValue(vec![Value::ArrayValue(vec![s("s"), Value::ArrayValue(vec![entry("1-0"), entry("2-0")])])])));
+    assert_eq!(db.run_command(read(">")), Return::ValueReturn(Value::Nil));
+    assert_eq!(db.run_command(Command::XAck {key: "s".to_string(), group: "g".to_string(), ids: vec!["1-0".to_string()]}), Return::ValueReturn(Value::IntValue(1)));
+    assert_eq!(db.run_command(read("0")), Return::ValueReturn(Value::ArrayValue(vec![Value::ArrayValue(vec![s("s"), Value::ArrayValue(vec![entry("2-0")])])])));
+    assert_eq!(db.run_command(Command::XPending {key: "s".to_string(), group: "g".to_string()}), Return::ValueReturn(Value::ArrayValue(vec![
+        Value::IntValue(1), s("2-0"), s("2-0"), Value::ArrayValue(vec![Value::ArrayValue(vec![s("alice"), s("1")])]),
+    ])));
+    let options = ClaimOptions {idle: None, time: None, retrycount: None, force: false, justid: true, lastid: None};
+    assert_eq!(db.run_command(Command::XClaim {key: "s".to_string(), group: "g".to_string(), consumer: "bob".to_string(), min_idle: 0, ids: vec!["2-0".to_string()], options: options}), Return::ValueReturn(Value::ArrayValue(vec![s("2-0")])));
+    assert_eq!(db.run_command(Command::XAutoClaim {key: "s".to_string(), group: "g".to_string(), consumer: "carol".to_string(), min_idle: 0, start: "0".to_string(), count: None, justid: false}), Return::ValueReturn(Value::ArrayValue(vec![
+        s("0-0"), Value::ArrayValue(vec![entry("2-0")]), Value::ArrayValue(vec![]),
+    ])));
+    match db.run_command(Command::XPendingRange {key: "s".to_string(), group: "g".to_string(), idle: None, start: "-".to_string(), end: "+".to_string(), count: 10, consumer: None}) {
+        Return::ValueReturn(Value::ArrayValue(pending)) => {
+            assert_eq!(pending.len(), 1);
+            match pending[0] {
+                Value::ArrayValue(ref p) => {
+                    assert_eq!(p[1], s("carol"));
+                    assert_eq!(p[3], Value::IntValue(3));
+                }
+                _ => panic!("unexpected {:?}", pending),
+            }
+        }
+        r => panic!("unexpected {:?}", r),
+    }
+    match db.run_command(Command::XInfoGroups {key: "s".to_string()}) {
+        Return::ValueReturn(Value::ArrayValue(groups)) => assert_eq!(groups, vec![Value::ArrayValue(vec![
+            s("name"), s("g"), s("consumers"), Value::IntValue(3), s("pending"), Value::IntValue(1),
+            s("last-delivered-id"), s("2-0"), s("entries-read"), Value::IntValue(2), s("lag"), Value::IntValue(0),
+        ])]),
+        r => panic!("unexpected {:?}", r),
+    }
+    assert_eq!(db.run_command(Command::XGroupDelConsumer {key: "s".to_string(), group: "g".to_string(), consumer: "carol".to_string()}), Return::ValueReturn(Value::IntValue(1)));
+}
+
+#[test]
+fn test_notify() {
+    let mut db = RustisDb::new();
+    let mut config = Config::new();
+    config.set("notify-keyspace-events", "KEA").unwrap();
+    db.configure(&config);
+    let ev = |e:&str, k:&str| (e.to_string(), k.to_string());
+    let take_events = |db:&mut RustisDb| db.take_events().into_iter().map(|(_, e, k)| (e, k)).collect::<Vec<(String, Key)>>();
+    db.run_command(Command::Set {key: "a".to_string(), value: Value::IntValue(1), exp: Some(100)});
+    db.run_command(Command::Incr {key: "a".to_string()});
+    db.run_command(Command::Srem {key: "s".to_string(), members: vec!["x".to_string()]});
+    db.run_command(Command::Rename {key: "a".to_string(), newkey: "b".to_string()});
+    db.run_command(Command::Get {key: "a".to_string()});
+    assert_eq!(take_events(&mut db), vec![ev("set", "a"), ev("expire", "a"), ev("incrby", "a"), ev("rename_from", "a"), ev("rename_to", "b")]);
+    config.set("notify-keyspace-events", "Elnm").unwrap();
+    db.configure(&config);
+    db.run_command(Command::Rpush {key: "l".to_string(), values: vec!["x".to_string()]});
+    db.run_command(Command::Del {keys: vec!["l".to_string()]});
+    db.run_command(Command::Llen {key: "l".to_string()});
+    assert_eq!(take_events(&mut db), vec![ev("new", "l"), ev("rpush", "l"), ev("keymiss", "l")]);
+    config.set("notify-keyspace-events", "Ex").unwrap();
+    db.configure(&config);
+    db.run_command(Command::Set {key: "t".to_string(), value: Value::IntValue(1), exp: Some(1)});
+    assert!(db.next_expire().unwrap() <= now_ms() + 1);
+    ::std::thread::sleep(::std::time::Duration::from_millis(5));
+    // nothing looks the key up, the cron's pass expires it
+    db.active_expire();
+    assert_eq!(take_events(&mut db), vec![ev("expired", "t")]);
+    assert!(!db.values.contains_key("t"));
+}
+
+#[test]
+fn test_triggers() {
+    let mut db = RustisDb::new();
+    let get = |db:&mut RustisDb, key:&str| db.run_command(Command::Get {key: key.to_string()});
+    assert_eq!(db.run_command(Command::TriggerCreate {
+        name: "count".to_string(), pattern: "user:*".to_string(), event: "set".to_string(),
+        commands: vec!["INCR writes".to_string(), "SET last {key}".to_string()], replace: false,
+    }), Return::Ok);
+    db.run_command(Command::Set {key: "user:1".to_string(), value: Value::IntValue(1), exp: None});
+    db.run_command(Command::Set {key: "user:2".to_string(), value: Value::IntValue(1), exp: None});
+    db.run_command(Command::Set {key: "other".to_string(), value: Value::IntValue(1), exp: None});
+    assert_eq!(get(&mut db, "writes"), Return::ValueReturn(Value::IntValue(2)));
+    // the trigger's own SET of `last` doesn't fire it again
+    assert_eq!(get(&mut db, "last"), Return::ValueReturn(Value::StrValue("user:2".to_string())));
+    assert_eq!(db.run_command(Command::TriggerDelete {name: "count".to_string()}), Return::Ok);
+    db.run_command(Command::Set {key: "user:3".to_string(), value: Value::IntValue(1), exp: None});
+    assert_eq!(get(&mut db, "writes"), Return::ValueReturn(Value::IntValue(2)));
+}
+
+#[test]
+fn test_watch_versions() {
+    let mut db = RustisDb::new();
+    let mut other = RustisDb::new();
+    let key = "k".to_string();
+    let v = db.watch(&key);
+    db.run_command(Command::Get {key: key.clone()});
+    db.run_command(Command::Sadd {key: "s".to_string(), members: vec!["x".to_string()]});
+    assert_eq!(db.key_version(&key), v);
+    db.run_command(Command::Set {key: key.clone(), value: Value::IntValue(1), exp: None});
+    let v = db.key_version(&key);
+    assert!(v > 0);
+    // a key whose time has passed changes when it's expired
+    db.set_expire(&key, Some(now_ms() - 1));
+    assert!(db.key_version(&key) > v);
+    let v = db.key_version(&key);
+    db.run_command(Command::FlushDb);
+    assert_eq!(db.key_version(&key), v);
+    other.run_command(Command::Set {key: key.clone(), value: Value::IntValue(1), exp: None});
+    RustisDb::swap_data(&mut db, &mut other);
+    assert!(db.key_version(&key) > v);
+    db.unwatch(&key);
+    assert_eq!(db.key_version(&key), 0);
+}
+
+#[test]
+fn test_changes() {
+    let mut db = RustisDb::new();
+    db.run_command(Command::Set {key: "a".to_string(), value: Value::IntValue(1), exp: None});
+    db.run_command(Command::Sadd {key: "s".to_string(), members: vec!["x".to_string()]});
+    db.run_command(Command::Get {key: "a".to_string()});
+    db.run_command(Command::Get {key: "missing".to_string()});
+    assert_eq!(db.changes(), 2);
+    db.run_command(Command::FlushDb);
+    assert_eq!(db.changes(), 4);
+}
+
+#[test]
+fn test_propagated() {
+    let mut config = Config::new();
+    config.appendonly = true;
+    let mut db = RustisDb::new();
+    db.configure(&config);
+    let args = |v:&[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+    db.run_command(Command::Set {key: "a".to_string(), value: Value::IntValue(1), exp: Some(100000)});
+    let at = db.get_entry(&"a".to_string()).unwrap().unwrap().1.unwrap().to_string();
+    assert_eq!(db.take_propagated(), vec![args(&["SET", "a", "1"]), args(&["PEXPIREAT", "a", &at])]);
+    // reads and writes that change nothing aren't logged
+    db.run_command(Command::Get {key: "a".to_string()});
+    db.run_command(Command::Expire {key: "missing".to_string(), seconds: 10});
+    assert_eq!(db.take_propagated(), Vec::<Vec<String>>::new());
+    db.run_command(Command::Pexpire {key: "a".to_string(), milliseconds: 0});
+    assert_eq!(db.take_propagated(), vec![args(&["DEL", "a"])]);
+    let id = match db.run_command(Command::XAdd {key: "s".to_string(), nomkstream: false, trim: None, id: "*".to_string(), fields: vec![("f".to_string(), "v".to_string())]}) {
+        Return::ValueReturn(Value::StrValue(id)) => id,
+        r => panic!("{:?}", r),
+    };
+    assert_eq!(db.take_propagated(), vec![args(&["XADD", "s", &id, "f", "v"])]);
+    db.run_command(Command::Set {key: "b".to_string(), value: Value::IntValue(1), exp: Some(1)});
+    db.take_propagated();
+    ::std::thread::sleep(::std::time::Duration::from_millis(5));
+    db.run_command(Command::Get {key: "b".to_string()});
+    assert_eq!(db.take_propagated(), vec![args(&["DEL", "b"])]);
+}
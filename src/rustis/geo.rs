@@ -0,0 +1,288 @@
+// geohash encoding of coordinates into sorted set scores, following redis'
+// geohash.c and geohash_helper.c so scores are interchangeable
+
+pub const GEO_LONG_MIN:f64 = -180.0;
+pub const GEO_LONG_MAX:f64 = 180.0;
+pub const GEO_LAT_MIN:f64 = -85.05112878;
+pub const GEO_LAT_MAX:f64 = 85.05112878;
+const GEO_STEP_MAX:u32 = 26;
+const EARTH_RADIUS_IN_METERS:f64 = 6372797.560856;
+const MERCATOR_MAX:f64 = 20037726.37;
+const GEO_ALPHABET:&'static [u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+pub fn valid_lon_lat(lon:f64, lat:f64) -> bool {
+    return lon >= GEO_LONG_MIN && lon <= GEO_LONG_MAX && lat >= GEO_LAT_MIN && lat <= GEO_LAT_MAX;
+}
+
+// spreads the low 32 bits of x over the even bits of the result
+fn spread(x:u32) -> u64 {
+    let mut x = x as u64;
+    x = (x | (x << 16)) & 0x0000ffff0000ffff;
+    x = (x | (x << 8)) & 0x00ff00ff00ff00ff;
+    x = (x | (x << 4)) & 0x0f0f0f0f0f0f0f0f;
+    x = (x | (x << 2)) & 0x3333333333333333;
+    x = (x | (x << 1)) & 0x5555555555555555;
+    return x;
+}
+
+fn squash(x:u64) -> u32 {
+    let mut x = x & 0x5555555555555555;
+    x = (x | (x >> 1)) & 0x3333333333333333;
+    x = (x | (x >> 2)) & 0x0f0f0f0f0f0f0f0f;
+    x = (x | (x >> 4)) & 0x00ff00ff00ff00ff;
+    x = (x | (x >> 8)) & 0x0000ffff0000ffff;
+    x = (x | (x >> 16)) & 0x00000000ffffffff;
+    return x as u32;
+}
+
+fn encode(lon:f64, lat:f64, lon_range:(f64, f64), lat_range:(f64, f64)) -> u64 {
+    let lat_offset = (lat - lat_range.0) / (lat_range.1 - lat_range.0);
+    let lon_offset = (lon - lon_range.0) / (lon_range.1 - lon_range.0);
+    let lat_bits = (lat_offset * (1u64 << GEO_STEP_MAX) as f64) as u32;
+    let lon_bits = (lon_offset * (1u64 << GEO_STEP_MAX) as f64) as u32;
+    return spread(lat_bits) | (spread(lon_bits) << 1);
+}
+
+// the 52 bit geohash used as a sorted set score
+pub fn encode_score(lon:f64, lat:f64) -> f64 {
+    return encode(lon, lat, (GEO_LONG_MIN, GEO_LONG_MAX), (GEO_LAT_MIN, GEO_LAT_MAX)) as f64;
+}
+
+// the center of the area a score covers, as (longitude, latitude)
+pub fn decode_score(score:f64) -> (f64, f64) {
+    let bits = score as u64;
+    let lat_bits = squash(bits) as f64;
+    let lon_bits = squash(bits >> 1) as f64;
+    let cells = (1u64 << GEO_STEP_MAX) as f64;
+    let lat_scale = GEO_LAT_MAX - GEO_LAT_MIN;
+    let lon_scale = GEO_LONG_MAX - GEO_LONG_MIN;
+    let lat_min = GEO_LAT_MIN + (lat_bits / cells) * lat_scale;
+    let lat_max = GEO_LAT_MIN + ((lat_bits + 1.0) / cells) * lat_scale;
+    let lon_min = GEO_LONG_MIN + (lon_bits / cells) * lon_scale;
+    let lon_max = GEO_LONG_MIN + ((lon_bits + 1.0) / cells) * lon_scale;
+    let lon = ((lon_min + lon_max) / 2.0).max(GEO_LONG_MIN).min(GEO_LONG_MAX);
+    let lat = ((lat_min + lat_max) / 2.0).max(GEO_LAT_MIN).min(GEO_LAT_MAX);
+    return (lon, lat);
+}
+
+// the cell of a point with `step` bits per coordinate, as (longitude
+// bits, latitude bits)
+fn cell(lon:f64, lat:f64, step:u32) -> (u32, u32) {
+    let cells = (1u64 << step) as f64;
+    let lon_bits = ((lon - GEO_LONG_MIN) / (GEO_LONG_MAX - GEO_LONG_MIN) * cells) as u64;
+    let lat_bits = ((lat - GEO_LAT_MIN) / (GEO_LAT_MAX - GEO_LAT_MIN) * cells) as u64;
+    let max = (1u64 << step) - 1;
+    return (lon_bits.min(max) as u32, lat_bits.min(max) as u32);
+}
+
+// the area of a cell as (min longitude, max longitude, min latitude, max
+// latitude)
+fn cell_area(lon_bits:u32, lat_bits:u32, step:u32) -> (f64, f64, f64, f64) {
+    let cells = (1u64 << step) as f64;
+    let lon_scale = (GEO_LONG_MAX - GEO_LONG_MIN) / cells;
+    let lat_scale = (GEO_LAT_MAX - GEO_LAT_MIN) / cells;
+    return (
+        GEO_LONG_MIN + lon_bits as f64 * lon_scale, GEO_LONG_MIN + (lon_bits as f64 + 1.0) * lon_scale,
+        GEO_LAT_MIN + lat_bits as f64 * lat_scale, GEO_LAT_MIN + (lat_bits as f64 + 1.0) * lat_scale,
+    );
+}
+
+// how many bits per coordinate cells need to be about as big as the radius,
+// following geohashEstimateStepsByRadius
+fn estimate_step(radius:f64, lat:f64) -> u32 {
+    if radius == 0.0 {
+        return GEO_STEP_MAX;
+    }
+    let mut range = radius;
+    let mut step:i32 = 1;
+    while range < MERCATOR_MAX {
+        range *= 2.0;
+        step += 1;
+    }
+    // cells narrow towards the poles
+    step -= 2;
+    if lat > 66.0 || lat < -66.0 {
+        step -= 1;
+        if lat > 80.0 || lat < -80.0 {
+            step -= 1;
+        }
+    }
+    return step.max(1).min(GEO_STEP_MAX as i32) as u32;
+}
+
+// the score ranges [min, max) of the cells a search around a point has to
+// look at: the cell of the point and its 8 neighbours, which wrap around the
+// 180th meridian, sized so that they cover a circle of `radius` or a box of
+// `width` by `height` meters, like geohashCalculateAreasByShapeWGS84
+pub fn search_ranges(lon:f64, lat:f64, radius:f64, width:f64, height:f64) -> Vec<(f64, f64)> {
+    let radius = radius * 1.0001;
+    let mut step = estimate_step(radius, lat);
+    let (mut lon_bits, mut lat_bits) = cell(lon, lat, step);
+    // near the edges of its cell the neighbours may not reach far enough
+    if step > 1 {
+        let cells = 1u32 << step;
+        let (_, _, _, north) = cell_area(lon_bits, (lat_bits + 1).min(cells - 1), step);
+        let (_, _, south, _) = cell_area(lon_bits, lat_bits.saturating_sub(1), step);
+        let (_, east, _, _) = cell_area((lon_bits + 1) % cells, lat_bits, step);
+        let (west, _, _, _) = cell_area((lon_bits + cells - 1) % cells, lat_bits, step);
+        if distance(lon, lat, lon, north) < radius || distance(lon, lat, lon, south) < radius ||
+            distance(lon, lat, east, lat) < radius || distance(lon, lat, west, lat) < radius {
+            step -= 1;
+            let (x, y) = cell(lon, lat, step);
+            lon_bits = x;
+            lat_bits = y;
+        }
+    }
+    // neighbours on a side the search doesn't reach past the cell are skipped
+    let lat_delta = (height / 2.0 / EARTH_RADIUS_IN_METERS).to_degrees();
+    let lon_delta_top = (width / 2.0 / EARTH_RADIUS_IN_METERS / (lat + lat_delta).to_radians().cos()).to_degrees();
+    let lon_delta_bottom = (width / 2.0 / EARTH_RADIUS_IN_METERS / (lat - lat_delta).to_radians().cos()).to_degrees();
+    let lon_delta = if lat < 0.0 {lon_delta_bottom} else {lon_delta_top};
+    let (min_lon, max_lon, min_lat, max_lat) = cell_area(lon_bits, lat_bits, step);
+    let skip = |dx:i64, dy:i64| step >= 2 && (
+        (dy < 0 && min_lat < lat - lat_delta) || (dy > 0 && max_lat > lat + lat_delta) ||
+        (dx < 0 && min_lon < lon - lon_delta) || (dx > 0 && max_lon > lon + lon_delta));
+    let cells = 1i64 << step;
+    let shift = 2 * (GEO_STEP_MAX - step);
+    let mut ranges = Vec::with_capacity(9);
+    for dy in -1..2 {
+        let y = lat_bits as i64 + dy;
+        if y < 0 || y >= cells || skip(0, dy) {
+            continue;
+        }
+        for dx in -1..2 {
+            if skip(dx, 0) {
+                continue;
+            }
+            let x = (lon_bits as i64 + dx + cells) % cells;
+            let bits = spread(y as u32) | (spread(x as u32) << 1);
+            ranges.push(((bits << shift) as f64, ((bits + 1) << shift) as f64));
+        }
+    }
+    // with few bits the neighbours on both sides can be the same cell
+    ranges.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    ranges.dedup();
+    return ranges;
+}
+
+// the standard 11 character geohash string, which uses the full latitude
+// range instead of the mercator limits
+pub fn geohash_string(score:f64) -> String {
+    let (lon, lat) = decode_score(score);
+    let bits = encode(lon, lat, (-180.0, 180.0), (-90.0, 90.0));
+    let mut s = String::with_capacity(11);
+    for i in 0..11 {
+        let idx = if i == 10 {0} else {(bits >> (52 - ((i + 1) * 5))) & 0x1f};
+        s.push(GEO_ALPHABET[idx as usize] as char);
+    }
+    return s;
+}
+
+// great-circle distance in meters
+pub fn distance(lon1:f64, lat1:f64, lon2:f64, lat2:f64) -> f64 {
+    let lat1r = lat1.to_radians();
+    let lat2r = lat2.to_radians();
+    let u = ((lat2r - lat1r) / 2.0).sin();
+    let v = ((lon2.to_radians() - lon1.to_radians()) / 2.0).sin();
+    let a = u * u + lat1r.cos() * lat2r.cos() * v * v;
+    return 2.0 * EARTH_RADIUS_IN_METERS * a.sqrt().asin();
+}
+
+// the distance from the center to a point inside a box of the given size in
+// meters, or None if the point is outside it
+pub fn distance_in_box(lon:f64, lat:f64, width:f64, height:f64, point_lon:f64, point_lat:f64) -> Option<f64> {
+    let lat_distance = EARTH_RADIUS_IN_METERS * (point_lat.to_radians() - lat.to_radians()).abs();
+    if lat_distance > height / 2.0 {
+        return None;
+    }
+    let lon_distance = distance(point_lon, point_lat, lon, point_lat);
+    if lon_distance > width / 2.0 {
+        return None;
+    }
+    return Some(distance(lon, lat, point_lon, point_lat));
+}
+
+// formats like printf's %.17g, which redis uses for coordinates
+pub fn format_coordinate(x:f64) -> String {
+    if x == 0.0 {
+        return "0".to_string();
+    }
+    let digits = x.abs().log10().floor() as i32 + 1;
+    let decimals = if digits >= 17 {0} else {(17 - digits) as usize};
+    let s = format!("{:.*}", decimals, x);
+    if s.contains('.') {
+        return s.trim_end_matches('0').trim_end_matches('.').to_string();
+    }
+    return s;
+}
+
+pub fn format_distance(x:f64) -> String {
+    return format!("{:.4}", x);
+}
+
+#[test]
+fn test_geohash_round_trip() {
+    let score = encode_score(13.361389, 38.115556);
+    // the score redis stores for Palermo
+    assert_eq!(score, 3479099956230698.0);
+    let (lon, lat) = decode_score(score);
+    assert!((lon - 13.361389).abs() < 0.00001);
+    assert!((lat - 38.115556).abs() < 0.00001);
+    assert_eq!(geohash_string(score), "sqc8b49rny0");
+    assert_eq!(encode_score(15.087269, 37.502669), 3479447370796909.0);
+}
+
+#[test]
+fn test_distance() {
+    let (lon1, lat1) = decode_score(encode_score(13.361389, 38.115556));
+    let (lon2, lat2) = decode_score(encode_score(15.087269, 37.502669));
+    // redis reports 166274.1516 meters between Palermo and Catania
+    assert_eq!(format_distance(distance(lon1, lat1, lon2, lat2)), "166274.1516");
+    assert!(distance_in_box(15.0, 37.0, 400000.0, 400000.0, lon1, lat1).is_some());
+    assert!(distance_in_box(15.0, 37.0, 100000.0, 400000.0, lon1, lat1).is_none());
+}
+
+#[test]
+fn test_format_coordinate() {
+    assert_eq!(format_coordinate(13.361389338970184), "13.361389338970184");
+    assert_eq!(format_coordinate(-0.5), "-0.5");
+    assert_eq!(format_coordinate(180.0), "180");
+}
+
+#[test]
+fn test_search_ranges() {
+    let covered = |ranges:&Vec<(f64, f64)>, lon:f64, lat:f64| {
+        let score = encode_score(lon, lat);
+        ranges.iter().any(|&(min, max)| score >= min && score < max)
+    };
+    // the cells wrap around the 180th meridian, and leave out far points
+    let ranges = search_ranges(179.999, 0.0, 10000.0, 20000.0, 20000.0);
+    assert!(ranges.len() <= 9);
+    assert!(covered(&ranges, 179.99, 0.0));
+    assert!(covered(&ranges, -179.99, 0.0));
+    assert!(!covered(&ranges, 179.0, 0.0));
+    assert!(!covered(&ranges, -179.0, 0.0));
+    assert!(!covered(&ranges, 0.0, 0.0));
+    // every point in the circle is in one of the cells
+    for &(lon, lat, radius) in [(179.9, 10.0, 50000.0), (-179.95, -45.0, 5000.0), (13.36, 38.11, 200000.0), (0.0, 84.9, 100000.0), (-120.0, -70.0, 1000.0)].iter() {
+        let ranges = search_ranges(lon, lat, radius, radius * 2.0, radius * 2.0);
+        for i in -50..51 {
+            for j in -50..51 {
+                let point_lat = lat + j as f64 * radius / 40.0 / 111000.0;
+                let mut point_lon = lon + i as f64 * radius / 40.0 / 111000.0 / point_lat.to_radians().cos();
+                if point_lon > 180.0 {
+                    point_lon -= 360.0;
+                } else if point_lon < -180.0 {
+                    point_lon += 360.0;
+                }
+                if !valid_lon_lat(point_lon, point_lat) {
+                    continue;
+                }
+                let (x, y) = decode_score(encode_score(point_lon, point_lat));
+                if distance(lon, lat, x, y) <= radius {
+                    assert!(covered(&ranges, point_lon, point_lat), "{},{} around {},{}", point_lon, point_lat, lon, lat);
+                }
+            }
+        }
+    }
+}
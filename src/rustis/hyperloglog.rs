@@ -0,0 +1,334 @@
+// HyperLogLog cardinality estimation, stored in the same string format as
+// redis so values can be moved between the two with GET/SET and DUMP/RESTORE
+
+const HLL_P:u32 = 14;
+const HLL_Q:u32 = 64 - HLL_P;
+const HLL_REGISTERS:usize = 1 << HLL_P;
+const HLL_BITS:usize = 6;
+const HLL_REGISTER_MAX:u8 = (1 << HLL_BITS) - 1;
+const HLL_HDR_SIZE:usize = 16;
+const HLL_DENSE_SIZE:usize = HLL_HDR_SIZE + (HLL_REGISTERS * HLL_BITS + 7) / 8;
+const HLL_DENSE:u8 = 0;
+const HLL_SPARSE:u8 = 1;
+const HLL_ALPHA_INF:f64 = 0.721347520444481703680;
+const HLL_SEED:u64 = 0xadc83b19;
+
+// sparse opcodes
+const HLL_SPARSE_XZERO_BIT:u8 = 0x40;
+const HLL_SPARSE_VAL_BIT:u8 = 0x80;
+const HLL_SPARSE_VAL_MAX_VALUE:u8 = 32;
+const HLL_SPARSE_VAL_MAX_LEN:usize = 4;
+const HLL_SPARSE_ZERO_MAX_LEN:usize = 64;
+const HLL_SPARSE_XZERO_MAX_LEN:usize = 16384;
+
+// sparse values larger than this are converted to the dense representation
+pub const HLL_SPARSE_MAX_BYTES:usize = 3000;
+
+pub const INVALID_HLL_ERR:&'static str = "WRONGTYPE Key is not a valid HyperLogLog string value.";
+pub const CORRUPTED_HLL_ERR:&'static str = "INVALIDOBJ Corrupted HLL object detected";
+
+#[derive(Clone)]
+pub struct HyperLogLog {
+    registers:Vec<u8>,
+}
+
+impl HyperLogLog {
+    pub fn new() -> HyperLogLog {
+        return HyperLogLog {
+            registers: vec![0; HLL_REGISTERS],
+        };
+    }
+
+    pub fn is_hll(bytes:&[u8]) -> bool {
+        return bytes.len() >= HLL_HDR_SIZE && &bytes[0..4] == b"HYLL";
+    }
+
+    pub fn from_bytes(bytes:&[u8]) -> Result<HyperLogLog, &'static str> {
+        if !HyperLogLog::is_hll(bytes) || bytes[4] > HLL_SPARSE {
+            return Err(INVALID_HLL_ERR);
+        }
+        let mut hll = HyperLogLog::new();
+        let data = &bytes[HLL_HDR_SIZE..];
+        if bytes[4] == HLL_DENSE {
+            if bytes.len() != HLL_DENSE_SIZE {
+                return Err(INVALID_HLL_ERR);
+            }
+            for i in 0..HLL_REGISTERS {
+                hll.registers[i] = dense_get(data, i);
+            }
+            return Ok(hll);
+        }
+        let mut i = 0;
+        let mut index = 0;
+        while i < data.len() {
+            let op = data[i];
+            if op & HLL_SPARSE_VAL_BIT != 0 {
+                let value = ((op >> 2) & 0x1f) + 1;
+                let len = (op & 0x3) as usize + 1;
+                if index + len > HLL_REGISTERS {
+                    return Err(CORRUPTED_HLL_ERR);
+                }
+                for r in index..index + len {
+                    hll.registers[r] = value;
+                }
+                index += len;
+                i += 1;
+            } else if op & HLL_SPARSE_XZERO_BIT != 0 {
+                if i + 1 >= data.len() {
+                    return Err(CORRUPTED_HLL_ERR);
+                }
+                index += ((((op & 0x3f) as usize) << 8) | data[i + 1] as usize) + 1;
+                i += 2;
+            } else {
+                index += (op & 0x3f) as usize + 1;
+                i += 1;
+            }
+        }
+        if index != HLL_REGISTERS {
+            return Err(CORRUPTED_HLL_ERR);
+        }
+        return Ok(hll);
+    }
+
+    // serializes as sparse when that is allowed and small enough, otherwise
+    // dense; `count` fills in the cached cardinality
+    pub fn to_bytes(&self, sparse:bool, count:Option<u64>) -> Vec<u8> {
+        let mut bytes = b"HYLL".to_vec();
+        bytes.extend_from_slice(&[HLL_DENSE, 0, 0, 0]);
+        match count {
+            Some(c) => bytes.extend_from_slice(&c.to_le_bytes()),
+            None => bytes.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0x80]),
+        }
+        if sparse {
+            if let Some(data) = self.sparse_data() {
+                if HLL_HDR_SIZE + data.len() <= HLL_SPARSE_MAX_BYTES {
+                    bytes[4] = HLL_SPARSE;
+                    bytes.extend_from_slice(&data);
+                    return bytes;
+                }
+            }
+        }
+        let mut data = vec![0u8; HLL_DENSE_SIZE - HLL_HDR_SIZE];
+        for (i, &r) in self.registers.iter().enumerate() {
+            dense_set(&mut data, i, r);
+        }
+        bytes.extend_from_slice(&data);
+        return bytes;
+    }
+
+    fn sparse_data(&self) -> Option<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut i = 0;
+        while i < HLL_REGISTERS {
+            let value = self.registers[i];
+            if value > HLL_SPARSE_VAL_MAX_VALUE {
+                return None;
+            }
+            let mut run = 1;
+            while i + run < HLL_REGISTERS && self.registers[i + run] == value {
+                run += 1;
+            }
+            i += run;
+            while run > 0 {
+                if value == 0 && run > HLL_SPARSE_ZERO_MAX_LEN {
+                    let len = if run > HLL_SPARSE_XZERO_MAX_LEN {HLL_SPARSE_XZERO_MAX_LEN} else {run};
+                    data.push(HLL_SPARSE_XZERO_BIT | ((len - 1) >> 8) as u8);
+                    data.push(((len - 1) & 0xff) as u8);
+                    run -= len;
+                } else if value == 0 {
+                    data.push((run - 1) as u8);
+                    run = 0;
+                } else {
+                    let len = if run > HLL_SPARSE_VAL_MAX_LEN {HLL_SPARSE_VAL_MAX_LEN} else {run};
+                    data.push(HLL_SPARSE_VAL_BIT | ((value - 1) << 2) | (len - 1) as u8);
+                    run -= len;
+                }
+            }
+        }
+        return Some(data);
+    }
+
+    // returns whether the estimate may have changed
+    pub fn add(&mut self, element:&[u8]) -> bool {
+        let hash = murmurhash64a(element, HLL_SEED);
+        let index = (hash & (HLL_REGISTERS as u64 - 1)) as usize;
+        // the position of the first set bit in the remaining bits
+        let rest = (hash >> HLL_P) | (1 << HLL_Q);
+        let count = rest.trailing_zeros() as u8 + 1;
+        if count > self.registers[index] {
+            self.registers[index] = count;
+            return true;
+        }
+        return false;
+    }
+
+    pub fn merge(&mut self, other:&HyperLogLog) {
+        for (r, &o) in self.registers.iter_mut().zip(other.registers.iter()) {
+            if o > *r {
+                *r = o;
+            }
+        }
+    }
+
+    // the estimator from Otmar Ertl's "New cardinality estimation algorithms
+    // for HyperLogLog sketches", as used by redis
+    pub fn count(&self) -> u64 {
+        let m = HLL_REGISTERS as f64;
+        let mut histogram = [0u32; 64];
+        for &r in self.registers.iter() {
+            histogram[r as usize] += 1;
+        }
+        let q = HLL_Q as usize;
+        let mut z = m * tau((m - histogram[q + 1] as f64) / m);
+        for j in (1..q + 1).rev() {
+            z += histogram[j] as f64;
+            z *= 0.5;
+        }
+        z += m * sigma(histogram[0] as f64 / m);
+        return (HLL_ALPHA_INF * m * m / z).round() as u64;
+    }
+}
+
+// the cached cardinality in a serialized HLL's header, if valid
+pub fn cached_count(bytes:&[u8]) -> Option<u64> {
+    if bytes.len() < HLL_HDR_SIZE || bytes[15] & 0x80 != 0 {
+        return None;
+    }
+    let mut card = [0u8; 8];
+    card.copy_from_slice(&bytes[8..16]);
+    return Some(u64::from_le_bytes(card));
+}
+
+pub fn is_sparse(bytes:&[u8]) -> bool {
+    return bytes.len() > 4 && bytes[4] == HLL_SPARSE;
+}
+
+fn dense_get(data:&[u8], index:usize) -> u8 {
+    let byte = index * HLL_BITS / 8;
+    let fb = (index * HLL_BITS) & 7;
+    let b0 = data[byte] as u16;
+    let b1 = *data.get(byte + 1).unwrap_or(&0) as u16;
+    return (((b0 >> fb) | (b1 << (8 - fb))) & HLL_REGISTER_MAX as u16) as u8;
+}
+
+fn dense_set(data:&mut [u8], index:usize, value:u8) {
+    let byte = index * HLL_BITS / 8;
+    let fb = (index * HLL_BITS) & 7;
+    let v = value as u16;
+    data[byte] &= !((HLL_REGISTER_MAX as u16) << fb) as u8;
+    data[byte] |= (v << fb) as u8;
+    if byte + 1 < data.len() {
+        data[byte + 1] &= !((HLL_REGISTER_MAX as u16) >> (8 - fb)) as u8;
+        data[byte + 1] |= (v >> (8 - fb)) as u8;
+    }
+}
+
+fn sigma(x:f64) -> f64 {
+    if x == 1.0 {
+        return ::std::f64::INFINITY;
+    }
+    let mut x = x;
+    let mut y = 1.0;
+    let mut z = x;
+    loop {
+        x *= x;
+        let z_prime = z;
+        z += x * y;
+        y += y;
+        if z_prime == z {
+            return z;
+        }
+    }
+}
+
+fn tau(x:f64) -> f64 {
+    if x == 0.0 || x == 1.0 {
+        return 0.0;
+    }
+    let mut x = x;
+    let mut y = 1.0;
+    let mut z = 1.0 - x;
+    loop {
+        x = x.sqrt();
+        let z_prime = z;
+        y *= 0.5;
+        z -= (1.0 - x).powi(2) * y;
+        if z_prime == z {
+            return z / 3.0;
+        }
+    }
+}
+
+pub fn murmurhash64a(data:&[u8], seed:u64) -> u64 {
+    const M:u64 = 0xc6a4a7935bd1e995;
+    const R:u32 = 47;
+    let mut h = seed ^ (data.len() as u64).wrapping_mul(M);
+    let chunks = data.len() / 8;
+    for i in 0..chunks {
+        let mut k = 0u64;
+        for j in 0..8 {
+            k |= (data[i * 8 + j] as u64) << (8 * j);
+        }
+        k = k.wrapping_mul(M);
+        k ^= k >> R;
+        k = k.wrapping_mul(M);
+        h ^= k;
+        h = h.wrapping_mul(M);
+    }
+    let tail = &data[chunks * 8..];
+    if tail.len() > 0 {
+        for (j, &b) in tail.iter().enumerate() {
+            h ^= (b as u64) << (8 * j);
+        }
+        h = h.wrapping_mul(M);
+    }
+    h ^= h >> R;
+    h = h.wrapping_mul(M);
+    h ^= h >> R;
+    return h;
+}
+
+#[test]
+fn test_empty() {
+    let bytes = HyperLogLog::new().to_bytes(true, Some(0));
+    // header followed by a single XZERO covering every register, like redis
+    assert_eq!(bytes, b"HYLL\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x7f\xff".to_vec());
+    assert_eq!(HyperLogLog::from_bytes(&bytes).unwrap().count(), 0);
+}
+
+#[test]
+fn test_accuracy() {
+    let mut hll = HyperLogLog::new();
+    let mut checked = 0;
+    for i in 0..200000 {
+        hll.add(format!("element:{}", i).as_bytes());
+        if i + 1 == 10 || i + 1 == 1000 || i + 1 == 200000 {
+            let n = (i + 1) as f64;
+            let error = (hll.count() as f64 - n).abs() / n;
+            // several times the 0.81% standard error
+            assert!(error < 0.04, "error {} at {}", error, n);
+            checked += 1;
+        }
+    }
+    assert_eq!(checked, 3);
+}
+
+#[test]
+fn test_sparse_dense_round_trip() {
+    let mut hll = HyperLogLog::new();
+    for i in 0..100 {
+        hll.add(i.to_string().as_bytes());
+    }
+    let sparse = hll.to_bytes(true, None);
+    assert!(is_sparse(&sparse));
+    assert_eq!(cached_count(&sparse), None);
+    let dense = hll.to_bytes(false, Some(100));
+    assert!(!is_sparse(&dense));
+    assert_eq!(dense.len(), HLL_DENSE_SIZE);
+    assert_eq!(cached_count(&dense), Some(100));
+    let from_sparse = HyperLogLog::from_bytes(&sparse).unwrap();
+    let from_dense = HyperLogLog::from_bytes(&dense).unwrap();
+    assert_eq!(from_sparse.registers, hll.registers);
+    assert_eq!(from_dense.registers, hll.registers);
+    assert!(HyperLogLog::from_bytes(b"not an hll").is_err());
+}
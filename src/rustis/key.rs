@@ -1,11 +1,22 @@
 use std::cmp::Ordering;
+use std::ptr::null_mut;
+use libc::{timeval, gettimeofday, time_t, suseconds_t};
 
 pub type Key = String;
 
 #[derive(Clone, Eq, PartialEq)]
 pub struct ExpireTime {
-    key:Key,
-    expire_at:u64,
+    pub key:Key,
+    pub expire_at:u64,
+}
+
+impl ExpireTime {
+    pub fn new(key:Key, expire_at:u64) -> ExpireTime {
+        return ExpireTime {
+            key: key,
+            expire_at: expire_at,
+        };
+    }
 }
 
 impl Ord for ExpireTime {
@@ -19,3 +30,64 @@ impl PartialOrd for ExpireTime {
         Some(self.cmp(other))
     }
 }
+
+const LFU_INIT_VAL:u8 = 5;
+const LFU_LOG_FACTOR:f64 = 10.0;
+const LFU_DECAY_MINUTES:u64 = 1;
+
+// access metadata tracked for each key, following redis' LRU clock and
+// logarithmic LFU counter
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyMeta {
+    pub last_access:u64,
+    counter:u8,
+    counter_decremented_at:u64,
+}
+
+impl KeyMeta {
+    pub fn new(now:u64) -> KeyMeta {
+        return KeyMeta {
+            last_access: now,
+            counter: LFU_INIT_VAL,
+            counter_decremented_at: now / 60000,
+        };
+    }
+
+    // `r` is a random number in [0, 1) used for the probabilistic increment
+    pub fn access(&mut self, now:u64, r:f64) {
+        let mut counter = self.freq(now);
+        if counter < 255 {
+            let base = counter.saturating_sub(LFU_INIT_VAL) as f64;
+            if r < 1.0 / (base * LFU_LOG_FACTOR + 1.0) {
+                counter += 1;
+            }
+        }
+        self.counter = counter;
+        self.counter_decremented_at = now / 60000;
+        self.last_access = now;
+    }
+
+    pub fn set_freq(&mut self, now:u64, freq:u8) {
+        self.counter = freq;
+        self.counter_decremented_at = now / 60000;
+    }
+
+    pub fn freq(&self, now:u64) -> u8 {
+        let periods = (now / 60000).saturating_sub(self.counter_decremented_at) / LFU_DECAY_MINUTES;
+        return if periods > self.counter as u64 {0} else {self.counter - periods as u8};
+    }
+
+    pub fn idle_seconds(&self, now:u64) -> u64 {
+        return now.saturating_sub(self.last_access) / 1000;
+    }
+}
+
+// current unix time in milliseconds
+pub fn now_ms() -> u64 {
+    let mut t = timeval {tv_sec: 0 as time_t, tv_usec: 0 as suseconds_t};
+    unsafe {
+        gettimeofday(&mut t, null_mut());
+    }
+    return (t.tv_sec as u64) * 1000 + (t.tv_usec as u64) / 1000;
+}
+
@@ -0,0 +1,74 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use rustis::value::Value;
+
+// values with more allocations than this are freed in the background
+pub const LAZYFREE_THRESHOLD:usize = 64;
+
+// handle to a background thread that drops whatever it is sent, so freeing
+// large values doesn't stall the event loop
+#[derive(Clone)]
+pub struct LazyFree {
+    sender:Sender<Box<dyn Send>>,
+    pending:Arc<AtomicUsize>,
+}
+
+impl LazyFree {
+    pub fn new() -> LazyFree {
+        let (sender, receiver) = channel::<Box<dyn Send>>();
+        let pending = Arc::new(AtomicUsize::new(0));
+        let thread_pending = pending.clone();
+        thread::Builder::new().name("lazyfree".to_string()).spawn(move || {
+            for garbage in receiver {
+                drop(garbage);
+                thread_pending.fetch_sub(1, Ordering::SeqCst);
+            }
+        }).unwrap();
+        return LazyFree {
+            sender: sender,
+            pending: pending,
+        };
+    }
+
+    pub fn free<T:Send + 'static>(&self, garbage:T) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        if let Err(e) = self.sender.send(Box::new(garbage)) {
+            // the background thread is gone; free inline instead
+            self.pending.fetch_sub(1, Ordering::SeqCst);
+            drop(e);
+        }
+    }
+
+    // number of objects waiting to be freed
+    pub fn pending(&self) -> usize {
+        return self.pending.load(Ordering::SeqCst);
+    }
+}
+
+// roughly the number of allocations needed to free a value
+pub fn free_effort(value:&Value) -> usize {
+    match value {
+        &Value::ArrayValue(ref a) => a.len(),
+        &Value::ListValue(ref l) => l.len(),
+        &Value::SetValue(ref s) => s.len(),
+        &Value::SortedSetValue(ref z) => z.len(),
+        &Value::HashValue(ref h) => h.len(),
+        &Value::StreamValue(ref s) => s.len(),
+        _ => 1,
+    }
+}
+
+#[test]
+fn test_lazyfree() {
+    let lazyfree = LazyFree::new();
+    lazyfree.free(Value::ListValue((0..1000).map(|i| i.to_string()).collect()));
+    for _ in 0..100 {
+        if lazyfree.pending() == 0 {
+            break;
+        }
+        thread::sleep(::std::time::Duration::from_millis(10));
+    }
+    assert_eq!(lazyfree.pending(), 0);
+}
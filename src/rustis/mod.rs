@@ -1,6 +1,23 @@
+pub mod aof;
+pub mod binary;
 pub mod command;
+pub mod config;
+pub mod crc64;
 pub mod db;
+pub mod geo;
+pub mod hyperloglog;
 pub mod key;
+pub mod lazyfree;
+pub mod module;
+pub mod notify;
 pub mod parse;
+pub mod pubsub;
+pub mod rdb;
+pub mod scripting;
 pub mod server;
+pub mod snapshot;
+pub mod stream;
+pub mod trigger;
 pub mod value;
+pub mod wasm;
+pub mod zset;
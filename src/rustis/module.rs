@@ -0,0 +1,835 @@
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::mem;
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::ptr;
+use std::slice;
+use std::sync::Mutex;
+#[cfg(test)]
+use std::sync::atomic::{AtomicPtr, Ordering};
+use libc::{self, RTLD_LOCAL, RTLD_NOW};
+use rustis::binary::{byte_len, bytes_to_string, string_to_bytes};
+use rustis::command::{Command, Return};
+use rustis::db::RustisDb;
+use rustis::key::Key;
+use rustis::notify;
+use rustis::parse::ParseResult;
+use rustis::scripting::allowed_in_script;
+use rustis::value::Value;
+
+// native modules: shared libraries loaded with MODULE LOAD that add
+// commands, data types and keyspace event listeners.
+//
+// The interface is a C ABI so that modules don't depend on the compiler
+// version rustis was built with. A module exports
+//
+//   int rustis_module_init(const ModuleApi *api, ModuleCtx *ctx)
+//
+// which calls api->set_name and registers what the module provides, and
+// returns OK. Every function the module may call is in the ModuleApi table,
+// whose layout only ever grows at the end; `version` tells which functions
+// are there. Handlers run on the server thread, with a ctx that is only
+// valid until they return.
+
+pub const MODULE_API_VERSION:c_int = 1;
+pub const OK:c_int = 0;
+pub const ERR:c_int = 1;
+
+// command flags: the command may write to the dataset
+pub const FLAG_WRITE:c_int = 1;
+
+// how get_value will use the value
+pub const MODE_READ:c_int = 1;
+pub const MODE_WRITE:c_int = 2;
+
+// what redis itself replies whatever went wrong, with the details logged
+const LOAD_ERROR:&'static str = "ERR Error loading the extension. Please check the server logs.";
+
+pub type InitFn = unsafe extern "C" fn(*const ModuleApi, *mut ModuleCtx) -> c_int;
+pub type CommandHandler = unsafe extern "C" fn(*mut ModuleCtx);
+// (db, event, key, key length), for the notify::* classes subscribed to
+pub type EventCallback = unsafe extern "C" fn(c_int, *const c_char, *const u8, usize);
+
+// callbacks of a module data type; values are opaque pointers owned by the
+// server once stored in a key
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct TypeMethods {
+    // writes the value with save_bytes
+    pub rdb_save:unsafe extern "C" fn(*mut ModuleIO, *mut c_void),
+    // reads back what rdb_save wrote with load_bytes, given the encoding
+    // version it was written with; null if the data is bad
+    pub rdb_load:unsafe extern "C" fn(*mut ModuleIO, c_int) -> *mut c_void,
+    // called on the main thread, like every other callback
+    pub free:unsafe extern "C" fn(*mut c_void),
+    // optional, values are copied by saving and loading them without it
+    pub copy:Option<unsafe extern "C" fn(*const c_void) -> *mut c_void>,
+}
+
+#[repr(C)]
+pub struct ModuleApi {
+    pub version:c_int,
+    // during rustis_module_init
+    pub set_name:unsafe extern "C" fn(*mut ModuleCtx, *const c_char, c_int),
+    pub register_command:unsafe extern "C" fn(*mut ModuleCtx, *const c_char, CommandHandler, c_int) -> c_int,
+    pub create_type:unsafe extern "C" fn(*mut ModuleCtx, *const c_char, c_int, *const TypeMethods) -> *const ModuleType,
+    pub subscribe_events:unsafe extern "C" fn(*mut ModuleCtx, c_uint, EventCallback) -> c_int,
+    // the command's arguments, or MODULE LOAD's during init
+    pub arg_count:unsafe extern "C" fn(*mut ModuleCtx) -> c_int,
+    pub arg:unsafe extern "C" fn(*mut ModuleCtx, c_int, *mut usize) -> *const u8,
+    // runs a command, returning its RESP reply, valid until the next call
+    pub call:unsafe extern "C" fn(*mut ModuleCtx, *const *const u8, *const usize, c_int, *mut usize) -> *const u8,
+    pub reply_ok:unsafe extern "C" fn(*mut ModuleCtx) -> c_int,
+    pub reply_nil:unsafe extern "C" fn(*mut ModuleCtx) -> c_int,
+    pub reply_int:unsafe extern "C" fn(*mut ModuleCtx, i64) -> c_int,
+    pub reply_str:unsafe extern "C" fn(*mut ModuleCtx, *const u8, usize) -> c_int,
+    pub reply_error:unsafe extern "C" fn(*mut ModuleCtx, *const c_char) -> c_int,
+    pub reply_array:unsafe extern "C" fn(*mut ModuleCtx, usize) -> c_int,
+    // a key's value of a module type, null if the key doesn't exist; ERR if
+    // it holds another type
+    pub get_value:unsafe extern "C" fn(*mut ModuleCtx, *const u8, usize, *const ModuleType, c_int, *mut *mut c_void) -> c_int,
+    // stores a value in a key, which then owns it
+    pub set_value:unsafe extern "C" fn(*mut ModuleCtx, *const u8, usize, *const ModuleType, *mut c_void) -> c_int,
+    // fires a keyspace event of the `d` class
+    pub notify:unsafe extern "C" fn(*mut ModuleCtx, *const c_char, *const u8, usize) -> c_int,
+    pub save_bytes:unsafe extern "C" fn(*mut ModuleIO, *const u8, usize),
+    // null once everything saved has been read
+    pub load_bytes:unsafe extern "C" fn(*mut ModuleIO, *mut usize) -> *const u8,
+}
+
+static API:ModuleApi = ModuleApi {
+    version: MODULE_API_VERSION,
+    set_name: api_set_name,
+    register_command: api_register_command,
+    create_type: api_create_type,
+    subscribe_events: api_subscribe_events,
+    arg_count: api_arg_count,
+    arg: api_arg,
+    call: api_call,
+    reply_ok: api_reply_ok,
+    reply_nil: api_reply_nil,
+    reply_int: api_reply_int,
+    reply_str: api_reply_str,
+    reply_error: api_reply_error,
+    reply_array: api_reply_array,
+    get_value: api_get_value,
+    set_value: api_set_value,
+    notify: api_notify,
+    save_bytes: api_save_bytes,
+    load_bytes: api_load_bytes,
+};
+
+pub struct ModuleCtx {
+    // what rustis_module_init registered
+    name:Option<(String, c_int)>,
+    commands:Vec<(String, CommandHandler, c_int)>,
+    types:Vec<&'static ModuleType>,
+    subscriptions:Vec<(c_uint, EventCallback)>,
+    initializing:bool,
+    // the database of the command being run, only set while one runs
+    db:*mut RustisDb,
+    write:bool,
+    args:Vec<Vec<u8>>,
+    result:Vec<u8>,
+    reply:String,
+    // how many more values the reply needs, counting array elements
+    pending:usize,
+    ready_keys:Vec<Key>,
+}
+
+impl ModuleCtx {
+    fn new(args:Vec<Vec<u8>>) -> ModuleCtx {
+        return ModuleCtx {
+            name: None,
+            commands: Vec::new(),
+            types: Vec::new(),
+            subscriptions: Vec::new(),
+            initializing: false,
+            db: ptr::null_mut(),
+            write: false,
+            args: args,
+            result: Vec::new(),
+            reply: String::new(),
+            pending: 0,
+            ready_keys: Vec::new(),
+        };
+    }
+}
+
+pub struct ModuleType {
+    name:String,
+    encver:c_int,
+    methods:TypeMethods,
+}
+
+// the characters of type names, whose positions make up type ids
+const TYPE_NAME_CHARS:&'static str = "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+impl ModuleType {
+    // the id written to RDB files, like redis: 6 bits per character of the
+    // 9 character name followed by 10 bits of encoding version
+    pub fn id(&self) -> u64 {
+        let mut id = 0;
+        for c in self.name.chars() {
+            id = (id << 6) | TYPE_NAME_CHARS.find(c).unwrap() as u64;
+        }
+        return (id << 10) | (self.encver as u64 & 1023);
+    }
+}
+
+// types of every module loaded, which are never unloaded
+static TYPES:Mutex<Vec<&'static ModuleType>> = Mutex::new(Vec::new());
+
+fn find_type(id:u64) -> Option<&'static ModuleType> {
+    return TYPES.lock().unwrap().iter().find(|ty| ty.id() >> 10 == id >> 10).cloned();
+}
+
+// a value of a module data type, held in a key
+pub struct ModuleValue {
+    ty:&'static ModuleType,
+    ptr:*mut c_void,
+}
+
+// values are moved to the lazyfree thread only inside a flushed database,
+// which frees its module values on the main thread first, so a module's
+// free is never called concurrently with its other code
+unsafe impl Send for ModuleValue {}
+
+impl ModuleValue {
+    pub fn type_name(&self) -> &str {
+        return &self.ty.name;
+    }
+
+    pub fn type_id(&self) -> u64 {
+        return self.ty.id();
+    }
+
+    // the strings the module saves the value as
+    pub fn save(&self) -> Vec<Vec<u8>> {
+        let mut io = ModuleIO {items: Vec::new(), next: 0};
+        unsafe { (self.ty.methods.rdb_save)(&mut io, self.ptr) };
+        return io.items;
+    }
+
+    pub fn load(id:u64, items:Vec<Vec<u8>>) -> Result<ModuleValue, String> {
+        let ty = match find_type(id) {
+            Some(ty) => ty,
+            None => return Err(format!("no module type with id {}", id)),
+        };
+        let mut io = ModuleIO {items: items, next: 0};
+        let ptr = unsafe { (ty.methods.rdb_load)(&mut io, (id & 1023) as c_int) };
+        if ptr.is_null() {
+            return Err(format!("module type {} failed to load a value", ty.name));
+        }
+        return Ok(ModuleValue {ty: ty, ptr: ptr});
+    }
+
+    // a copy through the type's copy callback, or by saving and loading
+    // the value without one; either may fail
+    pub fn try_clone(&self) -> Result<ModuleValue, String> {
+        let ptr = match self.ty.methods.copy {
+            Some(copy) => unsafe { copy(self.ptr) },
+            None => {
+                let mut io = ModuleIO {items: self.save(), next: 0};
+                unsafe { (self.ty.methods.rdb_load)(&mut io, self.ty.encver) }
+            }
+        };
+        if ptr.is_null() {
+            return Err("ERR module key failed to copy".to_string());
+        }
+        return Ok(ModuleValue {ty: self.ty, ptr: ptr});
+    }
+}
+
+impl Drop for ModuleValue {
+    fn drop(&mut self) {
+        unsafe { (self.ty.methods.free)(self.ptr) };
+    }
+}
+
+// COPY, the only command that copies keys, goes through try_clone instead
+impl Clone for ModuleValue {
+    fn clone(&self) -> ModuleValue {
+        return self.try_clone().unwrap();
+    }
+}
+
+impl PartialEq for ModuleValue {
+    fn eq(&self, other:&ModuleValue) -> bool {
+        return self.ptr == other.ptr;
+    }
+}
+
+impl fmt::Debug for ModuleValue {
+    fn fmt(&self, f:&mut fmt::Formatter) -> fmt::Result {
+        return write!(f, "ModuleValue({}, {:?})", self.ty.name, self.ptr);
+    }
+}
+
+// what a type's rdb_save writes and rdb_load reads
+pub struct ModuleIO {
+    items:Vec<Vec<u8>>,
+    next:usize,
+}
+
+struct NativeModule {
+    version:c_int,
+    path:String,
+    args:Vec<String>,
+    // from dlopen, null for modules linked into the server
+    handle:*mut c_void,
+    commands:Vec<String>,
+    types:Vec<&'static ModuleType>,
+    subscriptions:Vec<(c_uint, EventCallback)>,
+}
+
+impl Drop for NativeModule {
+    fn drop(&mut self) {
+        if !self.handle.is_null() {
+            unsafe { libc::dlclose(self.handle) };
+        }
+    }
+}
+
+struct NativeCommand {
+    handler:CommandHandler,
+    flags:c_int,
+}
+
+pub struct Modules {
+    modules:BTreeMap<String, NativeModule>,
+    // by lowercase command name
+    commands:HashMap<String, NativeCommand>,
+    ready_keys:Vec<Key>,
+}
+
+impl Modules {
+    pub fn new() -> Modules {
+        return Modules {
+            modules: BTreeMap::new(),
+            commands: HashMap::new(),
+            ready_keys: Vec::new(),
+        };
+    }
+
+    pub fn handles(&self, name:&str) -> bool {
+        return self.commands.contains_key(&name.to_lowercase());
+    }
+
+    // whether a command was registered with the write flag
+    pub fn writes(&self, name:&str) -> bool {
+        return self.commands.get(&name.to_lowercase()).map_or(false, |command| command.flags & FLAG_WRITE != 0);
+    }
+
+    pub fn load(&mut self, path:&str, args:Vec<String>) -> Result<(), String> {
+        let cpath = match CString::new(path) {
+            Ok(cpath) => cpath,
+            Err(_) => return Err(LOAD_ERROR.to_string()),
+        };
+        let handle = unsafe { libc::dlopen(cpath.as_ptr(), RTLD_NOW | RTLD_LOCAL) };
+        if handle.is_null() {
+            println!("Module {} failed to load: {}", path, dlerror());
+            return Err(LOAD_ERROR.to_string());
+        }
+        let symbol = unsafe { libc::dlsym(handle, b"rustis_module_init\0".as_ptr() as *const c_char) };
+        if symbol.is_null() {
+            println!("Module {} does not export rustis_module_init() symbol. Module not loaded.", path);
+            unsafe { libc::dlclose(handle) };
+            return Err(LOAD_ERROR.to_string());
+        }
+        let init = unsafe { mem::transmute::<*mut c_void, InitFn>(symbol) };
+        return self.init(path, args, init, handle);
+    }
+
+    // runs a module's init function, taking ownership of its library handle
+    pub fn init(&mut self, path:&str, args:Vec<String>, init:InitFn, handle:*mut c_void) -> Result<(), String> {
+        let mut ctx = ModuleCtx::new(args.iter().map(|arg| string_to_bytes(arg)).collect());
+        ctx.initializing = true;
+        let status = unsafe { init(&API, &mut ctx) };
+        let module = NativeModule {
+            version: ctx.name.as_ref().map_or(0, |&(_, version)| version),
+            path: path.to_string(),
+            args: args,
+            handle: handle,
+            commands: ctx.commands.iter().map(|&(ref name, _, _)| name.clone()).collect(),
+            types: ctx.types,
+            subscriptions: ctx.subscriptions,
+        };
+        let name = match ctx.name {
+            Some((ref name, _)) if status == OK => name.clone(),
+            Some(_) => {
+                println!("Module {} initialization failed. Module not loaded", path);
+                return Err(LOAD_ERROR.to_string());
+            }
+            None => {
+                println!("Module {} did not call set_name. Module not loaded", path);
+                return Err(LOAD_ERROR.to_string());
+            }
+        };
+        if self.modules.contains_key(&name) {
+            println!("Module {} is already loaded. Module not loaded", name);
+            return Err(LOAD_ERROR.to_string());
+        }
+        if let Some(command) = module.commands.iter().find(|command| self.commands.contains_key(*command)) {
+            println!("Module {} registers command '{}', which another module already did. Module not loaded", name, command);
+            return Err(LOAD_ERROR.to_string());
+        }
+        {
+            let mut types = TYPES.lock().unwrap();
+            if let Some(ty) = module.types.iter().find(|ty| types.iter().any(|t| t.name == ty.name)) {
+                println!("Module {} creates type {}, which another module already did. Module not loaded", name, ty.name);
+                return Err(LOAD_ERROR.to_string());
+            }
+            types.extend(module.types.iter().cloned());
+        }
+        for (command, handler, flags) in ctx.commands {
+            self.commands.insert(command, NativeCommand {handler: handler, flags: flags});
+        }
+        println!("Module '{}' loaded from {}", name, path);
+        self.modules.insert(name, module);
+        return Ok(());
+    }
+
+    pub fn unload(&mut self, name:&str) -> Result<(), String> {
+        match self.modules.get(name) {
+            Some(module) if !module.types.is_empty() => {
+                return Err("ERR Error unloading module: the module exports one or more module-side data types, can't unload".to_string());
+            }
+            Some(_) => {}
+            None => return Err("ERR Error unloading module: no such module with that name".to_string()),
+        }
+        let module = self.modules.remove(name).unwrap();
+        for command in module.commands.iter() {
+            self.commands.remove(command);
+        }
+        return Ok(());
+    }
+
+    pub fn list(&self) -> Value {
+        return Value::ArrayValue(self.modules.iter().map(|(name, module)| Value::ArrayValue(vec![
+            Value::StrValue("name".to_string()), Value::StrValue(name.clone()),
+            Value::StrValue("ver".to_string()), Value::IntValue(module.version as i64),
+            Value::StrValue("path".to_string()), Value::StrValue(module.path.clone()),
+            Value::StrValue("args".to_string()),
+            Value::ArrayValue(module.args.iter().map(|arg| Value::StrValue(arg.clone())).collect()),
+        ])).collect());
+    }
+
+    // runs a module command against a database, returning its RESP reply
+    pub fn call(&mut self, db:&mut RustisDb, args:Vec<String>) -> Result<String, String> {
+        let command = match args.first().and_then(|name| self.commands.get(&name.to_lowercase())) {
+            Some(command) => command,
+            None => return Err(Command::unknown_command_error(&args)),
+        };
+        let mut ctx = ModuleCtx::new(args.iter().map(|arg| string_to_bytes(arg)).collect());
+        ctx.db = db as *mut RustisDb;
+        ctx.write = command.flags & FLAG_WRITE != 0;
+        ctx.pending = 1;
+        unsafe { (command.handler)(&mut ctx) };
+        self.ready_keys.extend(ctx.ready_keys.drain(..));
+        // a handler that doesn't finish its reply gets nils for the rest
+        let mut reply = ctx.reply;
+        for _ in 0..ctx.pending {
+            reply.push_str(&format!("{}", Value::Nil));
+        }
+        return Ok(reply);
+    }
+
+    // the event classes some module subscribed to
+    pub fn event_classes(&self) -> u32 {
+        return self.modules.values().flat_map(|module| module.subscriptions.iter()).fold(0, |classes, &(c, _)| classes | c as u32);
+    }
+
+    pub fn notify(&self, db:usize, class:u32, event:&str, key:&Key) {
+        let cevent = match CString::new(event) {
+            Ok(cevent) => cevent,
+            Err(_) => return,
+        };
+        let key = string_to_bytes(key);
+        for module in self.modules.values() {
+            for &(classes, callback) in module.subscriptions.iter() {
+                if classes as u32 & class != 0 {
+                    unsafe { callback(db as c_int, cevent.as_ptr(), key.as_ptr(), key.len()) };
+                }
+            }
+        }
+    }
+
+    // streams appended to by module commands, which may unblock readers
+    pub fn take_ready_keys(&mut self) -> Vec<Key> {
+        return mem::replace(&mut self.ready_keys, Vec::new());
+    }
+}
+
+fn dlerror() -> String {
+    let e = unsafe { libc::dlerror() };
+    if e.is_null() {
+        return "unknown error".to_string();
+    }
+    return unsafe { CStr::from_ptr(e) }.to_string_lossy().into_owned();
+}
+
+unsafe fn c_str(s:*const c_char) -> Option<String> {
+    if s.is_null() {
+        return None;
+    }
+    return Some(bytes_to_string(CStr::from_ptr(s).to_bytes()));
+}
+
+unsafe fn binary(ptr:*const u8, len:usize) -> String {
+    if ptr.is_null() || len == 0 {
+        return String::new();
+    }
+    return bytes_to_string(slice::from_raw_parts(ptr, len));
+}
+
+unsafe extern "C" fn api_set_name(ctx:*mut ModuleCtx, name:*const c_char, version:c_int) {
+    let ctx = &mut *ctx;
+    if let Some(name) = c_str(name) {
+        if ctx.initializing && ctx.name.is_none() && !name.is_empty() {
+            ctx.name = Some((name, version));
+        }
+    }
+}
+
+unsafe extern "C" fn api_register_command(ctx:*mut ModuleCtx, name:*const c_char, handler:CommandHandler, flags:c_int) -> c_int {
+    let ctx = &mut *ctx;
+    let name = match c_str(name) {
+        Some(name) => name.to_lowercase(),
+        None => return ERR,
+    };
+    if !ctx.initializing || name.is_empty() || name.contains(' ') || ctx.commands.iter().any(|&(ref n, _, _)| *n == name) {
+        return ERR;
+    }
+    ctx.commands.push((name, handler, flags));
+    return OK;
+}
+
+unsafe extern "C" fn api_create_type(ctx:*mut ModuleCtx, name:*const c_char, encver:c_int, methods:*const TypeMethods) -> *const ModuleType {
+    let ctx = &mut *ctx;
+    let name = match c_str(name) {
+        Some(name) => name,
+        None => return ptr::null(),
+    };
+    if !ctx.initializing || methods.is_null() || encver < 0 || encver > 1023 ||
+        name.len() != 9 || !name.chars().all(|c| TYPE_NAME_CHARS.contains(c)) ||
+        ctx.types.iter().any(|ty| ty.name == name) {
+        return ptr::null();
+    }
+    // types live as long as the server
+    let ty:&'static ModuleType = Box::leak(Box::new(ModuleType {name: name, encver: encver, methods: *methods}));
+    ctx.types.push(ty);
+    return ty;
+}
+
+unsafe extern "C" fn api_subscribe_events(ctx:*mut ModuleCtx, classes:c_uint, callback:EventCallback) -> c_int {
+    let ctx = &mut *ctx;
+    if !ctx.initializing {
+        return ERR;
+    }
+    ctx.subscriptions.push((classes, callback));
+    return OK;
+}
+
+unsafe extern "C" fn api_arg_count(ctx:*mut ModuleCtx) -> c_int {
+    return (*ctx).args.len() as c_int;
+}
+
+unsafe extern "C" fn api_arg(ctx:*mut ModuleCtx, index:c_int, len:*mut usize) -> *const u8 {
+    let ctx = &*ctx;
+    return match ctx.args.get(index as usize) {
+        Some(arg) if index >= 0 => {
+            *len = arg.len();
+            arg.as_ptr()
+        }
+        _ => ptr::null(),
+    };
+}
+
+unsafe extern "C" fn api_call(ctx:*mut ModuleCtx, argv:*const *const u8, lens:*const usize, argc:c_int, reply_len:*mut usize) -> *const u8 {
+    let ctx = &mut *ctx;
+    if ctx.db.is_null() || argv.is_null() || lens.is_null() || argc <= 0 {
+        return ptr::null();
+    }
+    let mut request = format!("*{}\r\n", argc);
+    for i in 0..argc as usize {
+        let arg = binary(*argv.offset(i as isize), *lens.offset(i as isize));
+        request.push_str(&format!("${}\r\n{}\r\n", byte_len(&arg), arg));
+    }
+    let ParseResult(_, mut commands) = Command::parse(&request);
+    let result = match commands.pop() {
+        Some(Ok(ref cmd)) if !allowed_in_script(cmd) => Return::Error("ERR This Redis command is not allowed from module commands".to_string()),
+        Some(Ok(ref cmd)) if cmd.is_write() && !ctx.write => Return::Error("ERR Write commands are not allowed from read-only module commands".to_string()),
+        Some(Ok(cmd)) => {
+            if let Command::XAdd {ref key, ..} = cmd {
+                ctx.ready_keys.push(key.clone());
+            }
+            (*ctx.db).run_command(cmd)
+        }
+        Some(Err(e)) => Return::Error(e),
+        None => return ptr::null(),
+    };
+    ctx.result = string_to_bytes(&format!("{}", result));
+    *reply_len = ctx.result.len();
+    return ctx.result.as_ptr();
+}
+
+unsafe fn add_reply(ctx:*mut ModuleCtx, reply:String, elements:usize) -> c_int {
+    let ctx = &mut *ctx;
+    if ctx.initializing || ctx.pending == 0 {
+        return ERR;
+    }
+    ctx.pending += elements;
+    ctx.pending -= 1;
+    ctx.reply.push_str(&reply);
+    return OK;
+}
+
+unsafe extern "C" fn api_reply_ok(ctx:*mut ModuleCtx) -> c_int {
+    return add_reply(ctx, format!("{}", Return::Ok), 0);
+}
+
+unsafe extern "C" fn api_reply_nil(ctx:*mut ModuleCtx) -> c_int {
+    return add_reply(ctx, format!("{}", Value::Nil), 0);
+}
+
+unsafe extern "C" fn api_reply_int(ctx:*mut ModuleCtx, i:i64) -> c_int {
+    return add_reply(ctx, format!("{}", Value::IntValue(i)), 0);
+}
+
+unsafe extern "C" fn api_reply_str(ctx:*mut ModuleCtx, ptr:*const u8, len:usize) -> c_int {
+    return add_reply(ctx, format!("{}", Value::StrValue(binary(ptr, len))), 0);
+}
+
+unsafe extern "C" fn api_reply_error(ctx:*mut ModuleCtx, e:*const c_char) -> c_int {
+    // errors are a single line
+    let e = c_str(e).unwrap_or(String::new()).replace(|c| c == '\r' || c == '\n', " ");
+    return add_reply(ctx, format!("{}", Return::Error(e)), 0);
+}
+
+unsafe extern "C" fn api_reply_array(ctx:*mut ModuleCtx, len:usize) -> c_int {
+    return add_reply(ctx, format!("*{}\r\n", len), len);
+}
+
+unsafe extern "C" fn api_get_value(ctx:*mut ModuleCtx, key:*const u8, len:usize, ty:*const ModuleType, mode:c_int, value:*mut *mut c_void) -> c_int {
+    let ctx = &mut *ctx;
+    if ctx.db.is_null() || (mode & MODE_WRITE != 0 && !ctx.write) {
+        return ERR;
+    }
+    let key = binary(key, len);
+    let found = if mode & MODE_WRITE != 0 {
+        (*ctx.db).get_value_mut(&key).map(|v| &*v)
+    } else {
+        (*ctx.db).get_value(&key)
+    };
+    *value = match found {
+        Some(&Value::ModuleValue(ref v)) if v.ty as *const ModuleType == ty => v.ptr,
+        Some(_) => return ERR,
+        None => ptr::null_mut(),
+    };
+    return OK;
+}
+
+unsafe extern "C" fn api_set_value(ctx:*mut ModuleCtx, key:*const u8, len:usize, ty:*const ModuleType, value:*mut c_void) -> c_int {
+    let ctx = &mut *ctx;
+    if ctx.db.is_null() || !ctx.write || ty.is_null() || value.is_null() {
+        return ERR;
+    }
+    (*ctx.db).store_value(binary(key, len), Value::ModuleValue(ModuleValue {ty: &*ty, ptr: value}));
+    return OK;
+}
+
+unsafe extern "C" fn api_notify(ctx:*mut ModuleCtx, event:*const c_char, key:*const u8, len:usize) -> c_int {
+    let ctx = &mut *ctx;
+    let event = match c_str(event) {
+        Some(event) => event,
+        None => return ERR,
+    };
+    if ctx.db.is_null() {
+        return ERR;
+    }
+    (*ctx.db).notify(notify::MODULE, &event, &binary(key, len));
+    return OK;
+}
+
+unsafe extern "C" fn api_save_bytes(io:*mut ModuleIO, ptr:*const u8, len:usize) {
+    let bytes = if ptr.is_null() {Vec::new()} else {slice::from_raw_parts(ptr, len).to_vec()};
+    (*io).items.push(bytes);
+}
+
+unsafe extern "C" fn api_load_bytes(io:*mut ModuleIO, len:*mut usize) -> *const u8 {
+    let io = &mut *io;
+    return match io.items.get(io.next) {
+        Some(item) => {
+            io.next += 1;
+            *len = item.len();
+            item.as_ptr()
+        }
+        None => ptr::null(),
+    };
+}
+
+
+// a test module with a counter type, linked into the test binary
+#[cfg(test)]
+static TEST_API:AtomicPtr<ModuleApi> = AtomicPtr::new(0 as *mut ModuleApi);
+#[cfg(test)]
+static TEST_TYPE:AtomicPtr<ModuleType> = AtomicPtr::new(0 as *mut ModuleType);
+#[cfg(test)]
+static TEST_EVENTS:Mutex<Vec<(c_int, String, String)>> = Mutex::new(Vec::new());
+
+#[cfg(test)]
+unsafe fn test_api() -> &'static ModuleApi {
+    return &*TEST_API.load(Ordering::SeqCst);
+}
+
+#[cfg(test)]
+unsafe fn test_arg<'a>(ctx:*mut ModuleCtx, i:c_int) -> &'a [u8] {
+    let mut len = 0;
+    let p = (test_api().arg)(ctx, i, &mut len);
+    return slice::from_raw_parts(p, len);
+}
+
+#[cfg(test)]
+unsafe extern "C" fn test_save(io:*mut ModuleIO, value:*mut c_void) {
+    let s = (*(value as *mut i64)).to_string();
+    (test_api().save_bytes)(io, s.as_ptr(), s.len());
+}
+
+#[cfg(test)]
+unsafe extern "C" fn test_load(io:*mut ModuleIO, _encver:c_int) -> *mut c_void {
+    let mut len = 0;
+    let p = (test_api().load_bytes)(io, &mut len);
+    if p.is_null() {
+        return ptr::null_mut();
+    }
+    return match bytes_to_string(slice::from_raw_parts(p, len)).parse::<i64>() {
+        Ok(n) => Box::into_raw(Box::new(n)) as *mut c_void,
+        Err(_) => ptr::null_mut(),
+    };
+}
+
+#[cfg(test)]
+unsafe extern "C" fn test_load_nothing(_io:*mut ModuleIO, _encver:c_int) -> *mut c_void {
+    return ptr::null_mut();
+}
+
+#[cfg(test)]
+unsafe extern "C" fn test_free(value:*mut c_void) {
+    drop(Box::from_raw(value as *mut i64));
+}
+
+#[cfg(test)]
+unsafe extern "C" fn test_incr(ctx:*mut ModuleCtx) {
+    let api = test_api();
+    let key = test_arg(ctx, 1);
+    let ty = TEST_TYPE.load(Ordering::SeqCst);
+    let mut value = ptr::null_mut();
+    if (api.get_value)(ctx, key.as_ptr(), key.len(), ty, MODE_WRITE, &mut value) != OK {
+        (api.reply_error)(ctx, b"WRONGTYPE Operation against a key holding the wrong kind of value\0".as_ptr() as *const c_char);
+        return;
+    }
+    if value.is_null() {
+        value = Box::into_raw(Box::new(0i64)) as *mut c_void;
+        (api.set_value)(ctx, key.as_ptr(), key.len(), ty, value);
+    }
+    *(value as *mut i64) += 1;
+    (api.notify)(ctx, b"cnt.incr\0".as_ptr() as *const c_char, key.as_ptr(), key.len());
+    (api.reply_int)(ctx, *(value as *mut i64));
+}
+
+// replies with what GET and SET of the key reply
+#[cfg(test)]
+unsafe extern "C" fn test_get(ctx:*mut ModuleCtx) {
+    let api = test_api();
+    let key = test_arg(ctx, 1);
+    (api.reply_array)(ctx, 2);
+    for &(argv, lens, argc) in [
+        (&[b"GET".as_ptr(), key.as_ptr(), ptr::null()], &[3, key.len(), 0], 2),
+        (&[b"SET".as_ptr(), key.as_ptr(), b"x".as_ptr()], &[3, key.len(), 1], 3),
+    ].iter() {
+        let mut len = 0;
+        let reply = (api.call)(ctx, argv.as_ptr(), lens.as_ptr(), argc, &mut len);
+        (api.reply_str)(ctx, reply, len);
+    }
+}
+
+#[cfg(test)]
+unsafe extern "C" fn test_on_event(db:c_int, event:*const c_char, key:*const u8, len:usize) {
+    let event = CStr::from_ptr(event).to_string_lossy().into_owned();
+    TEST_EVENTS.lock().unwrap().push((db, event, binary(key, len)));
+}
+
+#[cfg(test)]
+unsafe extern "C" fn test_init(api:*const ModuleApi, ctx:*mut ModuleCtx) -> c_int {
+    TEST_API.store(api as *mut ModuleApi, Ordering::SeqCst);
+    let api = &*api;
+    (api.set_name)(ctx, b"counter\0".as_ptr() as *const c_char, 2);
+    let methods = TypeMethods {rdb_save: test_save, rdb_load: test_load, free: test_free, copy: None};
+    let ty = (api.create_type)(ctx, b"counter-t\0".as_ptr() as *const c_char, 1, &methods);
+    // the module wants one argument
+    if ty.is_null() || (api.arg_count)(ctx) != 1 {
+        return ERR;
+    }
+    TEST_TYPE.store(ty as *mut ModuleType, Ordering::SeqCst);
+    (api.register_command)(ctx, b"cnt.incr\0".as_ptr() as *const c_char, test_incr, FLAG_WRITE);
+    (api.register_command)(ctx, b"cnt.get\0".as_ptr() as *const c_char, test_get, 0);
+    (api.subscribe_events)(ctx, (notify::MODULE | notify::STRING) as c_uint, test_on_event);
+    return OK;
+}
+
+#[cfg(test)]
+unsafe extern "C" fn test_init_nameless(_api:*const ModuleApi, _ctx:*mut ModuleCtx) -> c_int {
+    return OK;
+}
+
+#[test]
+fn test_native_module() {
+    use rustis::rdb;
+    let args = |a:&[&str]| a.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+    let mut modules = Modules::new();
+    assert_eq!(modules.init("nameless.so", vec![], test_init_nameless, ptr::null_mut()), Err(LOAD_ERROR.to_string()));
+    assert_eq!(modules.init("counter.so", vec![], test_init, ptr::null_mut()), Err(LOAD_ERROR.to_string()));
+    assert_eq!(modules.init("counter.so", args(&["x"]), test_init, ptr::null_mut()), Ok(()));
+    assert!(modules.handles("CNT.INCR"));
+    assert_eq!(format!("{}", modules.list()), "*1\r\n*8\r\n$4\r\nname\r\n$7\r\ncounter\r\n$3\r\nver\r\n:2\r\n$4\r\npath\r\n$10\r\ncounter.so\r\n$4\r\nargs\r\n*1\r\n$1\r\nx\r\n");
+
+    let mut db = RustisDb::new();
+    db.set_module_event_classes(modules.event_classes());
+    assert_eq!(modules.call(&mut db, args(&["cnt.incr", "c"])), Ok(":1\r\n".to_string()));
+    assert_eq!(modules.call(&mut db, args(&["CNT.INCR", "c"])), Ok(":2\r\n".to_string()));
+    assert_eq!(db.run_command(Command::Type {key: "c".to_string()}), Return::ValueReturn(Value::StrValue("counter-t".to_string())));
+    db.run_command(Command::Set {key: "s".to_string(), value: Value::IntValue(1), exp: None});
+    assert_eq!(modules.call(&mut db, args(&["cnt.incr", "s"])), Ok("-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string()));
+    // read only commands can't write
+    assert_eq!(modules.call(&mut db, args(&["cnt.get", "s"])), Ok("*2\r\n$4\r\n:1\r\n\r\n$68\r\n-ERR Write commands are not allowed from read-only module commands\r\n\r\n".to_string()));
+
+    for (class, event, key) in db.take_events() {
+        modules.notify(0, class, &event, &key);
+    }
+    assert_eq!(*TEST_EVENTS.lock().unwrap(), vec![
+        (0, "cnt.incr".to_string(), "c".to_string()),
+        (0, "cnt.incr".to_string(), "c".to_string()),
+        (0, "set".to_string(), "s".to_string()),
+    ]);
+
+    // values are persisted through the type's callbacks
+    let payload = rdb::dump(db.get_value(&"c".to_string()).unwrap()).unwrap();
+    assert_eq!(payload[0], rdb::RDB_TYPE_MODULE_2);
+    match rdb::restore(&payload) {
+        Ok(Value::ModuleValue(ref v)) => assert_eq!((v.type_name(), v.save()), ("counter-t", vec![b"2".to_vec()])),
+        _ => panic!("the module value wasn't restored"),
+    }
+    assert_eq!(db.run_command(Command::Copy {source: "c".to_string(), destination: "c2".to_string(), db: None, replace: false}), Return::ValueReturn(Value::IntValue(1)));
+    assert_eq!(modules.call(&mut db, args(&["cnt.incr", "c2"])), Ok(":3\r\n".to_string()));
+    // a value its type can't load back can't be copied
+    let methods = TypeMethods {rdb_save: test_save, rdb_load: test_load_nothing, free: test_free, copy: None};
+    let ty = Box::leak(Box::new(ModuleType {name: "broken-t".to_string(), encver: 1, methods: methods}));
+    db.insert_entry("b".to_string(), Value::ModuleValue(ModuleValue {ty: ty, ptr: Box::into_raw(Box::new(1i64)) as *mut c_void}), None);
+    assert_eq!(db.run_command(Command::Copy {source: "b".to_string(), destination: "b2".to_string(), db: None, replace: false}), Return::Error("ERR module key failed to copy".to_string()));
+    assert!(!db.contains_key(&"b2".to_string()));
+
+    assert_eq!(modules.unload("nope"), Err("ERR Error unloading module: no such module with that name".to_string()));
+    assert_eq!(modules.unload("counter"), Err("ERR Error unloading module: the module exports one or more module-side data types, can't unload".to_string()));
+}
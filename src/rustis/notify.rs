@@ -0,0 +1,89 @@
+// keyspace notification classes, as set with notify-keyspace-events
+pub const KEYSPACE:u32 = 1 << 0;
+pub const KEYEVENT:u32 = 1 << 1;
+pub const GENERIC:u32 = 1 << 2;
+pub const STRING:u32 = 1 << 3;
+pub const LIST:u32 = 1 << 4;
+pub const SET:u32 = 1 << 5;
+pub const HASH:u32 = 1 << 6;
+pub const ZSET:u32 = 1 << 7;
+pub const EXPIRED:u32 = 1 << 8;
+// 1 << 9 is redis's evicted class, which never fires without maxmemory
+pub const STREAM:u32 = 1 << 10;
+pub const MODULE:u32 = 1 << 11;
+pub const KEY_MISS:u32 = 1 << 12;
+pub const NEW:u32 = 1 << 13;
+// `A`, which leaves out key misses and new keys like redis does
+pub const ALL:u32 = GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | STREAM | MODULE;
+
+const CLASSES:[(char, u32); 9] = [
+    ('g', GENERIC), ('$', STRING), ('l', LIST), ('s', SET), ('h', HASH),
+    ('z', ZSET), ('x', EXPIRED), ('t', STREAM), ('d', MODULE),
+];
+
+pub fn parse_flags(s:&str) -> Option<u32> {
+    let mut flags = 0;
+    for c in s.chars() {
+        flags |= match c {
+            'A' => ALL,
+            'K' => KEYSPACE,
+            'E' => KEYEVENT,
+            'm' => KEY_MISS,
+            'n' => NEW,
+            // redis configs may still name the evicted class
+            'e' => 0,
+            c => match CLASSES.iter().find(|&&(class, _)| class == c) {
+                Some(&(_, flag)) => flag,
+                None => return None,
+            },
+        };
+    }
+    return Some(flags);
+}
+
+pub fn flags_to_string(flags:u32) -> String {
+    let mut s = String::new();
+    if flags & ALL == ALL {
+        s.push('A');
+    } else {
+        for &(c, flag) in CLASSES.iter() {
+            if flags & flag != 0 {
+                s.push(c);
+            }
+        }
+    }
+    for &(c, flag) in [('K', KEYSPACE), ('E', KEYEVENT), ('m', KEY_MISS), ('n', NEW)].iter() {
+        if flags & flag != 0 {
+            s.push(c);
+        }
+    }
+    return s;
+}
+
+// whether an event of the class gets published at all
+pub fn enabled(flags:u32, class:u32) -> bool {
+    return flags & class != 0 && flags & (KEYSPACE | KEYEVENT) != 0;
+}
+
+pub fn keyspace_channel(db:usize, key:&str) -> String {
+    return format!("__keyspace@{}__:{}", db, key);
+}
+
+pub fn keyevent_channel(db:usize, event:&str) -> String {
+    return format!("__keyevent@{}__:{}", db, event);
+}
+
+#[test]
+fn test_flags() {
+    assert_eq!(parse_flags(""), Some(0));
+    assert_eq!(parse_flags("KEA"), Some(KEYSPACE | KEYEVENT | ALL));
+    assert_eq!(parse_flags("Ex"), Some(KEYEVENT | EXPIRED));
+    assert_eq!(parse_flags("Kq"), None);
+    assert_eq!(parse_flags("Ee"), Some(KEYEVENT));
+    assert_eq!(flags_to_string(parse_flags("AKE").unwrap()), "AKE");
+    assert_eq!(flags_to_string(parse_flags("xgE$").unwrap()), "g$xE");
+    assert_eq!(flags_to_string(parse_flags("Kmn").unwrap()), "Kmn");
+    assert!(enabled(KEYSPACE | STRING, STRING));
+    assert!(!enabled(STRING, STRING));
+    assert!(!enabled(KEYEVENT | LIST, STRING));
+}
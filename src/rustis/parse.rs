@@ -1,11 +1,12 @@
-use nom::{IResult, digit};
+use nom::{IResult, ErrorKind, Needed, digit};
 use rustis::key::Key;
-use rustis::command::Command;
+use rustis::command::{ClaimOptions, Command, GeoFrom, GeoSearchOptions, GeoShape, RestorePolicy, SortOptions, StreamTrim};
 use rustis::value::Value;
 
-// represents the number of characters consumed, plus a Vec of parsed commands
+// represents the number of characters consumed, plus a Vec of parsed
+// commands or the errors for the ones that didn't parse
 #[derive(Debug, PartialEq)]
-pub struct ParseResult(pub usize, pub Vec<Command>);
+pub struct ParseResult(pub usize, pub Vec<Result<Command, String>>);
 
 // parse the RESP protocol as a Vec<&str>
 
@@ -39,24 +40,38 @@ named!(pub resp_array_parser<&str, Vec<&str>>, do_parse!(
 // parse the space-joined Vec<&str> into a command
 
 named!(char_sequence<&str, &str>, do_parse!(
-    chars: is_not!("\" \r\n") >>
+    chars: is_not!("\" \t\r\n") >>
     (chars)
 ));
 
-named!(quoted_char_sequence<&str, &str>, do_parse!(
-    char!('"') >>
-    chars: opt!(is_not!("\"")) >>
-    char!('"') >>
-    (match chars {
-        Some(x) => x,
-        None => ""
-    })
-));
+// a double quoted string in which `\"` and `\\` are escaped, as produced by
+// Command::quote
+fn quoted_char_sequence(input:&str) -> IResult<&str, String> {
+    let mut chars = input.char_indices();
+    match chars.next() {
+        Some((_, '"')) => {}
+        Some(_) => return IResult::Error(error_position!(ErrorKind::Char, input)),
+        None => return IResult::Incomplete(Needed::Size(1)),
+    }
+    let mut s = String::new();
+    let mut escaped = false;
+    for (i, c) in chars {
+        if escaped {
+            s.push(c);
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' {
+            return IResult::Done(&input[i + 1..], s);
+        } else {
+            s.push(c);
+        }
+    }
+    return IResult::Incomplete(Needed::Unknown);
+}
 
-named!(parsed_udigit<&str, i64>, do_parse!(
-    val: digit >>
-    (val.parse::<i64>().unwrap())
-));
+// numbers out of range don't parse, rather than panicking
+named!(parsed_udigit<&str, i64>, map_res!(digit, |val:&str| val.parse::<i64>()));
 
 named!(parsed_digit<&str, i64>, do_parse!(
     sign: opt!(complete!(tag!("-"))) >>
@@ -67,27 +82,20 @@ named!(parsed_digit<&str, i64>, do_parse!(
     }) * val)
 ));
 
-named!(parsed_float<&str, f64>, do_parse!(
-    base: parsed_digit >>
-    dec: opt!(complete!(do_parse!(tag!(".") >> n: digit >> (n)))) >>
-    (format!("{}.{}", base, match dec {
-        Some(x) => x,
-        None => "0",
-    }).parse::<f64>().unwrap())
-));
+fn parsed_float(input:&str) -> IResult<&str, f64> {
+    let end = input.find(|c:char| !(c.is_digit(10) || c == '-' || c == '+' || c == '.' || c == 'e' || c == 'E')).unwrap_or(input.len());
+    return match input[..end].parse::<f64>() {
+        Ok(f) => IResult::Done(&input[end..], f),
+        Err(_) => IResult::Error(error_position!(ErrorKind::Digit, input)),
+    };
+}
 
-named!(parsed_string<&str, String>, do_parse!(
-    chars: alt!(
-        quoted_char_sequence |
-        char_sequence
-    ) >>
-    (chars.to_string())
+named!(parsed_string<&str, String>, alt!(
+    quoted_char_sequence |
+    map!(char_sequence, |chars:&str| chars.to_string())
 ));
 
-named!(key_parser<&str, Key>, do_parse!(
-    char: char_sequence >>
-    (char.to_string())
-));
+named!(key_parser<&str, Key>, call!(parsed_string));
 
 named!(intvalue_parser<&str, Value>, do_parse!(
     val: parsed_digit >>
@@ -110,11 +118,31 @@ named!(get_parser<&str, Command>, ws!(do_parse!(
     (Command::Get {key: key})
 )));
 
+// the digits of a time, which is None when it's out of range
+named!(expire_digits<&str, Option<i64>>, map!(
+    recognize!(pair!(opt!(complete!(tag!("-"))), digit)),
+    |digits:&str| digits.parse::<i64>().ok()
+));
+
+// a time that isn't positive or overflows becomes one SET refuses with
+// "invalid expire time"
+named!(set_exp_parser<&str, u64>, ws!(alt!(
+    do_parse!(tag_no_case!("EX") >> seconds: expire_digits >> (match seconds {
+        Some(seconds) if seconds > 0 => (seconds as u64).checked_mul(1000).unwrap_or(u64::max_value()),
+        _ => u64::max_value(),
+    })) |
+    do_parse!(tag_no_case!("PX") >> milliseconds: expire_digits >> (match milliseconds {
+        Some(milliseconds) if milliseconds > 0 => milliseconds as u64,
+        _ => u64::max_value(),
+    }))
+)));
+
 named!(set_parser<&str, Command>, ws!(do_parse!(
     tag_no_case!("SET") >>
     key: key_parser >>
     value: value_parser >>
-    (Command::Set {key: key, value: value, exp: None})
+    exp: opt!(complete!(set_exp_parser)) >>
+    (Command::Set {key: key, value: value, exp: exp})
 )));
 
 named!(append_parser<&str, Command>, ws!(do_parse!(
@@ -198,12 +226,421 @@ named!(srem_parser<&str, Command>, ws!(do_parse!(
     (Command::Srem {key: key, members: members})
 )));
 
+named!(geo_unit_parser<&str, f64>, alt!(
+    map!(tag_no_case!("KM"), |_| 1000.0) |
+    map!(tag_no_case!("MI"), |_| 1609.34) |
+    map!(tag_no_case!("FT"), |_| 0.3048) |
+    map!(tag_no_case!("M"), |_| 1.0)
+));
+
+enum GeoAddOption {
+    Nx,
+    Xx,
+    Ch,
+}
+
+named!(geoadd_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("GEOADD") >>
+    key: key_parser >>
+    options: many0!(complete!(ws!(alt!(
+        map!(tag_no_case!("NX"), |_| GeoAddOption::Nx) |
+        map!(tag_no_case!("XX"), |_| GeoAddOption::Xx) |
+        map!(tag_no_case!("CH"), |_| GeoAddOption::Ch)
+    )))) >>
+    items: many1!(complete!(ws!(tuple!(parsed_float, parsed_float, parsed_string)))) >>
+    (Command::GeoAdd {
+        key: key,
+        nx: options.iter().any(|o| match o {&GeoAddOption::Nx => true, _ => false}),
+        xx: options.iter().any(|o| match o {&GeoAddOption::Xx => true, _ => false}),
+        ch: options.iter().any(|o| match o {&GeoAddOption::Ch => true, _ => false}),
+        items: items,
+    })
+)));
+
+named!(geopos_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("GEOPOS") >>
+    key: key_parser >>
+    members: many0!(complete!(parsed_string)) >>
+    (Command::GeoPos {key: key, members: members})
+)));
+
+named!(geodist_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("GEODIST") >>
+    key: key_parser >>
+    member1: parsed_string >>
+    member2: parsed_string >>
+    unit: opt!(complete!(geo_unit_parser)) >>
+    (Command::GeoDist {key: key, member1: member1, member2: member2, unit: unit.unwrap_or(1.0)})
+)));
+
+named!(geohash_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("GEOHASH") >>
+    key: key_parser >>
+    members: many0!(complete!(parsed_string)) >>
+    (Command::GeoHash {key: key, members: members})
+)));
+
+enum GeoSearchOption {
+    From(GeoFrom),
+    By(GeoShape, f64),
+    Asc,
+    Desc,
+    Count(usize, bool),
+    WithCoord,
+    WithDist,
+    WithHash,
+    StoreDist,
+}
+
+named!(geosearch_option_parser<&str, GeoSearchOption>, ws!(alt!(
+    do_parse!(tag_no_case!("FROMMEMBER") >> member: parsed_string >> (GeoSearchOption::From(GeoFrom::Member(member)))) |
+    do_parse!(tag_no_case!("FROMLONLAT") >> lon: parsed_float >> lat: parsed_float >> (GeoSearchOption::From(GeoFrom::LonLat(lon, lat)))) |
+    do_parse!(tag_no_case!("BYRADIUS") >> radius: parsed_float >> unit: geo_unit_parser >> (GeoSearchOption::By(GeoShape::Radius(radius * unit), unit))) |
+    do_parse!(tag_no_case!("BYBOX") >> width: parsed_float >> height: parsed_float >> unit: geo_unit_parser >> (GeoSearchOption::By(GeoShape::Box(width * unit, height * unit), unit))) |
+    map!(tag_no_case!("ASC"), |_| GeoSearchOption::Asc) |
+    map!(tag_no_case!("DESC"), |_| GeoSearchOption::Desc) |
+    do_parse!(tag_no_case!("COUNT") >> count: parsed_udigit >> any: opt!(complete!(tag_no_case!("ANY"))) >> (GeoSearchOption::Count(count as usize, any.is_some()))) |
+    map!(tag_no_case!("WITHCOORD"), |_| GeoSearchOption::WithCoord) |
+    map!(tag_no_case!("WITHDIST"), |_| GeoSearchOption::WithDist) |
+    map!(tag_no_case!("WITHHASH"), |_| GeoSearchOption::WithHash)
+)));
+
+fn geosearch_options(options:Vec<GeoSearchOption>) -> (GeoSearchOptions, bool) {
+    let mut search = GeoSearchOptions {from: None, by: None, unit: 1.0, desc: None, count: None, withcoord: false, withdist: false, withhash: false};
+    let mut storedist = false;
+    for option in options {
+        match option {
+            GeoSearchOption::From(from) => search.from = Some(from),
+            GeoSearchOption::By(shape, unit) => {
+                search.by = Some(shape);
+                search.unit = unit;
+            }
+            GeoSearchOption::Asc => search.desc = Some(false),
+            GeoSearchOption::Desc => search.desc = Some(true),
+            GeoSearchOption::Count(count, any) => search.count = Some((count, any)),
+            GeoSearchOption::WithCoord => search.withcoord = true,
+            GeoSearchOption::WithDist => search.withdist = true,
+            GeoSearchOption::WithHash => search.withhash = true,
+            GeoSearchOption::StoreDist => storedist = true,
+        }
+    }
+    return (search, storedist);
+}
+
+named!(geosearch_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("GEOSEARCH") >>
+    key: key_parser >>
+    options: many1!(complete!(geosearch_option_parser)) >>
+    (Command::GeoSearch {key: key, options: geosearch_options(options).0})
+)));
+
+named!(geosearchstore_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("GEOSEARCHSTORE") >>
+    destination: key_parser >>
+    source: key_parser >>
+    options: many1!(complete!(alt!(
+        geosearch_option_parser |
+        map!(ws!(tag_no_case!("STOREDIST")), |_| GeoSearchOption::StoreDist)
+    ))) >>
+    ({
+        let (options, storedist) = geosearch_options(options);
+        Command::GeoSearchStore {destination: destination, source: source, options: options, storedist: storedist}
+    })
+)));
+
+named!(stream_trim_parser<&str, StreamTrim>, ws!(do_parse!(
+    minid: alt!(map!(tag_no_case!("MAXLEN"), |_| false) | map!(tag_no_case!("MINID"), |_| true)) >>
+    approx: opt!(complete!(alt!(tag!("=") | tag!("~")))) >>
+    threshold: parsed_string >>
+    limit: opt!(complete!(ws!(do_parse!(tag_no_case!("LIMIT") >> limit: parsed_digit >> (limit))))) >>
+    (StreamTrim {minid: minid, threshold: threshold, approx: approx == Some("~"), limit: limit})
+)));
+
+named!(xadd_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("XADD") >>
+    key: key_parser >>
+    nomkstream: opt!(complete!(tag_no_case!("NOMKSTREAM"))) >>
+    trim: opt!(complete!(stream_trim_parser)) >>
+    id: parsed_string >>
+    fields: many1!(complete!(ws!(tuple!(parsed_string, parsed_string)))) >>
+    (Command::XAdd {key: key, nomkstream: nomkstream.is_some(), trim: trim, id: id, fields: fields})
+)));
+
+named!(stream_count_parser<&str, i64>, ws!(do_parse!(
+    tag_no_case!("COUNT") >>
+    count: parsed_digit >>
+    (count)
+)));
+
+named!(xrange_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("XRANGE") >>
+    key: key_parser >>
+    start: parsed_string >>
+    end: parsed_string >>
+    count: opt!(complete!(stream_count_parser)) >>
+    (Command::XRange {key: key, start: start, end: end, count: count})
+)));
+
+named!(xrevrange_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("XREVRANGE") >>
+    key: key_parser >>
+    end: parsed_string >>
+    start: parsed_string >>
+    count: opt!(complete!(stream_count_parser)) >>
+    (Command::XRevRange {key: key, end: end, start: start, count: count})
+)));
+
+named!(xlen_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("XLEN") >>
+    key: key_parser >>
+    (Command::XLen {key: key})
+)));
+
+named!(xtrim_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("XTRIM") >>
+    key: key_parser >>
+    trim: stream_trim_parser >>
+    (Command::XTrim {key: key, trim: trim})
+)));
+
+named!(xdel_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("XDEL") >>
+    key: key_parser >>
+    ids: many1!(complete!(parsed_string)) >>
+    (Command::XDel {key: key, ids: ids})
+)));
+
+named!(xinfo_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("XINFO") >>
+    cmd: alt!(
+        ws!(do_parse!(tag_no_case!("STREAM") >> key: key_parser >> (Command::XInfoStream {key: key}))) |
+        ws!(do_parse!(tag_no_case!("GROUPS") >> key: key_parser >> (Command::XInfoGroups {key: key}))) |
+        ws!(do_parse!(tag_no_case!("CONSUMERS") >> key: key_parser >> group: parsed_string >> (Command::XInfoConsumers {key: key, group: group})))
+    ) >>
+    (cmd)
+)));
+
+named!(entries_read_parser<&str, i64>, ws!(do_parse!(
+    tag_no_case!("ENTRIESREAD") >>
+    entries_read: parsed_digit >>
+    (entries_read)
+)));
+
+named!(xgroup_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("XGROUP") >>
+    cmd: alt!(
+        ws!(do_parse!(
+            tag_no_case!("CREATECONSUMER") >> key: key_parser >> group: parsed_string >> consumer: parsed_string >>
+            (Command::XGroupCreateConsumer {key: key, group: group, consumer: consumer})
+        )) |
+        ws!(do_parse!(
+            tag_no_case!("CREATE") >> key: key_parser >> group: parsed_string >> id: parsed_string >>
+            mkstream: opt!(complete!(tag_no_case!("MKSTREAM"))) >>
+            entries_read: opt!(complete!(entries_read_parser)) >>
+            (Command::XGroupCreate {key: key, group: group, id: id, mkstream: mkstream.is_some(), entries_read: entries_read})
+        )) |
+        ws!(do_parse!(
+            tag_no_case!("SETID") >> key: key_parser >> group: parsed_string >> id: parsed_string >>
+            entries_read: opt!(complete!(entries_read_parser)) >>
+            (Command::XGroupSetId {key: key, group: group, id: id, entries_read: entries_read})
+        )) |
+        ws!(do_parse!(
+            tag_no_case!("DESTROY") >> key: key_parser >> group: parsed_string >>
+            (Command::XGroupDestroy {key: key, group: group})
+        )) |
+        ws!(do_parse!(
+            tag_no_case!("DELCONSUMER") >> key: key_parser >> group: parsed_string >> consumer: parsed_string >>
+            (Command::XGroupDelConsumer {key: key, group: group, consumer: consumer})
+        ))
+    ) >>
+    (cmd)
+)));
+
+enum XReadOption {
+    Count(i64),
+    Block(i64),
+    NoAck,
+}
+
+named!(xread_option_parser<&str, XReadOption>, ws!(alt!(
+    map!(stream_count_parser, |count| XReadOption::Count(count)) |
+    do_parse!(tag_no_case!("BLOCK") >> timeout: parsed_digit >> (XReadOption::Block(timeout)))
+)));
+
+named!(xreadgroup_option_parser<&str, XReadOption>, ws!(alt!(
+    xread_option_parser |
+    map!(tag_no_case!("NOACK"), |_| XReadOption::NoAck)
+)));
+
+// folds XREAD options into (count, block, noack)
+fn xread_options(options:Vec<XReadOption>) -> (Option<i64>, Option<i64>, bool) {
+    let (mut count, mut block, mut noack) = (None, None, false);
+    for option in options {
+        match option {
+            XReadOption::Count(c) => count = Some(c),
+            XReadOption::Block(timeout) => block = Some(timeout),
+            XReadOption::NoAck => noack = true,
+        }
+    }
+    return (count, block, noack);
+}
+
+// the keys come first and the ids second; an odd count leaves one more key
+// than ids, which the command rejects when it runs
+fn split_streams(mut args:Vec<String>) -> (Vec<Key>, Vec<String>) {
+    let ids = args.split_off((args.len() + 1) / 2);
+    return (args, ids);
+}
+
+named!(xreadgroup_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("XREADGROUP") >>
+    tag_no_case!("GROUP") >>
+    group: parsed_string >>
+    consumer: parsed_string >>
+    options: many0!(complete!(xreadgroup_option_parser)) >>
+    tag_no_case!("STREAMS") >>
+    args: many1!(complete!(parsed_string)) >>
+    ({
+        let (keys, ids) = split_streams(args);
+        let (count, block, noack) = xread_options(options);
+        Command::XReadGroup {group: group, consumer: consumer, count: count, block: block, noack: noack, keys: keys, ids: ids}
+    })
+)));
+
+named!(xread_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("XREAD") >>
+    options: many0!(complete!(xread_option_parser)) >>
+    tag_no_case!("STREAMS") >>
+    args: many1!(complete!(parsed_string)) >>
+    ({
+        let (keys, ids) = split_streams(args);
+        let (count, block, _) = xread_options(options);
+        Command::XRead {count: count, block: block, keys: keys, ids: ids}
+    })
+)));
+
+named!(xack_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("XACK") >>
+    key: key_parser >>
+    group: parsed_string >>
+    ids: many1!(complete!(parsed_string)) >>
+    (Command::XAck {key: key, group: group, ids: ids})
+)));
+
+named!(xpending_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("XPENDING") >>
+    key: key_parser >>
+    group: parsed_string >>
+    range: opt!(complete!(ws!(do_parse!(
+        idle: opt!(complete!(ws!(do_parse!(tag_no_case!("IDLE") >> idle: parsed_digit >> (idle))))) >>
+        start: parsed_string >>
+        end: parsed_string >>
+        count: parsed_digit >>
+        consumer: opt!(complete!(parsed_string)) >>
+        (idle, start, end, count, consumer)
+    )))) >>
+    (match range {
+        Some((idle, start, end, count, consumer)) => Command::XPendingRange {
+            key: key, group: group, idle: idle, start: start, end: end, count: count, consumer: consumer,
+        },
+        None => Command::XPending {key: key, group: group},
+    })
+)));
+
+fn is_stream_id_char(c:char) -> bool {
+    return c.is_digit(10) || c == '-';
+}
+
+named!(stream_id_parser<&str, String>, map!(take_while1_s!(is_stream_id_char), |id:&str| id.to_string()));
+
+enum ClaimOption {
+    Idle(i64),
+    Time(i64),
+    RetryCount(i64),
+    Force,
+    JustId,
+    LastId(String),
+}
+
+named!(claim_option_parser<&str, ClaimOption>, ws!(alt!(
+    do_parse!(tag_no_case!("IDLE") >> idle: parsed_digit >> (ClaimOption::Idle(idle))) |
+    do_parse!(tag_no_case!("TIME") >> time: parsed_digit >> (ClaimOption::Time(time))) |
+    do_parse!(tag_no_case!("RETRYCOUNT") >> count: parsed_digit >> (ClaimOption::RetryCount(count))) |
+    map!(tag_no_case!("FORCE"), |_| ClaimOption::Force) |
+    map!(tag_no_case!("JUSTID"), |_| ClaimOption::JustId) |
+    do_parse!(tag_no_case!("LASTID") >> id: parsed_string >> (ClaimOption::LastId(id)))
+)));
+
+fn claim_options(options:Vec<ClaimOption>) -> ClaimOptions {
+    let mut claim = ClaimOptions {idle: None, time: None, retrycount: None, force: false, justid: false, lastid: None};
+    for option in options {
+        match option {
+            ClaimOption::Idle(idle) => claim.idle = Some(idle),
+            ClaimOption::Time(time) => claim.time = Some(time),
+            ClaimOption::RetryCount(count) => claim.retrycount = Some(count),
+            ClaimOption::Force => claim.force = true,
+            ClaimOption::JustId => claim.justid = true,
+            ClaimOption::LastId(id) => claim.lastid = Some(id),
+        }
+    }
+    return claim;
+}
+
+named!(xclaim_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("XCLAIM") >>
+    key: key_parser >>
+    group: parsed_string >>
+    consumer: parsed_string >>
+    min_idle: parsed_digit >>
+    ids: many1!(complete!(ws!(stream_id_parser))) >>
+    options: many0!(complete!(claim_option_parser)) >>
+    (Command::XClaim {key: key, group: group, consumer: consumer, min_idle: min_idle, ids: ids, options: claim_options(options)})
+)));
+
+named!(xautoclaim_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("XAUTOCLAIM") >>
+    key: key_parser >>
+    group: parsed_string >>
+    consumer: parsed_string >>
+    min_idle: parsed_digit >>
+    start: parsed_string >>
+    count: opt!(complete!(stream_count_parser)) >>
+    justid: opt!(complete!(tag_no_case!("JUSTID"))) >>
+    (Command::XAutoClaim {key: key, group: group, consumer: consumer, min_idle: min_idle, start: start, count: count, justid: justid.is_some()})
+)));
+
+named!(pfadd_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("PFADD") >>
+    key: key_parser >>
+    elements: many0!(complete!(parsed_string)) >>
+    (Command::Pfadd {key: key, elements: elements})
+)));
+
+named!(pfcount_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("PFCOUNT") >>
+    keys: many1!(key_parser) >>
+    (Command::Pfcount {keys: keys})
+)));
+
+named!(pfmerge_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("PFMERGE") >>
+    destkey: key_parser >>
+    sourcekeys: many0!(complete!(key_parser)) >>
+    (Command::Pfmerge {destkey: destkey, sourcekeys: sourcekeys})
+)));
+
 named!(del_parser<&str, Command>, ws!(do_parse!(
     tag_no_case!("DEL") >>
     keys: many1!(key_parser) >>
     (Command::Del {keys: keys})
 )));
 
+named!(unlink_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("UNLINK") >>
+    keys: many1!(key_parser) >>
+    (Command::Unlink {keys: keys})
+)));
+
 named!(exists_parser<&str, Command>, ws!(do_parse!(
     tag_no_case!("EXISTS") >>
     key: key_parser >>
@@ -216,6 +653,190 @@ named!(type_parser<&str, Command>, ws!(do_parse!(
     (Command::Type {key: key})
 )));
 
+named!(rename_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("RENAME") >>
+    key: key_parser >>
+    newkey: key_parser >>
+    (Command::Rename {key: key, newkey: newkey})
+)));
+
+named!(renamenx_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("RENAMENX") >>
+    key: key_parser >>
+    newkey: key_parser >>
+    (Command::RenameNx {key: key, newkey: newkey})
+)));
+
+named!(copy_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("COPY") >>
+    source: key_parser >>
+    destination: key_parser >>
+    db: opt!(complete!(ws!(preceded!(tag_no_case!("DB"), parsed_udigit)))) >>
+    replace: opt!(complete!(tag_no_case!("REPLACE"))) >>
+    (Command::Copy {source: source, destination: destination, db: db.map(|x| x as usize), replace: replace.is_some()})
+)));
+
+named!(move_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("MOVE") >>
+    key: key_parser >>
+    db: parsed_udigit >>
+    (Command::Move {key: key, db: db as usize})
+)));
+
+named!(expire_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("EXPIRE") >>
+    key: key_parser >>
+    seconds: parsed_digit >>
+    (Command::Expire {key: key, seconds: seconds})
+)));
+
+named!(pexpire_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("PEXPIRE") >>
+    key: key_parser >>
+    milliseconds: parsed_digit >>
+    (Command::Pexpire {key: key, milliseconds: milliseconds})
+)));
+
+named!(pexpireat_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("PEXPIREAT") >>
+    key: key_parser >>
+    timestamp: parsed_digit >>
+    (Command::PexpireAt {key: key, timestamp: timestamp})
+)));
+
+named!(ttl_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("TTL") >>
+    key: key_parser >>
+    (Command::Ttl {key: key})
+)));
+
+named!(pttl_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("PTTL") >>
+    key: key_parser >>
+    (Command::Pttl {key: key})
+)));
+
+named!(touch_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("TOUCH") >>
+    keys: many1!(key_parser) >>
+    (Command::Touch {keys: keys})
+)));
+
+named!(randomkey_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("RANDOMKEY") >>
+    (Command::RandomKey)
+)));
+
+named!(object_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("OBJECT") >>
+    cmd: alt!(
+        ws!(do_parse!(tag_no_case!("ENCODING") >> key: key_parser >> (Command::ObjectEncoding {key: key}))) |
+        ws!(do_parse!(tag_no_case!("FREQ") >> key: key_parser >> (Command::ObjectFreq {key: key}))) |
+        ws!(do_parse!(tag_no_case!("IDLETIME") >> key: key_parser >> (Command::ObjectIdleTime {key: key}))) |
+        ws!(do_parse!(tag_no_case!("REFCOUNT") >> key: key_parser >> (Command::ObjectRefCount {key: key})))
+    ) >>
+    (cmd)
+)));
+
+enum SortOption {
+    By(String),
+    Limit(i64, i64),
+    Get(String),
+    Asc,
+    Desc,
+    Alpha,
+    Store(Key),
+}
+
+named!(sort_option_parser<&str, SortOption>, ws!(alt!(
+    do_parse!(tag_no_case!("BY") >> pattern: parsed_string >> (SortOption::By(pattern))) |
+    do_parse!(tag_no_case!("LIMIT") >> offset: parsed_digit >> count: parsed_digit >> (SortOption::Limit(offset, count))) |
+    do_parse!(tag_no_case!("GET") >> pattern: parsed_string >> (SortOption::Get(pattern))) |
+    map!(tag_no_case!("ASC"), |_| SortOption::Asc) |
+    map!(tag_no_case!("DESC"), |_| SortOption::Desc) |
+    map!(tag_no_case!("ALPHA"), |_| SortOption::Alpha)
+)));
+
+named!(sort_store_parser<&str, SortOption>, ws!(do_parse!(
+    tag_no_case!("STORE") >>
+    destination: key_parser >>
+    (SortOption::Store(destination))
+)));
+
+fn sort_options(options:Vec<SortOption>) -> (SortOptions, Option<Key>) {
+    let mut sort = SortOptions {by: None, limit: None, get: vec![], desc: false, alpha: false};
+    let mut store = None;
+    for option in options {
+        match option {
+            SortOption::By(pattern) => sort.by = Some(pattern),
+            SortOption::Limit(offset, count) => sort.limit = Some((offset, count)),
+            SortOption::Get(pattern) => sort.get.push(pattern),
+            SortOption::Asc => sort.desc = false,
+            SortOption::Desc => sort.desc = true,
+            SortOption::Alpha => sort.alpha = true,
+            SortOption::Store(destination) => store = Some(destination),
+        }
+    }
+    return (sort, store);
+}
+
+named!(sort_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("SORT") >>
+    key: key_parser >>
+    options: many0!(complete!(alt!(sort_option_parser | sort_store_parser))) >>
+    ({
+        let (options, store) = sort_options(options);
+        Command::Sort {key: key, options: options, store: store}
+    })
+)));
+
+named!(sort_ro_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("SORT_RO") >>
+    key: key_parser >>
+    options: many0!(complete!(sort_option_parser)) >>
+    (Command::SortRo {key: key, options: sort_options(options).0})
+)));
+
+named!(dump_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("DUMP") >>
+    key: key_parser >>
+    (Command::Dump {key: key})
+)));
+
+enum RestoreOption {
+    Replace,
+    AbsTtl,
+    IdleTime(i64),
+    Freq(i64),
+}
+
+named!(restore_option_parser<&str, RestoreOption>, ws!(alt!(
+    map!(tag_no_case!("REPLACE"), |_| RestoreOption::Replace) |
+    map!(tag_no_case!("ABSTTL"), |_| RestoreOption::AbsTtl) |
+    do_parse!(tag_no_case!("IDLETIME") >> seconds: parsed_digit >> (RestoreOption::IdleTime(seconds))) |
+    do_parse!(tag_no_case!("FREQ") >> freq: parsed_digit >> (RestoreOption::Freq(freq)))
+)));
+
+named!(restore_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("RESTORE") >>
+    key: key_parser >>
+    ttl: parsed_digit >>
+    payload: parsed_string >>
+    options: many0!(complete!(restore_option_parser)) >>
+    ({
+        let (mut replace, mut absttl, mut idletime, mut freq) = (false, false, None, None);
+        for option in options {
+            match option {
+                RestoreOption::Replace => replace = true,
+                RestoreOption::AbsTtl => absttl = true,
+                RestoreOption::IdleTime(i) => idletime = Some(i),
+                RestoreOption::Freq(f) => freq = Some(f),
+            }
+        }
+        Command::Restore {key: key, ttl: ttl, payload: payload, replace: replace, absttl: absttl, idletime: idletime, freq: freq}
+    })
+)));
+
 named!(incr_parser<&str, Command>, ws!(do_parse!(
     tag_no_case!("INCR") >>
     key: key_parser >>
@@ -255,14 +876,21 @@ named!(select_parser<&str, Command>, ws!(do_parse!(
     (Command::Select(db as usize))
 )));
 
+named!(flush_mode_parser<&str, bool>, alt!(
+    map!(tag_no_case!("ASYNC"), |_| true) |
+    map!(tag_no_case!("SYNC"), |_| false)
+));
+
 named!(flushdb_parser<&str, Command>, ws!(do_parse!(
     tag_no_case!("FLUSHDB") >>
-    (Command::FlushDb)
+    async_mode: opt!(complete!(flush_mode_parser)) >>
+    (if async_mode == Some(true) {Command::FlushDbAsync} else {Command::FlushDb})
 )));
 
 named!(flushall_parser<&str, Command>, ws!(do_parse!(
     tag_no_case!("FLUSHALL") >>
-    (Command::FlushAll)
+    async_mode: opt!(complete!(flush_mode_parser)) >>
+    (if async_mode == Some(true) {Command::FlushAllAsync} else {Command::FlushAll})
 )));
 
 named!(swapdb_parser<&str, Command>, ws!(do_parse!(
@@ -280,10 +908,7 @@ named!(dbsize_parser<&str, Command>, ws!(do_parse!(
 named!(ping_parser<&str, Command>, ws!(do_parse!(
     tag_no_case!("PING") >>
     message: opt!(complete!(parsed_string)) >>
-    (Command::Ping {message: match message {
-        Some(x) => x.to_string(),
-        None => "PONG".to_string(),
-    }})
+    (Command::Ping {message: message.map(|x| x.to_string())})
 )));
 
 named!(echo_parser<&str, Command>, ws!(do_parse!(
@@ -297,6 +922,263 @@ named!(time_parser<&str, Command>, ws!(do_parse!(
     (Command::Time)
 )));
 
+named!(on_off_parser<&str, bool>, alt!(
+    map!(tag_no_case!("ON"), |_| true) |
+    map!(tag_no_case!("OFF"), |_| false)
+));
+
+named!(client_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("CLIENT") >>
+    tag_no_case!("NO-TOUCH") >>
+    on: on_off_parser >>
+    (Command::ClientNoTouch(on))
+)));
+
+named!(config_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("CONFIG") >>
+    cmd: alt!(
+        ws!(do_parse!(tag_no_case!("GET") >> parameter: parsed_string >> (Command::ConfigGet {parameter: parameter}))) |
+        ws!(do_parse!(tag_no_case!("SET") >> parameter: parsed_string >> value: parsed_string >> (Command::ConfigSet {parameter: parameter, value: value})))
+    ) >>
+    (cmd)
+)));
+
+named!(subscribe_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("SUBSCRIBE") >>
+    channels: many1!(complete!(parsed_string)) >>
+    (Command::Subscribe {channels: channels})
+)));
+
+named!(unsubscribe_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("UNSUBSCRIBE") >>
+    channels: many0!(complete!(parsed_string)) >>
+    (Command::Unsubscribe {channels: channels})
+)));
+
+named!(psubscribe_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("PSUBSCRIBE") >>
+    patterns: many1!(complete!(parsed_string)) >>
+    (Command::Psubscribe {patterns: patterns})
+)));
+
+named!(punsubscribe_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("PUNSUBSCRIBE") >>
+    patterns: many0!(complete!(parsed_string)) >>
+    (Command::Punsubscribe {patterns: patterns})
+)));
+
+named!(publish_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("PUBLISH") >>
+    channel: parsed_string >>
+    message: parsed_string >>
+    (Command::Publish {channel: channel, message: message})
+)));
+
+named!(pubsub_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("PUBSUB") >>
+    cmd: alt!(
+        ws!(do_parse!(tag_no_case!("CHANNELS") >> pattern: opt!(complete!(parsed_string)) >> (Command::PubsubChannels {pattern: pattern}))) |
+        ws!(do_parse!(tag_no_case!("NUMSUB") >> channels: many0!(complete!(parsed_string)) >> (Command::PubsubNumSub {channels: channels}))) |
+        map!(tag_no_case!("NUMPAT"), |_| Command::PubsubNumPat)
+    ) >>
+    (cmd)
+)));
+
+named!(multi_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("MULTI") >>
+    (Command::Multi)
+)));
+
+named!(exec_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("EXEC") >>
+    (Command::Exec)
+)));
+
+named!(discard_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("DISCARD") >>
+    (Command::Discard)
+)));
+
+named!(watch_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("WATCH") >>
+    keys: many1!(complete!(parsed_string)) >>
+    (Command::Watch {keys: keys})
+)));
+
+named!(unwatch_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("UNWATCH") >>
+    (Command::Unwatch)
+)));
+
+// EVALSHA goes first, as EVAL would take its SHA suffix for the script
+named!(evalsha_parser<&str, Command>, ws!(do_parse!(
+    readonly: alt!(map!(tag_no_case!("EVALSHA_RO"), |_| true) | map!(tag_no_case!("EVALSHA"), |_| false)) >>
+    sha: parsed_string >>
+    numkeys: parsed_digit >>
+    args: many0!(complete!(parsed_string)) >>
+    (Command::EvalSha {sha: sha, numkeys: numkeys, args: args, readonly: readonly})
+)));
+
+named!(eval_parser<&str, Command>, ws!(do_parse!(
+    readonly: alt!(map!(tag_no_case!("EVAL_RO"), |_| true) | map!(tag_no_case!("EVAL"), |_| false)) >>
+    script: parsed_string >>
+    numkeys: parsed_digit >>
+    args: many0!(complete!(parsed_string)) >>
+    (Command::Eval {script: script, numkeys: numkeys, args: args, readonly: readonly})
+)));
+
+named!(script_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("SCRIPT") >>
+    cmd: alt!(
+        ws!(do_parse!(tag_no_case!("LOAD") >> script: parsed_string >> (Command::ScriptLoad {script: script}))) |
+        ws!(do_parse!(tag_no_case!("EXISTS") >> shas: many1!(complete!(parsed_string)) >> (Command::ScriptExists {shas: shas}))) |
+        ws!(do_parse!(tag_no_case!("FLUSH") >> opt!(complete!(alt!(tag_no_case!("ASYNC") | tag_no_case!("SYNC")))) >> (Command::ScriptFlush))) |
+        map!(tag_no_case!("KILL"), |_| Command::ScriptKill)
+    ) >>
+    (cmd)
+)));
+
+enum FunctionListOption {
+    WithCode,
+    LibraryName(String),
+}
+
+named!(function_list_option_parser<&str, FunctionListOption>, ws!(alt!(
+    map!(tag_no_case!("WITHCODE"), |_| FunctionListOption::WithCode) |
+    do_parse!(tag_no_case!("LIBRARYNAME") >> pattern: parsed_string >> (FunctionListOption::LibraryName(pattern)))
+)));
+
+named!(restore_policy_parser<&str, RestorePolicy>, alt!(
+    map!(tag_no_case!("APPEND"), |_| RestorePolicy::Append) |
+    map!(tag_no_case!("REPLACE"), |_| RestorePolicy::Replace) |
+    map!(tag_no_case!("FLUSH"), |_| RestorePolicy::Flush)
+));
+
+named!(function_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("FUNCTION") >>
+    cmd: alt!(
+        ws!(do_parse!(
+            tag_no_case!("LOAD") >>
+            replace: opt!(complete!(tag_no_case!("REPLACE"))) >>
+            code: parsed_string >>
+            (Command::FunctionLoad {code: code, replace: replace.is_some()})
+        )) |
+        ws!(do_parse!(tag_no_case!("DELETE") >> library: parsed_string >> (Command::FunctionDelete {library: library}))) |
+        ws!(do_parse!(tag_no_case!("FLUSH") >> opt!(complete!(alt!(tag_no_case!("ASYNC") | tag_no_case!("SYNC")))) >> (Command::FunctionFlush))) |
+        ws!(do_parse!(
+            tag_no_case!("LIST") >>
+            options: many0!(complete!(function_list_option_parser)) >>
+            ({
+                let (mut pattern, mut withcode) = (None, false);
+                for option in options {
+                    match option {
+                        FunctionListOption::WithCode => withcode = true,
+                        FunctionListOption::LibraryName(p) => pattern = Some(p),
+                    }
+                }
+                Command::FunctionList {pattern: pattern, withcode: withcode}
+            })
+        )) |
+        map!(tag_no_case!("DUMP"), |_| Command::FunctionDump) |
+        ws!(do_parse!(
+            tag_no_case!("RESTORE") >>
+            payload: parsed_string >>
+            policy: opt!(complete!(restore_policy_parser)) >>
+            (Command::FunctionRestore {payload: payload, policy: policy.unwrap_or(RestorePolicy::Append)})
+        )) |
+        map!(tag_no_case!("KILL"), |_| Command::FunctionKill)
+    ) >>
+    (cmd)
+)));
+
+named!(fcall_parser<&str, Command>, ws!(do_parse!(
+    readonly: alt!(map!(tag_no_case!("FCALL_RO"), |_| true) | map!(tag_no_case!("FCALL"), |_| false)) >>
+    function: parsed_string >>
+    numkeys: parsed_digit >>
+    args: many0!(complete!(parsed_string)) >>
+    (Command::FCall {function: function, numkeys: numkeys, args: args, readonly: readonly})
+)));
+
+named!(wasm_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("WASM") >>
+    cmd: alt!(
+        ws!(do_parse!(tag_no_case!("LOAD") >> path: parsed_string >> (Command::WasmLoad {path: path}))) |
+        ws!(do_parse!(tag_no_case!("UNLOAD") >> name: parsed_string >> (Command::WasmUnload {name: name}))) |
+        map!(tag_no_case!("LIST"), |_| Command::WasmList)
+    ) >>
+    (cmd)
+)));
+
+named!(trigger_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("TRIGGER") >>
+    cmd: alt!(
+        ws!(do_parse!(
+            tag_no_case!("CREATE") >>
+            replace: opt!(complete!(tag_no_case!("REPLACE"))) >>
+            name: parsed_string >>
+            pattern: parsed_string >>
+            event: parsed_string >>
+            commands: many1!(complete!(parsed_string)) >>
+            (Command::TriggerCreate {name: name, pattern: pattern, event: event, commands: commands, replace: replace.is_some()})
+        )) |
+        ws!(do_parse!(tag_no_case!("DELETE") >> name: parsed_string >> (Command::TriggerDelete {name: name}))) |
+        map!(tag_no_case!("LIST"), |_| Command::TriggerList) |
+        map!(tag_no_case!("DUMP"), |_| Command::TriggerDump) |
+        ws!(do_parse!(
+            tag_no_case!("RESTORE") >>
+            payload: parsed_string >>
+            policy: opt!(complete!(restore_policy_parser)) >>
+            (Command::TriggerRestore {payload: payload, policy: policy.unwrap_or(RestorePolicy::Append)})
+        ))
+    ) >>
+    (cmd)
+)));
+
+// a command line split into its arguments
+named!(pub words_parser<&str, Vec<String>>, ws!(many0!(complete!(parsed_string))));
+
+named!(module_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("MODULE") >>
+    cmd: alt!(
+        ws!(do_parse!(
+            tag_no_case!("LOAD") >>
+            path: parsed_string >>
+            args: many0!(complete!(parsed_string)) >>
+            (Command::ModuleLoad {path: path, args: args})
+        )) |
+        ws!(do_parse!(tag_no_case!("UNLOAD") >> name: parsed_string >> (Command::ModuleUnload {name: name}))) |
+        map!(tag_no_case!("LIST"), |_| Command::ModuleList)
+    ) >>
+    (cmd)
+)));
+
+named!(save_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("SAVE") >>
+    (Command::Save)
+)));
+
+named!(bgsave_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("BGSAVE") >>
+    schedule: opt!(complete!(tag_no_case!("SCHEDULE"))) >>
+    (Command::BgSave {schedule: schedule.is_some()})
+)));
+
+named!(bgrewriteaof_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("BGREWRITEAOF") >>
+    (Command::BgRewriteAof)
+)));
+
+named!(lastsave_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("LASTSAVE") >>
+    (Command::LastSave)
+)));
+
+named!(info_parser<&str, Command>, ws!(do_parse!(
+    tag_no_case!("INFO") >>
+    section: opt!(complete!(parsed_string)) >>
+    (Command::Info {section: section})
+)));
+
 named!(pub command_parser<&str, Command>, alt!(
     select_parser |
     flushdb_parser |
@@ -317,9 +1199,51 @@ named!(pub command_parser<&str, Command>, alt!(
     sismember_parser |
     srem_parser |
     lset_parser |
+    pfadd_parser |
+    geoadd_parser |
+    geopos_parser |
+    geodist_parser |
+    geohash_parser |
+    geosearchstore_parser |
+    geosearch_parser |
+    xadd_parser |
+    xrange_parser |
+    xrevrange_parser |
+    xlen_parser |
+    xtrim_parser |
+    xdel_parser |
+    xinfo_parser |
+    xgroup_parser |
+    xreadgroup_parser |
+    xread_parser |
+    xack_parser |
+    xpending_parser |
+    xclaim_parser |
+    xautoclaim_parser |
+    pfcount_parser |
+    pfmerge_parser |
     del_parser |
     exists_parser |
     type_parser |
+    renamenx_parser |
+    rename_parser |
+    copy_parser |
+    move_parser |
+    expire_parser |
+    pexpireat_parser |
+    pexpire_parser |
+    ttl_parser |
+    pttl_parser |
+    touch_parser |
+    randomkey_parser |
+    object_parser |
+    client_parser |
+    config_parser |
+    unlink_parser |
+    dump_parser |
+    sort_ro_parser |
+    sort_parser |
+    restore_parser |
     incrbyfloat_parser |
     incrby_parser |
     incr_parser |
@@ -327,7 +1251,31 @@ named!(pub command_parser<&str, Command>, alt!(
     decr_parser |
     echo_parser |
     ping_parser |
-    time_parser
+    time_parser |
+    subscribe_parser |
+    unsubscribe_parser |
+    psubscribe_parser |
+    punsubscribe_parser |
+    publish_parser |
+    pubsub_parser |
+    multi_parser |
+    exec_parser |
+    discard_parser |
+    watch_parser |
+    unwatch_parser |
+    evalsha_parser |
+    eval_parser |
+    script_parser |
+    function_parser |
+    fcall_parser |
+    wasm_parser |
+    module_parser |
+    trigger_parser |
+    save_parser |
+    bgsave_parser |
+    bgrewriteaof_parser |
+    lastsave_parser |
+    info_parser
 ));
 
 
@@ -364,22 +1312,34 @@ fn test_parse_float() {
     assert_eq!(parsed_float("1.0"), IResult::Done("", 1.0));
     assert_eq!(parsed_float("1.2"), IResult::Done("", 1.2));
     assert_eq!(parsed_float("-2.0"), IResult::Done("", -2.0));
+    assert_eq!(parsed_float("-0.5"), IResult::Done("", -0.5));
+    assert_eq!(parsed_float("1e3 x"), IResult::Done(" x", 1000.0));
 }
 
 #[test]
 fn test_parse_quoted_chars() {
-    assert_eq!(quoted_char_sequence("\"\""), IResult::Done("", ""));
-    assert_eq!(quoted_char_sequence("\"abc123def\""), IResult::Done("", "abc123def"));
-    assert_eq!(quoted_char_sequence("\" \""), IResult::Done("", " "));
-    assert_eq!(quoted_char_sequence("\"hello world\""), IResult::Done("", "hello world"));
+    assert_eq!(quoted_char_sequence("\"\""), IResult::Done("", "".to_string()));
+    assert_eq!(quoted_char_sequence("\"abc123def\""), IResult::Done("", "abc123def".to_string()));
+    assert_eq!(quoted_char_sequence("\" \""), IResult::Done("", " ".to_string()));
+    assert_eq!(quoted_char_sequence("\"hello world\""), IResult::Done("", "hello world".to_string()));
+    assert_eq!(quoted_char_sequence("\"say \\\"hi\\\" \\\\o/\""), IResult::Done("", "say \"hi\" \\o/".to_string()));
 }
 
 #[test]
 fn test_parse_command() {
     assert_eq!(command_parser("SELECT 1"), IResult::Done("", Command::Select(1)));
     assert_eq!(command_parser("DBSIZE"), IResult::Done("", Command::DbSize));
+    assert_eq!(command_parser("MULTI"), IResult::Done("", Command::Multi));
+    assert_eq!(command_parser("exec"), IResult::Done("", Command::Exec));
+    assert_eq!(command_parser("DISCARD"), IResult::Done("", Command::Discard));
+    assert_eq!(command_parser("WATCH a b"), IResult::Done("", Command::Watch {keys: vec!["a".to_string(), "b".to_string()]}));
+    assert_eq!(command_parser("UNWATCH"), IResult::Done("", Command::Unwatch));
     assert_eq!(command_parser("FLUSHALL"), IResult::Done("", Command::FlushAll));
     assert_eq!(command_parser("FLUSHDB"), IResult::Done("", Command::FlushDb));
+    assert_eq!(command_parser("FLUSHDB ASYNC"), IResult::Done("", Command::FlushDbAsync));
+    assert_eq!(command_parser("FLUSHALL async"), IResult::Done("", Command::FlushAllAsync));
+    assert_eq!(command_parser("UNLINK abcd"), IResult::Done("", Command::Unlink {keys: vec!["abcd".to_string()]}));
+    assert_eq!(command_parser("CONFIG SET lazyfree-lazy-expire yes"), IResult::Done("", Command::ConfigSet {parameter: "lazyfree-lazy-expire".to_string(), value: "yes".to_string()}));
     assert_eq!(command_parser("GET abcd"), IResult::Done("", Command::Get {key: "abcd".to_string()}));
     assert_eq!(command_parser("SET abc 1"), IResult::Done("", Command::Set {key: "abc".to_string(), value: Value::IntValue(1), exp: None}));
     assert_eq!(command_parser("EXISTS abcd"), IResult::Done("", Command::Exists {key: "abcd".to_string()}));
@@ -388,6 +1348,145 @@ fn test_parse_command() {
     assert_eq!(command_parser("INCR abcd"), IResult::Done("", Command::Incr {key: "abcd".to_string()}));
     assert_eq!(command_parser("INCRBY abcd 10"), IResult::Done("", Command::IncrBy {key: "abcd".to_string(), increment: 10}));
     assert_eq!(command_parser("INCRBYFLOAT abcd 0.1"), IResult::Done("", Command::IncrByFloat {key: "abcd".to_string(), increment: 0.1}));
-    assert_eq!(command_parser("PING"), IResult::Done("", Command::Ping {message: "PONG".to_string()}));
+    assert_eq!(command_parser("SET abc 1 EX 10"), IResult::Done("", Command::Set {key: "abc".to_string(), value: Value::IntValue(1), exp: Some(10000)}));
+    assert_eq!(command_parser("SET abc 1 EX 9223372036854775807"), IResult::Done("", Command::Set {key: "abc".to_string(), value: Value::IntValue(1), exp: Some(u64::max_value())}));
+    for time in ["EX 0", "PX 0", "EX -1", "px -10", "EX 99999999999999999999"].iter() {
+        assert_eq!(command_parser(&format!("SET abc 1 {}", time)), IResult::Done("", Command::Set {key: "abc".to_string(), value: Value::IntValue(1), exp: Some(u64::max_value())}));
+    }
+    // a number out of range is a string, not a panic
+    assert_eq!(command_parser("SET abc 99999999999999999999"), IResult::Done("", Command::Set {key: "abc".to_string(), value: Value::StrValue("99999999999999999999".to_string()), exp: None}));
+    assert!(match command_parser("LINDEX abc 99999999999999999999") {IResult::Done("", _) => false, _ => true});
+    assert_eq!(command_parser("RENAME abc def"), IResult::Done("", Command::Rename {key: "abc".to_string(), newkey: "def".to_string()}));
+    assert_eq!(command_parser("RENAMENX abc def"), IResult::Done("", Command::RenameNx {key: "abc".to_string(), newkey: "def".to_string()}));
+    assert_eq!(command_parser("COPY abc def"), IResult::Done("", Command::Copy {source: "abc".to_string(), destination: "def".to_string(), db: None, replace: false}));
+    assert_eq!(command_parser("COPY abc def DB 2 REPLACE"), IResult::Done("", Command::Copy {source: "abc".to_string(), destination: "def".to_string(), db: Some(2), replace: true}));
+    assert_eq!(command_parser("MOVE abc 3"), IResult::Done("", Command::Move {key: "abc".to_string(), db: 3}));
+    assert_eq!(command_parser("TOUCH abc def"), IResult::Done("", Command::Touch {keys: vec!["abc".to_string(), "def".to_string()]}));
+    assert_eq!(command_parser("OBJECT FREQ abc"), IResult::Done("", Command::ObjectFreq {key: "abc".to_string()}));
+    assert_eq!(command_parser("object idletime abc"), IResult::Done("", Command::ObjectIdleTime {key: "abc".to_string()}));
+    assert_eq!(command_parser("CLIENT NO-TOUCH on"), IResult::Done("", Command::ClientNoTouch(true)));
+    assert_eq!(command_parser("RESTORE abc 0 \"a\\\"b\" REPLACE FREQ 3"), IResult::Done("", Command::Restore {key: "abc".to_string(), ttl: 0, payload: "a\"b".to_string(), replace: true, absttl: false, idletime: None, freq: Some(3)}));
+    assert_eq!(command_parser("SORT ids BY weight_* LIMIT 0 10 GET # GET user:*->name DESC ALPHA STORE out"), IResult::Done("", Command::Sort {
+        key: "ids".to_string(),
+        options: SortOptions {by: Some("weight_*".to_string()), limit: Some((0, 10)), get: vec!["#".to_string(), "user:*->name".to_string()], desc: true, alpha: true},
+        store: Some("out".to_string()),
+    }));
+    assert_eq!(command_parser("SORT_RO ids"), IResult::Done("", Command::SortRo {key: "ids".to_string(), options: SortOptions {by: None, limit: None, get: vec![], desc: false, alpha: false}}));
+    // SORT_RO has no STORE, which is left over
+    assert_eq!(command_parser("SORT_RO ids STORE out"), IResult::Done("STORE out", Command::SortRo {key: "ids".to_string(), options: SortOptions {by: None, limit: None, get: vec![], desc: false, alpha: false}}));
+    assert_eq!(command_parser("PFADD hll a b"), IResult::Done("", Command::Pfadd {key: "hll".to_string(), elements: vec!["a".to_string(), "b".to_string()]}));
+    assert_eq!(command_parser("PFMERGE dest"), IResult::Done("", Command::Pfmerge {destkey: "dest".to_string(), sourcekeys: vec![]}));
+    assert_eq!(command_parser("GEOADD Sicily CH 13.361389 38.115556 Palermo -0.1278 51.5074 London"), IResult::Done("", Command::GeoAdd {
+        key: "Sicily".to_string(), nx: false, xx: false, ch: true,
+        items: vec![(13.361389, 38.115556, "Palermo".to_string()), (-0.1278, 51.5074, "London".to_string())],
+    }));
+    assert_eq!(command_parser("GEOSEARCH Sicily FROMLONLAT 15 37 BYRADIUS 200 km ASC COUNT 1 ANY WITHDIST"), IResult::Done("", Command::GeoSearch {
+        key: "Sicily".to_string(),
+        options: GeoSearchOptions {from: Some(GeoFrom::LonLat(15.0, 37.0)), by: Some(GeoShape::Radius(200000.0)), unit: 1000.0, desc: Some(false), count: Some((1, true)), withcoord: false, withdist: true, withhash: false},
+    }));
+    assert_eq!(command_parser("XADD s NOMKSTREAM MAXLEN ~ 1000 * name \"a b\" n 1"), IResult::Done("", Command::XAdd {
+        key: "s".to_string(), nomkstream: true,
+        trim: Some(StreamTrim {minid: false, threshold: "1000".to_string(), approx: true, limit: None}),
+        id: "*".to_string(), fields: vec![("name".to_string(), "a b".to_string()), ("n".to_string(), "1".to_string())],
+    }));
+    assert_eq!(command_parser("XTRIM s MINID 5-0 LIMIT 10"), IResult::Done("", Command::XTrim {
+        key: "s".to_string(), trim: StreamTrim {minid: true, threshold: "5-0".to_string(), approx: false, limit: Some(10)},
+    }));
+    assert_eq!(command_parser("XREVRANGE s + (1-1 COUNT 2"), IResult::Done("", Command::XRevRange {
+        key: "s".to_string(), end: "+".to_string(), start: "(1-1".to_string(), count: Some(2),
+    }));
+    assert_eq!(command_parser("XREADGROUP GROUP g alice NOACK COUNT 10 STREAMS s1 s2 > 0"), IResult::Done("", Command::XReadGroup {
+        group: "g".to_string(), consumer: "alice".to_string(), count: Some(10), block: None, noack: true,
+        keys: vec!["s1".to_string(), "s2".to_string()], ids: vec![">".to_string(), "0".to_string()],
+    }));
+    assert_eq!(command_parser("XREAD COUNT 2 BLOCK 0 STREAMS a b $ 0-0"), IResult::Done("", Command::XRead {
+        count: Some(2), block: Some(0), keys: vec!["a".to_string(), "b".to_string()], ids: vec!["$".to_string(), "0-0".to_string()],
+    }));
+    assert_eq!(command_parser("XCLAIM s g bob 3600000 1-0 2-5 IDLE 0 JUSTID"), IResult::Done("", Command::XClaim {
+        key: "s".to_string(), group: "g".to_string(), consumer: "bob".to_string(), min_idle: 3600000,
+        ids: vec!["1-0".to_string(), "2-5".to_string()],
+        options: ClaimOptions {idle: Some(0), time: None, retrycount: None, force: false, justid: true, lastid: None},
+    }));
+    assert_eq!(command_parser("XPENDING s g IDLE 10 - + 5 bob"), IResult::Done("", Command::XPendingRange {
+        key: "s".to_string(), group: "g".to_string(), idle: Some(10), start: "-".to_string(), end: "+".to_string(), count: 5, consumer: Some("bob".to_string()),
+    }));
+    assert_eq!(command_parser("XPENDING s g"), IResult::Done("", Command::XPending {key: "s".to_string(), group: "g".to_string()}));
+    assert_eq!(command_parser("XGROUP CREATE s g $ MKSTREAM"), IResult::Done("", Command::XGroupCreate {
+        key: "s".to_string(), group: "g".to_string(), id: "$".to_string(), mkstream: true, entries_read: None,
+    }));
+    assert_eq!(command_parser("PSUBSCRIBE cache:* news.[ab]"), IResult::Done("", Command::Psubscribe {patterns: vec!["cache:*".to_string(), "news.[ab]".to_string()]}));
+    assert_eq!(command_parser("PUBSUB NUMPAT"), IResult::Done("", Command::PubsubNumPat));
+    assert_eq!(command_parser("UNSUBSCRIBE"), IResult::Done("", Command::Unsubscribe {channels: vec![]}));
+    assert_eq!(command_parser("PUBSUB NUMSUB a b"), IResult::Done("", Command::PubsubNumSub {channels: vec!["a".to_string(), "b".to_string()]}));
+    assert_eq!(command_parser("PING"), IResult::Done("", Command::Ping {message: None}));
+    assert_eq!(command_parser("PING PONG"), IResult::Done("", Command::Ping {message: Some("PONG".to_string())}));
     assert_eq!(command_parser("ECHO \"hello world\""), IResult::Done("", Command::Echo {message: "hello world".to_string()}));
 }
+
+#[test]
+fn test_parse_scripting() {
+    assert_eq!(command_parser("EVAL \"return 1\" 1 k a"), IResult::Done("", Command::Eval {script: "return 1".to_string(), numkeys: 1, args: vec!["k".to_string(), "a".to_string()], readonly: false}));
+    assert_eq!(command_parser("eval_ro \"return 1\" 0"), IResult::Done("", Command::Eval {script: "return 1".to_string(), numkeys: 0, args: vec![], readonly: true}));
+    assert_eq!(command_parser("EVALSHA abc 0 x"), IResult::Done("", Command::EvalSha {sha: "abc".to_string(), numkeys: 0, args: vec!["x".to_string()], readonly: false}));
+    assert_eq!(command_parser("EVALSHA_RO abc 1 k"), IResult::Done("", Command::EvalSha {sha: "abc".to_string(), numkeys: 1, args: vec!["k".to_string()], readonly: true}));
+    assert_eq!(command_parser("SCRIPT LOAD \"return 1\""), IResult::Done("", Command::ScriptLoad {script: "return 1".to_string()}));
+    assert_eq!(command_parser("SCRIPT EXISTS a b"), IResult::Done("", Command::ScriptExists {shas: vec!["a".to_string(), "b".to_string()]}));
+    assert_eq!(command_parser("SCRIPT FLUSH ASYNC"), IResult::Done("", Command::ScriptFlush));
+    assert_eq!(command_parser("SCRIPT KILL"), IResult::Done("", Command::ScriptKill));
+}
+
+#[test]
+fn test_parse_functions() {
+    assert_eq!(command_parser("FUNCTION LOAD REPLACE \"#!lua name=lib\""), IResult::Done("", Command::FunctionLoad {code: "#!lua name=lib".to_string(), replace: true}));
+    assert_eq!(command_parser("FUNCTION DELETE lib"), IResult::Done("", Command::FunctionDelete {library: "lib".to_string()}));
+    assert_eq!(command_parser("FUNCTION FLUSH SYNC"), IResult::Done("", Command::FunctionFlush));
+    assert_eq!(command_parser("FUNCTION LIST"), IResult::Done("", Command::FunctionList {pattern: None, withcode: false}));
+    assert_eq!(command_parser("FUNCTION LIST WITHCODE LIBRARYNAME l*"), IResult::Done("", Command::FunctionList {pattern: Some("l*".to_string()), withcode: true}));
+    assert_eq!(command_parser("FUNCTION DUMP"), IResult::Done("", Command::FunctionDump));
+    assert_eq!(command_parser("FUNCTION RESTORE x"), IResult::Done("", Command::FunctionRestore {payload: "x".to_string(), policy: RestorePolicy::Append}));
+    assert_eq!(command_parser("FUNCTION RESTORE x flush"), IResult::Done("", Command::FunctionRestore {payload: "x".to_string(), policy: RestorePolicy::Flush}));
+    assert_eq!(command_parser("FUNCTION KILL"), IResult::Done("", Command::FunctionKill));
+    assert_eq!(command_parser("FCALL f 1 k a"), IResult::Done("", Command::FCall {function: "f".to_string(), numkeys: 1, args: vec!["k".to_string(), "a".to_string()], readonly: false}));
+    assert_eq!(command_parser("FCALL_RO f 0"), IResult::Done("", Command::FCall {function: "f".to_string(), numkeys: 0, args: vec![], readonly: true}));
+}
+
+#[test]
+fn test_parse_wasm() {
+    assert_eq!(command_parser("WASM LOAD /tmp/m.wasm"), IResult::Done("", Command::WasmLoad {path: "/tmp/m.wasm".to_string()}));
+    assert_eq!(command_parser("wasm unload m"), IResult::Done("", Command::WasmUnload {name: "m".to_string()}));
+    assert_eq!(command_parser("WASM LIST"), IResult::Done("", Command::WasmList));
+}
+
+#[test]
+fn test_parse_module() {
+    assert_eq!(command_parser("MODULE LOAD /tmp/m.so a b"), IResult::Done("", Command::ModuleLoad {path: "/tmp/m.so".to_string(), args: vec!["a".to_string(), "b".to_string()]}));
+    assert_eq!(command_parser("module load /tmp/m.so"), IResult::Done("", Command::ModuleLoad {path: "/tmp/m.so".to_string(), args: vec![]}));
+    assert_eq!(command_parser("MODULE UNLOAD m"), IResult::Done("", Command::ModuleUnload {name: "m".to_string()}));
+    assert_eq!(command_parser("MODULE LIST"), IResult::Done("", Command::ModuleList));
+}
+
+#[test]
+fn test_parse_trigger() {
+    assert_eq!(command_parser("TRIGGER CREATE count user:* set \"INCR users\" \"SADD changed {key}\""), IResult::Done("", Command::TriggerCreate {
+        name: "count".to_string(), pattern: "user:*".to_string(), event: "set".to_string(),
+        commands: vec!["INCR users".to_string(), "SADD changed {key}".to_string()], replace: false,
+    }));
+    assert_eq!(command_parser("trigger create replace t * * \"DEL x\""), IResult::Done("", Command::TriggerCreate {
+        name: "t".to_string(), pattern: "*".to_string(), event: "*".to_string(), commands: vec!["DEL x".to_string()], replace: true,
+    }));
+    assert_eq!(command_parser("TRIGGER DELETE t"), IResult::Done("", Command::TriggerDelete {name: "t".to_string()}));
+    assert_eq!(command_parser("TRIGGER LIST"), IResult::Done("", Command::TriggerList));
+    assert_eq!(command_parser("TRIGGER RESTORE x REPLACE"), IResult::Done("", Command::TriggerRestore {payload: "x".to_string(), policy: RestorePolicy::Replace}));
+    assert_eq!(words_parser("SADD \"a b\" {key}"), IResult::Done("", vec!["SADD".to_string(), "a b".to_string(), "{key}".to_string()]));
+}
+
+#[test]
+fn test_parse_save() {
+    assert_eq!(command_parser("SAVE"), IResult::Done("", Command::Save));
+    assert_eq!(command_parser("bgsave"), IResult::Done("", Command::BgSave {schedule: false}));
+    assert_eq!(command_parser("BGSAVE SCHEDULE"), IResult::Done("", Command::BgSave {schedule: true}));
+    assert_eq!(command_parser("bgrewriteaof"), IResult::Done("", Command::BgRewriteAof));
+    assert_eq!(command_parser("LASTSAVE"), IResult::Done("", Command::LastSave));
+    assert_eq!(command_parser("INFO"), IResult::Done("", Command::Info {section: None}));
+    assert_eq!(command_parser("info persistence"), IResult::Done("", Command::Info {section: Some("persistence".to_string())}));
+}
@@ -0,0 +1,312 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+// which connections, by token, are subscribed to which channels and patterns
+pub struct PubSub {
+    channels:HashMap<String, HashSet<usize>>,
+    clients:HashMap<usize, BTreeSet<String>>,
+    // patterns grouped by their literal prefix, so a publish only tries the
+    // patterns whose prefix the channel starts with
+    patterns:HashMap<String, HashMap<String, HashSet<usize>>>,
+    pattern_count:usize,
+    client_patterns:HashMap<usize, BTreeSet<String>>,
+}
+
+impl PubSub {
+    pub fn new() -> PubSub {
+        return PubSub {
+            channels: HashMap::new(),
+            clients: HashMap::new(),
+            patterns: HashMap::new(),
+            pattern_count: 0,
+            client_patterns: HashMap::new(),
+        };
+    }
+
+    // returns false if the client was already subscribed
+    pub fn subscribe(&mut self, token:usize, channel:&str) -> bool {
+        if !self.clients.entry(token).or_insert_with(BTreeSet::new).insert(channel.to_string()) {
+            return false;
+        }
+        self.channels.entry(channel.to_string()).or_insert_with(HashSet::new).insert(token);
+        return true;
+    }
+
+    pub fn unsubscribe(&mut self, token:usize, channel:&str) -> bool {
+        let removed = match self.clients.get_mut(&token) {
+            Some(channels) => channels.remove(channel),
+            None => false,
+        };
+        if !removed {
+            return false;
+        }
+        let empty = match self.channels.get_mut(channel) {
+            Some(tokens) => {
+                tokens.remove(&token);
+                tokens.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            self.channels.remove(channel);
+        }
+        if self.clients.get(&token).map_or(false, |channels| channels.is_empty()) {
+            self.clients.remove(&token);
+        }
+        return true;
+    }
+
+    // the channels a client is subscribed to
+    pub fn client_channels(&self, token:usize) -> Vec<String> {
+        return self.clients.get(&token).map_or(vec![], |channels| channels.iter().cloned().collect());
+    }
+
+    pub fn psubscribe(&mut self, token:usize, pattern:&str) -> bool {
+        if !self.client_patterns.entry(token).or_insert_with(BTreeSet::new).insert(pattern.to_string()) {
+            return false;
+        }
+        let tokens = self.patterns.entry(literal_prefix(pattern)).or_insert_with(HashMap::new)
+            .entry(pattern.to_string()).or_insert_with(HashSet::new);
+        if tokens.is_empty() {
+            self.pattern_count += 1;
+        }
+        tokens.insert(token);
+        return true;
+    }
+
+    pub fn punsubscribe(&mut self, token:usize, pattern:&str) -> bool {
+        let removed = match self.client_patterns.get_mut(&token) {
+            Some(patterns) => patterns.remove(pattern),
+            None => false,
+        };
+        if !removed {
+            return false;
+        }
+        let prefix = literal_prefix(pattern);
+        let empty = match self.patterns.get_mut(&prefix) {
+            Some(patterns) => {
+                let gone = match patterns.get_mut(pattern) {
+                    Some(tokens) => {
+                        tokens.remove(&token);
+                        tokens.is_empty()
+                    }
+                    None => false,
+                };
+                if gone {
+                    patterns.remove(pattern);
+                    self.pattern_count -= 1;
+                }
+                patterns.is_empty()
+            }
+            None => false,
+        };
+        if empty {
+            self.patterns.remove(&prefix);
+        }
+        if self.client_patterns.get(&token).map_or(false, |patterns| patterns.is_empty()) {
+            self.client_patterns.remove(&token);
+        }
+        return true;
+    }
+
+    pub fn client_patterns(&self, token:usize) -> Vec<String> {
+        return self.client_patterns.get(&token).map_or(vec![], |patterns| patterns.iter().cloned().collect());
+    }
+
+    // the number of distinct patterns with subscribers
+    pub fn pattern_count(&self) -> usize {
+        return self.pattern_count;
+    }
+
+    // the patterns matching a channel, with their subscribers
+    pub fn pattern_subscribers(&self, channel:&str) -> Vec<(String, Vec<usize>)> {
+        let mut matches = vec![];
+        let prefixes = channel.char_indices().map(|(i, _)| &channel[..i]).chain(Some(channel));
+        for prefix in prefixes {
+            if let Some(patterns) = self.patterns.get(prefix) {
+                for (pattern, tokens) in patterns.iter() {
+                    if glob_match(pattern, channel) {
+                        matches.push((pattern.clone(), tokens.iter().cloned().collect()));
+                    }
+                }
+            }
+        }
+        return matches;
+    }
+
+    // a client with any subscriptions is in subscriber mode
+    pub fn subscription_count(&self, token:usize) -> usize {
+        return self.clients.get(&token).map_or(0, |channels| channels.len()) +
+            self.client_patterns.get(&token).map_or(0, |patterns| patterns.len());
+    }
+
+    pub fn subscribers(&self, channel:&str) -> Vec<usize> {
+        return self.channels.get(channel).map_or(vec![], |tokens| tokens.iter().cloned().collect());
+    }
+
+    // channels with at least one subscriber, optionally filtered by a glob
+    pub fn active_channels(&self, pattern:Option<&str>) -> Vec<String> {
+        let mut channels = self.channels.keys().filter(|channel| pattern.map_or(true, |p| glob_match(p, channel))).cloned().collect::<Vec<String>>();
+        channels.sort();
+        return channels;
+    }
+
+    pub fn remove_client(&mut self, token:usize) {
+        for channel in self.client_channels(token) {
+            self.unsubscribe(token, &channel);
+        }
+        for pattern in self.client_patterns(token) {
+            self.punsubscribe(token, &pattern);
+        }
+    }
+}
+
+// the part of a pattern before its first wildcard, which every matching
+// channel starts with
+fn literal_prefix(pattern:&str) -> String {
+    let end = pattern.find(|c| c == '*' || c == '?' || c == '[' || c == '\\').unwrap_or(pattern.len());
+    return pattern[..end].to_string();
+}
+
+// redis-style glob matching with `*`, `?`, `[...]` classes (with `^` negation
+// and `a-z` ranges) and `\` escapes
+pub fn glob_match(pattern:&str, s:&str) -> bool {
+    let p = pattern.chars().collect::<Vec<char>>();
+    let s = s.chars().collect::<Vec<char>>();
+    return glob_match_chars(&p, &s);
+}
+
+fn glob_match_chars(p:&[char], s:&[char]) -> bool {
+    let (mut pi, mut si) = (0, 0);
+    // where to resume after the last `*` when a later match fails
+    let mut backtrack:Option<(usize, usize)> = None;
+    while si < s.len() {
+        let mut matched = None;
+        if pi < p.len() {
+            match p[pi] {
+                '*' => {
+                    backtrack = Some((pi, si));
+                    pi += 1;
+                    continue;
+                }
+                '?' => matched = Some(pi + 1),
+                '[' => {
+                    if let Some((end, hit)) = match_class(p, pi, s[si]) {
+                        if hit {
+                            matched = Some(end);
+                        }
+                    }
+                }
+                '\\' if pi + 1 < p.len() => {
+                    if p[pi + 1] == s[si] {
+                        matched = Some(pi + 2);
+                    }
+                }
+                c => {
+                    if c == s[si] {
+                        matched = Some(pi + 1);
+                    }
+                }
+            }
+        }
+        match (matched, backtrack) {
+            (Some(next), _) => {
+                pi = next;
+                si += 1;
+            }
+            (None, Some((star, from))) => {
+                pi = star + 1;
+                si = from + 1;
+                backtrack = Some((star, from + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    while pi < p.len() && p[pi] == '*' {
+        pi += 1;
+    }
+    return pi == p.len();
+}
+
+// matches c against the class starting at p[start] == '[', returning the
+// index after the class and whether it matched
+fn match_class(p:&[char], start:usize, c:char) -> Option<(usize, bool)> {
+    let mut i = start + 1;
+    let negate = i < p.len() && p[i] == '^';
+    if negate {
+        i += 1;
+    }
+    let mut hit = false;
+    while i < p.len() && p[i] != ']' {
+        if p[i] == '\\' && i + 1 < p.len() {
+            hit |= p[i + 1] == c;
+            i += 2;
+        } else if i + 2 < p.len() && p[i + 1] == '-' && p[i + 2] != ']' {
+            let (lo, hi) = if p[i] <= p[i + 2] {(p[i], p[i + 2])} else {(p[i + 2], p[i])};
+            hit |= c >= lo && c <= hi;
+            i += 3;
+        } else {
+            hit |= p[i] == c;
+            i += 1;
+        }
+    }
+    if i >= p.len() {
+        // an unterminated class matches like redis, up to the end of pattern
+        return Some((i, hit != negate));
+    }
+    return Some((i + 1, hit != negate));
+}
+
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("*", ""));
+    assert!(glob_match("cache:*", "cache:users:1"));
+    assert!(!glob_match("cache:*", "session:1"));
+    assert!(glob_match("h?llo", "hello"));
+    assert!(glob_match("h[ae]llo", "hallo"));
+    assert!(!glob_match("h[^e]llo", "hello"));
+    assert!(glob_match("h[a-c]llo", "hbllo"));
+    assert!(glob_match("a*b*c", "axxbyyc"));
+    assert!(!glob_match("a*b*c", "axxbyy"));
+    assert!(glob_match("\\*x", "*x"));
+    assert!(!glob_match("\\*x", "ax"));
+}
+
+#[test]
+fn test_pubsub() {
+    let mut pubsub = PubSub::new();
+    assert!(pubsub.subscribe(1, "news"));
+    assert!(!pubsub.subscribe(1, "news"));
+    assert!(pubsub.subscribe(2, "news"));
+    assert!(pubsub.subscribe(2, "sport"));
+    assert_eq!(pubsub.subscription_count(2), 2);
+    assert_eq!(pubsub.active_channels(Some("n*")), vec!["news".to_string()]);
+    pubsub.remove_client(2);
+    assert_eq!(pubsub.subscribers("news"), vec![1]);
+    assert_eq!(pubsub.active_channels(None), vec!["news".to_string()]);
+    assert!(pubsub.unsubscribe(1, "news"));
+    assert_eq!(pubsub.active_channels(None), Vec::<String>::new());
+}
+
+#[test]
+fn test_patterns() {
+    let mut pubsub = PubSub::new();
+    assert!(pubsub.psubscribe(1, "cache:*"));
+    assert!(pubsub.psubscribe(2, "cache:*"));
+    assert!(pubsub.psubscribe(2, "*"));
+    assert!(pubsub.psubscribe(2, "cache:users:[0-9]"));
+    for i in 0..1000 {
+        pubsub.psubscribe(3, &format!("other:{}:*", i));
+    }
+    assert_eq!(pubsub.pattern_count(), 1003);
+    let mut matches = pubsub.pattern_subscribers("cache:users:1");
+    matches.sort();
+    assert_eq!(matches.iter().map(|&(ref p, _)| p.as_str()).collect::<Vec<&str>>(), vec!["*", "cache:*", "cache:users:[0-9]"]);
+    assert_eq!(matches[1].1.len(), 2);
+    assert_eq!(pubsub.subscription_count(2), 3);
+    pubsub.remove_client(2);
+    pubsub.remove_client(3);
+    assert_eq!(pubsub.pattern_count(), 1);
+    assert!(pubsub.punsubscribe(1, "cache:*"));
+    assert_eq!(pubsub.pattern_count(), 0);
+    assert!(pubsub.pattern_subscribers("cache:x").is_empty());
+}
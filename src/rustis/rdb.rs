@@ -0,0 +1,1182 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use rustis::binary::{bytes_to_string, string_to_bytes};
+use rustis::crc64::crc64;
+use rustis::key::now_ms;
+use rustis::module::ModuleValue;
+use rustis::stream::{Consumer, ConsumerGroup, PendingEntry, Stream, StreamId, STREAM_NODE_MAX_ENTRIES};
+use rustis::value::Value;
+use rustis::zset::SortedSet;
+
+// serialization of values in redis' RDB object format, as used by DUMP and
+// RESTORE
+
+// the version written to DUMP payloads; old enough for any redis >= 5 to load
+pub const RDB_VERSION:u16 = 9;
+// the newest version we know how to read
+pub const RDB_MAX_VERSION:u16 = 12;
+
+pub const RDB_TYPE_STRING:u8 = 0;
+pub const RDB_TYPE_LIST:u8 = 1;
+pub const RDB_TYPE_SET:u8 = 2;
+pub const RDB_TYPE_ZSET:u8 = 3;
+pub const RDB_TYPE_HASH:u8 = 4;
+pub const RDB_TYPE_ZSET_2:u8 = 5;
+pub const RDB_TYPE_MODULE_2:u8 = 7;
+pub const RDB_TYPE_LIST_ZIPLIST:u8 = 10;
+pub const RDB_TYPE_SET_INTSET:u8 = 11;
+pub const RDB_TYPE_ZSET_ZIPLIST:u8 = 12;
+pub const RDB_TYPE_HASH_ZIPLIST:u8 = 13;
+pub const RDB_TYPE_LIST_QUICKLIST:u8 = 14;
+pub const RDB_TYPE_STREAM_LISTPACKS:u8 = 15;
+pub const RDB_TYPE_HASH_LISTPACK:u8 = 16;
+pub const RDB_TYPE_ZSET_LISTPACK:u8 = 17;
+pub const RDB_TYPE_LIST_QUICKLIST_2:u8 = 18;
+pub const RDB_TYPE_STREAM_LISTPACKS_2:u8 = 19;
+pub const RDB_TYPE_SET_LISTPACK:u8 = 20;
+pub const RDB_TYPE_STREAM_LISTPACKS_3:u8 = 21;
+// hashes with field expirations, from redis 7.4
+pub const RDB_TYPE_HASH_METADATA_PRE_GA:u8 = 22;
+pub const RDB_TYPE_HASH_LISTPACK_EX_PRE_GA:u8 = 23;
+pub const RDB_TYPE_HASH_METADATA:u8 = 24;
+pub const RDB_TYPE_HASH_LISTPACK_EX:u8 = 25;
+
+pub const RDB_OPCODE_FUNCTION2:u8 = 245;
+
+// what a module value is saved as, after its type id
+const RDB_MODULE_OPCODE_EOF:u64 = 0;
+const RDB_MODULE_OPCODE_STRING:u64 = 5;
+
+const RDB_6BITLEN:u8 = 0;
+const RDB_14BITLEN:u8 = 1;
+const RDB_32BITLEN:u8 = 0x80;
+const RDB_64BITLEN:u8 = 0x81;
+const RDB_ENCVAL:u8 = 3;
+
+const RDB_ENC_INT8:u8 = 0;
+const RDB_ENC_INT16:u8 = 1;
+const RDB_ENC_INT32:u8 = 2;
+const RDB_ENC_LZF:u8 = 3;
+
+const QUICKLIST_NODE_CONTAINER_PLAIN:u64 = 1;
+const QUICKLIST_NODE_CONTAINER_PACKED:u64 = 2;
+
+// flags of the entries in a stream's listpack nodes
+const STREAM_ITEM_FLAG_DELETED:i64 = 1;
+const STREAM_ITEM_FLAG_SAMEFIELDS:i64 = 2;
+
+pub fn write_length(out:&mut Vec<u8>, len:u64) {
+    if len < (1 << 6) {
+        out.push((len as u8) | (RDB_6BITLEN << 6));
+    } else if len < (1 << 14) {
+        out.push(((len >> 8) as u8) | (RDB_14BITLEN << 6));
+        out.push(len as u8);
+    } else if len <= 0xffffffff {
+        out.push(RDB_32BITLEN);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(RDB_64BITLEN);
+        out.extend_from_slice(&len.to_be_bytes());
+    }
+}
+
+// strings that look like small integers are stored in integer form, like
+// redis does
+pub fn write_string(out:&mut Vec<u8>, s:&[u8]) {
+    if s.len() <= 11 {
+        if let Some(i) = ::std::str::from_utf8(s).ok().and_then(|x| x.parse::<i64>().ok()) {
+            if i.to_string().as_bytes() == s && write_int(out, i) {
+                return;
+            }
+        }
+    }
+    write_length(out, s.len() as u64);
+    out.extend_from_slice(s);
+}
+
+fn write_int(out:&mut Vec<u8>, i:i64) -> bool {
+    if i >= i8::min_value() as i64 && i <= i8::max_value() as i64 {
+        out.push((RDB_ENCVAL << 6) | RDB_ENC_INT8);
+        out.push(i as i8 as u8);
+    } else if i >= i16::min_value() as i64 && i <= i16::max_value() as i64 {
+        out.push((RDB_ENCVAL << 6) | RDB_ENC_INT16);
+        out.extend_from_slice(&(i as i16).to_le_bytes());
+    } else if i >= i32::min_value() as i64 && i <= i32::max_value() as i64 {
+        out.push((RDB_ENCVAL << 6) | RDB_ENC_INT32);
+        out.extend_from_slice(&(i as i32).to_le_bytes());
+    } else {
+        return false;
+    }
+    return true;
+}
+
+fn write_str(out:&mut Vec<u8>, s:&str) {
+    write_string(out, &string_to_bytes(s));
+}
+
+pub fn value_type(value:&Value) -> Option<u8> {
+    return match value {
+        &Value::IntValue(_) | &Value::StrValue(_) => Some(RDB_TYPE_STRING),
+        &Value::ListValue(_) => Some(RDB_TYPE_LIST),
+        &Value::SetValue(_) => Some(RDB_TYPE_SET),
+        &Value::SortedSetValue(_) => Some(RDB_TYPE_ZSET_2),
+        &Value::HashValue(_) => Some(RDB_TYPE_HASH),
+        &Value::StreamValue(_) => Some(RDB_TYPE_STREAM_LISTPACKS_3),
+        &Value::ModuleValue(_) => Some(RDB_TYPE_MODULE_2),
+        _ => None,
+    };
+}
+
+// the type a value is written as in a file of the given version, where
+// streams use the newest layout that version knows
+pub fn value_type_for(value:&Value, version:u16) -> Option<u8> {
+    return match value {
+        &Value::StreamValue(_) if version < 10 => Some(RDB_TYPE_STREAM_LISTPACKS),
+        &Value::StreamValue(_) if version < 11 => Some(RDB_TYPE_STREAM_LISTPACKS_2),
+        _ => value_type(value),
+    };
+}
+
+// writes a value's object body; the type byte is written separately
+pub fn write_value(out:&mut Vec<u8>, value:&Value) -> Result<(), String> {
+    return match value_type(value) {
+        Some(t) => write_value_as(out, value, t),
+        None => Err("ERR value can't be serialized".to_string()),
+    };
+}
+
+// writes a value's object body in the layout of type `t`
+pub fn write_value_as(out:&mut Vec<u8>, value:&Value, t:u8) -> Result<(), String> {
+    match value {
+        &Value::IntValue(i) => {
+            if !write_int(out, i) {
+                write_str(out, &i.to_string());
+            }
+        }
+        &Value::StrValue(ref s) => write_str(out, s),
+        &Value::ListValue(ref l) => {
+            write_length(out, l.len() as u64);
+            for item in l {
+                write_str(out, item);
+            }
+        }
+        &Value::SetValue(ref s) => {
+            write_length(out, s.len() as u64);
+            for member in s {
+                write_str(out, member);
+            }
+        }
+        &Value::SortedSetValue(ref z) => {
+            write_length(out, z.len() as u64);
+            for (member, score) in z {
+                write_str(out, member);
+                out.extend_from_slice(&score.to_bits().to_le_bytes());
+            }
+        }
+        &Value::HashValue(ref h) => {
+            write_length(out, h.len() as u64);
+            for (field, value) in h {
+                write_str(out, field);
+                write_str(out, value);
+            }
+        }
+        &Value::StreamValue(ref s) => write_stream(out, s, t),
+        &Value::ModuleValue(ref v) => {
+            write_length(out, v.type_id());
+            for item in v.save() {
+                write_length(out, RDB_MODULE_OPCODE_STRING);
+                write_string(out, &item);
+            }
+            write_length(out, RDB_MODULE_OPCODE_EOF);
+        }
+        _ => return Err("ERR value can't be serialized".to_string()),
+    }
+    return Ok(());
+}
+
+// stream ids are written as 128 bit big endian numbers, like the keys of
+// redis' radix tree of stream nodes
+fn write_stream_id(out:&mut Vec<u8>, id:&StreamId) {
+    out.extend_from_slice(&id.ms.to_be_bytes());
+    out.extend_from_slice(&id.seq.to_be_bytes());
+}
+
+fn write_ms_time(out:&mut Vec<u8>, ms:u64) {
+    out.extend_from_slice(&ms.to_le_bytes());
+}
+
+// a stream in the layout of type `t`: its entries in listpack nodes of up
+// to STREAM_NODE_MAX_ENTRIES, each starting with a master entry whose
+// fields the node's entries can share, then its metadata and consumer
+// groups
+fn write_stream(out:&mut Vec<u8>, s:&Stream, t:u8) {
+    let entries = s.entries.iter().collect::<Vec<(&StreamId, &Vec<(String, String)>)>>();
+    let int = |i:i64| i.to_string().into_bytes();
+    write_length(out, ((entries.len() + STREAM_NODE_MAX_ENTRIES - 1) / STREAM_NODE_MAX_ENTRIES) as u64);
+    for node in entries.chunks(STREAM_NODE_MAX_ENTRIES) {
+        let (master, master_fields) = node[0];
+        let mut items = vec![int(node.len() as i64), int(0), int(master_fields.len() as i64)];
+        items.extend(master_fields.iter().map(|&(ref field, _)| string_to_bytes(field)));
+        items.push(int(0));
+        for &(id, fields) in node {
+            let same = fields.len() == master_fields.len() && fields.iter().zip(master_fields.iter()).all(|(a, b)| a.0 == b.0);
+            let ms_diff = int(id.ms.wrapping_sub(master.ms) as i64);
+            let seq_diff = int(id.seq.wrapping_sub(master.seq) as i64);
+            if same {
+                items.extend(vec![int(STREAM_ITEM_FLAG_SAMEFIELDS), ms_diff, seq_diff]);
+                items.extend(fields.iter().map(|&(_, ref value)| string_to_bytes(value)));
+                items.push(int(fields.len() as i64 + 3));
+            } else {
+                items.extend(vec![int(0), ms_diff, seq_diff, int(fields.len() as i64)]);
+                for &(ref field, ref value) in fields {
+                    items.push(string_to_bytes(field));
+                    items.push(string_to_bytes(value));
+                }
+                items.push(int(fields.len() as i64 * 2 + 4));
+            }
+        }
+        let mut key = Vec::new();
+        write_stream_id(&mut key, master);
+        write_string(out, &key);
+        write_string(out, &write_listpack(&items));
+    }
+    write_length(out, s.len() as u64);
+    write_length(out, s.last_id.ms);
+    write_length(out, s.last_id.seq);
+    if t >= RDB_TYPE_STREAM_LISTPACKS_2 {
+        let first = s.first_id();
+        write_length(out, first.ms);
+        write_length(out, first.seq);
+        write_length(out, s.max_deleted_id.ms);
+        write_length(out, s.max_deleted_id.seq);
+        write_length(out, s.entries_added);
+    }
+    write_length(out, s.groups.len() as u64);
+    for (name, g) in s.groups.iter() {
+        write_str(out, name);
+        write_length(out, g.last_id.ms);
+        write_length(out, g.last_id.seq);
+        if t >= RDB_TYPE_STREAM_LISTPACKS_2 {
+            // an unknown count is -1
+            write_length(out, g.entries_read.unwrap_or(u64::max_value()));
+        }
+        write_length(out, g.pending.len() as u64);
+        for (id, entry) in g.pending.iter() {
+            write_stream_id(out, id);
+            write_ms_time(out, entry.delivery_time);
+            write_length(out, entry.delivery_count);
+        }
+        write_length(out, g.consumers.len() as u64);
+        for (name, consumer) in g.consumers.iter() {
+            write_str(out, name);
+            write_ms_time(out, consumer.seen_time);
+            if t >= RDB_TYPE_STREAM_LISTPACKS_3 {
+                write_ms_time(out, consumer.active_time.unwrap_or(u64::max_value()));
+            }
+            write_length(out, consumer.pending.len() as u64);
+            for id in consumer.pending.iter() {
+                write_stream_id(out, id);
+            }
+        }
+    }
+}
+
+pub fn dump(value:&Value) -> Result<Vec<u8>, String> {
+    let mut out = Vec::new();
+    let t = match value_type_for(value, RDB_VERSION) {
+        Some(t) => t,
+        None => return Err("ERR value can't be serialized".to_string()),
+    };
+    out.push(t);
+    write_value_as(&mut out, value, t)?;
+    write_footer(&mut out);
+    return Ok(out);
+}
+
+pub fn restore(payload:&[u8]) -> Result<Value, String> {
+    let body = match payload_body(payload) {
+        Some(body) => body,
+        None => return Err("ERR DUMP payload version or checksum are wrong".to_string()),
+    };
+    let mut reader = RdbReader::new(body);
+    let value = reader.read_typed_value().map_err(|_| "ERR Bad data format".to_string())?;
+    if !reader.at_end() {
+        return Err("ERR Bad data format".to_string());
+    }
+    return Ok(value);
+}
+
+// FUNCTION DUMP's payload: the code of each library, with the DUMP footer
+pub fn dump_functions(codes:&[String]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for code in codes {
+        out.push(RDB_OPCODE_FUNCTION2);
+        write_string(&mut out, &string_to_bytes(code));
+    }
+    write_footer(&mut out);
+    return out;
+}
+
+pub fn restore_functions(payload:&[u8]) -> Result<Vec<String>, String> {
+    let body = match payload_body(payload) {
+        Some(body) => body,
+        None => return Err("ERR payload version or checksum are wrong".to_string()),
+    };
+    let mut reader = RdbReader::new(body);
+    let mut codes = Vec::new();
+    while !reader.at_end() {
+        if reader.read_u8()? != RDB_OPCODE_FUNCTION2 {
+            return Err("ERR given type is not a function".to_string());
+        }
+        let code = reader.read_string().map_err(|_| "ERR Bad data format".to_string())?;
+        codes.push(bytes_to_string(&code));
+    }
+    return Ok(codes);
+}
+
+// TRIGGER DUMP's payload: each trigger as a list of its fields, with the
+// DUMP footer
+pub fn dump_triggers(triggers:&[Vec<String>]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for fields in triggers {
+        out.push(RDB_TYPE_LIST);
+        write_value(&mut out, &Value::ListValue(fields.iter().cloned().collect())).unwrap();
+    }
+    write_footer(&mut out);
+    return out;
+}
+
+pub fn restore_triggers(payload:&[u8]) -> Result<Vec<Vec<String>>, String> {
+    let body = match payload_body(payload) {
+        Some(body) => body,
+        None => return Err("ERR payload version or checksum are wrong".to_string()),
+    };
+    let mut reader = RdbReader::new(body);
+    let mut triggers = Vec::new();
+    while !reader.at_end() {
+        match reader.read_typed_value() {
+            Ok(Value::ListValue(fields)) => triggers.push(fields.into_iter().collect()),
+            _ => return Err("ERR Bad data format".to_string()),
+        }
+    }
+    return Ok(triggers);
+}
+
+// the RDB version and a CRC64 of everything before it
+fn write_footer(out:&mut Vec<u8>) {
+    out.extend_from_slice(&RDB_VERSION.to_le_bytes());
+    let crc = crc64(out);
+    out.extend_from_slice(&crc.to_le_bytes());
+}
+
+// the payload without its footer, if the version and checksum are good
+fn payload_body(payload:&[u8]) -> Option<&[u8]> {
+    if payload.len() < 10 {
+        return None;
+    }
+    let (body, footer) = payload.split_at(payload.len() - 10);
+    let version = u16::from_le_bytes([footer[0], footer[1]]);
+    if version > RDB_MAX_VERSION {
+        return None;
+    }
+    let mut crc = [0u8; 8];
+    crc.copy_from_slice(&footer[2..]);
+    if crc64(&payload[..payload.len() - 8]) != u64::from_le_bytes(crc) {
+        return None;
+    }
+    return Some(body);
+}
+
+pub struct RdbReader<'a> {
+    data:&'a [u8],
+    pos:usize,
+}
+
+impl<'a> RdbReader<'a> {
+    pub fn new(data:&'a [u8]) -> RdbReader<'a> {
+        return RdbReader {
+            data: data,
+            pos: 0,
+        };
+    }
+
+    pub fn at_end(&self) -> bool {
+        return self.pos == self.data.len();
+    }
+
+    pub fn position(&self) -> usize {
+        return self.pos;
+    }
+
+    pub fn read_bytes(&mut self, n:usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < n {
+            return Err("unexpected end of data".to_string());
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        return Ok(bytes);
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, String> {
+        return Ok(self.read_bytes(1)?[0]);
+    }
+
+    // returns the length, and whether it is actually a special string encoding
+    fn read_length_or_encoding(&mut self) -> Result<(u64, bool), String> {
+        let first = self.read_u8()?;
+        match first >> 6 {
+            RDB_6BITLEN => Ok(((first & 0x3f) as u64, false)),
+            RDB_14BITLEN => Ok(((((first & 0x3f) as u64) << 8) | self.read_u8()? as u64, false)),
+            RDB_ENCVAL => Ok(((first & 0x3f) as u64, true)),
+            _ => {
+                if first == RDB_32BITLEN {
+                    let b = self.read_bytes(4)?;
+                    Ok((u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as u64, false))
+                } else if first == RDB_64BITLEN {
+                    let mut b = [0u8; 8];
+                    b.copy_from_slice(self.read_bytes(8)?);
+                    Ok((u64::from_be_bytes(b), false))
+                } else {
+                    Err(format!("unknown length encoding {}", first))
+                }
+            }
+        }
+    }
+
+    pub fn read_length(&mut self) -> Result<u64, String> {
+        return match self.read_length_or_encoding()? {
+            (len, false) => Ok(len),
+            _ => Err("unexpected string encoding".to_string()),
+        };
+    }
+
+    pub fn read_string(&mut self) -> Result<Vec<u8>, String> {
+        let (len, encoded) = self.read_length_or_encoding()?;
+        if !encoded {
+            return Ok(self.read_bytes(len as usize)?.to_vec());
+        }
+        let i = match len as u8 {
+            RDB_ENC_INT8 => self.read_u8()? as i8 as i64,
+            RDB_ENC_INT16 => {
+                let b = self.read_bytes(2)?;
+                i16::from_le_bytes([b[0], b[1]]) as i64
+            }
+            RDB_ENC_INT32 => {
+                let b = self.read_bytes(4)?;
+                i32::from_le_bytes([b[0], b[1], b[2], b[3]]) as i64
+            }
+            RDB_ENC_LZF => {
+                let compressed_len = self.read_length()? as usize;
+                let len = self.read_length()? as usize;
+                let compressed = self.read_bytes(compressed_len)?;
+                return lzf_decompress(compressed, len);
+            }
+            e => return Err(format!("unknown string encoding {}", e)),
+        };
+        return Ok(i.to_string().into_bytes());
+    }
+
+    fn read_str(&mut self) -> Result<String, String> {
+        return Ok(bytes_to_string(&self.read_string()?));
+    }
+
+    // scores in the original zset encoding are stored as text
+    fn read_double(&mut self) -> Result<f64, String> {
+        return match self.read_u8()? {
+            253 => Ok(::std::f64::NAN),
+            254 => Ok(::std::f64::INFINITY),
+            255 => Ok(::std::f64::NEG_INFINITY),
+            len => parse_float(self.read_bytes(len as usize)?),
+        };
+    }
+
+    fn read_binary_double(&mut self) -> Result<f64, String> {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(self.read_bytes(8)?);
+        return Ok(f64::from_bits(u64::from_le_bytes(b)));
+    }
+
+    pub fn read_typed_value(&mut self) -> Result<Value, String> {
+        let t = self.read_u8()?;
+        return self.read_value(t);
+    }
+
+    pub fn read_value(&mut self, t:u8) -> Result<Value, String> {
+        match t {
+            RDB_TYPE_STRING => {
+                // like SET, integers are kept as IntValue
+                let s = bytes_to_string(&self.read_string()?);
+                return match s.parse::<i64>() {
+                    Ok(i) if i.to_string() == s => Ok(Value::IntValue(i)),
+                    _ => Ok(Value::StrValue(s)),
+                };
+            }
+            RDB_TYPE_LIST => {
+                let len = self.read_length()?;
+                let mut l = VecDeque::new();
+                for _ in 0..len {
+                    l.push_back(self.read_str()?);
+                }
+                return Ok(Value::ListValue(l));
+            }
+            RDB_TYPE_SET => {
+                let len = self.read_length()?;
+                let mut s = HashSet::new();
+                for _ in 0..len {
+                    s.insert(self.read_str()?);
+                }
+                return Ok(Value::SetValue(s));
+            }
+            RDB_TYPE_ZSET | RDB_TYPE_ZSET_2 => {
+                let len = self.read_length()?;
+                let mut z = SortedSet::new();
+                for _ in 0..len {
+                    let member = self.read_str()?;
+                    let score = if t == RDB_TYPE_ZSET {self.read_double()?} else {self.read_binary_double()?};
+                    z.insert(member, score);
+                }
+                return Ok(Value::SortedSetValue(z));
+            }
+            RDB_TYPE_HASH => {
+                let len = self.read_length()?;
+                let mut h = HashMap::new();
+                for _ in 0..len {
+                    let field = self.read_str()?;
+                    let value = self.read_str()?;
+                    h.insert(field, value);
+                }
+                return Ok(Value::HashValue(h));
+            }
+            RDB_TYPE_LIST_ZIPLIST => {
+                let entries = ziplist_entries(&self.read_string()?)?;
+                return Ok(Value::ListValue(entries.iter().map(|e| bytes_to_string(e)).collect()));
+            }
+            RDB_TYPE_SET_INTSET => {
+                let members = intset_entries(&self.read_string()?)?;
+                return Ok(Value::SetValue(members.iter().map(|i| i.to_string()).collect()));
+            }
+            RDB_TYPE_SET_LISTPACK => {
+                let entries = listpack_entries(&self.read_string()?)?;
+                return Ok(Value::SetValue(entries.iter().map(|e| bytes_to_string(e)).collect()));
+            }
+            RDB_TYPE_ZSET_ZIPLIST | RDB_TYPE_ZSET_LISTPACK => {
+                let blob = self.read_string()?;
+                let entries = if t == RDB_TYPE_ZSET_ZIPLIST {ziplist_entries(&blob)?} else {listpack_entries(&blob)?};
+                if entries.len() % 2 != 0 {
+                    return Err("odd number of zset entries".to_string());
+                }
+                let mut z = SortedSet::new();
+                for pair in entries.chunks(2) {
+                    z.insert(bytes_to_string(&pair[0]), parse_float(&pair[1])?);
+                }
+                return Ok(Value::SortedSetValue(z));
+            }
+            RDB_TYPE_HASH_ZIPLIST | RDB_TYPE_HASH_LISTPACK => {
+                let blob = self.read_string()?;
+                let entries = if t == RDB_TYPE_HASH_ZIPLIST {ziplist_entries(&blob)?} else {listpack_entries(&blob)?};
+                if entries.len() % 2 != 0 {
+                    return Err("odd number of hash entries".to_string());
+                }
+                let mut h = HashMap::new();
+                for pair in entries.chunks(2) {
+                    h.insert(bytes_to_string(&pair[0]), bytes_to_string(&pair[1]));
+                }
+                return Ok(Value::HashValue(h));
+            }
+            // rustis has no field expirations, so fields that already expired
+            // are dropped and the others are kept without theirs. Expiration
+            // times are absolute, or relative to the hash's earliest one
+            RDB_TYPE_HASH_METADATA_PRE_GA | RDB_TYPE_HASH_METADATA => {
+                let min_expire = if t == RDB_TYPE_HASH_METADATA {self.read_ms_time()?} else {1};
+                let len = self.read_length()?;
+                let now = now_ms();
+                let mut h = HashMap::new();
+                for _ in 0..len {
+                    let ttl = self.read_length()?;
+                    let field = self.read_str()?;
+                    let value = self.read_str()?;
+                    if ttl == 0 || ttl.saturating_add(min_expire).saturating_sub(1) > now {
+                        h.insert(field, value);
+                    }
+                }
+                return Ok(Value::HashValue(h));
+            }
+            RDB_TYPE_HASH_LISTPACK_EX_PRE_GA | RDB_TYPE_HASH_LISTPACK_EX => {
+                if t == RDB_TYPE_HASH_LISTPACK_EX {
+                    self.read_ms_time()?;
+                }
+                let entries = listpack_entries(&self.read_string()?)?;
+                if entries.len() % 3 != 0 {
+                    return Err("hash entries aren't field, value and TTL triplets".to_string());
+                }
+                let now = now_ms();
+                let mut h = HashMap::new();
+                for triplet in entries.chunks(3) {
+                    let ttl = ::std::str::from_utf8(&triplet[2]).ok().and_then(|x| x.parse::<u64>().ok()).ok_or("invalid hash field TTL".to_string())?;
+                    if ttl == 0 || ttl > now {
+                        h.insert(bytes_to_string(&triplet[0]), bytes_to_string(&triplet[1]));
+                    }
+                }
+                return Ok(Value::HashValue(h));
+            }
+            RDB_TYPE_LIST_QUICKLIST | RDB_TYPE_LIST_QUICKLIST_2 => {
+                let nodes = self.read_length()?;
+                let mut l = VecDeque::new();
+                for _ in 0..nodes {
+                    let container = if t == RDB_TYPE_LIST_QUICKLIST_2 {self.read_length()?} else {QUICKLIST_NODE_CONTAINER_PACKED};
+                    let blob = self.read_string()?;
+                    if container == QUICKLIST_NODE_CONTAINER_PLAIN {
+                        l.push_back(bytes_to_string(&blob));
+                        continue;
+                    }
+                    let entries = if t == RDB_TYPE_LIST_QUICKLIST {ziplist_entries(&blob)?} else {listpack_entries(&blob)?};
+                    for e in entries {
+                        l.push_back(bytes_to_string(&e));
+                    }
+                }
+                return Ok(Value::ListValue(l));
+            }
+            RDB_TYPE_MODULE_2 => {
+                let id = self.read_length()?;
+                let mut items = Vec::new();
+                loop {
+                    match self.read_length()? {
+                        RDB_MODULE_OPCODE_EOF => break,
+                        RDB_MODULE_OPCODE_STRING => items.push(self.read_string()?),
+                        opcode => return Err(format!("unsupported module opcode {}", opcode)),
+                    }
+                }
+                return ModuleValue::load(id, items).map(Value::ModuleValue);
+            }
+            RDB_TYPE_STREAM_LISTPACKS | RDB_TYPE_STREAM_LISTPACKS_2 | RDB_TYPE_STREAM_LISTPACKS_3 => {
+                return self.read_stream(t).map(Value::StreamValue);
+            }
+            _ => return Err(format!("unsupported object type {}", t)),
+        }
+    }
+
+    fn read_stream_id(&mut self) -> Result<StreamId, String> {
+        let b = self.read_bytes(16)?;
+        let mut ms = [0u8; 8];
+        let mut seq = [0u8; 8];
+        ms.copy_from_slice(&b[..8]);
+        seq.copy_from_slice(&b[8..]);
+        return Ok(StreamId::new(u64::from_be_bytes(ms), u64::from_be_bytes(seq)));
+    }
+
+    fn read_ms_time(&mut self) -> Result<u64, String> {
+        return Ok(read_le(self.read_bytes(8)?));
+    }
+
+    fn read_stream(&mut self, t:u8) -> Result<Stream, String> {
+        let mut s = Stream::new();
+        let nodes = self.read_length()?;
+        for _ in 0..nodes {
+            let key = self.read_string()?;
+            if key.len() != 16 {
+                return Err("invalid stream node key".to_string());
+            }
+            let master = RdbReader::new(&key).read_stream_id()?;
+            let entries = listpack_entries(&self.read_string()?)?;
+            read_stream_node(&mut s, master, &entries)?;
+        }
+        let _len = self.read_length()?;
+        s.last_id = StreamId::new(self.read_length()?, self.read_length()?);
+        if t >= RDB_TYPE_STREAM_LISTPACKS_2 {
+            let _first_id = StreamId::new(self.read_length()?, self.read_length()?);
+            s.max_deleted_id = StreamId::new(self.read_length()?, self.read_length()?);
+            s.entries_added = self.read_length()?;
+        } else {
+            s.entries_added = s.len() as u64;
+        }
+        let groups = self.read_length()?;
+        for _ in 0..groups {
+            let name = self.read_str()?;
+            let last_id = StreamId::new(self.read_length()?, self.read_length()?);
+            let entries_read = if t >= RDB_TYPE_STREAM_LISTPACKS_2 {
+                match self.read_length()? {
+                    n if n == u64::max_value() => None,
+                    n => Some(n),
+                }
+            } else {
+                None
+            };
+            let mut g = ConsumerGroup::new(last_id, entries_read);
+            let pending = self.read_length()?;
+            for _ in 0..pending {
+                let id = self.read_stream_id()?;
+                let delivery_time = self.read_ms_time()?;
+                let delivery_count = self.read_length()?;
+                g.pending.insert(id, PendingEntry {consumer: String::new(), delivery_time: delivery_time, delivery_count: delivery_count});
+            }
+            let consumers = self.read_length()?;
+            for _ in 0..consumers {
+                let consumer_name = self.read_str()?;
+                let seen_time = self.read_ms_time()?;
+                // older streams don't keep when a consumer was last active
+                let active_time = if t >= RDB_TYPE_STREAM_LISTPACKS_3 {
+                    match self.read_ms_time()? {
+                        n if n == u64::max_value() => None,
+                        n => Some(n),
+                    }
+                } else {
+                    Some(seen_time)
+                };
+                let mut consumer = Consumer {pending: Default::default(), seen_time: seen_time, active_time: active_time};
+                let owned = self.read_length()?;
+                for _ in 0..owned {
+                    let id = self.read_stream_id()?;
+                    match g.pending.get_mut(&id) {
+                        Some(entry) => entry.consumer = consumer_name.clone(),
+                        None => return Err("consumer pending entry not in the group's PEL".to_string()),
+                    }
+                    consumer.pending.insert(id);
+                }
+                g.consumers.insert(consumer_name, consumer);
+            }
+            s.groups.insert(name, g);
+        }
+        return Ok(s);
+    }
+}
+
+fn parse_lp_int(entry:&[u8]) -> Result<i64, String> {
+    return ::std::str::from_utf8(entry).ok().and_then(|x| x.parse::<i64>().ok()).ok_or("invalid stream node".to_string());
+}
+
+// adds the live entries of one listpack node, whose ids are stored as
+// differences from the node's master id
+fn read_stream_node(s:&mut Stream, master:StreamId, entries:&[Vec<u8>]) -> Result<(), String> {
+    let entry = |i:usize| entries.get(i).ok_or("truncated stream node".to_string());
+    let int = |i:usize| entry(i).and_then(|e| parse_lp_int(e));
+    let master_fields_len = int(2)? as usize;
+    let mut master_fields = Vec::new();
+    for i in 0..master_fields_len {
+        master_fields.push(bytes_to_string(entry(3 + i)?));
+    }
+    let mut pos = 3 + master_fields_len + 1;
+    while pos < entries.len() {
+        let flags = int(pos)?;
+        let id = StreamId::new(master.ms.wrapping_add(int(pos + 1)? as u64), master.seq.wrapping_add(int(pos + 2)? as u64));
+        pos += 3;
+        let mut fields = Vec::new();
+        if flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            for field in master_fields.iter() {
+                fields.push((field.clone(), bytes_to_string(entry(pos)?)));
+                pos += 1;
+            }
+        } else {
+            let n = int(pos)? as usize;
+            pos += 1;
+            for _ in 0..n {
+                fields.push((bytes_to_string(entry(pos)?), bytes_to_string(entry(pos + 1)?)));
+                pos += 2;
+            }
+        }
+        // skip the entry's lp-count
+        pos += 1;
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            s.entries.insert(id, fields);
+        }
+    }
+    return Ok(());
+}
+
+fn parse_float(s:&[u8]) -> Result<f64, String> {
+    return ::std::str::from_utf8(s).ok()
+        .and_then(|x| match x {
+            "inf" | "+inf" => Some(::std::f64::INFINITY),
+            "-inf" => Some(::std::f64::NEG_INFINITY),
+            _ => x.parse::<f64>().ok(),
+        })
+        .ok_or("invalid float".to_string());
+}
+
+fn read_le(b:&[u8]) -> u64 {
+    let mut v = 0u64;
+    for (i, &byte) in b.iter().enumerate() {
+        v |= (byte as u64) << (8 * i);
+    }
+    return v;
+}
+
+// sign-extends the low `bits` bits of `v`
+fn sign_extend(v:u64, bits:u32) -> i64 {
+    let shift = 64 - bits;
+    return ((v << shift) as i64) >> shift;
+}
+
+fn slice(blob:&[u8], start:usize, len:usize) -> Result<&[u8], String> {
+    if start + len > blob.len() {
+        return Err("truncated entry".to_string());
+    }
+    return Ok(&blob[start..start + len]);
+}
+
+pub fn ziplist_entries(blob:&[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let mut entries = Vec::new();
+    let mut pos = 10;
+    loop {
+        let first = *slice(blob, pos, 1)?.first().unwrap();
+        if first == 0xff {
+            break;
+        }
+        // skip the previous entry length
+        pos += if first == 0xfe {5} else {1};
+        let enc = slice(blob, pos, 1)?[0];
+        pos += 1;
+        match enc >> 6 {
+            0 => {
+                let len = (enc & 0x3f) as usize;
+                entries.push(slice(blob, pos, len)?.to_vec());
+                pos += len;
+            }
+            1 => {
+                let len = (((enc & 0x3f) as usize) << 8) | slice(blob, pos, 1)?[0] as usize;
+                pos += 1;
+                entries.push(slice(blob, pos, len)?.to_vec());
+                pos += len;
+            }
+            2 => {
+                let b = slice(blob, pos, 4)?;
+                let len = u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as usize;
+                pos += 4;
+                entries.push(slice(blob, pos, len)?.to_vec());
+                pos += len;
+            }
+            _ => {
+                let (i, size) = match enc {
+                    0xc0 => (sign_extend(read_le(slice(blob, pos, 2)?), 16), 2),
+                    0xd0 => (sign_extend(read_le(slice(blob, pos, 4)?), 32), 4),
+                    0xe0 => (sign_extend(read_le(slice(blob, pos, 8)?), 64), 8),
+                    0xf0 => (sign_extend(read_le(slice(blob, pos, 3)?), 24), 3),
+                    0xfe => (sign_extend(read_le(slice(blob, pos, 1)?), 8), 1),
+                    0xf1..=0xfd => ((enc & 0x0f) as i64 - 1, 0),
+                    _ => return Err(format!("unknown ziplist encoding {}", enc)),
+                };
+                pos += size;
+                entries.push(i.to_string().into_bytes());
+            }
+        }
+    }
+    return Ok(entries);
+}
+
+// encodes a listpack, storing strings that are integers as integers
+pub fn write_listpack(items:&[Vec<u8>]) -> Vec<u8> {
+    let mut out = vec![0; 6];
+    for item in items {
+        let start = out.len();
+        match ::std::str::from_utf8(item).ok().and_then(|s| s.parse::<i64>().ok().filter(|i| i.to_string() == s)) {
+            Some(i) if i >= 0 && i < 128 => out.push(i as u8),
+            Some(i) if i >= -4096 && i < 4096 => {
+                let v = (i as u64) & 0x1fff;
+                out.push(0xc0 | (v >> 8) as u8);
+                out.push(v as u8);
+            }
+            Some(i) => {
+                let (enc, size) = if i >= -32768 && i < 32768 {
+                    (0xf1, 2)
+                } else if i >= -8388608 && i < 8388608 {
+                    (0xf2, 3)
+                } else if i >= i32::min_value() as i64 && i <= i32::max_value() as i64 {
+                    (0xf3, 4)
+                } else {
+                    (0xf4, 8)
+                };
+                out.push(enc);
+                out.extend_from_slice(&i.to_le_bytes()[..size]);
+            }
+            None if item.len() < 64 => {
+                out.push(0x80 | item.len() as u8);
+                out.extend_from_slice(item);
+            }
+            None if item.len() < 4096 => {
+                out.push(0xe0 | (item.len() >> 8) as u8);
+                out.push(item.len() as u8);
+                out.extend_from_slice(item);
+            }
+            None => {
+                out.push(0xf0);
+                out.extend_from_slice(&(item.len() as u32).to_le_bytes());
+                out.extend_from_slice(item);
+            }
+        }
+        // the entry's length again, readable backwards 7 bits at a time
+        let len = (out.len() - start) as u64;
+        let groups = if len < 128 {1} else if len < 16384 {2} else if len < 2097152 {3} else if len < 268435456 {4} else {5};
+        for g in (0..groups).rev() {
+            let bits = ((len >> (7 * g)) & 127) as u8;
+            out.push(if g == groups - 1 {bits} else {bits | 128});
+        }
+    }
+    out.push(0xff);
+    let total = out.len() as u32;
+    out[0..4].copy_from_slice(&total.to_le_bytes());
+    let count = if items.len() < 65535 {items.len() as u16} else {65535};
+    out[4..6].copy_from_slice(&count.to_le_bytes());
+    return out;
+}
+
+pub fn listpack_entries(blob:&[u8]) -> Result<Vec<Vec<u8>>, String> {
+    let mut entries = Vec::new();
+    let mut pos = 6;
+    loop {
+        let enc = slice(blob, pos, 1)?[0];
+        if enc == 0xff {
+            break;
+        }
+        let start = pos;
+        pos += 1;
+        if enc & 0x80 == 0 {
+            entries.push((enc as i64).to_string().into_bytes());
+        } else if enc & 0xc0 == 0x80 {
+            let len = (enc & 0x3f) as usize;
+            entries.push(slice(blob, pos, len)?.to_vec());
+            pos += len;
+        } else if enc & 0xe0 == 0xc0 {
+            let v = (((enc & 0x1f) as u64) << 8) | slice(blob, pos, 1)?[0] as u64;
+            pos += 1;
+            entries.push(sign_extend(v, 13).to_string().into_bytes());
+        } else if enc & 0xf0 == 0xe0 {
+            let len = (((enc & 0x0f) as usize) << 8) | slice(blob, pos, 1)?[0] as usize;
+            pos += 1;
+            entries.push(slice(blob, pos, len)?.to_vec());
+            pos += len;
+        } else if enc == 0xf0 {
+            let len = read_le(slice(blob, pos, 4)?) as usize;
+            pos += 4;
+            entries.push(slice(blob, pos, len)?.to_vec());
+            pos += len;
+        } else {
+            let size = match enc {
+                0xf1 => 2,
+                0xf2 => 3,
+                0xf3 => 4,
+                0xf4 => 8,
+                _ => return Err(format!("unknown listpack encoding {}", enc)),
+            };
+            let v = sign_extend(read_le(slice(blob, pos, size)?), (size * 8) as u32);
+            pos += size;
+            entries.push(v.to_string().into_bytes());
+        }
+        // skip the backwards entry length
+        let len = pos - start;
+        pos += if len < 128 {1} else if len < 16384 {2} else if len < 2097152 {3} else if len < 268435456 {4} else {5};
+    }
+    return Ok(entries);
+}
+
+pub fn intset_entries(blob:&[u8]) -> Result<Vec<i64>, String> {
+    let size = read_le(slice(blob, 0, 4)?) as usize;
+    let len = read_le(slice(blob, 4, 4)?) as usize;
+    if size != 2 && size != 4 && size != 8 {
+        return Err("invalid intset encoding".to_string());
+    }
+    // the length is checked against the blob before anything is allocated
+    if len.checked_mul(size).and_then(|n| n.checked_add(8)) != Some(blob.len()) {
+        return Err("invalid intset length".to_string());
+    }
+    let mut members = Vec::with_capacity(len);
+    for i in 0..len {
+        members.push(sign_extend(read_le(slice(blob, 8 + i * size, size)?), (size * 8) as u32));
+    }
+    return Ok(members);
+}
+
+pub fn lzf_decompress(input:&[u8], len:usize) -> Result<Vec<u8>, String> {
+    // the claimed length can't be trusted, so it only bounds the output
+    let mut out = Vec::with_capacity(len.min(input.len() * 4));
+    let mut i = 0;
+    while i < input.len() {
+        if out.len() > len {
+            return Err("lzf length mismatch".to_string());
+        }
+        let ctrl = input[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            // literal run
+            out.extend_from_slice(slice(input, i, ctrl + 1)?);
+            i += ctrl + 1;
+        } else {
+            // back reference
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *slice(input, i, 1)?.first().unwrap() as usize;
+                i += 1;
+            }
+            let offset = ((ctrl & 0x1f) << 8) + *slice(input, i, 1)?.first().unwrap() as usize + 1;
+            i += 1;
+            if offset > out.len() {
+                return Err("invalid lzf back reference".to_string());
+            }
+            let start = out.len() - offset;
+            for j in 0..run + 2 {
+                let b = out[start + j];
+                out.push(b);
+            }
+        }
+    }
+    if out.len() != len {
+        return Err("lzf length mismatch".to_string());
+    }
+    return Ok(out);
+}
+
+#[test]
+fn test_dump_redis_payload() {
+    // the example from redis' DUMP documentation
+    let payload = b"\x00\xc0\n\t\x00\xbem\x06\x89Z(\x00\n";
+    assert_eq!(restore(payload), Ok(Value::IntValue(10)));
+    assert_eq!(dump(&Value::IntValue(10)), Ok(payload.to_vec()));
+}
+
+#[test]
+fn test_dump_round_trip() {
+    let mut h = HashMap::new();
+    h.insert("field".to_string(), "value".to_string());
+    let mut z = SortedSet::new();
+    z.insert("one".to_string(), 1.5);
+    z.insert("two".to_string(), -2.0);
+    let values = vec![
+        Value::IntValue(12),
+        Value::IntValue(1 << 40),
+        Value::StrValue("abc\u{0}\u{ff} def".to_string()),
+        Value::ListValue(vec!["a".to_string(), "b".to_string()].into_iter().collect()),
+        Value::SetValue(vec!["x".to_string()].into_iter().collect()),
+        Value::SortedSetValue(z),
+        Value::HashValue(h),
+    ];
+    for value in values {
+        let payload = dump(&value).unwrap();
+        assert_eq!(restore(&payload), Ok(value));
+    }
+    let mut payload = dump(&Value::IntValue(1)).unwrap();
+    let last = payload.len() - 1;
+    payload[last] ^= 1;
+    assert_eq!(restore(&payload), Err("ERR DUMP payload version or checksum are wrong".to_string()));
+
+    // lengths claimed by a well-formed payload are checked before they're
+    // allocated
+    let mut lzf = vec![RDB_TYPE_STRING, (RDB_ENCVAL << 6) | RDB_ENC_LZF];
+    write_length(&mut lzf, 6);
+    write_length(&mut lzf, u64::max_value() >> 1);
+    lzf.extend_from_slice(b"\x02abc\x20\x02");
+    write_footer(&mut lzf);
+    assert_eq!(restore(&lzf), Err("ERR Bad data format".to_string()));
+    let mut intset = vec![RDB_TYPE_SET_INTSET];
+    write_string(&mut intset, b"\x02\x00\x00\x00\xff\xff\xff\xff\x01\x00");
+    write_footer(&mut intset);
+    assert_eq!(restore(&intset), Err("ERR Bad data format".to_string()));
+}
+
+#[test]
+fn test_dump_functions() {
+    let codes = vec!["#!lua name=a\nredis.register_function('f', function() end)".to_string(), "#!lua name=b".to_string()];
+    let payload = dump_functions(&codes);
+    assert_eq!(restore_functions(&payload), Ok(codes));
+    assert_eq!(restore_functions(&dump_functions(&[])), Ok(vec![]));
+    assert!(restore_functions(&dump(&Value::IntValue(1)).unwrap()).is_err());
+}
+
+#[test]
+fn test_compact_encodings() {
+    // listpack of "a", 1, -3 and a 13 bit int
+    let lp = b"\x00\x00\x00\x00\x04\x00\x81a\x02\x01\x01\xc0\xff\x02\xd0\x00\x02\xff";
+    let entries = listpack_entries(lp).unwrap();
+    assert_eq!(entries, vec![b"a".to_vec(), b"1".to_vec(), b"255".to_vec(), b"-4096".to_vec()]);
+    // intset of 16 bit ints
+    let is = b"\x02\x00\x00\x00\x02\x00\x00\x00\x01\x00\xff\xff";
+    assert_eq!(intset_entries(is), Ok(vec![1, -1]));
+    assert!(intset_entries(&is[..10]).is_err());
+    assert_eq!(lzf_decompress(b"\x02abc\x20\x02", 6), Ok(b"abcabc".to_vec()));
+}
+
+#[test]
+fn test_stream_round_trip() {
+    let mut s = Stream::new();
+    for i in 0..150 {
+        let fields = if i % 7 == 0 {vec![("other".to_string(), "x".repeat(i))]} else {vec![("n".to_string(), i.to_string()), ("v".to_string(), "value".to_string())]};
+        s.add(StreamId::new(1000 + i as u64 / 3, i as u64 % 3), fields);
+    }
+    s.delete(&StreamId::new(1001, 0));
+    let mut g = ConsumerGroup::new(StreamId::new(1010, 1), Some(31));
+    g.consumer("alice", 5000).active_time = Some(4000);
+    g.consumer("bob", 6000);
+    g.assign(StreamId::new(1002, 2), "alice", 4500, 2);
+    g.assign(StreamId::new(1003, 0), "bob", 5500, 1);
+    s.groups.insert("g".to_string(), g);
+    s.groups.insert("empty".to_string(), ConsumerGroup::new(StreamId::new(0, 0), None));
+    let value = Value::StreamValue(s.clone());
+    // DUMP writes the layout of its RDB version, which redis 5 can load
+    let payload = dump(&value).unwrap();
+    assert_eq!(payload[0], RDB_TYPE_STREAM_LISTPACKS);
+    match restore(&payload) {
+        Ok(Value::StreamValue(read)) => {
+            assert_eq!(read.entries, s.entries);
+            assert_eq!(read.groups["g"].pending, s.groups["g"].pending);
+        }
+        other => panic!("{:?}", other),
+    }
+    let mut out = Vec::new();
+    write_value_as(&mut out, &value, RDB_TYPE_STREAM_LISTPACKS_3).unwrap();
+    assert_eq!(RdbReader::new(&out).read_stream(RDB_TYPE_STREAM_LISTPACKS_3), Ok(s.clone()));
+    // older layouts lose what they have no room for
+    for &(version, t) in [(9, RDB_TYPE_STREAM_LISTPACKS), (10, RDB_TYPE_STREAM_LISTPACKS_2)].iter() {
+        let value = Value::StreamValue(s.clone());
+        assert_eq!(value_type_for(&value, version), Some(t));
+        let mut out = Vec::new();
+        write_value_as(&mut out, &value, t).unwrap();
+        let mut reader = RdbReader::new(&out);
+        let read = reader.read_stream(t).unwrap();
+        assert!(reader.at_end());
+        assert_eq!(read.entries, s.entries);
+        assert_eq!(read.last_id, s.last_id);
+        assert_eq!(read.groups["g"].pending, s.groups["g"].pending);
+        assert_eq!(read.groups["g"].entries_read, if version == 9 {None} else {Some(31)});
+        // a consumer's last activity becomes its last interaction
+        assert_eq!(read.groups["g"].consumers["alice"].active_time, Some(5000));
+    }
+    // a listpack written here reads back the same
+    let items = vec![b"a".to_vec(), b"-1".to_vec(), b"4000".to_vec(), b"100000".to_vec(), b"01".to_vec(), vec![b'z'; 100]];
+    assert_eq!(listpack_entries(&write_listpack(&items)), Ok(items));
+}
+
+#[test]
+fn test_hash_field_expirations() {
+    let live = 1u64 << 50;
+    let hash = |fields:&[(&str, &str)]| Value::HashValue(fields.iter().map(|&(f, v)| (f.to_string(), v.to_string())).collect());
+    let expected = hash(&[("a", "1"), ("c", "3")]);
+    // field, value and expiration time triplets, where 0 is none
+    let triplets = vec![(b"a", b"1", 0), (b"b", b"2", 1000), (b"c", b"3", live)];
+
+    let mut pre_ga = Vec::new();
+    let mut metadata = Vec::new();
+    write_ms_time(&mut metadata, 1000);
+    for out in vec![&mut pre_ga, &mut metadata] {
+        write_length(out, 3);
+    }
+    for &(field, value, at) in triplets.iter() {
+        write_length(&mut pre_ga, at);
+        write_length(&mut metadata, if at == 0 {0} else {at - 1000 + 1});
+        for out in vec![&mut pre_ga, &mut metadata] {
+            write_string(out, field);
+            write_string(out, value);
+        }
+    }
+    assert_eq!(RdbReader::new(&pre_ga).read_value(RDB_TYPE_HASH_METADATA_PRE_GA), Ok(expected.clone()));
+    assert_eq!(RdbReader::new(&metadata).read_value(RDB_TYPE_HASH_METADATA), Ok(expected.clone()));
+
+    let mut items = Vec::new();
+    for &(field, value, at) in triplets.iter() {
+        items.extend(vec![field.to_vec(), value.to_vec(), at.to_string().into_bytes()]);
+    }
+    let mut listpack_ex = Vec::new();
+    write_ms_time(&mut listpack_ex, 1000);
+    write_string(&mut listpack_ex, &write_listpack(&items));
+    assert_eq!(RdbReader::new(&listpack_ex).read_value(RDB_TYPE_HASH_LISTPACK_EX), Ok(expected.clone()));
+    assert_eq!(RdbReader::new(&listpack_ex[8..]).read_value(RDB_TYPE_HASH_LISTPACK_EX_PRE_GA), Ok(expected));
+    items.pop();
+    let mut uneven = Vec::new();
+    write_string(&mut uneven, &write_listpack(&items));
+    assert!(RdbReader::new(&uneven).read_value(RDB_TYPE_HASH_LISTPACK_EX_PRE_GA).is_err());
+}
@@ -0,0 +1,850 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::mem;
+use std::rc::Rc;
+use mio::tcp::{TcpListener, TcpStream};
+use mlua::{self, Function, HookTriggers, Lua, LuaOptions, MultiValue, RegistryKey, StdLib, Table};
+use mlua::Value as LuaValue;
+use nom::IResult;
+use sha1_smol::Sha1;
+use rustis::binary::{byte_len, bytes_to_string, string_to_bytes};
+use rustis::command::{Command, RestorePolicy, Return};
+use rustis::config::Config;
+use rustis::db::RustisDb;
+use rustis::key::{Key, now_ms};
+use rustis::parse::{ParseResult, command_parser};
+use rustis::pubsub::glob_match;
+use rustis::rdb;
+use rustis::server::flush_output;
+use rustis::value::Value;
+
+const BUSY_ERR:&'static str = "BUSY Redis is busy running a script. You can only call SCRIPT KILL or SHUTDOWN NOSAVE.";
+const BUSY_FUNCTION_ERR:&'static str = "BUSY Redis is busy running a script. You can only call FUNCTION KILL or SHUTDOWN NOSAVE.";
+const UNKILLABLE_ERR:&'static str = "UNKILLABLE Sorry the script already executed write commands against the dataset. You can either wait the script termination or kill the server in a hard way using the SHUTDOWN NOSAVE command.";
+const KILLED_ERR:&'static str = "ERR Script killed by user with SCRIPT KILL...";
+const KILLED_FUNCTION_ERR:&'static str = "ERR Script killed by user with FUNCTION KILL...";
+const LOAD_TIMEOUT_ERR:&'static str = "FUNCTION LOAD timeout";
+// how many VM instructions run between checks of the busy timeout
+const HOOK_INSTRUCTIONS:u32 = 10000;
+// how long a library's code may take to register its functions
+const LOAD_TIMEOUT_MS:u64 = 500;
+const FUNCTION_FLAGS:[&'static str; 5] = ["no-writes", "allow-oom", "allow-stale", "no-cluster", "allow-cross-slot-keys"];
+
+// `redis.call` raises the error tables `redis.pcall` returns. As in redis 7,
+// scripts can't change the globals or the libraries: the globals live in a
+// table of their own behind an empty _G, and the libraries behind empty
+// tables that refuse writes. It returns the globals and the redis library.
+const PRELUDE:&'static str = r#"
+redis = {}
+redis.LOG_DEBUG = 0
+redis.LOG_VERBOSE = 1
+redis.LOG_NOTICE = 2
+redis.LOG_WARNING = 3
+redis.call = function(...)
+    local reply = redis.pcall(...)
+    if type(reply) == 'table' and reply.err then
+        error(reply, 2)
+    end
+    return reply
+end
+redis.error_reply = function(e) return {err = e} end
+redis.status_reply = function(s) return {ok = s} end
+redis.log = function(level, message) end
+loadfile = nil
+dofile = nil
+KEYS = {}
+ARGV = {}
+__run = function(f, ...) return pcall(f, ...) end
+local error, getmetatable, pairs, rawset, setmetatable, tostring = error, getmetatable, pairs, rawset, setmetatable, tostring
+local G, globals, readonly = _G, {}, {}
+local function protect(t)
+    local proxy = setmetatable({}, {
+        __index = t,
+        __newindex = function() error("Attempt to modify a readonly table", 2) end,
+        __metatable = false,
+    })
+    readonly[proxy] = true
+    return proxy
+end
+for name, value in pairs(G) do
+    globals[name] = value
+end
+for name in pairs(globals) do
+    rawset(G, name, nil)
+end
+readonly[G] = true
+local lib = globals.redis
+for _, name in pairs({'redis', 'string', 'table', 'math'}) do
+    globals[name] = protect(globals[name])
+end
+getmetatable('').__index = globals.string
+getmetatable('').__metatable = false
+globals.rawset = function(t, k, v)
+    if readonly[t] then
+        error("Attempt to modify a readonly table", 2)
+    end
+    return rawset(t, k, v)
+end
+setmetatable(G, {
+    __newindex = function(t, n)
+        if globals[n] ~= nil then
+            error("Attempt to modify a readonly table", 2)
+        end
+        error("Script attempted to create global variable '" .. tostring(n) .. "'", 2)
+    end,
+    __index = function(t, n)
+        local value = globals[n]
+        if value == nil then
+            error("Script attempted to access nonexistent global variable '" .. tostring(n) .. "'", 2)
+        end
+        return value
+    end,
+    __metatable = false,
+})
+return globals, lib
+"#;
+
+// a client connection that a long running script answers -BUSY to, without
+// a token if the script accepted it
+pub struct BusyClient {
+    pub token:Option<usize>,
+    pub stream:TcpStream,
+    // the connection's unparsed input and unsent output, handed back when
+    // the script ends
+    pub buf:String,
+    pub out:Vec<u8>,
+}
+
+struct Busy {
+    running:bool,
+    // whether it's FCALL rather than EVAL, which FUNCTION KILL stops
+    function:bool,
+    // set while a library registers its functions, which has to be quick
+    loading:bool,
+    started:u64,
+    threshold:u64,
+    clients:Vec<BusyClient>,
+    // the server's socket, to accept connections on while a script runs
+    listener:Option<TcpListener>,
+    wrote:bool,
+    killed:bool,
+}
+
+impl Busy {
+    // accepts new connections and reads what the clients sent, rejecting
+    // it unless it's SCRIPT KILL or FUNCTION KILL
+    fn serve_clients(&mut self) {
+        if let Some(ref listener) = self.listener {
+            while let Ok((stream, _)) = listener.accept() {
+                self.clients.push(BusyClient {token: None, stream: stream, buf: String::new(), out: Vec::new()});
+            }
+        }
+        let kill = if self.function {Command::FunctionKill} else {Command::ScriptKill};
+        let busy_err = if self.function {BUSY_FUNCTION_ERR} else {BUSY_ERR};
+        for client in self.clients.iter_mut() {
+            let mut bytes = Vec::new();
+            // reads until the socket would block
+            let _ = client.stream.read_to_end(&mut bytes);
+            client.buf.push_str(&bytes_to_string(&bytes));
+            let ParseResult(parsed_chars, commands) = Command::parse(&client.buf);
+            client.buf.drain(0..parsed_chars);
+            for cmd in commands {
+                let reply = match cmd {
+                    Ok(ref cmd) if *cmd == kill && self.wrote => format!("{}", Return::Error(UNKILLABLE_ERR.to_string())),
+                    Ok(ref cmd) if *cmd == kill => {
+                        self.killed = true;
+                        format!("{}", Return::Ok)
+                    }
+                    _ => format!("{}", Return::Error(busy_err.to_string())),
+                };
+                client.out.extend(string_to_bytes(&reply));
+            }
+            flush_output(&mut client.stream, &mut client.out);
+        }
+    }
+}
+
+struct Library {
+    code:String,
+    // its functions, in the order they were registered
+    functions:Vec<String>,
+}
+
+struct LibraryFunction {
+    library:String,
+    callback:RegistryKey,
+    flags:Vec<String>,
+}
+
+// a script or function about to run, and what it's called with
+struct Invocation<'a> {
+    // how errors refer to it
+    name:String,
+    keys:&'a [String],
+    argv:&'a [String],
+    readonly:bool,
+    // why writes are refused right now, if they are
+    refused:Option<&'a str>,
+    // functions get keys and arguments as parameters rather than KEYS and ARGV
+    function:bool,
+}
+
+pub struct Scripting {
+    lua:Lua,
+    // compiled scripts by the hex SHA1 of their body
+    scripts:HashMap<String, RegistryKey>,
+    libraries:BTreeMap<String, Library>,
+    functions:HashMap<String, LibraryFunction>,
+    busy:Rc<RefCell<Busy>>,
+    ready_keys:Vec<Key>,
+}
+
+impl Scripting {
+    pub fn new() -> Scripting {
+        let lua = Lua::new_with(StdLib::TABLE | StdLib::STRING | StdLib::MATH, LuaOptions::new()).unwrap();
+        {
+            let sha1hex = lua.create_function(|_, s:mlua::String| Ok(sha1_hex(s.as_bytes()))).unwrap();
+            let (globals, redis):(Table, Table) = lua.load(PRELUDE).eval().unwrap();
+            redis.raw_set("sha1hex", sha1hex).unwrap();
+            lua.set_named_registry_value("globals", globals).unwrap();
+            lua.set_named_registry_value("redis", redis).unwrap();
+        }
+        let busy = Rc::new(RefCell::new(Busy {
+            running: false,
+            function: false,
+            loading: false,
+            started: 0,
+            threshold: Config::new().busy_reply_threshold,
+            clients: Vec::new(),
+            listener: None,
+            wrote: false,
+            killed: false,
+        }));
+        let hook_busy = busy.clone();
+        lua.set_hook(HookTriggers::new().every_nth_instruction(HOOK_INSTRUCTIONS), move |_, _| {
+            let mut busy = hook_busy.borrow_mut();
+            let elapsed = now_ms().saturating_sub(busy.started);
+            if busy.loading && elapsed >= LOAD_TIMEOUT_MS {
+                return Err(mlua::Error::RuntimeError(LOAD_TIMEOUT_ERR.to_string()));
+            }
+            if !busy.running || elapsed < busy.threshold {
+                return Ok(());
+            }
+            busy.serve_clients();
+            if busy.killed {
+                let killed = if busy.function {KILLED_FUNCTION_ERR} else {KILLED_ERR};
+                return Err(mlua::Error::RuntimeError(killed.to_string()));
+            }
+            return Ok(());
+        });
+        return Scripting {
+            lua: lua,
+            scripts: HashMap::new(),
+            libraries: BTreeMap::new(),
+            functions: HashMap::new(),
+            busy: busy,
+            ready_keys: Vec::new(),
+        };
+    }
+
+    pub fn configure(&mut self, config:&Config) {
+        self.busy.borrow_mut().threshold = config.busy_reply_threshold;
+    }
+
+    pub fn set_listener(&mut self, listener:TcpListener) {
+        self.busy.borrow_mut().listener = Some(listener);
+    }
+
+    // compiles and caches a script, returning its SHA1
+    pub fn load(&mut self, script:&str) -> Result<String, String> {
+        let body = string_to_bytes(script);
+        let sha = sha1_hex(&body);
+        if self.scripts.contains_key(&sha) {
+            return Ok(sha);
+        }
+        let f = match self.lua.load(&body[..]).set_name("@user_script").into_function() {
+            Ok(f) => f,
+            Err(e) => return Err(format!("ERR Error compiling script (new function): {}", lua_error_message(&e))),
+        };
+        let key = self.lua.create_registry_value(f).unwrap();
+        self.scripts.insert(sha.clone(), key);
+        return Ok(sha);
+    }
+
+    pub fn exists(&self, sha:&str) -> bool {
+        return self.scripts.contains_key(&sha.to_lowercase());
+    }
+
+    pub fn flush(&mut self) {
+        for (_, key) in self.scripts.drain() {
+            let _ = self.lua.remove_registry_value(key);
+        }
+        self.lua.expire_registry_values();
+    }
+
+    // loads a library whose code starts with a `#!lua name=<library>` line,
+    // running it so it can register its functions
+    pub fn function_load(&mut self, code:&str, replace:bool) -> Result<String, String> {
+        let (name, body) = library_metadata(code)?;
+        if self.libraries.contains_key(&name) && !replace {
+            return Err(format!("ERR Library '{}' already exists", name));
+        }
+        let mut registered:Vec<(String, RegistryKey, Vec<String>)> = Vec::new();
+        let result = {
+            let lua = &self.lua;
+            // the metadata line is left blank so line numbers stay right
+            let source = format!("\n{}", body);
+            let f = match lua.load(&string_to_bytes(&source)[..]).set_name("@user_function").into_function() {
+                Ok(f) => f,
+                Err(e) => return Err(format!("ERR Error compiling function: {}", lua_error_message(&e))),
+            };
+            {
+                let mut busy = self.busy.borrow_mut();
+                busy.loading = true;
+                busy.started = now_ms();
+            }
+            lua.scope(|scope| {
+                let register = scope.create_function_mut(|lua, args:MultiValue| {
+                    let (name, callback, flags) = register_function_args(args)?;
+                    if registered.iter().any(|&(ref n, _, _)| *n == name) {
+                        return Err(mlua::Error::RuntimeError("Function already exists in the library".to_string()));
+                    }
+                    registered.push((name, lua.create_registry_value(callback)?, flags));
+                    return Ok(());
+                })?;
+                let redis:Table = lua.named_registry_value("redis")?;
+                redis.raw_set("register_function", register)?;
+                let result = f.call::<_, ()>(());
+                redis.raw_set("register_function", LuaValue::Nil)?;
+                return result;
+            })
+        };
+        self.busy.borrow_mut().loading = false;
+        if let Err(e) = result {
+            return Err(format!("ERR Error registering functions: {}", lua_error_message(&e)));
+        }
+        if registered.is_empty() {
+            return Err("ERR No functions registered".to_string());
+        }
+        for &(ref function, _, _) in registered.iter() {
+            match self.functions.get(function) {
+                Some(existing) if existing.library != name => return Err(format!("ERR Function {} already exists", function)),
+                _ => {}
+            }
+        }
+        self.function_delete(&name).ok();
+        let mut names = Vec::with_capacity(registered.len());
+        for (function, callback, flags) in registered {
+            names.push(function.clone());
+            self.functions.insert(function, LibraryFunction {
+                library: name.clone(),
+                callback: callback,
+                flags: flags,
+            });
+        }
+        self.libraries.insert(name.clone(), Library {
+            code: code.to_string(),
+            functions: names,
+        });
+        return Ok(name);
+    }
+
+    pub fn function_delete(&mut self, library:&str) -> Result<(), String> {
+        let library = match self.libraries.remove(library) {
+            Some(library) => library,
+            None => return Err("ERR Library not found".to_string()),
+        };
+        for name in library.functions {
+            if let Some(function) = self.functions.remove(&name) {
+                let _ = self.lua.remove_registry_value(function.callback);
+            }
+        }
+        self.lua.expire_registry_values();
+        return Ok(());
+    }
+
+    pub fn function_flush(&mut self) {
+        let names = self.libraries.keys().cloned().collect::<Vec<String>>();
+        for name in names {
+            self.function_delete(&name).ok();
+        }
+    }
+
+    // FUNCTION LIST's reply, for libraries whose names match the pattern
+    pub fn function_list(&self, pattern:Option<&str>, withcode:bool) -> Value {
+        return Value::ArrayValue(self.libraries.iter().filter(|&(name, _)| match pattern {
+            Some(pattern) => glob_match(pattern, name),
+            None => true,
+        }).map(|(name, library)| {
+            let functions = library.functions.iter().map(|f| {
+                let flags = self.functions[f].flags.iter().map(|flag| Value::StrValue(flag.clone())).collect();
+                Value::ArrayValue(vec![
+                    Value::StrValue("name".to_string()), Value::StrValue(f.clone()),
+                    Value::StrValue("description".to_string()), Value::Nil,
+                    Value::StrValue("flags".to_string()), Value::ArrayValue(flags),
+                ])
+            }).collect();
+            let mut reply = vec![
+                Value::StrValue("library_name".to_string()), Value::StrValue(name.clone()),
+                Value::StrValue("engine".to_string()), Value::StrValue("LUA".to_string()),
+                Value::StrValue("functions".to_string()), Value::ArrayValue(functions),
+            ];
+            if withcode {
+                reply.push(Value::StrValue("library_code".to_string()));
+                reply.push(Value::StrValue(library.code.clone()));
+            }
+            Value::ArrayValue(reply)
+        }).collect());
+    }
+
+    // the code of every library, which is all it takes to recreate them
+    pub fn library_codes(&self) -> Vec<String> {
+        return self.libraries.values().map(|library| library.code.clone()).collect();
+    }
+
+    pub fn function_dump(&self) -> String {
+        return bytes_to_string(&rdb::dump_functions(&self.library_codes()));
+    }
+
+    // restores dumped libraries all at once: on any error, the libraries
+    // loaded before are put back
+    pub fn function_restore(&mut self, payload:&str, policy:RestorePolicy) -> Result<(), String> {
+        let codes = rdb::restore_functions(&string_to_bytes(payload))?;
+        let previous = self.library_codes();
+        if policy == RestorePolicy::Flush {
+            self.function_flush();
+        }
+        for code in codes.iter() {
+            let result = match library_metadata(code) {
+                Ok((ref name, _)) if policy == RestorePolicy::Append && self.libraries.contains_key(name) => {
+                    Err(format!("ERR Library '{}' already exists", name))
+                }
+                Ok(_) => self.function_load(code, policy == RestorePolicy::Replace),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                self.function_flush();
+                for code in previous.iter() {
+                    self.function_load(code, false).ok();
+                }
+                return Err(e);
+            }
+        }
+        return Ok(());
+    }
+
+    // runs EVAL, EVALSHA or FCALL against a database, replying with the
+    // RESP the result converts to. While it runs past the busy threshold,
+    // it answers the other clients, and those it accepts, itself. The
+    // commands it calls that change data fail with `refused` if it's set.
+    pub fn eval(&mut self, db:&mut RustisDb, cmd:Command, refused:Option<&str>, clients:Vec<BusyClient>) -> (Result<String, String>, Vec<BusyClient>) {
+        let (sha, function, numkeys, args, readonly) = match cmd {
+            Command::Eval {script, numkeys, args, readonly} => {
+                match self.load(&script) {
+                    Ok(sha) => (Some(sha), None, numkeys, args, readonly),
+                    Err(e) => return (Err(e), clients),
+                }
+            }
+            Command::EvalSha {sha, numkeys, args, readonly} => (Some(sha.to_lowercase()), None, numkeys, args, readonly),
+            Command::FCall {function, numkeys, args, readonly} => (None, Some(function), numkeys, args, readonly),
+            _ => return (Ok(format!("{}", Value::Nil)), clients),
+        };
+        if numkeys < 0 {
+            return (Err("ERR Number of keys can't be negative".to_string()), clients);
+        }
+        if numkeys as usize > args.len() {
+            return (Err("ERR Number of keys can't be greater than number of args".to_string()), clients);
+        }
+        let (keys, argv) = args.split_at(numkeys as usize);
+        let (callback, invocation) = match (sha, function) {
+            (Some(sha), _) => match self.scripts.get(&sha) {
+                Some(callback) => (callback, Invocation {name: format!("f_{}", sha), keys: keys, argv: argv, readonly: readonly, refused: refused, function: false}),
+                None => return (Err("NOSCRIPT No matching script. Please use EVAL.".to_string()), clients),
+            },
+            (None, Some(name)) => match self.functions.get(&name) {
+                Some(f) => {
+                    let no_writes = f.flags.iter().any(|flag| flag == "no-writes");
+                    if readonly && !no_writes {
+                        return (Err("ERR Can not execute a script with write flag using *_ro command.".to_string()), clients);
+                    }
+                    (&f.callback, Invocation {name: name, keys: keys, argv: argv, readonly: no_writes, refused: refused, function: true})
+                }
+                None => return (Err("ERR Function not found".to_string()), clients),
+            },
+            (None, None) => return (Ok(format!("{}", Value::Nil)), clients),
+        };
+        let (result, clients, ready_keys) = run(&self.lua, &self.busy, db, callback, invocation, clients);
+        self.ready_keys.extend(ready_keys);
+        return (result, clients);
+    }
+
+    // whether FCALL of a function may change data, which it may unless
+    // it has the no-writes flag
+    pub fn function_writes(&self, name:&str) -> bool {
+        return self.functions.get(name).map_or(false, |f| !f.flags.iter().any(|flag| flag == "no-writes"));
+    }
+
+    // streams appended to by scripts, which may unblock readers
+    pub fn take_ready_keys(&mut self) -> Vec<Key> {
+        return mem::replace(&mut self.ready_keys, Vec::new());
+    }
+}
+
+fn run(lua:&Lua, busy:&Rc<RefCell<Busy>>, db:&mut RustisDb, callback:&RegistryKey, invocation:Invocation, clients:Vec<BusyClient>) -> (Result<String, String>, Vec<BusyClient>, Vec<Key>) {
+    {
+        let mut busy = busy.borrow_mut();
+        busy.running = true;
+        busy.function = invocation.function;
+        busy.started = now_ms();
+        busy.clients = clients;
+        busy.wrote = false;
+        busy.killed = false;
+    }
+    let mut ready_keys = Vec::new();
+    let name = &invocation.name;
+    let killed = if invocation.function {KILLED_FUNCTION_ERR} else {KILLED_ERR};
+    let readonly = invocation.readonly;
+    let refused = invocation.refused;
+    let result = lua.scope(|scope| {
+        let pcall = scope.create_function_mut(|lua, args:MultiValue| {
+            return redis_call(lua, db, busy, readonly, refused, &mut ready_keys, args);
+        })?;
+        let globals:Table = lua.named_registry_value("globals")?;
+        let redis:Table = lua.named_registry_value("redis")?;
+        redis.raw_set("pcall", pcall)?;
+        let keys = lua.create_sequence_from(invocation.keys.iter().map(|k| lua.create_string(&string_to_bytes(k))).collect::<mlua::Result<Vec<_>>>()?)?;
+        let argv = lua.create_sequence_from(invocation.argv.iter().map(|a| lua.create_string(&string_to_bytes(a))).collect::<mlua::Result<Vec<_>>>()?)?;
+        let f:Function = lua.registry_value(callback)?;
+        let run:Function = globals.raw_get("__run")?;
+        let (ok, value):(bool, LuaValue) = if invocation.function {
+            run.call((f, keys, argv))?
+        } else {
+            globals.raw_set("KEYS", keys)?;
+            globals.raw_set("ARGV", argv)?;
+            run.call(f)?
+        };
+        if ok {
+            return Ok(Ok(to_resp(&value)));
+        }
+        return Ok(Err(match value {
+            LuaValue::Table(ref t) => match t.raw_get::<_, LuaValue>("err")? {
+                LuaValue::String(e) => bytes_to_string(e.as_bytes()),
+                _ => format!("ERR Error running script (call to {}): @user_script: unknown error", name),
+            },
+            LuaValue::Error(ref e) if lua_error_message(e) == killed => killed.to_string(),
+            LuaValue::Error(ref e) => format!("ERR Error running script (call to {}): @{}", name, lua_error_message(e)),
+            LuaValue::String(ref s) => format!("ERR Error running script (call to {}): @{}", name, bytes_to_string(s.as_bytes())),
+            _ => format!("ERR Error running script (call to {}): @user_script: unknown error", name),
+        }));
+    });
+    let clients = {
+        let mut busy = busy.borrow_mut();
+        busy.running = false;
+        mem::replace(&mut busy.clients, Vec::new())
+    };
+    return match result {
+        Ok(result) => (result, clients, ready_keys),
+        Err(e) => (Err(format!("ERR Error running script (call to {}): @{}", name, lua_error_message(&e))), clients, ready_keys),
+    };
+}
+
+// the library name from the `#!lua name=<library>` line, and the code after it
+fn library_metadata(code:&str) -> Result<(String, &str), String> {
+    if !code.starts_with("#!") {
+        return Err("ERR Missing library metadata".to_string());
+    }
+    let (shebang, body) = match code.find('\n') {
+        Some(i) => (&code[2..i], &code[i + 1..]),
+        None => (&code[2..], ""),
+    };
+    let mut parts = shebang.split_whitespace();
+    match parts.next() {
+        Some(engine) if engine.to_lowercase() == "lua" => {}
+        engine => return Err(format!("ERR Engine '{}' not found", engine.unwrap_or(""))),
+    }
+    let mut name = None;
+    for part in parts {
+        if part.starts_with("name=") {
+            name = Some(part[5..].to_string());
+        } else {
+            return Err(format!("ERR Invalid metadata value given: {}", part));
+        }
+    }
+    return match name {
+        Some(ref name) if !valid_name(name) => Err("ERR Library names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string()),
+        Some(name) => Ok((name, body)),
+        None => Err("ERR Library name was not given".to_string()),
+    };
+}
+
+fn valid_name(name:&str) -> bool {
+    return !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+}
+
+// redis.register_function takes a name and a callback, or a table with
+// function_name, callback and optionally flags and a description
+fn register_function_args(args:MultiValue) -> mlua::Result<(String, Function, Vec<String>)> {
+    let args = args.into_vec();
+    let (name, callback, flags) = match args.len() {
+        1 => match args[0] {
+            LuaValue::Table(ref t) => (t.raw_get("function_name")?, t.raw_get("callback")?, t.raw_get("flags")?),
+            _ => return Err(mlua::Error::RuntimeError("calling redis.register_function with a single argument is only applicable to Lua table (representing named arguments).".to_string())),
+        },
+        2 => (args[0].clone(), args[1].clone(), LuaValue::Nil),
+        _ => return Err(mlua::Error::RuntimeError("wrong number of arguments to redis.register_function".to_string())),
+    };
+    let name = match name {
+        LuaValue::String(ref s) if valid_name(s.to_str().unwrap_or("")) => s.to_str().unwrap().to_string(),
+        _ => return Err(mlua::Error::RuntimeError("Function names can only contain letters, numbers, or underscores(_) and must be at least one character long".to_string())),
+    };
+    let callback = match callback {
+        LuaValue::Function(f) => f,
+        _ => return Err(mlua::Error::RuntimeError("callback argument given to redis.register_function must be a function".to_string())),
+    };
+    let mut parsed = Vec::new();
+    match flags {
+        LuaValue::Nil => {}
+        LuaValue::Table(t) => {
+            for flag in t.sequence_values::<String>() {
+                let flag = flag?;
+                if !FUNCTION_FLAGS.contains(&flag.as_str()) {
+                    return Err(mlua::Error::RuntimeError("unknown flag given".to_string()));
+                }
+                parsed.push(flag);
+            }
+        }
+        _ => return Err(mlua::Error::RuntimeError("flags argument to redis.register_function must be a table representing function flags".to_string())),
+    }
+    return Ok((name, callback, parsed));
+}
+
+pub fn sha1_hex(bytes:&[u8]) -> String {
+    return Sha1::from(bytes).digest().to_string();
+}
+
+// the innermost message of an error raised through rust callbacks
+fn lua_error_message(e:&mlua::Error) -> String {
+    return match e {
+        &mlua::Error::CallbackError {ref cause, ..} => lua_error_message(cause),
+        &mlua::Error::RuntimeError(ref s) => s.clone(),
+        &mlua::Error::SyntaxError {ref message, ..} => message.clone(),
+        e => e.to_string(),
+    };
+}
+
+// commands that need the server rather than a single database
+pub fn allowed_in_script(cmd:&Command) -> bool {
+    return match cmd {
+        &Command::Select(_) | &Command::SwapDb(..) | &Command::Move {..} | &Command::Copy {db: Some(_), ..} |
+        &Command::FlushAll | &Command::FlushAllAsync | &Command::ClientNoTouch(_) |
+        &Command::ConfigGet {..} | &Command::ConfigSet {..} |
+        &Command::Subscribe {..} | &Command::Unsubscribe {..} | &Command::Psubscribe {..} | &Command::Punsubscribe {..} |
+        &Command::Publish {..} | &Command::PubsubChannels {..} | &Command::PubsubNumSub {..} | &Command::PubsubNumPat |
+        &Command::Multi | &Command::Exec | &Command::Discard | &Command::Watch {..} | &Command::Unwatch |
+        &Command::Eval {..} | &Command::EvalSha {..} | &Command::ScriptLoad {..} | &Command::ScriptExists {..} |
+        &Command::ScriptFlush | &Command::ScriptKill | &Command::FCall {..} |
+        &Command::FunctionLoad {..} | &Command::FunctionDelete {..} | &Command::FunctionFlush | &Command::FunctionList {..} |
+        &Command::FunctionDump | &Command::FunctionRestore {..} | &Command::FunctionKill |
+        &Command::WasmLoad {..} | &Command::WasmUnload {..} | &Command::WasmList | &Command::Custom {..} |
+        &Command::ModuleLoad {..} | &Command::ModuleUnload {..} | &Command::ModuleList |
+        &Command::TriggerCreate {..} | &Command::TriggerDelete {..} | &Command::TriggerList |
+        &Command::TriggerDump | &Command::TriggerRestore {..} |
+        &Command::Save | &Command::BgSave {..} | &Command::BgRewriteAof | &Command::LastSave | &Command::Info {..} => false,
+        _ => true,
+    };
+}
+
+fn error_table<'lua>(lua:&'lua Lua, e:&str) -> mlua::Result<LuaValue<'lua>> {
+    let t = lua.create_table()?;
+    t.raw_set("err", e)?;
+    return Ok(LuaValue::Table(t));
+}
+
+// redis.pcall: runs a command, returning errors as {err = ...} tables
+fn redis_call<'lua>(lua:&'lua Lua, db:&mut RustisDb, busy:&Rc<RefCell<Busy>>, readonly:bool, refused:Option<&str>, ready_keys:&mut Vec<Key>, args:MultiValue<'lua>) -> mlua::Result<LuaValue<'lua>> {
+    let mut parts = Vec::with_capacity(args.len());
+    for arg in args {
+        parts.push(match arg {
+            LuaValue::String(s) => bytes_to_string(s.as_bytes()),
+            LuaValue::Integer(i) => i.to_string(),
+            LuaValue::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => (n as i64).to_string(),
+            LuaValue::Number(n) => n.to_string(),
+            _ => return error_table(lua, "ERR Lua redis lib command arguments must be strings or integers"),
+        });
+    }
+    if parts.is_empty() {
+        return error_table(lua, "ERR Please specify at least one argument for this redis lib call");
+    }
+    let joined = parts.iter().map(|p| Command::quote(p)).collect::<Vec<String>>().join(" ");
+    let cmd = match command_parser(&joined) {
+        IResult::Done("", cmd) => cmd,
+        _ if Command::arity_error(&parts).is_some() => return error_table(lua, "ERR Wrong number of args calling Redis command from script"),
+        _ => return error_table(lua, "ERR Unknown Redis command called from script"),
+    };
+    if !allowed_in_script(&cmd) {
+        return error_table(lua, "ERR This Redis command is not allowed from script");
+    }
+    if cmd.is_write() {
+        if readonly {
+            return error_table(lua, "ERR Write commands are not allowed from read-only scripts.");
+        }
+        if let Some(e) = refused {
+            return error_table(lua, e);
+        }
+        busy.borrow_mut().wrote = true;
+    }
+    if let Command::XAdd {ref key, ..} = cmd {
+        ready_keys.push(key.clone());
+    }
+    return match db.run_command(cmd) {
+        Return::Ok => {
+            let t = lua.create_table()?;
+            t.raw_set("ok", "OK")?;
+            Ok(LuaValue::Table(t))
+        }
+        Return::Queued => {
+            let t = lua.create_table()?;
+            t.raw_set("ok", "QUEUED")?;
+            Ok(LuaValue::Table(t))
+        }
+        Return::Status(s) => {
+            let t = lua.create_table()?;
+            t.raw_set("ok", s)?;
+            Ok(LuaValue::Table(t))
+        }
+        Return::Error(e) => error_table(lua, &e),
+        Return::ValueReturn(v) => to_lua(lua, v),
+    };
+}
+
+// redis replies become lua values the way redis converts them: nil is false
+fn to_lua<'lua>(lua:&'lua Lua, value:Value) -> mlua::Result<LuaValue<'lua>> {
+    return Ok(match value {
+        Value::IntValue(i) => LuaValue::Integer(i as mlua::Integer),
+        Value::StrValue(s) => LuaValue::String(lua.create_string(&string_to_bytes(&s))?),
+        Value::ArrayValue(values) => {
+            let t = lua.create_table()?;
+            for (i, v) in values.into_iter().enumerate() {
+                t.raw_set(i + 1, to_lua(lua, v)?)?;
+            }
+            LuaValue::Table(t)
+        }
+        _ => LuaValue::Boolean(false),
+    });
+}
+
+// the RESP reply for a script's result; tables are arrays up to their first
+// nil, unless they carry an `ok` or `err` field
+fn to_resp(value:&LuaValue) -> String {
+    return match value {
+        &LuaValue::Boolean(true) => ":1\r\n".to_string(),
+        &LuaValue::Integer(i) => format!(":{}\r\n", i),
+        &LuaValue::Number(n) => format!(":{}\r\n", n as i64),
+        &LuaValue::String(ref s) => {
+            let s = bytes_to_string(s.as_bytes());
+            format!("${}\r\n{}\r\n", byte_len(&s), s)
+        }
+        &LuaValue::Table(ref t) => {
+            if let Ok(LuaValue::String(e)) = t.raw_get::<_, LuaValue>("err") {
+                return format!("-{}\r\n", bytes_to_string(e.as_bytes()));
+            }
+            if let Ok(LuaValue::String(s)) = t.raw_get::<_, LuaValue>("ok") {
+                return format!("+{}\r\n", bytes_to_string(s.as_bytes()));
+            }
+            let mut items = Vec::new();
+            for i in 1.. {
+                match t.raw_get::<_, LuaValue>(i) {
+                    Ok(LuaValue::Nil) | Err(_) => break,
+                    Ok(v) => items.push(to_resp(&v)),
+                }
+            }
+            format!("*{}\r\n{}", items.len(), items.concat())
+        }
+        _ => format!("{}", Value::Nil),
+    };
+}
+
+#[test]
+fn test_eval() {
+    let mut scripting = Scripting::new();
+    let mut db = RustisDb::new();
+    let mut eval = |db:&mut RustisDb, script:&str, numkeys:i64, args:Vec<&str>| {
+        let cmd = Command::Eval {script: script.to_string(), numkeys: numkeys, args: args.into_iter().map(|a| a.to_string()).collect(), readonly: false};
+        return scripting.eval(db, cmd, None, vec![]).0;
+    };
+    assert_eq!(eval(&mut db, "return redis.call('SET', KEYS[1], ARGV[1])", 1, vec!["k", "v"]), Ok("+OK\r\n".to_string()));
+    assert_eq!(eval(&mut db, "return {redis.call('get', KEYS[1]), 1.5, false, 'x', nil, 'y'}", 1, vec!["k"]), Ok("*4\r\n$1\r\nv\r\n:1\r\n$-1\r\n$1\r\nx\r\n".to_string()));
+    assert_eq!(eval(&mut db, "return redis.call('get', 'missing')", 0, vec![]), Ok("$-1\r\n".to_string()));
+    assert_eq!(eval(&mut db, "return redis.call('incr', KEYS[1])", 1, vec!["k"]), Err("ERR value is not an integer or out of range".to_string()));
+    assert_eq!(eval(&mut db, "return redis.pcall('incr', KEYS[1])", 1, vec!["k"]), Ok("-ERR value is not an integer or out of range\r\n".to_string()));
+    assert_eq!(eval(&mut db, "return redis.call('nosuchcommand')", 0, vec![]), Err("ERR Unknown Redis command called from script".to_string()));
+    assert_eq!(eval(&mut db, "return redis.call('get')", 0, vec![]), Err("ERR Wrong number of args calling Redis command from script".to_string()));
+    assert!(eval(&mut db, "x = 1", 0, vec![]).unwrap_err().contains("Script attempted to create global variable 'x'"));
+    assert!(eval(&mut db, "return (", 0, vec![]).unwrap_err().starts_with("ERR Error compiling script"));
+    assert_eq!(eval(&mut db, "return 1", 2, vec!["a"]), Err("ERR Number of keys can't be greater than number of args".to_string()));
+    assert_eq!(eval(&mut db, "return redis.sha1hex('')", 0, vec![]), Ok("$40\r\nda39a3ee5e6b4b0d3255bfef95601890afd80709\r\n".to_string()));
+    // the globals and libraries can't be changed for later scripts
+    for script in ["redis.call = nil", "redis = nil", "string.rep = nil", "table.insert = nil", "math.floor = nil", "rawset(redis, 'call', nil)", "rawset(_G, 'KEYS', nil)", "setmetatable(_G, nil)", "getmetatable('').__index = {}"].iter() {
+        assert!(eval(&mut db, script, 0, vec![]).is_err(), "{}", script);
+    }
+    assert_eq!(eval(&mut db, "return redis.call('get', KEYS[1]):upper() .. string.rep('!', 2) .. #ARGV", 1, vec!["k", "a"]), Ok("$4\r\nV!!1\r\n".to_string()));
+    assert!(eval(&mut db, "redis = nil", 0, vec![]).unwrap_err().contains("Attempt to modify a readonly table"));
+}
+
+#[test]
+fn test_script_cache() {
+    let mut scripting = Scripting::new();
+    let mut db = RustisDb::new();
+    let sha = scripting.load("return redis.call('set', 'k', 'v')").unwrap();
+    assert!(scripting.exists(&sha.to_uppercase()));
+    let evalsha = |readonly:bool| Command::EvalSha {sha: sha.clone(), numkeys: 0, args: vec![], readonly: readonly};
+    assert_eq!(scripting.eval(&mut db, evalsha(true), None, vec![]).0, Err("ERR Write commands are not allowed from read-only scripts.".to_string()));
+    assert_eq!(scripting.eval(&mut db, evalsha(false), None, vec![]).0, Ok("+OK\r\n".to_string()));
+    scripting.flush();
+    assert!(!scripting.exists(&sha));
+    assert_eq!(scripting.eval(&mut db, evalsha(false), None, vec![]).0, Err("NOSCRIPT No matching script. Please use EVAL.".to_string()));
+}
+
+#[test]
+fn test_functions() {
+    let mut scripting = Scripting::new();
+    let mut db = RustisDb::new();
+    let code = "#!lua name=lib\nredis.register_function('setk', function(keys, args) return redis.call('set', keys[1], args[1]) end)\nredis.register_function{function_name='getk', callback=function(keys) return redis.call('get', keys[1]) end, flags={'no-writes'}}";
+    assert_eq!(scripting.function_load(code, false), Ok("lib".to_string()));
+    assert_eq!(scripting.function_load(code, false), Err("ERR Library 'lib' already exists".to_string()));
+    assert_eq!(scripting.function_load(code, true), Ok("lib".to_string()));
+    assert_eq!(scripting.function_load("#!lua name=other\nredis.register_function('getk', function() end)", false), Err("ERR Function getk already exists".to_string()));
+    assert_eq!(scripting.function_load("return 1", false), Err("ERR Missing library metadata".to_string()));
+    assert_eq!(scripting.function_load("#!lua name=empty\nlocal x = 1", false), Err("ERR No functions registered".to_string()));
+    assert!(scripting.function_load("#!lua name=bad\nredis.register_function('f', function() end, 1)", false).is_err());
+    let mut fcall = |scripting:&mut Scripting, function:&str, args:Vec<&str>, readonly:bool| {
+        let cmd = Command::FCall {function: function.to_string(), numkeys: 1, args: args.into_iter().map(|a| a.to_string()).collect(), readonly: readonly};
+        return scripting.eval(&mut db, cmd, None, vec![]).0;
+    };
+    assert_eq!(fcall(&mut scripting, "setk", vec!["k", "v"], false), Ok("+OK\r\n".to_string()));
+    assert_eq!(fcall(&mut scripting, "setk", vec!["k", "v"], true), Err("ERR Can not execute a script with write flag using *_ro command.".to_string()));
+    assert_eq!(fcall(&mut scripting, "getk", vec!["k"], true), Ok("$1\r\nv\r\n".to_string()));
+    assert_eq!(fcall(&mut scripting, "nope", vec!["k"], false), Err("ERR Function not found".to_string()));
+    match scripting.function_list(Some("l*"), true) {
+        Value::ArrayValue(ref libraries) => {
+            assert_eq!(libraries.len(), 1);
+            match libraries[0] {
+                Value::ArrayValue(ref library) => {
+                    assert_eq!(library[1], Value::StrValue("lib".to_string()));
+                    assert_eq!(library[7], Value::StrValue(code.to_string()));
+                }
+                _ => panic!("expected a library"),
+            }
+        }
+        _ => panic!("expected an array"),
+    }
+    let dump = scripting.function_dump();
+    assert_eq!(scripting.function_restore(&dump, RestorePolicy::Append), Err("ERR Library 'lib' already exists".to_string()));
+    assert_eq!(scripting.function_delete("lib"), Ok(()));
+    assert_eq!(scripting.function_delete("lib"), Err("ERR Library not found".to_string()));
+    assert_eq!(scripting.function_restore(&dump, RestorePolicy::Append), Ok(()));
+    assert_eq!(fcall(&mut scripting, "getk", vec!["k"], false), Ok("$1\r\nv\r\n".to_string()));
+    scripting.function_flush();
+    assert_eq!(scripting.function_list(None, false), Value::ArrayValue(vec![]));
+}
@@ -1,22 +1,73 @@
-use std::collections::HashMap;
-use std::io::{Read, Write};
+use std::collections::{HashMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::mem;
+use std::fmt::Display;
+use std::io::{self, Read, Write};
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::Duration;
+use libc::{self, pid_t};
 use mio::*;
 use mio::unix::*;
 use mio::tcp::{TcpListener, TcpStream};
+use rustis::aof::{self, Aof, AppendFsync, Manifest};
+use rustis::binary::{bytes_to_string, string_to_bytes};
 use rustis::command::{Command, Return};
+use rustis::config::Config;
 use rustis::db::RustisDb;
+use rustis::key::{Key, now_ms};
+use rustis::lazyfree::LazyFree;
+use rustis::module::Modules;
+use rustis::notify;
 use rustis::parse::ParseResult;
+use rustis::pubsub::PubSub;
+use rustis::scripting::{BusyClient, Scripting};
+use rustis::snapshot::{Item, SnapshotReader, SnapshotWriter, TRIGGERS_AUX};
+use rustis::wasm::Wasm;
+use rustis::value::Value;
 
 const LISTENER:Token = Token(0);
 const MAX_CONNECTIONS:usize = 0x1000;
 const EVENT_PREALLOCATE:usize = 0x400;
+const LOADING_ERR:&'static str = "LOADING Redis is loading the dataset in memory";
+// how many items of the snapshot are loaded between serving clients
+const LOADING_POLL_ITEMS:usize = 1000;
+const MISCONF_ERR:&'static str = "MISCONF Redis is configured to save RDB snapshots, but it's currently unable to persist to disk. Commands that may modify the data set are disabled, because this instance is configured to report errors during writes if RDB snapshotting fails (stop-writes-on-bgsave-error option). Please check the Redis logs for details about the RDB error.";
+// how often background saves are checked on and started by the save rules
+const CRON_MS:u64 = 100;
+// seconds to wait before a save rule or an automatic rewrite retries a
+// failed one
+const BGSAVE_RETRY_DELAY:u64 = 5;
 
+// an XREAD or XREADGROUP BLOCK waiting for entries on its keys
+struct Blocked {
+    cmd: Command,
+    keys: Vec<Key>,
+    deadline: Option<u64>,
+    // the order clients blocked in, so the longest waiting is served first
+    seq: u64,
+}
 
 struct ClientConnection {
     stream: TcpStream,
     buf: String,
+    // replies the socket hasn't taken yet, sent once it's writable again
+    out: Vec<u8>,
+    // parsed commands, held back while the client is blocked
+    queue: VecDeque<Result<Command, String>>,
+    blocked: Option<Blocked>,
+    // commands queued since MULTI, and whether one of them was rejected
+    multi: Option<Vec<Command>>,
+    multi_failed: bool,
+    // WATCHed keys with their database and version when watched
+    watched: Vec<(usize, Key, u64)>,
+    // replies collected while EXEC runs the queued commands
+    replies: Option<Vec<String>>,
+    // replies held back until the writes they acknowledge are logged
+    held: Option<Vec<String>>,
     db: usize,
+    no_touch: bool,
 }
 
 impl ClientConnection {
@@ -24,9 +75,45 @@ impl ClientConnection {
         ClientConnection {
             stream: stream,
             buf: String::new(),
+            out: Vec::new(),
+            queue: VecDeque::new(),
+            blocked: None,
+            multi: None,
+            multi_failed: false,
+            watched: Vec::new(),
+            replies: None,
+            held: None,
             db: 0,
+            no_touch: false,
         }
     }
+
+    // queues `bytes` behind the output still pending and writes what the
+    // socket takes now
+    fn write(&mut self, bytes:&[u8]) {
+        self.out.extend_from_slice(bytes);
+        self.flush();
+    }
+
+    fn flush(&mut self) {
+        flush_output(&mut self.stream, &mut self.out);
+    }
+}
+
+// writes what the socket takes of a client's pending output. A write that
+// would block leaves the rest for the next writable event; any other error
+// means the peer went away, which its hangup handles
+pub fn flush_output<W:Write>(stream:&mut W, out:&mut Vec<u8>) {
+    let mut written = 0;
+    while written < out.len() {
+        match stream.write(&out[written..]) {
+            Ok(0) => break,
+            Ok(n) => written += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        }
+    }
+    out.drain(0..written);
 }
 
 pub struct RustisServer {
@@ -34,94 +121,1718 @@ pub struct RustisServer {
     poll:Poll,
     connections:HashMap<usize, ClientConnection>,
     dbs:Vec<RustisDb>,
+    config:Config,
+    // streams appended to since blocked clients were last served
+    ready_keys:Vec<(usize, Key)>,
+    block_seq:u64,
+    pubsub:PubSub,
+    scripting:Scripting,
+    wasm:Wasm,
+    modules:Modules,
+    // set while the snapshot is loaded at startup
+    loading:bool,
+    bgsave_child:Option<pid_t>,
+    // a BGSAVE SCHEDULE waiting for the running save to finish
+    bgsave_scheduled:bool,
+    // unix time of the last successful save, and of the last BGSAVE
+    lastsave:u64,
+    last_bgsave_try:u64,
+    last_bgsave_ok:bool,
+    // the databases' change count when the data last saved was taken, and
+    // when the running background save forked
+    dirty_saved:u64,
+    dirty_before_bgsave:u64,
+    // changes made outside the databases, to functions
+    dirty:u64,
+    aof:Option<Aof>,
+    // why the last write to the append only file failed, which refuses
+    // writes until a retry succeeds
+    aof_write_error:Option<String>,
+    // the files of the append only file, once it was read or written
+    manifest:Option<Manifest>,
+    aof_rewrite_child:Option<pid_t>,
+    aof_rewrite_scheduled:bool,
+    // how many incremental files the running rewrite doesn't replace
+    aof_rewrite_kept:usize,
+    last_aof_rewrite_try:u64,
+    last_aof_rewrite_ok:bool,
+    // the size of the append only file's parts, when it was last rewritten
+    // and when the running rewrite started
+    aof_current_size:u64,
+    aof_base_size:u64,
+    aof_rewrite_start_size:u64,
+    // the writes of the running command or transaction, with their database,
+    // waiting to be logged together
+    propagated:Vec<(usize, Vec<String>)>,
 }
 
 impl RustisServer {
-    pub fn new(db_count:usize) -> RustisServer {
+    pub fn new(db_count:usize, config:Config) -> RustisServer {
+        let lazyfree = LazyFree::new();
         let mut dbs = Vec::with_capacity(db_count);
         for _ in 0..db_count {
-            dbs.push(RustisDb::new());
+            let mut db = RustisDb::new();
+            db.configure(&config);
+            db.set_lazyfree(lazyfree.clone());
+            dbs.push(db);
         }
+        let mut scripting = Scripting::new();
+        scripting.configure(&config);
+        let mut wasm = Wasm::new();
+        wasm.configure(&config);
         RustisServer {
             client_tokens: (1..MAX_CONNECTIONS+1).collect::<Vec<usize>>(),
             poll: Poll::new().unwrap(),
             connections: HashMap::new(),
             dbs: dbs,
+            config: config,
+            ready_keys: Vec::new(),
+            block_seq: 0,
+            pubsub: PubSub::new(),
+            scripting: scripting,
+            wasm: wasm,
+            modules: Modules::new(),
+            loading: false,
+            bgsave_child: None,
+            bgsave_scheduled: false,
+            lastsave: now_ms() / 1000,
+            last_bgsave_try: 0,
+            last_bgsave_ok: true,
+            dirty_saved: 0,
+            dirty_before_bgsave: 0,
+            dirty: 0,
+            aof: None,
+            aof_write_error: None,
+            manifest: None,
+            aof_rewrite_child: None,
+            aof_rewrite_scheduled: false,
+            aof_rewrite_kept: 0,
+            last_aof_rewrite_try: 0,
+            last_aof_rewrite_ok: true,
+            aof_current_size: 0,
+            aof_base_size: 0,
+            aof_rewrite_start_size: 0,
+            propagated: Vec::new(),
         }
     }
 
-    pub fn run(&mut self, src:String) {
+    // loads a native module before the server starts, for --loadmodule
+    pub fn load_module(&mut self, path:&str, args:Vec<String>) -> Result<(), String> {
+        self.modules.load(path, args)?;
+        self.update_module_event_classes();
+        return Ok(());
+    }
+
+    // runs the server, first importing the RDB file at `import` in place of
+    // the data it would load at startup
+    pub fn run(&mut self, src:String, import:Option<String>) {
+        let imported = import.is_some();
+        if let Some(path) = import {
+            self.import_rdb(&path);
+        } else if self.config.appendonly {
+            // the append only file is replayed before clients are accepted
+            self.load_aof();
+        }
         println!("rustis server listening on {}...", src);
         let addr = src.parse::<SocketAddr>().unwrap();
         let server = TcpListener::bind(&addr).unwrap();
         self.poll.register(&server, LISTENER, Ready::readable(), PollOpt::edge()).unwrap();
+        if let Ok(listener) = server.try_clone() {
+            self.scripting.set_listener(listener);
+        }
         let mut events = Events::with_capacity(EVENT_PREALLOCATE);
+        if !imported && !self.config.appendonly {
+            self.load(&server, &mut events);
+        }
 
         loop {
-            self.poll.poll(&mut events, None).unwrap();
-
-            for event in events.iter() {
-                match event.token() {
-                    LISTENER => {
-                        let (s, _) = server.accept().unwrap();
-                        let token = self.get_client_token();
-                        self.poll.register(&s, Token(token), Ready::readable() | UnixReady::hup(), PollOpt::edge()).unwrap();
-                        self.connections.insert(token, ClientConnection::new(s));
+            let timeout = self.next_block_timeout();
+            self.poll.poll(&mut events, timeout).unwrap();
+            self.handle_events(&server, &events);
+            self.serve_blocked();
+            self.expire_blocked();
+            self.expire_keys();
+            self.check_bgsave();
+            self.check_aof_rewrite();
+            self.save_cron();
+            self.aof_cron();
+        }
+    }
+
+    fn handle_events(&mut self, server:&TcpListener, events:&Events) {
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => {
+                    // the event only comes again for new connections, so
+                    // everyone waiting is accepted
+                    while let Ok((s, _)) = server.accept() {
+                        self.add_connection(s);
                         println!("new connection");
                     }
-                    Token(t) => {
-                        let read = event.readiness().contains(Ready::readable());
-                        let mut hup = event.readiness().contains(UnixReady::hup());
-                        if read {
-                            let mut connection = self.connections.get_mut(&t).unwrap();
-                            let stream = &mut connection.stream;
-                            let buf = &mut connection.buf;
-                            stream.read_to_string(buf);
-                            let parse = Command::parse(buf);
-                            match parse {
-                                ParseResult(parsed_chars, mut c) => {
-                                    while c.len() > 0 {
-                                        let cmd = c.remove(0);
-                                        let mut should_run = true;
-                                        match cmd {
-                                            Command::Select(db) => {
-                                                if db < self.dbs.len() {
-                                                    connection.db = db;
-                                                } else {
-                                                    should_run = false;
-                                                    stream.write_fmt(format_args!("{}", Return::Error("ERR db out of range".to_string()))).unwrap();
-                                                }
-                                            }
-                                            Command::SwapDb(db1, db2) => {
-                                                let dbs = &mut self.dbs;
-                                                dbs.swap(db1, db2);
-                                            }
-                                            Command::FlushAll => {
-                                                let dbs = &mut self.dbs;
-                                                for db in dbs {
-                                                    db.run_command(Command::FlushDb);
-                                                }
-                                            }
-                                            _ => {}
-                                        }
-                                        if should_run {
-                                            let result = self.dbs[connection.db].run_command(cmd);
-                                            stream.write_fmt(format_args!("{}", result)).unwrap();
-                                        }
-                                    }
-                                    buf.drain(0..parsed_chars);
-                                }
-                            }
+                }
+                Token(t) => {
+                    let read = event.readiness().contains(Ready::readable());
+                    let write = event.readiness().contains(Ready::writable());
+                    let hup = event.readiness().contains(UnixReady::hup());
+                    if write {
+                        if let Some(connection) = self.connections.get_mut(&t) {
+                            connection.flush();
+                        }
+                    }
+                    if read {
+                        {
+                            let connection = self.connections.get_mut(&t).unwrap();
+                            let mut bytes = Vec::new();
+                            // reads until the socket would block
+                            let _ = connection.stream.read_to_end(&mut bytes);
+                            connection.buf.push_str(&bytes_to_string(&bytes));
+                            let ParseResult(parsed_chars, commands) = Command::parse(&connection.buf);
+                            connection.queue.extend(commands);
+                            connection.buf.drain(0..parsed_chars);
                         }
-                        if hup {
-                            let connection = self.connections.remove(&t).unwrap();
-                            self.poll.deregister(&connection.stream).unwrap();
-                            self.recycle_client_token(t);
-                            println!("hup");
+                        self.process(t);
+                    }
+                    if hup {
+                        self.unwatch_all(t);
+                        let connection = self.connections.remove(&t).unwrap();
+                        self.poll.deregister(&connection.stream).unwrap();
+                        self.pubsub.remove_client(t);
+                        self.recycle_client_token(t);
+                        println!("hup");
+                    }
+                }
+            }
+        }
+    }
+
+    // loads the snapshot, if there is one, serving clients with -LOADING
+    // replies until it's done
+    fn load(&mut self, server:&TcpListener, events:&mut Events) {
+        let path = self.snapshot_path();
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(_) => return,
+        };
+        let start = now_ms();
+        self.loading = true;
+        if let Err(e) = self.load_snapshot(&data, server, events) {
+            eprintln!("Error loading {}: {}", path.display(), e);
+            process::exit(1);
+        }
+        self.loading = false;
+        println!("DB loaded from disk: {:.3} seconds", (now_ms() - start) as f64 / 1000.0);
+    }
+
+    // loads an RDB file written by redis or rustis, then saves the data as a
+    // snapshot or a new append only file, so it's what later starts load
+    fn import_rdb(&mut self, path:&str) {
+        let start = now_ms();
+        let result = fs::read(path).map_err(|e| format!("Can't open {}: {}", path, e))
+            .and_then(|data| {
+                let mut reader = SnapshotReader::new(&data)?;
+                let now = now_ms();
+                let mut db = 0;
+                while let Some(item) = reader.next()? {
+                    self.load_item(&mut db, item, now)?;
+                }
+                return Ok(());
+            })
+            .and_then(|()| if self.config.appendonly {self.start_aof()} else {self.save()});
+        if let Err(e) = result {
+            eprintln!("Error importing {}: {}", path, e);
+            process::exit(1);
+        }
+        println!("DB imported from {}: {:.3} seconds", path, (now_ms() - start) as f64 / 1000.0);
+    }
+
+    fn load_snapshot(&mut self, data:&[u8], server:&TcpListener, events:&mut Events) -> Result<(), String> {
+        let mut reader = SnapshotReader::new(data)?;
+        let now = now_ms();
+        let mut db = 0;
+        let mut items = 0;
+        while let Some(item) = reader.next()? {
+            self.load_item(&mut db, item, now)?;
+            items += 1;
+            if items % LOADING_POLL_ITEMS == 0 {
+                self.poll.poll(events, Some(Duration::from_millis(0))).unwrap();
+                self.handle_events(server, events);
+            }
+        }
+        return Ok(());
+    }
+
+    fn load_item(&mut self, db:&mut usize, item:Item, now:u64) -> Result<(), String> {
+        match item {
+            Item::SelectDb(index) => {
+                if index >= self.dbs.len() {
+                    return Err(format!("FATAL: Data file was created with a Redis server configured to handle more than {} databases. Exiting", self.dbs.len()));
+                }
+                *db = index;
+            }
+            // keys that expired while the server was down are dropped
+            Item::Entry(key, value, expire_at) => {
+                if expire_at.map_or(true, |at| at > now) {
+                    self.dbs[*db].insert_entry(key, value, expire_at);
+                }
+            }
+            Item::Function(code) => {
+                self.scripting.function_load(&code, true)?;
+            }
+            Item::Aux(ref name, ref value) if name == TRIGGERS_AUX => self.dbs[*db].load_triggers(value)?,
+            Item::Aux(..) => {}
+        }
+        return Ok(());
+    }
+
+    fn aof_dir(&self) -> PathBuf {
+        return Path::new(&self.config.dir).join(&self.config.appenddirname);
+    }
+
+    fn read_manifest(&self) -> Result<Manifest, String> {
+        let name = &self.config.appendfilename;
+        return match fs::read_to_string(self.aof_dir().join(Manifest::file_name(name))) {
+            Ok(text) => Manifest::parse(name, &text),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Manifest::new(name)),
+            Err(e) => Err(format!("Can't open the AOF manifest {}: {}", Manifest::file_name(name), e)),
+        };
+    }
+
+    // writes the manifest to a temporary file first, so a crash leaves
+    // either the old one or the new one
+    fn persist_manifest(&self, manifest:&Manifest) -> Result<(), String> {
+        let dir = self.aof_dir();
+        let name = Manifest::file_name(&self.config.appendfilename);
+        let temp = dir.join(format!("temp-{}", name));
+        return File::create(&temp)
+            .and_then(|mut file| file.write_all(manifest.to_string().as_bytes()).and_then(|()| file.sync_all()))
+            .and_then(|()| fs::rename(&temp, dir.join(&name)))
+            .map_err(|e| format!("Can't persist the AOF manifest {}: {}", name, e));
+    }
+
+    // replays the append only file's parts, then keeps appending to it
+    fn load_aof(&mut self) {
+        if let Err(e) = self.open_aof_files() {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+
+    fn open_aof_files(&mut self) -> Result<(), String> {
+        let dir = self.aof_dir();
+        fs::create_dir_all(&dir).map_err(|e| format!("Can't create the append-only dir {}: {}", dir.display(), e))?;
+        let mut manifest = self.read_manifest()?;
+        // an append only file from before there were parts becomes the base
+        let legacy = Path::new(&self.config.dir).join(&self.config.appendfilename);
+        if manifest.files().is_empty() && legacy.is_file() {
+            let name = self.config.appendfilename.clone();
+            fs::rename(&legacy, dir.join(&name)).map_err(|e| format!("Error moving {} into {}: {}", legacy.display(), dir.display(), e))?;
+            manifest.rewritten(name, 0);
+            self.persist_manifest(&manifest)?;
+            println!("Successfully migrated an old-style AOF into the AOF directory");
+        }
+        let files = manifest.files();
+        let start = now_ms();
+        for (i, file) in files.iter().enumerate() {
+            let path = dir.join(file);
+            let data = fs::read(&path).map_err(|e| format!("Can't open the append-only file {}: {}", path.display(), e))?;
+            self.replay_aof(&path, &data, i + 1 == files.len())?;
+            self.aof_current_size += fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        }
+        if !files.is_empty() {
+            // replaying doesn't count as changes, and isn't logged again
+            for db in self.dbs.iter_mut() {
+                db.take_propagated();
+                db.take_events();
+            }
+            self.dirty_saved = self.changes();
+            println!("DB loaded from append only file: {:.3} seconds", (now_ms() - start) as f64 / 1000.0);
+        }
+        self.aof_base_size = self.aof_current_size;
+        self.manifest = Some(manifest);
+        // a new file starts with a base, and writes are logged after the
+        // last incremental file
+        if files.is_empty() {
+            let temp = dir.join(format!("temp-rewriteaof-{}.aof", process::id()));
+            self.write_base(&temp)?;
+            self.install_base(&temp, 0)?;
+        }
+        let last = self.manifest.as_ref().unwrap().incrs.last().cloned();
+        match last {
+            Some(file) => {
+                let path = dir.join(&file);
+                let aof = Aof::open(&path, self.config.appendfsync).map_err(|e| format!("Can't open the append-only file {}: {}", path.display(), e))?;
+                self.aof = Some(aof);
+            }
+            None => self.open_incr()?,
+        }
+        return Ok(());
+    }
+
+    // switches writes to a new incremental file
+    fn open_incr(&mut self) -> Result<(), String> {
+        let mut manifest = match self.manifest.take() {
+            Some(manifest) => manifest,
+            None => self.read_manifest()?,
+        };
+        let file = manifest.new_incr();
+        let path = self.aof_dir().join(&file);
+        let opened = Aof::open(&path, self.config.appendfsync)
+            .map_err(|e| format!("Can't open the append-only file {}: {}", path.display(), e))
+            .and_then(|aof| self.persist_manifest(&manifest).map(|()| aof));
+        let aof = match opened {
+            Ok(aof) => aof,
+            Err(e) => {
+                manifest.incrs.pop();
+                self.manifest = Some(manifest);
+                return Err(e);
+            }
+        };
+        self.manifest = Some(manifest);
+        if let Some(old) = mem::replace(&mut self.aof, Some(aof)) {
+            if let Err(e) = old.close() {
+                eprintln!("Error closing the AOF file: {}", e);
+            }
+        }
+        return Ok(());
+    }
+
+    // writes a base file with the data as it is now
+    fn write_base(&self, path:&Path) -> Result<(), String> {
+        let data = self.snapshot()?;
+        return File::create(path)
+            .and_then(|mut file| file.write_all(&data).and_then(|()| file.sync_all()))
+            .map_err(|e| format!("Error writing the AOF base {}: {}", path.display(), e));
+    }
+
+    // makes a written base file current, removing the files it replaces
+    fn install_base(&mut self, temp:&Path, kept:usize) -> Result<(), String> {
+        let dir = self.aof_dir();
+        let mut manifest = match self.manifest.clone() {
+            Some(manifest) => manifest,
+            None => self.read_manifest()?,
+        };
+        let base = manifest.next_base();
+        fs::rename(temp, dir.join(&base)).map_err(|e| format!("Error trying to rename the temporary AOF base file: {}", e))?;
+        manifest.rewritten(base.clone(), kept);
+        self.persist_manifest(&manifest)?;
+        if !manifest.history.is_empty() {
+            for file in manifest.history.drain(..) {
+                let _ = fs::remove_file(dir.join(file));
+            }
+            self.persist_manifest(&manifest)?;
+        }
+        self.manifest = Some(manifest);
+        // writes made while the base was written count toward the next rewrite
+        let base_size = fs::metadata(dir.join(&base)).map(|m| m.len()).unwrap_or(0);
+        self.aof_current_size = base_size + self.aof_current_size.saturating_sub(self.aof_rewrite_start_size);
+        self.aof_base_size = self.aof_current_size;
+        return Ok(());
+    }
+
+    // forks a child that writes the data as a new base file, while writes
+    // made in the meantime go to a new incremental file
+    fn bgrewriteaof(&mut self) -> Result<(), String> {
+        self.last_aof_rewrite_try = now_ms() / 1000;
+        let dir = self.aof_dir();
+        fs::create_dir_all(&dir).map_err(|e| format!("ERR Can't create the append-only dir {}: {}", dir.display(), e))?;
+        let kept = if self.aof.is_some() {
+            self.open_incr().map_err(|e| format!("ERR {}", e))?;
+            1
+        } else {
+            0
+        };
+        self.aof_rewrite_start_size = self.aof_current_size;
+        let pid = unsafe {libc::fork()};
+        if pid == 0 {
+            let temp = dir.join(format!("temp-rewriteaof-bg-{}.aof", process::id()));
+            let code = match self.write_base(&temp) {
+                Ok(()) => 0,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    1
+                }
+            };
+            unsafe {libc::_exit(code)};
+        }
+        if pid < 0 {
+            self.last_aof_rewrite_ok = false;
+            return Err(format!("ERR Can't rewrite append only file in background: fork: {}", io::Error::last_os_error()));
+        }
+        println!("Background append only file rewriting started by pid {}", pid);
+        self.aof_rewrite_child = Some(pid);
+        self.aof_rewrite_kept = kept;
+        self.aof_rewrite_scheduled = false;
+        return Ok(());
+    }
+
+    // installs the base a finished rewrite wrote, then starts a scheduled one
+    fn check_aof_rewrite(&mut self) {
+        if let Some(pid) = self.aof_rewrite_child {
+            let mut status = 0;
+            if unsafe {libc::waitpid(pid, &mut status, libc::WNOHANG)} == 0 {
+                return;
+            }
+            self.aof_rewrite_child = None;
+            let temp = self.aof_dir().join(format!("temp-rewriteaof-bg-{}.aof", pid));
+            let mut ok = libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0;
+            if ok {
+                let kept = self.aof_rewrite_kept;
+                if let Err(e) = self.install_base(&temp, kept) {
+                    eprintln!("{}", e);
+                    ok = false;
+                }
+            }
+            if ok {
+                println!("Background AOF rewrite finished successfully");
+            } else {
+                let _ = fs::remove_file(&temp);
+                eprintln!("Background AOF rewrite terminated with error");
+            }
+            self.last_aof_rewrite_ok = ok;
+        }
+        if self.aof_rewrite_scheduled && self.bgsave_child.is_none() {
+            if let Err(e) = self.bgrewriteaof() {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    fn kill_aof_rewrite(&mut self) {
+        if let Some(pid) = self.aof_rewrite_child.take() {
+            let mut status = 0;
+            unsafe {
+                libc::kill(pid, libc::SIGUSR1);
+                libc::waitpid(pid, &mut status, 0);
+            }
+            let _ = fs::remove_file(self.aof_dir().join(format!("temp-rewriteaof-bg-{}.aof", pid)));
+            println!("Killing running AOF rewrite child: {}", pid);
+        }
+        self.aof_rewrite_scheduled = false;
+    }
+
+    // syncs the append only file with appendfsync everysec, and rewrites it
+    // once it grew past auto-aof-rewrite-percentage of its size after the
+    // last rewrite
+    fn aof_cron(&mut self) {
+        match self.aof {
+            Some(ref mut aof) => aof.cron(),
+            None => return,
+        }
+        // what a failed write left buffered is retried
+        if self.aof_write_error.is_some() {
+            self.flush_aof();
+        }
+        if self.bgsave_child.is_some() || self.aof_rewrite_child.is_some() || self.config.auto_aof_rewrite_percentage == 0 {
+            return;
+        }
+        let now = now_ms() / 1000;
+        if !self.last_aof_rewrite_ok && now.saturating_sub(self.last_aof_rewrite_try) <= BGSAVE_RETRY_DELAY {
+            return;
+        }
+        if self.aof_current_size <= self.config.auto_aof_rewrite_min_size {
+            return;
+        }
+        let base = if self.aof_base_size > 0 {self.aof_base_size} else {1};
+        let growth = (self.aof_current_size * 100 / base).saturating_sub(100);
+        if growth >= self.config.auto_aof_rewrite_percentage {
+            println!("Starting automatic rewriting of AOF on {}% growth", growth);
+            if let Err(e) = self.bgrewriteaof() {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    // replays one of the append only file's parts; only the last one may have
+    // been cut short
+    fn replay_aof(&mut self, path:&Path, data:&[u8], last:bool) -> Result<(), String> {
+        let mut db = 0;
+        // the file may start with a snapshot of the data it logs changes to
+        let mut preamble = 0;
+        if data.starts_with(b"REDIS") {
+            println!("Reading RDB preamble from AOF file...");
+            let mut reader = SnapshotReader::new(data)?;
+            let now = now_ms();
+            while let Some(item) = reader.next()? {
+                self.load_item(&mut db, item, now)?;
+            }
+            preamble = reader.len();
+            db = 0;
+        }
+        let (commands, truncated) = aof::parse_log(&data[preamble..])?;
+        // a transaction without its EXEC was cut short too
+        let mut multi = None;
+        let mut end = 0;
+        for &(ref cmd, offset) in commands.iter() {
+            match cmd {
+                &Command::Multi => multi = Some(end),
+                &Command::Exec => multi = None,
+                _ => {}
+            }
+            end = offset;
+        }
+        let valid = match (multi, truncated) {
+            (Some(start), _) => Some(start),
+            (None, truncated) => truncated,
+        };
+        if let Some(valid) = valid {
+            if !last {
+                return Err(format!("Unexpected end of file reading the append only file {}", path.display()));
+            }
+            if !self.config.aof_load_truncated {
+                return Err("Unexpected end of file reading the append only file. You can: 1) Make a backup of your AOF file, then use ./redis-check-aof --fix <filename>. 2) Alternatively you can set the 'aof-load-truncated' configuration option to yes and restart the server.".to_string());
+            }
+            eprintln!("!!! Warning: short read while loading the AOF file {}!!!", path.display());
+            OpenOptions::new().write(true).open(path).and_then(|file| file.set_len((preamble + valid) as u64))
+                .map_err(|e| format!("Error truncating the AOF file: {}", e))?;
+            eprintln!("AOF {} loaded anyway because aof-load-truncated is enabled", path.display());
+        }
+        let mut queued = None;
+        for (cmd, offset) in commands {
+            if valid.map_or(false, |valid| offset > valid) {
+                break;
+            }
+            match cmd {
+                Command::Multi => queued = Some(Vec::new()),
+                Command::Exec => {
+                    for cmd in queued.take().unwrap_or(Vec::new()) {
+                        self.replay(&mut db, cmd)?;
+                    }
+                }
+                cmd => match queued {
+                    Some(ref mut queued) => queued.push(cmd),
+                    None => self.replay(&mut db, cmd)?,
+                },
+            }
+        }
+        return Ok(());
+    }
+
+    // runs a logged command; only commands that can't be run fail loading
+    fn replay(&mut self, db:&mut usize, cmd:Command) -> Result<(), String> {
+        match cmd {
+            Command::Select(index) => {
+                if index >= self.dbs.len() {
+                    return Err(format!("Bad file format reading the append only file: SELECT {} is out of range", index));
+                }
+                *db = index;
+            }
+            Command::SwapDb(a, b) => {
+                if a < self.dbs.len() && b < self.dbs.len() && a != b {
+                    let (a, b) = RustisServer::db_pair(&mut self.dbs, a, b);
+                    RustisDb::swap_data(a, b);
+                }
+            }
+            Command::Move {key, db: dst} => {
+                RustisServer::move_key(&mut self.dbs, *db, &key, dst);
+            }
+            Command::Copy {source, destination, db: Some(dst), replace} => {
+                RustisServer::copy_key(&mut self.dbs, *db, &source, dst, &destination, replace);
+            }
+            Command::FlushAll | Command::FlushAllAsync => {
+                for db in self.dbs.iter_mut() {
+                    db.run_command(Command::FlushDb);
+                }
+            }
+            Command::Eval {..} | Command::EvalSha {..} | Command::FCall {..} => {
+                let _ = self.scripting.eval(&mut self.dbs[*db], cmd, None, Vec::new());
+            }
+            Command::FunctionLoad {code, replace} => {
+                let _ = self.scripting.function_load(&code, replace);
+            }
+            Command::FunctionDelete {library} => {
+                let _ = self.scripting.function_delete(&library);
+            }
+            Command::FunctionFlush => self.scripting.function_flush(),
+            Command::FunctionRestore {payload, policy} => {
+                let _ = self.scripting.function_restore(&payload, policy);
+            }
+            Command::Custom {args} => {
+                if !args.first().map_or(false, |name| self.modules.handles(name)) {
+                    return Err(format!("Unknown command '{}' reading the append only file", args.first().map_or("", |name| name.as_str())));
+                }
+                let _ = self.modules.call(&mut self.dbs[*db], args);
+            }
+            cmd => {
+                self.dbs[*db].run_command(cmd);
+            }
+        }
+        return Ok(());
+    }
+
+    // runs a client's queued commands until it runs out or gets blocked
+    fn process(&mut self, token:usize) {
+        loop {
+            let cmd = match self.connections.get_mut(&token) {
+                Some(ref mut connection) if connection.blocked.is_none() => connection.queue.pop_front(),
+                _ => None,
+            };
+            // commands that didn't parse are unknown unless a module added them
+            let cmd = match cmd {
+                Some(Ok(Command::Custom {ref args})) if !args.first().map_or(false, |name| self.modules.handles(name) || self.wasm.handles(name)) => Some(Err(Command::unknown_command_error(args))),
+                cmd => cmd,
+            };
+            let cmd = match cmd {
+                Some(Ok(_)) if self.loading => Some(Err(LOADING_ERR.to_string())),
+                Some(Ok(cmd)) => match self.write_error() {
+                    Some(e) if self.may_write(&cmd) => Some(Err(e)),
+                    _ => Some(Ok(cmd)),
+                },
+                cmd => cmd,
+            };
+            match cmd {
+                Some(Ok(cmd)) => {
+                    if self.aof.is_some() {
+                        self.connections.get_mut(&token).unwrap().held = Some(Vec::new());
+                    }
+                    self.execute(token, cmd);
+                    self.release_replies(token);
+                    self.publish_events();
+                }
+                Some(Err(e)) => {
+                    // a transaction with a command that didn't parse can't run
+                    if let Some(connection) = self.connections.get_mut(&token) {
+                        if connection.multi.is_some() {
+                            connection.multi_failed = true;
                         }
                     }
+                    self.reply_to(token, &Return::Error(e));
+                }
+                None => return,
+            }
+        }
+    }
+
+    // runs a command, logging the writes it made to the append only file
+    fn execute(&mut self, token:usize, cmd:Command) {
+        if self.aof.is_none() {
+            self.execute_command(token, cmd);
+            return;
+        }
+        let db = self.connections[&token].db;
+        // native module commands are logged as they were sent, instead of
+        // the commands they ran, since their values can't be written as
+        // commands; so are the commands that change data outside of a
+        // database's commands
+        let native = match cmd {
+            Command::Custom {ref args} => args.first().map_or(false, |name| self.modules.handles(name)),
+            _ => false,
+        };
+        let verbatim = native || match cmd {
+            Command::SwapDb(..) | Command::Move {..} |
+            Command::FunctionLoad {..} | Command::FunctionDelete {..} | Command::FunctionFlush | Command::FunctionRestore {..} => true,
+            Command::Copy {db: Some(dst), ..} => dst != db,
+            _ => false,
+        };
+        let verbatim = if verbatim {cmd.args()} else {None};
+        let changes = self.changes();
+        self.execute_command(token, cmd);
+        if native {
+            for db in self.dbs.iter_mut() {
+                db.take_propagated();
+            }
+        }
+        self.collect_propagated();
+        if let Some(args) = verbatim {
+            if self.changes() != changes {
+                self.propagated.push((db, args));
+            }
+        }
+        // a transaction is logged once EXEC is done
+        if self.connections.get(&token).map_or(true, |c| c.replies.is_none()) {
+            self.write_propagated();
+        }
+    }
+
+    fn execute_command(&mut self, token:usize, cmd:Command) {
+        // subscribed clients may only manage their subscriptions and ping
+        if self.pubsub.subscription_count(token) > 0 {
+            match cmd {
+                Command::Subscribe {..} | Command::Unsubscribe {..} |
+                Command::Psubscribe {..} | Command::Punsubscribe {..} => {}
+                Command::Ping {message} => {
+                    self.reply_to(token, &Value::ArrayValue(vec![Value::StrValue("pong".to_string()), Value::StrValue(message.unwrap_or(String::new()))]));
+                    return;
+                }
+                _ => {
+                    let error = format!("ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context", cmd.name());
+                    self.reply_to(token, &Return::Error(error));
+                    return;
+                }
+            }
+        }
+        match cmd {
+            Command::Multi | Command::Exec | Command::Discard | Command::Watch {..} => {
+                self.execute_transaction(token, cmd);
+                return;
+            }
+            _ => {}
+        }
+        if let Some(ref mut queued) = self.connections.get_mut(&token).unwrap().multi {
+            queued.push(cmd);
+            self.reply_to(token, &Return::Queued);
+            return;
+        }
+        match cmd {
+            Command::Unwatch => {
+                self.execute_transaction(token, cmd);
+                return;
+            }
+            Command::Subscribe {..} | Command::Unsubscribe {..} | Command::Psubscribe {..} | Command::Punsubscribe {..} |
+            Command::Publish {..} | Command::PubsubChannels {..} | Command::PubsubNumSub {..} | Command::PubsubNumPat => {
+                self.execute_pubsub(token, cmd);
+                return;
+            }
+            Command::Eval {..} | Command::EvalSha {..} | Command::ScriptLoad {..} |
+            Command::ScriptExists {..} | Command::ScriptFlush | Command::ScriptKill |
+            Command::FCall {..} | Command::FunctionLoad {..} | Command::FunctionDelete {..} | Command::FunctionFlush |
+            Command::FunctionList {..} | Command::FunctionDump | Command::FunctionRestore {..} | Command::FunctionKill => {
+                self.execute_script(token, cmd);
+                return;
+            }
+            // native module commands take precedence over wasm ones
+            Command::ModuleLoad {..} | Command::ModuleUnload {..} | Command::ModuleList => {
+                self.execute_module(token, cmd);
+                return;
+            }
+            Command::Custom {ref args} if args.first().map_or(false, |name| self.modules.handles(name)) => {
+                self.execute_module(token, cmd);
+                return;
+            }
+            Command::WasmLoad {..} | Command::WasmUnload {..} | Command::WasmList | Command::Custom {..} => {
+                self.execute_wasm(token, cmd);
+                return;
+            }
+            Command::Save | Command::BgSave {..} | Command::BgRewriteAof | Command::LastSave | Command::Info {..} => {
+                self.execute_persistence(token, cmd);
+                return;
+            }
+            Command::ConfigSet {parameter, value} => {
+                let result = match self.config_set(&parameter, &value) {
+                    Ok(()) => Return::Ok,
+                    Err(e) => Return::Error(e),
+                };
+                self.reply_to(token, &result);
+                return;
+            }
+            _ => {}
+        }
+        let connection = self.connections.get_mut(&token).unwrap();
+        let mut should_run = true;
+        match cmd {
+            Command::Select(db) => {
+                if db < self.dbs.len() {
+                    connection.db = db;
+                } else {
+                    should_run = false;
+                    RustisServer::reply(connection, &Return::Error("ERR db out of range".to_string()));
+                }
+            }
+            Command::SwapDb(db1, db2) => {
+                should_run = false;
+                if db1 >= self.dbs.len() || db2 >= self.dbs.len() {
+                    RustisServer::reply(connection, &Return::Error("ERR DB index is out of range".to_string()));
+                    return;
+                }
+                if db1 != db2 {
+                    let (a, b) = RustisServer::db_pair(&mut self.dbs, db1, db2);
+                    RustisDb::swap_data(a, b);
+                }
+                RustisServer::reply(connection, &Return::Ok);
+            }
+            Command::Move {ref key, db} => {
+                should_run = false;
+                let result = RustisServer::move_key(&mut self.dbs, connection.db, key, db);
+                RustisServer::reply(connection, &result);
+            }
+            Command::Copy {ref source, ref destination, db: Some(db), replace} if db != connection.db => {
+                should_run = false;
+                let result = RustisServer::copy_key(&mut self.dbs, connection.db, source, db, destination, replace);
+                RustisServer::reply(connection, &result);
+            }
+            Command::ClientNoTouch(on) => {
+                should_run = false;
+                connection.no_touch = on;
+                RustisServer::reply(connection, &Return::Ok);
+            }
+            Command::FlushAll => {
+                let dbs = &mut self.dbs;
+                for db in dbs {
+                    db.run_command(Command::FlushDb);
+                }
+            }
+            Command::FlushAllAsync => {
+                let dbs = &mut self.dbs;
+                for db in dbs {
+                    db.run_command(Command::FlushDbAsync);
+                }
+            }
+            Command::XRead {block: Some(timeout), ..} | Command::XReadGroup {block: Some(timeout), ..} => {
+                if timeout < 0 {
+                    RustisServer::reply(connection, &Return::Error("ERR timeout is negative".to_string()));
+                    return;
+                }
+                let cmd = self.dbs[connection.db].resolve_last_ids(cmd);
+                match self.dbs[connection.db].run_command(cmd.clone()) {
+                    // inside a transaction a read that would block returns right away
+                    Return::ValueReturn(Value::Nil) if connection.replies.is_none() => {
+                        self.block_seq += 1;
+                        connection.blocked = Some(Blocked {
+                            keys: cmd.keys().into_iter().cloned().collect(),
+                            cmd: cmd,
+                            deadline: if timeout > 0 {Some(now_ms() + timeout as u64)} else {None},
+                            seq: self.block_seq,
+                        });
+                    }
+                    result => RustisServer::reply(connection, &result),
                 }
+                return;
+            }
+            Command::ConfigGet {ref parameter} => {
+                should_run = false;
+                let result = match self.config.get(parameter) {
+                    Some(value) => Value::ArrayValue(vec![Value::StrValue(parameter.to_lowercase()), Value::StrValue(value)]),
+                    None => Value::ArrayValue(vec![]),
+                };
+                RustisServer::reply(connection, &result);
             }
+            _ => {}
+        }
+        if should_run {
+            let db = &mut self.dbs[connection.db];
+            // appending to a stream may unblock readers waiting on it
+            if let Command::XAdd {ref key, ..} = cmd {
+                self.ready_keys.push((connection.db, key.clone()));
+            }
+            let result = if connection.no_touch {
+                db.run_command_untouched(cmd)
+            } else {
+                db.run_command(cmd)
+            };
+            RustisServer::reply(connection, &result);
+        }
+    }
+
+    fn execute_transaction(&mut self, token:usize, cmd:Command) {
+        match cmd {
+            Command::Multi => {
+                let result = {
+                    let connection = self.connections.get_mut(&token).unwrap();
+                    if connection.multi.is_some() {
+                        Return::Error("ERR MULTI calls can not be nested".to_string())
+                    } else {
+                        connection.multi = Some(Vec::new());
+                        connection.multi_failed = false;
+                        Return::Ok
+                    }
+                };
+                self.reply_to(token, &result);
+            }
+            Command::Discard => {
+                let result = match self.connections.get_mut(&token).unwrap().multi.take() {
+                    Some(_) => {
+                        self.unwatch_all(token);
+                        Return::Ok
+                    }
+                    None => Return::Error("ERR DISCARD without MULTI".to_string()),
+                };
+                self.reply_to(token, &result);
+            }
+            Command::Watch {keys} => {
+                if self.connections[&token].multi.is_some() {
+                    self.reply_to(token, &Return::Error("ERR WATCH inside MULTI is not allowed".to_string()));
+                    return;
+                }
+                let connection = self.connections.get_mut(&token).unwrap();
+                for key in keys {
+                    if !connection.watched.iter().any(|&(db, ref k, _)| db == connection.db && *k == key) {
+                        let version = self.dbs[connection.db].watch(&key);
+                        connection.watched.push((connection.db, key, version));
+                    }
+                }
+                RustisServer::reply(connection, &Return::Ok);
+            }
+            Command::Unwatch => {
+                self.unwatch_all(token);
+                self.reply_to(token, &Return::Ok);
+            }
+            Command::Exec => {
+                let (queued, failed) = {
+                    let connection = self.connections.get_mut(&token).unwrap();
+                    (connection.multi.take(), connection.multi_failed)
+                };
+                let queued = match queued {
+                    Some(queued) => queued,
+                    None => {
+                        self.reply_to(token, &Return::Error("ERR EXEC without MULTI".to_string()));
+                        return;
+                    }
+                };
+                if failed {
+                    self.unwatch_all(token);
+                    self.reply_to(token, &Return::Error("EXECABORT Transaction discarded because of previous errors.".to_string()));
+                    return;
+                }
+                // writes queued before a background save failed don't run either
+                if let Some(e) = self.write_error() {
+                    if queued.iter().any(|cmd| self.may_write(cmd)) {
+                        self.unwatch_all(token);
+                        self.reply_to(token, &Return::Error(format!("EXECABORT Transaction discarded because of: {}", e)));
+                        return;
+                    }
+                }
+                // a change to any watched key aborts the transaction
+                let watched = self.connections[&token].watched.clone();
+                let changed = watched.iter().any(|&(db, ref key, version)| self.dbs[db].key_version(key) != version);
+                self.unwatch_all(token);
+                if changed {
+                    self.reply_to(token, &Value::Nil);
+                    return;
+                }
+                self.connections.get_mut(&token).unwrap().replies = Some(Vec::with_capacity(queued.len()));
+                for cmd in queued {
+                    self.execute(token, cmd);
+                }
+                let replies = self.connections.get_mut(&token).unwrap().replies.take().unwrap();
+                self.reply_to(token, &format!("*{}\r\n{}", replies.len(), replies.concat()));
+            }
+            _ => {}
+        }
+    }
+
+    fn collect_propagated(&mut self) {
+        for (index, db) in self.dbs.iter_mut().enumerate() {
+            for args in db.take_propagated() {
+                self.propagated.push((index, args));
+            }
+        }
+    }
+
+    // writes the collected writes to the append only file, as a transaction
+    // if there's more than one, so they are replayed all or not at all
+    fn write_propagated(&mut self) {
+        self.collect_propagated();
+        let propagated = mem::replace(&mut self.propagated, Vec::new());
+        let aof = match self.aof {
+            Some(ref mut aof) => aof,
+            None => return,
+        };
+        if propagated.is_empty() {
+            return;
+        }
+        let transaction = propagated.len() > 1;
+        if transaction {
+            aof.feed(propagated[0].0, &["MULTI".to_string()]);
+        }
+        for (db, args) in propagated.iter() {
+            aof.feed(*db, args);
+        }
+        if transaction {
+            aof.feed(propagated[propagated.len() - 1].0, &["EXEC".to_string()]);
+        }
+        self.flush_aof();
+    }
+
+    fn flush_aof(&mut self) {
+        let flushed = match self.aof {
+            Some(ref mut aof) => aof.flush(),
+            None => return,
+        };
+        match flushed {
+            Ok(written) => {
+                self.aof_current_size += written as u64;
+                if self.aof_write_error.take().is_some() {
+                    println!("AOF write error looks solved, Redis can write again.");
+                }
+            }
+            Err(e) => {
+                eprintln!("Error writing to the AOF file: {}", e);
+                if self.config.appendfsync == AppendFsync::Always {
+                    eprintln!("Can't recover from AOF write error when the AOF fsync policy is 'always'. Exiting...");
+                    process::exit(1);
+                }
+                self.aof_write_error = Some(e.to_string());
+            }
+        }
+    }
+
+    fn add_connection(&mut self, stream:TcpStream) -> usize {
+        let token = self.get_client_token();
+        self.poll.register(&stream, Token(token), Ready::readable() | Ready::writable() | UnixReady::hup(), PollOpt::edge()).unwrap();
+        self.connections.insert(token, ClientConnection::new(stream));
+        return token;
+    }
+
+    // sends the replies held while the command's writes were logged
+    fn release_replies(&mut self, token:usize) {
+        if let Some(connection) = self.connections.get_mut(&token) {
+            if let Some(held) = connection.held.take() {
+                connection.write(&string_to_bytes(&held.concat()));
+            }
+        }
+    }
+
+    fn unwatch_all(&mut self, token:usize) {
+        let watched = match self.connections.get_mut(&token) {
+            Some(connection) => mem::replace(&mut connection.watched, Vec::new()),
+            None => return,
+        };
+        for (db, key, _) in watched {
+            self.dbs[db].unwatch(&key);
+        }
+    }
+
+    fn execute_script(&mut self, token:usize, cmd:Command) {
+        match cmd {
+            Command::Eval {..} | Command::EvalSha {..} | Command::FCall {..} => {
+                let db = self.connections[&token].db;
+                // a script running past busy-reply-threshold answers the
+                // other clients itself, so it takes their unparsed input and
+                // unsent output
+                let clients = self.connections.iter_mut().filter(|&(&t, _)| t != token).filter_map(|(&t, c)| match c.stream.try_clone() {
+                    Ok(stream) => Some(BusyClient {
+                        token: Some(t),
+                        stream: stream,
+                        buf: mem::replace(&mut c.buf, String::new()),
+                        out: mem::replace(&mut c.out, Vec::new()),
+                    }),
+                    Err(_) => None,
+                }).collect();
+                // scripts run while writes are refused, but their writes fail
+                let refused = self.write_error();
+                let (result, clients) = self.scripting.eval(&mut self.dbs[db], cmd, refused.as_ref().map(|e| e.as_str()), clients);
+                // and gives back those it accepted
+                for client in clients {
+                    let token = match client.token {
+                        Some(t) => t,
+                        None => self.add_connection(client.stream),
+                    };
+                    if let Some(connection) = self.connections.get_mut(&token) {
+                        connection.buf = client.buf;
+                        connection.out = client.out;
+                        connection.flush();
+                    }
+                }
+                for key in self.scripting.take_ready_keys() {
+                    self.ready_keys.push((db, key));
+                }
+                match result {
+                    Ok(reply) => self.reply_to(token, &reply),
+                    Err(e) => self.reply_to(token, &Return::Error(e)),
+                }
+            }
+            Command::ScriptLoad {script} => {
+                match self.scripting.load(&script) {
+                    Ok(sha) => self.reply_to(token, &Value::StrValue(sha)),
+                    Err(e) => self.reply_to(token, &Return::Error(e)),
+                }
+            }
+            Command::ScriptExists {shas} => {
+                let exists = shas.iter().map(|sha| Value::IntValue(if self.scripting.exists(sha) {1} else {0})).collect();
+                self.reply_to(token, &Value::ArrayValue(exists));
+            }
+            Command::ScriptFlush => {
+                self.scripting.flush();
+                self.reply_to(token, &Return::Ok);
+            }
+            Command::FunctionLoad {code, replace} => {
+                match self.scripting.function_load(&code, replace) {
+                    Ok(library) => {
+                        self.dirty += 1;
+                        self.reply_to(token, &Value::StrValue(library));
+                    }
+                    Err(e) => self.reply_to(token, &Return::Error(e)),
+                }
+            }
+            Command::FunctionDelete {library} => {
+                match self.scripting.function_delete(&library) {
+                    Ok(()) => {
+                        self.dirty += 1;
+                        self.reply_to(token, &Return::Ok);
+                    }
+                    Err(e) => self.reply_to(token, &Return::Error(e)),
+                }
+            }
+            Command::FunctionFlush => {
+                self.scripting.function_flush();
+                self.dirty += 1;
+                self.reply_to(token, &Return::Ok);
+            }
+            Command::FunctionList {pattern, withcode} => {
+                let list = self.scripting.function_list(pattern.as_ref().map(|p| p.as_str()), withcode);
+                self.reply_to(token, &list);
+            }
+            Command::FunctionDump => {
+                let payload = self.scripting.function_dump();
+                self.reply_to(token, &Value::StrValue(payload));
+            }
+            Command::FunctionRestore {payload, policy} => {
+                match self.scripting.function_restore(&payload, policy) {
+                    Ok(()) => {
+                        self.dirty += 1;
+                        self.reply_to(token, &Return::Ok);
+                    }
+                    Err(e) => self.reply_to(token, &Return::Error(e)),
+                }
+            }
+            // scripts that run long enough to be killed get SCRIPT KILL or
+            // FUNCTION KILL from inside their busy hook
+            Command::ScriptKill | Command::FunctionKill => {
+                self.reply_to(token, &Return::Error("NOTBUSY No scripts in execution right now.".to_string()));
+            }
+            _ => {}
+        }
+    }
+
+    fn execute_wasm(&mut self, token:usize, cmd:Command) {
+        let result = match cmd {
+            Command::WasmLoad {path} => self.wasm.load(&path).map(|()| format!("{}", Return::Ok)),
+            Command::WasmUnload {name} => self.wasm.unload(&name).map(|()| format!("{}", Return::Ok)),
+            Command::WasmList => Ok(format!("{}", self.wasm.list())),
+            Command::Custom {args} => {
+                let db = self.connections[&token].db;
+                let result = self.wasm.call(&mut self.dbs[db], args);
+                for key in self.wasm.take_ready_keys() {
+                    self.ready_keys.push((db, key));
+                }
+                result
+            }
+            _ => return,
+        };
+        match result {
+            Ok(reply) => self.reply_to(token, &reply),
+            Err(e) => self.reply_to(token, &Return::Error(e)),
+        }
+    }
+
+    fn execute_module(&mut self, token:usize, cmd:Command) {
+        let loading = match cmd {
+            Command::ModuleLoad {..} | Command::ModuleUnload {..} => true,
+            _ => false,
+        };
+        if loading {
+            let local = self.connections[&token].stream.peer_addr().map_or(false, |addr| addr.ip().is_loopback());
+            if let Err(e) = self.config.check_module_command(local) {
+                self.reply_to(token, &Return::Error(e));
+                return;
+            }
+        }
+        let result = match cmd {
+            Command::ModuleLoad {path, args} => self.modules.load(&path, args).map(|()| format!("{}", Return::Ok)),
+            Command::ModuleUnload {name} => self.modules.unload(&name).map(|()| format!("{}", Return::Ok)),
+            Command::ModuleList => Ok(format!("{}", self.modules.list())),
+            Command::Custom {args} => {
+                let db = self.connections[&token].db;
+                let result = self.modules.call(&mut self.dbs[db], args);
+                for key in self.modules.take_ready_keys() {
+                    self.ready_keys.push((db, key));
+                }
+                result
+            }
+            _ => return,
+        };
+        if loading {
+            self.update_module_event_classes();
+        }
+        match result {
+            Ok(reply) => self.reply_to(token, &reply),
+            Err(e) => self.reply_to(token, &Return::Error(e)),
+        }
+    }
+
+    // the databases record the events modules subscribed to
+    fn update_module_event_classes(&mut self) {
+        let classes = self.modules.event_classes();
+        for db in self.dbs.iter_mut() {
+            db.set_module_event_classes(classes);
+        }
+    }
+
+    fn execute_persistence(&mut self, token:usize, cmd:Command) {
+        let result = match cmd {
+            Command::Save if self.bgsave_child.is_some() => Return::Error("ERR Background save already in progress".to_string()),
+            Command::Save => match self.save() {
+                Ok(()) => Return::Ok,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    Return::Error("ERR".to_string())
+                }
+            },
+            Command::BgSave {schedule: true} if self.bgsave_child.is_some() || self.aof_rewrite_child.is_some() => {
+                self.bgsave_scheduled = true;
+                Return::Status("Background saving scheduled".to_string())
+            }
+            Command::BgSave {..} if self.bgsave_child.is_some() => Return::Error("ERR Background save already in progress".to_string()),
+            Command::BgSave {..} if self.aof_rewrite_child.is_some() => Return::Error("ERR Another child process is active (AOF?): can't BGSAVE right now. Use BGSAVE SCHEDULE in order to schedule a BGSAVE whenever possible.".to_string()),
+            Command::BgSave {..} => match self.bgsave() {
+                Ok(()) => Return::Status("Background saving started".to_string()),
+                Err(e) => Return::Error(e),
+            },
+            Command::BgRewriteAof if self.aof_rewrite_child.is_some() => Return::Error("ERR Background append only file rewriting already in progress".to_string()),
+            Command::BgRewriteAof if self.bgsave_child.is_some() => {
+                self.aof_rewrite_scheduled = true;
+                Return::Status("Background append only file rewriting scheduled".to_string())
+            }
+            Command::BgRewriteAof => match self.bgrewriteaof() {
+                Ok(()) => Return::Status("Background append only file rewriting started".to_string()),
+                Err(e) => Return::Error(e),
+            },
+            Command::LastSave => Return::ValueReturn(Value::IntValue(self.lastsave as i64)),
+            Command::Info {section} => Return::ValueReturn(Value::StrValue(self.info(section))),
+            _ => return,
+        };
+        self.reply_to(token, &result);
+    }
+
+    fn config_set(&mut self, parameter:&str, value:&str) -> Result<(), String> {
+        let appendonly = self.config.appendonly;
+        self.config.set(parameter, value)?;
+        // turning the append only file on starts it with a base holding the
+        // data, so it can be replayed on its own
+        if self.config.appendonly && !appendonly {
+            if let Err(e) = self.start_aof() {
+                self.config.appendonly = false;
+                eprintln!("{}", e);
+                return Err("ERR Background append only file rewriting error".to_string());
+            }
+        }
+        if !self.config.appendonly && appendonly {
+            self.kill_aof_rewrite();
+            if let Some(aof) = self.aof.take() {
+                if let Err(e) = aof.close() {
+                    eprintln!("Error closing the AOF file: {}", e);
+                }
+            }
+        }
+        if let Some(ref mut aof) = self.aof {
+            aof.set_fsync(self.config.appendfsync);
+        }
+        for db in self.dbs.iter_mut() {
+            db.configure(&self.config);
+        }
+        self.scripting.configure(&self.config);
+        self.wasm.configure(&self.config);
+        return Ok(());
+    }
+
+    fn start_aof(&mut self) -> Result<(), String> {
+        self.kill_aof_rewrite();
+        let dir = self.aof_dir();
+        fs::create_dir_all(&dir).map_err(|e| format!("Can't create the append-only dir {}: {}", dir.display(), e))?;
+        let temp = dir.join(format!("temp-rewriteaof-{}.aof", process::id()));
+        self.write_base(&temp)?;
+        self.aof_rewrite_start_size = self.aof_current_size;
+        self.install_base(&temp, 0)?;
+        return self.open_incr();
+    }
+
+    // INFO, which only has the persistence section
+    fn info(&self, section:Option<String>) -> String {
+        match section.map(|s| s.to_lowercase()) {
+            None => {}
+            Some(ref s) if s == "default" || s == "all" || s == "everything" || s == "persistence" => {}
+            _ => return String::new(),
+        }
+        let mut info = format!("# Persistence\r\nloading:{}\r\nrdb_changes_since_last_save:{}\r\nrdb_bgsave_in_progress:{}\r\nrdb_last_save_time:{}\r\nrdb_last_bgsave_status:{}\r\naof_enabled:{}\r\naof_rewrite_in_progress:{}\r\naof_rewrite_scheduled:{}\r\naof_last_bgrewrite_status:{}\r\naof_last_write_status:{}\r\n",
+            self.loading as u8, self.changes() - self.dirty_saved, self.bgsave_child.is_some() as u8,
+            self.lastsave, if self.last_bgsave_ok {"ok"} else {"err"},
+            self.aof.is_some() as u8, self.aof_rewrite_child.is_some() as u8, self.aof_rewrite_scheduled as u8,
+            if self.last_aof_rewrite_ok {"ok"} else {"err"}, if self.aof_write_error.is_none() {"ok"} else {"err"});
+        if self.aof.is_some() {
+            info.push_str(&format!("aof_current_size:{}\r\naof_base_size:{}\r\n", self.aof_current_size, self.aof_base_size));
+        }
+        return info;
+    }
+
+    fn changes(&self) -> u64 {
+        return self.dirty + self.dbs.iter().map(|db| db.changes()).sum::<u64>();
+    }
+
+    // with stop-writes-on-bgsave-error, writes are refused while the save
+    // rules can't be honoured, as they are while the append only file can't
+    // be written
+    fn write_error(&self) -> Option<String> {
+        if self.config.stop_writes_on_bgsave_error && !self.config.save_params.is_empty() && !self.last_bgsave_ok {
+            return Some(MISCONF_ERR.to_string());
+        }
+        return match (&self.aof, &self.aof_write_error) {
+            (&Some(_), &Some(ref e)) => Some(format!("MISCONF Errors writing to the AOF file: {}", e)),
+            _ => None,
+        };
+    }
+
+    // whether a command is refused while writes are: besides the commands
+    // that change data, FCALL of a function without no-writes and module
+    // commands flagged as writes
+    fn may_write(&self, cmd:&Command) -> bool {
+        return match cmd {
+            &Command::FCall {ref function, readonly: false, ..} => self.scripting.function_writes(function),
+            &Command::Custom {ref args} => args.first().map_or(false, |name| self.modules.writes(name) || self.wasm.writes(name)),
+            cmd => cmd.is_write(),
+        };
+    }
+
+    fn snapshot_path(&self) -> PathBuf {
+        return Path::new(&self.config.dir).join(&self.config.dbfilename);
+    }
+
+    fn snapshot(&self) -> Result<Vec<u8>, String> {
+        let mut writer = SnapshotWriter::new(now_ms() / 1000);
+        for code in self.scripting.library_codes() {
+            writer.function(&code)?;
+        }
+        for (index, db) in self.dbs.iter().enumerate() {
+            db.snapshot(index, &mut writer)?;
+        }
+        return Ok(writer.finish());
+    }
+
+    // writes a temporary file and renames it over the old snapshot, so a
+    // failed save never leaves a partial one behind
+    fn write_snapshot(&self) -> Result<(), String> {
+        let data = self.snapshot()?;
+        let temp = Path::new(&self.config.dir).join(format!("temp-{}.rdb", process::id()));
+        let result = File::create(&temp)
+            .and_then(|mut file| file.write_all(&data).and_then(|()| file.sync_all()))
+            .and_then(|()| fs::rename(&temp, self.snapshot_path()));
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temp);
+            return Err(format!("Failed saving the DB in {}: {}", temp.display(), e));
+        }
+        return Ok(());
+    }
+
+    fn save(&mut self) -> Result<(), String> {
+        let changes = self.changes();
+        self.write_snapshot()?;
+        self.dirty_saved = changes;
+        self.lastsave = now_ms() / 1000;
+        self.last_bgsave_ok = true;
+        println!("DB saved on disk");
+        return Ok(());
+    }
+
+    // forks a child that writes the snapshot from its copy of the data while
+    // the server keeps serving clients
+    fn bgsave(&mut self) -> Result<(), String> {
+        self.last_bgsave_try = now_ms() / 1000;
+        self.dirty_before_bgsave = self.changes();
+        let pid = unsafe {libc::fork()};
+        if pid == 0 {
+            let code = match self.write_snapshot() {
+                Ok(()) => 0,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    1
+                }
+            };
+            unsafe {libc::_exit(code)};
+        }
+        if pid < 0 {
+            self.last_bgsave_ok = false;
+            return Err(format!("ERR Can't save in background: fork: {}", io::Error::last_os_error()));
+        }
+        println!("Background saving started by pid {}", pid);
+        self.bgsave_child = Some(pid);
+        self.bgsave_scheduled = false;
+        return Ok(());
+    }
+
+    // reaps a finished background save, then starts a scheduled one
+    fn check_bgsave(&mut self) {
+        if let Some(pid) = self.bgsave_child {
+            let mut status = 0;
+            if unsafe {libc::waitpid(pid, &mut status, libc::WNOHANG)} == 0 {
+                return;
+            }
+            let ok = libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0;
+            if ok {
+                self.dirty_saved = self.dirty_before_bgsave;
+                self.lastsave = now_ms() / 1000;
+                println!("Background saving terminated with success");
+            } else {
+                eprintln!("Background saving error");
+            }
+            self.last_bgsave_ok = ok;
+            self.bgsave_child = None;
+        }
+        if self.bgsave_scheduled && self.aof_rewrite_child.is_none() {
+            if let Err(e) = self.bgsave() {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    fn execute_pubsub(&mut self, token:usize, cmd:Command) {
+        match cmd {
+            Command::Subscribe {channels} => {
+                for channel in channels {
+                    self.pubsub.subscribe(token, &channel);
+                    let count = self.pubsub.subscription_count(token);
+                    self.reply_to(token, &RustisServer::subscription_reply("subscribe", Value::StrValue(channel), count));
+                }
+            }
+            Command::Unsubscribe {channels} => {
+                let channels = if channels.is_empty() {self.pubsub.client_channels(token)} else {channels};
+                if channels.is_empty() {
+                    let count = self.pubsub.subscription_count(token);
+                    self.reply_to(token, &RustisServer::subscription_reply("unsubscribe", Value::Nil, count));
+                }
+                for channel in channels {
+                    self.pubsub.unsubscribe(token, &channel);
+                    let count = self.pubsub.subscription_count(token);
+                    self.reply_to(token, &RustisServer::subscription_reply("unsubscribe", Value::StrValue(channel), count));
+                }
+            }
+            Command::Psubscribe {patterns} => {
+                for pattern in patterns {
+                    self.pubsub.psubscribe(token, &pattern);
+                    let count = self.pubsub.subscription_count(token);
+                    self.reply_to(token, &RustisServer::subscription_reply("psubscribe", Value::StrValue(pattern), count));
+                }
+            }
+            Command::Punsubscribe {patterns} => {
+                let patterns = if patterns.is_empty() {self.pubsub.client_patterns(token)} else {patterns};
+                if patterns.is_empty() {
+                    let count = self.pubsub.subscription_count(token);
+                    self.reply_to(token, &RustisServer::subscription_reply("punsubscribe", Value::Nil, count));
+                }
+                for pattern in patterns {
+                    self.pubsub.punsubscribe(token, &pattern);
+                    let count = self.pubsub.subscription_count(token);
+                    self.reply_to(token, &RustisServer::subscription_reply("punsubscribe", Value::StrValue(pattern), count));
+                }
+            }
+            Command::Publish {channel, message} => {
+                let receivers = self.publish(&channel, &message);
+                self.reply_to(token, &Value::IntValue(receivers as i64));
+            }
+            Command::PubsubChannels {pattern} => {
+                let channels = self.pubsub.active_channels(pattern.as_ref().map(|p| p.as_str()));
+                self.reply_to(token, &Value::ArrayValue(channels.into_iter().map(Value::StrValue).collect()));
+            }
+            Command::PubsubNumSub {channels} => {
+                let mut reply = Vec::with_capacity(channels.len() * 2);
+                for channel in channels {
+                    let count = self.pubsub.subscribers(&channel).len();
+                    reply.push(Value::StrValue(channel));
+                    reply.push(Value::IntValue(count as i64));
+                }
+                self.reply_to(token, &Value::ArrayValue(reply));
+            }
+            Command::PubsubNumPat => {
+                let count = self.pubsub.pattern_count();
+                self.reply_to(token, &Value::IntValue(count as i64));
+            }
+            _ => {}
+        }
+    }
+
+    // sends a message to the channel's subscribers and to the clients with a
+    // matching pattern, returning how many deliveries were made
+    fn publish(&mut self, channel:&str, message:&str) -> usize {
+        let subscribers = self.pubsub.subscribers(channel);
+        let frame = Value::ArrayValue(vec![
+            Value::StrValue("message".to_string()),
+            Value::StrValue(channel.to_string()),
+            Value::StrValue(message.to_string()),
+        ]);
+        for t in subscribers.iter() {
+            self.reply_to(*t, &frame);
+        }
+        let mut receivers = subscribers.len();
+        for (pattern, tokens) in self.pubsub.pattern_subscribers(channel) {
+            let frame = Value::ArrayValue(vec![
+                Value::StrValue("pmessage".to_string()),
+                Value::StrValue(pattern),
+                Value::StrValue(channel.to_string()),
+                Value::StrValue(message.to_string()),
+            ]);
+            for t in tokens.iter() {
+                self.reply_to(*t, &frame);
+            }
+            receivers += tokens.len();
+        }
+        return receivers;
+    }
+
+    // publishes the keyspace events the databases recorded
+    fn publish_events(&mut self) {
+        for db in 0..self.dbs.len() {
+            for (class, event, key) in self.dbs[db].take_events() {
+                self.modules.notify(db, class, &event, &key);
+                if !notify::enabled(self.config.notify_keyspace_events, class) {
+                    continue;
+                }
+                if self.config.notify_keyspace_events & notify::KEYSPACE != 0 {
+                    self.publish(&notify::keyspace_channel(db, &key), &event);
+                }
+                if self.config.notify_keyspace_events & notify::KEYEVENT != 0 {
+                    self.publish(&notify::keyevent_channel(db, &event), &key);
+                }
+            }
+        }
+    }
+
+    fn subscription_reply(kind:&str, channel:Value, count:usize) -> Value {
+        return Value::ArrayValue(vec![Value::StrValue(kind.to_string()), channel, Value::IntValue(count as i64)]);
+    }
+
+    fn reply_to<T:Display>(&mut self, token:usize, reply:&T) {
+        if let Some(connection) = self.connections.get_mut(&token) {
+            RustisServer::reply(connection, reply);
+        }
+    }
+
+    // retries the reads blocked on keys that got new entries, oldest first
+    fn serve_blocked(&mut self) {
+        while let Some((db, key)) = self.ready_keys.pop() {
+            let mut tokens = self.connections.iter().filter_map(|(&t, c)| match c.blocked {
+                Some(ref b) if c.db == db && b.keys.contains(&key) => Some((b.seq, t)),
+                _ => None,
+            }).collect::<Vec<(u64, usize)>>();
+            tokens.sort();
+            for (_, t) in tokens {
+                let cmd = self.connections[&t].blocked.as_ref().unwrap().cmd.clone();
+                let done = match self.dbs[db].run_command(cmd) {
+                    Return::ValueReturn(Value::Nil) => false,
+                    result => {
+                        // the entries a group delivered are logged before the reply
+                        self.write_propagated();
+                        let connection = self.connections.get_mut(&t).unwrap();
+                        RustisServer::reply(connection, &result);
+                        connection.blocked = None;
+                        true
+                    }
+                };
+                self.publish_events();
+                if done {
+                    self.process(t);
+                }
+            }
+        }
+    }
+
+    // answers blocked reads whose timeout has passed with a null reply
+    fn expire_blocked(&mut self) {
+        let now = now_ms();
+        let expired = self.connections.iter().filter(|&(_, c)| match c.blocked {
+            Some(Blocked {deadline: Some(deadline), ..}) => deadline <= now,
+            _ => false,
+        }).map(|(&t, _)| t).collect::<Vec<usize>>();
+        for t in expired {
+            {
+                let connection = self.connections.get_mut(&t).unwrap();
+                connection.blocked = None;
+                RustisServer::reply(connection, &Value::Nil);
+            }
+            self.process(t);
+        }
+    }
+
+    // deletes the keys whose TTL passed even if no client looks them up, so
+    // their memory is freed and their "expired" events fire on time
+    fn expire_keys(&mut self) {
+        for db in self.dbs.iter_mut() {
+            db.active_expire();
+        }
+        self.write_propagated();
+        self.publish_events();
+    }
+
+    // starts a background save once any save rule's number of changes was
+    // made in its number of seconds, retrying failed saves less often
+    fn save_cron(&mut self) {
+        if self.bgsave_child.is_some() || self.aof_rewrite_child.is_some() {
+            return;
+        }
+        let now = now_ms() / 1000;
+        if !self.last_bgsave_ok && now.saturating_sub(self.last_bgsave_try) <= BGSAVE_RETRY_DELAY {
+            return;
+        }
+        let changes = self.changes() - self.dirty_saved;
+        let elapsed = now.saturating_sub(self.lastsave);
+        let rule = self.config.save_params.iter().find(|&&(seconds, min_changes)| changes >= min_changes && elapsed > seconds).cloned();
+        if let Some((seconds, min_changes)) = rule {
+            println!("{} changes in {} seconds. Saving...", min_changes, seconds);
+            if let Err(e) = self.bgsave() {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    // how long the event loop may wait before a blocked read times out, a
+    // key expires, background saves should be checked on, or the append only
+    // file synced
+    fn next_block_timeout(&self) -> Option<Duration> {
+        let now = now_ms();
+        let deadline = self.connections.values().filter_map(|c| c.blocked.as_ref().and_then(|b| b.deadline)).min();
+        let cron = self.bgsave_child.is_some() || self.aof_rewrite_child.is_some() || !self.config.save_params.is_empty() || self.aof.is_some();
+        let waits = [
+            deadline.map(|deadline| deadline.saturating_sub(now)),
+            self.dbs.iter().filter_map(|db| db.next_expire()).min().map(|at| at.saturating_sub(now)),
+            if cron {Some(CRON_MS)} else {None},
+            self.aof.as_ref().and_then(|aof| aof.next_fsync()),
+        ];
+        return waits.iter().filter_map(|&wait| wait).min().map(Duration::from_millis);
+    }
+
+    fn reply<T:Display>(connection:&mut ClientConnection, reply:&T) {
+        match (&mut connection.replies, &mut connection.held) {
+            (&mut Some(ref mut replies), _) | (&mut None, &mut Some(ref mut replies)) => replies.push(format!("{}", reply)),
+            (&mut None, &mut None) => {
+                connection.write(&string_to_bytes(&format!("{}", reply)));
+            }
+        }
+    }
+
+    // borrow two distinct databases mutably at the same time
+    fn db_pair(dbs:&mut Vec<RustisDb>, a:usize, b:usize) -> (&mut RustisDb, &mut RustisDb) {
+        if a < b {
+            let (left, right) = dbs.split_at_mut(b);
+            return (&mut left[a], &mut right[0]);
+        } else {
+            let (left, right) = dbs.split_at_mut(a);
+            return (&mut right[0], &mut left[b]);
+        }
+    }
+
+    fn move_key(dbs:&mut Vec<RustisDb>, src:usize, key:&Key, dst:usize) -> Return {
+        if dst >= dbs.len() {
+            return Return::Error("ERR DB index is out of range".to_string());
+        }
+        if src == dst {
+            return Return::Error("ERR source and destination objects are the same".to_string());
+        }
+        let (from, to) = RustisServer::db_pair(dbs, src, dst);
+        if !from.contains_key(key) || to.contains_key(key) {
+            return Return::ValueReturn(Value::IntValue(0));
+        }
+        let (value, expire_at) = from.remove_entry(key).unwrap();
+        to.insert_entry(key.clone(), value, expire_at);
+        from.notify(notify::GENERIC, "move_from", key);
+        to.notify(notify::GENERIC, "move_to", key);
+        return Return::ValueReturn(Value::IntValue(1));
+    }
+
+    fn copy_key(dbs:&mut Vec<RustisDb>, src:usize, source:&Key, dst:usize, destination:&Key, replace:bool) -> Return {
+        if dst >= dbs.len() {
+            return Return::Error("ERR DB index is out of range".to_string());
+        }
+        let (from, to) = RustisServer::db_pair(dbs, src, dst);
+        if !replace && to.contains_key(destination) {
+            return Return::ValueReturn(Value::IntValue(0));
+        }
+        match from.get_entry(source) {
+            Ok(Some((value, expire_at))) => {
+                to.remove_entry(destination);
+                to.insert_entry(destination.clone(), value, expire_at);
+                to.notify(notify::GENERIC, "copy_to", destination);
+                return Return::ValueReturn(Value::IntValue(1));
+            }
+            Ok(None) => {
+                return Return::ValueReturn(Value::IntValue(0));
+            }
+            Err(e) => return Return::Error(e),
         }
     }
 
@@ -133,3 +1844,46 @@ impl RustisServer {
         self.client_tokens.push(token);
     }
 }
+
+#[test]
+fn test_write_error() {
+    let mut server = RustisServer::new(1, Config::new());
+    assert_eq!(server.write_error(), None);
+    server.last_bgsave_ok = false;
+    assert_eq!(server.write_error(), Some(MISCONF_ERR.to_string()));
+    let code = "#!lua name=lib\nredis.register_function('setk', function(keys, args) return redis.call('set', keys[1], args[1]) end)\nredis.register_function{function_name='getk', callback=function(keys) return redis.call('get', keys[1]) end, flags={'no-writes'}}";
+    server.scripting.function_load(code, false).unwrap();
+    let fcall = |function:&str| Command::FCall {function: function.to_string(), numkeys: 1, args: vec!["k".to_string(), "v".to_string()], readonly: false};
+    assert!(server.may_write(&fcall("setk")));
+    assert!(!server.may_write(&fcall("getk")));
+    // EVAL still runs, but the writes it makes fail
+    let eval = |script:&str| Command::Eval {script: script.to_string(), numkeys: 0, args: vec![], readonly: false};
+    assert!(!server.may_write(&eval("return 1")));
+    let refused = server.write_error();
+    let refused = refused.as_ref().map(|e| e.as_str());
+    assert_eq!(server.scripting.eval(&mut server.dbs[0], eval("return redis.call('get', 'k')"), refused, vec![]).0, Ok("$-1\r\n".to_string()));
+    assert_eq!(server.scripting.eval(&mut server.dbs[0], eval("return redis.call('set', 'k', 'v')"), refused, vec![]).0, Err(MISCONF_ERR.to_string()));
+    assert_eq!(server.scripting.eval(&mut server.dbs[0], eval("return redis.pcall('set', 'k', 'v')"), refused, vec![]).0, Ok(format!("-{}\r\n", MISCONF_ERR)));
+    assert!(!server.dbs[0].contains_key(&"k".to_string()));
+
+    server.last_bgsave_ok = true;
+    server.aof_write_error = Some("No space left on device".to_string());
+    assert_eq!(server.write_error(), None);
+    let path = std::env::temp_dir().join(format!("rustis-test-{}.aof", process::id()));
+    server.aof = Some(Aof::open(&path, AppendFsync::No).unwrap());
+    assert_eq!(server.write_error(), Some("MISCONF Errors writing to the AOF file: No space left on device".to_string()));
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn test_expire_keys() {
+    let mut config = Config::new();
+    config.save_params.clear();
+    let mut server = RustisServer::new(1, config);
+    server.dbs[0].run_command(Command::Set {key: "k".to_string(), value: Value::IntValue(1), exp: Some(20)});
+    assert!(server.next_block_timeout().unwrap() <= Duration::from_millis(20));
+    ::std::thread::sleep(Duration::from_millis(25));
+    server.expire_keys();
+    assert_eq!(server.dbs[0].run_command(Command::DbSize), Return::ValueReturn(Value::IntValue(0)));
+    assert_eq!(server.next_block_timeout(), None);
+}
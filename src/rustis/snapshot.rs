@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use rustis::binary::{bytes_to_string, string_to_bytes};
+use rustis::crc64::crc64;
+use rustis::key::{now_ms, Key};
+use rustis::rdb::{self, RdbReader, RDB_MAX_VERSION, RDB_OPCODE_FUNCTION2};
+use rustis::value::Value;
+
+// SAVE and BGSAVE write RDB files the way redis 7.2 does
+pub const SNAPSHOT_VERSION:u16 = 11;
+
+const RDB_OPCODE_SLOT_INFO:u8 = 244;
+const RDB_OPCODE_MODULE_AUX:u8 = 247;
+const RDB_OPCODE_IDLE:u8 = 248;
+const RDB_OPCODE_FREQ:u8 = 249;
+const RDB_OPCODE_AUX:u8 = 250;
+const RDB_OPCODE_RESIZEDB:u8 = 251;
+const RDB_OPCODE_EXPIRETIME_MS:u8 = 252;
+const RDB_OPCODE_EXPIRETIME:u8 = 253;
+const RDB_OPCODE_SELECTDB:u8 = 254;
+const RDB_OPCODE_EOF:u8 = 255;
+
+// the aux field holding a database's triggers, written after its SELECTDB
+pub const TRIGGERS_AUX:&'static str = "rustis-triggers";
+
+// the oldest version written, which redis 5 and 6 load
+pub const SNAPSHOT_MIN_VERSION:u16 = 9;
+
+pub struct SnapshotWriter {
+    out:Vec<u8>,
+    version:u16,
+}
+
+impl SnapshotWriter {
+    pub fn new(ctime:u64) -> SnapshotWriter {
+        return SnapshotWriter::with_version(ctime, SNAPSHOT_VERSION);
+    }
+
+    // a writer of files in an older or newer version, claiming to be from
+    // the first redis release that wrote it
+    pub fn with_version(ctime:u64, version:u16) -> SnapshotWriter {
+        let redis_ver = match version {
+            v if v <= 9 => "6.2.0",
+            10 => "7.0.0",
+            11 => "7.2.0",
+            _ => "7.4.0",
+        };
+        let mut writer = SnapshotWriter {out: format!("REDIS{:04}", version).into_bytes(), version: version};
+        writer.aux("redis-ver", redis_ver.as_bytes());
+        writer.aux("redis-bits", format!("{}", 8 * ::std::mem::size_of::<usize>()).as_bytes());
+        writer.aux("ctime", ctime.to_string().as_bytes());
+        return writer;
+    }
+
+    pub fn aux(&mut self, name:&str, value:&[u8]) {
+        self.out.push(RDB_OPCODE_AUX);
+        rdb::write_string(&mut self.out, &string_to_bytes(name));
+        rdb::write_string(&mut self.out, value);
+    }
+
+    pub fn function(&mut self, code:&str) -> Result<(), String> {
+        if self.version < 10 {
+            return Err(format!("functions can't be saved in RDB format version {}", self.version));
+        }
+        self.out.push(RDB_OPCODE_FUNCTION2);
+        rdb::write_string(&mut self.out, &string_to_bytes(code));
+        return Ok(());
+    }
+
+    pub fn select_db(&mut self, index:usize, size:usize, expires:usize) {
+        self.out.push(RDB_OPCODE_SELECTDB);
+        rdb::write_length(&mut self.out, index as u64);
+        self.out.push(RDB_OPCODE_RESIZEDB);
+        rdb::write_length(&mut self.out, size as u64);
+        rdb::write_length(&mut self.out, expires as u64);
+    }
+
+    pub fn entry(&mut self, key:&Key, value:&Value, expire_at:Option<u64>) -> Result<(), String> {
+        let t = match rdb::value_type_for(value, self.version) {
+            Some(t) => t,
+            None => return Err(format!("value of '{}' can't be serialized", key)),
+        };
+        if let Some(at) = expire_at {
+            self.out.push(RDB_OPCODE_EXPIRETIME_MS);
+            self.out.extend_from_slice(&at.to_le_bytes());
+        }
+        self.out.push(t);
+        rdb::write_string(&mut self.out, &string_to_bytes(key));
+        return rdb::write_value_as(&mut self.out, value, t);
+    }
+
+    // the file's bytes, ending with EOF and a CRC64 of everything before it
+    pub fn finish(mut self) -> Vec<u8> {
+        self.out.push(RDB_OPCODE_EOF);
+        let crc = crc64(&self.out);
+        self.out.extend_from_slice(&crc.to_le_bytes());
+        return self.out;
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Item {
+    Aux(String, Vec<u8>),
+    Function(String),
+    SelectDb(usize),
+    Entry(Key, Value, Option<u64>),
+}
+
+// reads an RDB file one item at a time, so loading can be interleaved with
+// serving clients. The data may go on after the file, as it does in an
+// append only file with a snapshot preamble
+pub struct SnapshotReader<'a> {
+    data:&'a [u8],
+    reader:RdbReader<'a>,
+    version:u16,
+    done:bool,
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(data:&'a [u8]) -> Result<SnapshotReader<'a>, String> {
+        if data.len() < 9 || &data[..5] != b"REDIS" {
+            return Err("Wrong signature trying to load DB from file".to_string());
+        }
+        let version = match ::std::str::from_utf8(&data[5..9]).ok().and_then(|v| v.parse::<u16>().ok()) {
+            Some(v) if v >= 1 && v <= RDB_MAX_VERSION => v,
+            _ => return Err(format!("Can't handle RDB format version {}", bytes_to_string(&data[5..9]))),
+        };
+        return Ok(SnapshotReader {data: data, reader: RdbReader::new(&data[9..]), version: version, done: false});
+    }
+
+    pub fn version(&self) -> u16 {
+        return self.version;
+    }
+
+    // how many bytes the file took, once it was read to the end
+    pub fn len(&self) -> usize {
+        return 9 + self.reader.position();
+    }
+
+    // files from version 5 end with a checksum, which is zero if disabled
+    fn check_checksum(&mut self) -> Result<(), String> {
+        if self.version < 5 {
+            return Ok(());
+        }
+        let end = self.len();
+        let b = self.reader.read_bytes(8).map_err(|_| "Short read or OOM loading DB. Unrecoverable error, aborting now.".to_string())?;
+        let mut crc = [0u8; 8];
+        crc.copy_from_slice(b);
+        let expected = u64::from_le_bytes(crc);
+        if expected != 0 && crc64(&self.data[..end]) != expected {
+            return Err("Wrong RDB checksum. Aborting now.".to_string());
+        }
+        return Ok(());
+    }
+
+    // the next item, or None after EOF
+    pub fn next(&mut self) -> Result<Option<Item>, String> {
+        if self.done {
+            return Ok(None);
+        }
+        let mut expire_at = None;
+        loop {
+            let opcode = self.reader.read_u8()?;
+            match opcode {
+                RDB_OPCODE_EOF => {
+                    self.check_checksum()?;
+                    self.done = true;
+                    return Ok(None);
+                }
+                RDB_OPCODE_SELECTDB => return Ok(Some(Item::SelectDb(self.reader.read_length()? as usize))),
+                RDB_OPCODE_RESIZEDB => {
+                    self.reader.read_length()?;
+                    self.reader.read_length()?;
+                }
+                RDB_OPCODE_SLOT_INFO => {
+                    for _ in 0..3 {
+                        self.reader.read_length()?;
+                    }
+                }
+                RDB_OPCODE_EXPIRETIME => {
+                    let b = self.reader.read_bytes(4)?;
+                    expire_at = Some(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as u64 * 1000);
+                }
+                RDB_OPCODE_EXPIRETIME_MS => {
+                    let mut b = [0u8; 8];
+                    b.copy_from_slice(self.reader.read_bytes(8)?);
+                    expire_at = Some(u64::from_le_bytes(b));
+                }
+                // LRU and LFU hints aren't kept
+                RDB_OPCODE_IDLE => {
+                    self.reader.read_length()?;
+                }
+                RDB_OPCODE_FREQ => {
+                    self.reader.read_u8()?;
+                }
+                RDB_OPCODE_AUX => {
+                    let name = bytes_to_string(&self.reader.read_string()?);
+                    let value = self.reader.read_string()?;
+                    return Ok(Some(Item::Aux(name, value)));
+                }
+                RDB_OPCODE_FUNCTION2 => return Ok(Some(Item::Function(bytes_to_string(&self.reader.read_string()?)))),
+                RDB_OPCODE_MODULE_AUX => return Err("module aux data is not supported".to_string()),
+                t => {
+                    let key = bytes_to_string(&self.reader.read_string()?);
+                    let value = self.reader.read_value(t)?;
+                    // like redis, a hash whose fields all expired isn't loaded
+                    if let Value::HashValue(ref h) = value {
+                        if h.is_empty() {
+                            expire_at = None;
+                            continue;
+                        }
+                    }
+                    return Ok(Some(Item::Entry(key, value, expire_at)));
+                }
+            }
+        }
+    }
+}
+
+// rewrites an RDB file in another version, e.g. for an older redis to load.
+// The aux fields describing the writer are replaced and the rest are kept
+pub fn convert(data:&[u8], version:u16) -> Result<Vec<u8>, String> {
+    if version < SNAPSHOT_MIN_VERSION || version > RDB_MAX_VERSION {
+        return Err(format!("Can't write RDB format version {}", version));
+    }
+    let mut reader = SnapshotReader::new(data)?;
+    let mut items = Vec::new();
+    // each SELECTDB is followed by the database's sizes, so they're counted
+    // before anything is written
+    let mut sizes = HashMap::new();
+    let mut db = 0;
+    while let Some(item) = reader.next()? {
+        match item {
+            Item::SelectDb(index) => db = index,
+            Item::Entry(_, _, ref expire_at) => {
+                let size = sizes.entry(db).or_insert((0, 0));
+                size.0 += 1;
+                if expire_at.is_some() {
+                    size.1 += 1;
+                }
+            }
+            _ => {}
+        }
+        items.push(item);
+    }
+    let mut writer = SnapshotWriter::with_version(now_ms() / 1000, version);
+    for item in items {
+        match item {
+            Item::Aux(ref name, _) if name == "redis-ver" || name == "redis-bits" || name == "ctime" => {}
+            Item::Aux(name, value) => writer.aux(&name, &value),
+            Item::Function(code) => writer.function(&code)?,
+            Item::SelectDb(index) => {
+                let (size, expires) = sizes.get(&index).cloned().unwrap_or((0, 0));
+                writer.select_db(index, size, expires);
+            }
+            Item::Entry(key, value, expire_at) => writer.entry(&key, &value, expire_at)?,
+        }
+    }
+    return Ok(writer.finish());
+}
+
+#[test]
+fn test_snapshot_round_trip() {
+    let mut writer = SnapshotWriter::new(1700000000);
+    writer.function("#!lua name=lib\nredis.register_function('f', function() return 1 end)").unwrap();
+    writer.select_db(0, 2, 1);
+    writer.entry(&"a".to_string(), &Value::IntValue(300), None).unwrap();
+    writer.entry(&"l".to_string(), &Value::ListValue(vec!["x".to_string(), "".to_string()].into_iter().collect()), Some(1 << 42)).unwrap();
+    writer.select_db(3, 0, 0);
+    writer.aux(TRIGGERS_AUX, b"payload");
+    let data = writer.finish();
+    assert_eq!(&data[..9], b"REDIS0011");
+
+    let mut reader = SnapshotReader::new(&data).unwrap();
+    let mut items = Vec::new();
+    while let Some(item) = reader.next().unwrap() {
+        items.push(item);
+    }
+    assert_eq!(reader.version(), 11);
+    assert_eq!(&items[..2], &[Item::Aux("redis-ver".to_string(), b"7.2.0".to_vec()), Item::Aux("redis-bits".to_string(), b"64".to_vec())]);
+    assert_eq!(&items[3..], &[
+        Item::Function("#!lua name=lib\nredis.register_function('f', function() return 1 end)".to_string()),
+        Item::SelectDb(0),
+        Item::Entry("a".to_string(), Value::IntValue(300), None),
+        Item::Entry("l".to_string(), Value::ListValue(vec!["x".to_string(), "".to_string()].into_iter().collect()), Some(1 << 42)),
+        Item::SelectDb(3),
+        Item::Aux(TRIGGERS_AUX.to_string(), b"payload".to_vec()),
+    ]);
+
+    assert_eq!(reader.len(), data.len());
+
+    let read_all = |data:&[u8]| -> Result<usize, String> {
+        let mut reader = SnapshotReader::new(data)?;
+        while let Some(_) = reader.next()? {}
+        return Ok(reader.len());
+    };
+    let mut corrupt = data.clone();
+    corrupt[30] ^= 1;
+    assert!(read_all(&corrupt).is_err());
+    assert!(SnapshotReader::new(b"REDIS0099").is_err());
+    // a disabled checksum isn't checked
+    let mut unchecked = data[..data.len() - 8].to_vec();
+    unchecked.extend_from_slice(&[0; 8]);
+    assert_eq!(read_all(&unchecked), Ok(data.len()));
+    // whatever follows the file is left alone
+    let mut preamble = data.clone();
+    preamble.extend_from_slice(b"*1\r\n$4\r\nPING\r\n");
+    assert_eq!(read_all(&preamble), Ok(data.len()));
+}
+
+#[test]
+fn test_convert() {
+    let read_all = |data:&[u8]| -> Vec<Item> {
+        let mut reader = SnapshotReader::new(data).unwrap();
+        let mut items = Vec::new();
+        while let Some(item) = reader.next().unwrap() {
+            items.push(item);
+        }
+        return items;
+    };
+    let mut writer = SnapshotWriter::new(1700000000);
+    writer.aux("repl-offset", b"0");
+    writer.select_db(2, 2, 1);
+    writer.entry(&"s".to_string(), &Value::StrValue("x".to_string()), Some(1 << 42)).unwrap();
+    // a hash whose fields all expired is dropped
+    writer.out.push(rdb::RDB_TYPE_HASH_METADATA_PRE_GA);
+    rdb::write_string(&mut writer.out, b"expired");
+    rdb::write_length(&mut writer.out, 1);
+    rdb::write_length(&mut writer.out, 1000);
+    rdb::write_string(&mut writer.out, b"f");
+    rdb::write_string(&mut writer.out, b"v");
+    writer.entry(&"h".to_string(), &Value::HashValue(vec![("f".to_string(), "v".to_string())].into_iter().collect()), None).unwrap();
+    writer.aux(TRIGGERS_AUX, b"payload");
+    let data = writer.finish();
+
+    let converted = convert(&data, 9).unwrap();
+    assert_eq!(&converted[..9], b"REDIS0009");
+    let items = read_all(&converted);
+    assert_eq!(items[0], Item::Aux("redis-ver".to_string(), b"6.2.0".to_vec()));
+    assert_eq!(&items[3..], &[
+        Item::Aux("repl-offset".to_string(), b"0".to_vec()),
+        Item::SelectDb(2),
+        Item::Entry("s".to_string(), Value::StrValue("x".to_string()), Some(1 << 42)),
+        Item::Entry("h".to_string(), Value::HashValue(vec![("f".to_string(), "v".to_string())].into_iter().collect()), None),
+        Item::Aux(TRIGGERS_AUX.to_string(), b"payload".to_vec()),
+    ]);
+    assert_eq!(&read_all(&convert(&converted, 12).unwrap())[3..], &items[3..]);
+
+    let mut writer = SnapshotWriter::new(1700000000);
+    writer.function("#!lua name=lib\nredis.register_function('f', function() return 1 end)").unwrap();
+    let data = writer.finish();
+    assert!(convert(&data, 9).is_err());
+    assert!(convert(&data, 10).is_ok());
+    assert!(convert(&data, 13).is_err());
+}
@@ -0,0 +1,495 @@
+// the stream type: an append-only log of field-value entries keyed by
+// increasing `ms-seq` ids, following redis' t_stream.c
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter, Result};
+use std::u64;
+use rustis::key::now_ms;
+use rustis::value::Value;
+
+// entries per listpack node in redis; `~` trimming only removes whole nodes
+pub const STREAM_NODE_MAX_ENTRIES:usize = 100;
+
+pub const INVALID_ID_ERR:&'static str = "ERR Invalid stream ID specified as stream command argument";
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct StreamId {
+    pub ms:u64,
+    pub seq:u64,
+}
+
+pub const MIN_ID:StreamId = StreamId {ms: 0, seq: 0};
+pub const MAX_ID:StreamId = StreamId {ms: u64::MAX, seq: u64::MAX};
+
+impl StreamId {
+    pub fn new(ms:u64, seq:u64) -> StreamId {
+        return StreamId {ms: ms, seq: seq};
+    }
+
+    // parses `ms-seq`, or a bare `ms` with the given sequence number
+    pub fn parse(s:&str, default_seq:u64) -> Option<StreamId> {
+        let mut parts = s.splitn(2, '-');
+        let ms = match parts.next().and_then(|ms| ms.parse::<u64>().ok()) {
+            Some(ms) => ms,
+            None => return None,
+        };
+        return match parts.next() {
+            Some(seq) => seq.parse::<u64>().ok().map(|seq| StreamId::new(ms, seq)),
+            None => Some(StreamId::new(ms, default_seq)),
+        };
+    }
+
+    pub fn next(&self) -> Option<StreamId> {
+        if self.seq < u64::MAX {
+            return Some(StreamId::new(self.ms, self.seq + 1));
+        }
+        if self.ms < u64::MAX {
+            return Some(StreamId::new(self.ms + 1, 0));
+        }
+        return None;
+    }
+
+    pub fn prev(&self) -> Option<StreamId> {
+        if self.seq > 0 {
+            return Some(StreamId::new(self.ms, self.seq - 1));
+        }
+        if self.ms > 0 {
+            return Some(StreamId::new(self.ms - 1, u64::MAX));
+        }
+        return None;
+    }
+}
+
+impl Display for StreamId {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+// an XRANGE bound: `-`, `+`, or an id that a leading `(` makes exclusive. A
+// bare millisecond time covers every sequence number in it.
+pub fn parse_range_bound(s:&str, start:bool) -> ::std::result::Result<StreamId, String> {
+    if s == "-" {
+        return Ok(MIN_ID);
+    }
+    if s == "+" {
+        return Ok(MAX_ID);
+    }
+    let (exclusive, s) = if s.starts_with('(') {(true, &s[1..])} else {(false, s)};
+    let id = match StreamId::parse(s, if start {0} else {u64::MAX}) {
+        Some(id) => id,
+        None => return Err(INVALID_ID_ERR.to_string()),
+    };
+    if !exclusive {
+        return Ok(id);
+    }
+    let bound = if start {id.next()} else {id.prev()};
+    return bound.ok_or(format!("ERR invalid {} ID for the interval", if start {"start"} else {"end"}));
+}
+
+// how far XADD and XTRIM trim a stream
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TrimTo {
+    MaxLen(usize),
+    MinId(StreamId),
+}
+
+// an entry delivered to a consumer that has not acknowledged it yet
+#[derive(Clone, PartialEq, Debug)]
+pub struct PendingEntry {
+    pub consumer:String,
+    pub delivery_time:u64,
+    pub delivery_count:u64,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Consumer {
+    pub pending:BTreeSet<StreamId>,
+    // when the consumer last tried to read or claim, and last got anything
+    pub seen_time:u64,
+    pub active_time:Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct ConsumerGroup {
+    pub last_id:StreamId,
+    // None when it can't be known, e.g. after XGROUP SETID without ENTRIESREAD
+    pub entries_read:Option<u64>,
+    pub pending:BTreeMap<StreamId, PendingEntry>,
+    pub consumers:BTreeMap<String, Consumer>,
+}
+
+impl ConsumerGroup {
+    pub fn new(last_id:StreamId, entries_read:Option<u64>) -> ConsumerGroup {
+        return ConsumerGroup {
+            last_id: last_id,
+            entries_read: entries_read,
+            pending: BTreeMap::new(),
+            consumers: BTreeMap::new(),
+        };
+    }
+
+    // looks up a consumer, creating it if needed, and marks it as seen
+    pub fn consumer(&mut self, name:&str, now:u64) -> &mut Consumer {
+        let consumer = self.consumers.entry(name.to_string()).or_insert_with(|| Consumer {
+            pending: BTreeSet::new(),
+            seen_time: now,
+            active_time: None,
+        });
+        consumer.seen_time = now;
+        return consumer;
+    }
+
+    // makes `consumer` the owner of a pending entry, taking it from any
+    // previous owner
+    pub fn assign(&mut self, id:StreamId, consumer:&str, delivery_time:u64, delivery_count:u64) {
+        let previous = self.pending.insert(id, PendingEntry {
+            consumer: consumer.to_string(),
+            delivery_time: delivery_time,
+            delivery_count: delivery_count,
+        });
+        if let Some(previous) = previous {
+            if let Some(c) = self.consumers.get_mut(&previous.consumer) {
+                c.pending.remove(&id);
+            }
+        }
+        if let Some(c) = self.consumers.get_mut(consumer) {
+            c.pending.insert(id);
+        }
+    }
+
+    pub fn ack(&mut self, id:&StreamId) -> bool {
+        return match self.pending.remove(id) {
+            Some(entry) => {
+                if let Some(c) = self.consumers.get_mut(&entry.consumer) {
+                    c.pending.remove(id);
+                }
+                true
+            }
+            None => false,
+        };
+    }
+
+    // removes a consumer and its pending entries, returning how many it had
+    pub fn delete_consumer(&mut self, name:&str) -> Option<usize> {
+        let consumer = match self.consumers.remove(name) {
+            Some(consumer) => consumer,
+            None => return None,
+        };
+        for id in consumer.pending.iter() {
+            self.pending.remove(id);
+        }
+        return Some(consumer.pending.len());
+    }
+}
+
+// how XCLAIM and XAUTOCLAIM take over pending entries
+pub struct Claim {
+    pub min_idle:u64,
+    pub delivery_time:u64,
+    pub retrycount:Option<u64>,
+    pub force:bool,
+    pub justid:bool,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum ClaimResult {
+    Claimed,
+    // the entry was no longer in the stream and got dropped from the group
+    Deleted,
+    Skipped,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct Stream {
+    pub entries:BTreeMap<StreamId, Vec<(String, String)>>,
+    pub last_id:StreamId,
+    pub max_deleted_id:StreamId,
+    pub entries_added:u64,
+    pub groups:BTreeMap<String, ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn new() -> Stream {
+        return Stream {
+            entries: BTreeMap::new(),
+            last_id: MIN_ID,
+            max_deleted_id: MIN_ID,
+            entries_added: 0,
+            groups: BTreeMap::new(),
+        };
+    }
+
+    pub fn len(&self) -> usize {
+        return self.entries.len();
+    }
+
+    // the id XADD would give an entry for `*`, `ms-*` or an explicit id
+    pub fn next_id(&self, spec:&str) -> ::std::result::Result<StreamId, String> {
+        let id = if spec == "*" {
+            let ms = now_ms();
+            if ms > self.last_id.ms {
+                StreamId::new(ms, 0)
+            } else {
+                match self.last_id.next() {
+                    Some(id) => id,
+                    None => return Err("ERR The stream has exhausted the last possible ID, unable to add more items".to_string()),
+                }
+            }
+        } else if spec.ends_with("-*") {
+            let ms = match spec[..spec.len() - 2].parse::<u64>() {
+                Ok(ms) => ms,
+                Err(_) => return Err(INVALID_ID_ERR.to_string()),
+            };
+            if ms == self.last_id.ms {
+                match self.last_id.seq.checked_add(1) {
+                    Some(seq) => StreamId::new(ms, seq),
+                    None => return Err("ERR The ID specified in XADD is equal or smaller than the target stream top item".to_string()),
+                }
+            } else {
+                StreamId::new(ms, if ms == 0 {1} else {0})
+            }
+        } else {
+            match StreamId::parse(spec, 0) {
+                Some(id) => id,
+                None => return Err(INVALID_ID_ERR.to_string()),
+            }
+        };
+        if id == MIN_ID {
+            return Err("ERR The ID specified in XADD must be greater than 0-0".to_string());
+        }
+        if id <= self.last_id {
+            return Err("ERR The ID specified in XADD is equal or smaller than the target stream top item".to_string());
+        }
+        return Ok(id);
+    }
+
+    pub fn add(&mut self, id:StreamId, fields:Vec<(String, String)>) {
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        self.entries_added += 1;
+    }
+
+    pub fn range(&self, start:StreamId, end:StreamId, count:Option<usize>, rev:bool) -> Vec<(&StreamId, &Vec<(String, String)>)> {
+        if start > end {
+            return vec![];
+        }
+        let count = count.unwrap_or(usize::MAX);
+        let range = self.entries.range(start..=end);
+        return if rev {range.rev().take(count).collect()} else {range.take(count).collect()};
+    }
+
+    pub fn delete(&mut self, id:&StreamId) -> bool {
+        if self.entries.remove(id).is_none() {
+            return false;
+        }
+        if *id > self.max_deleted_id {
+            self.max_deleted_id = *id;
+        }
+        return true;
+    }
+
+    // removes the oldest entries and returns how many went. An approximate
+    // trim stops at node boundaries and never removes more than `limit`.
+    pub fn trim(&mut self, to:TrimTo, approx:bool, limit:Option<usize>) -> usize {
+        let mut n = match to {
+            TrimTo::MaxLen(len) => self.entries.len().saturating_sub(len),
+            TrimTo::MinId(id) => self.entries.range(..id).count(),
+        };
+        if let Some(limit) = limit {
+            n = n.min(limit);
+        }
+        if approx {
+            n -= n % STREAM_NODE_MAX_ENTRIES;
+        }
+        if n == 0 {
+            return 0;
+        }
+        let rest = match self.entries.keys().nth(n).cloned() {
+            Some(first) => self.entries.split_off(&first),
+            None => BTreeMap::new(),
+        };
+        self.entries = rest;
+        return n;
+    }
+
+    pub fn first_id(&self) -> StreamId {
+        return self.entries.keys().next().cloned().unwrap_or(MIN_ID);
+    }
+
+    // XREADGROUP `>`: delivers the entries after the group's cursor, adding
+    // them to the consumer's pending entries unless noack is set
+    pub fn read_group(&mut self, group:&str, consumer:&str, count:Option<usize>, noack:bool, now:u64) -> Vec<StreamId> {
+        let Stream {ref entries, ref mut groups, entries_added, last_id, ..} = *self;
+        let g = match groups.get_mut(group) {
+            Some(g) => g,
+            None => return vec![],
+        };
+        g.consumer(consumer, now);
+        let ids = match g.last_id.next() {
+            Some(start) => entries.range(start..).take(count.unwrap_or(usize::MAX)).map(|(id, _)| *id).collect(),
+            None => vec![],
+        };
+        if let Some(&last) = ids.last() {
+            g.last_id = last;
+            g.entries_read = if last == last_id {Some(entries_added)} else {g.entries_read.map(|n| n + ids.len() as u64)};
+            g.consumer(consumer, now).active_time = Some(now);
+            if !noack {
+                for id in ids.iter() {
+                    g.assign(*id, consumer, now, 1);
+                }
+            }
+        }
+        return ids;
+    }
+
+    // XREADGROUP with an id: redelivers the consumer's pending entries after it
+    pub fn read_history(&mut self, group:&str, consumer:&str, after:StreamId, count:Option<usize>, now:u64) -> Vec<StreamId> {
+        let g = match self.groups.get_mut(group) {
+            Some(g) => g,
+            None => return vec![],
+        };
+        let ids = match after.next() {
+            Some(start) => g.consumer(consumer, now).pending.range(start..).take(count.unwrap_or(usize::MAX)).cloned().collect::<Vec<StreamId>>(),
+            None => vec![],
+        };
+        for id in ids.iter() {
+            if let Some(entry) = g.pending.get_mut(id) {
+                entry.delivery_time = now;
+                entry.delivery_count += 1;
+            }
+        }
+        return ids;
+    }
+
+    pub fn claim(&mut self, group:&str, consumer:&str, id:StreamId, claim:&Claim, now:u64) -> ClaimResult {
+        let Stream {ref entries, ref mut groups, ..} = *self;
+        let g = match groups.get_mut(group) {
+            Some(g) => g,
+            None => return ClaimResult::Skipped,
+        };
+        g.consumer(consumer, now);
+        if !g.pending.contains_key(&id) {
+            if !claim.force || !entries.contains_key(&id) {
+                return ClaimResult::Skipped;
+            }
+            g.assign(id, consumer, now, 0);
+        }
+        if !entries.contains_key(&id) {
+            g.ack(&id);
+            return ClaimResult::Deleted;
+        }
+        let delivery_count = {
+            let entry = &g.pending[&id];
+            if claim.min_idle > 0 && now.saturating_sub(entry.delivery_time) < claim.min_idle {
+                return ClaimResult::Skipped;
+            }
+            claim.retrycount.unwrap_or(if claim.justid {entry.delivery_count} else {entry.delivery_count + 1})
+        };
+        g.assign(id, consumer, claim.delivery_time, delivery_count);
+        g.consumer(consumer, now).active_time = Some(now);
+        return ClaimResult::Claimed;
+    }
+
+    // XAUTOCLAIM: claims up to count idle entries from start on, looking at no
+    // more than ten times that many. Returns the claimed ids, the deleted ids
+    // that were dropped and the id the next call should start from.
+    pub fn autoclaim(&mut self, group:&str, consumer:&str, start:StreamId, count:usize, claim:&Claim, now:u64) -> (Vec<StreamId>, Vec<StreamId>, StreamId) {
+        let ids = match self.groups.get(group) {
+            Some(g) => g.pending.range(start..).take(count * 10 + 1).map(|(id, _)| *id).collect::<Vec<StreamId>>(),
+            None => vec![],
+        };
+        let (mut claimed, mut deleted) = (vec![], vec![]);
+        let mut cursor = MIN_ID;
+        for (i, id) in ids.into_iter().enumerate() {
+            if claimed.len() == count || i == count * 10 {
+                cursor = id;
+                break;
+            }
+            match self.claim(group, consumer, id, claim, now) {
+                ClaimResult::Claimed => claimed.push(id),
+                ClaimResult::Deleted => deleted.push(id),
+                ClaimResult::Skipped => {}
+            }
+        }
+        return (claimed, deleted, cursor);
+    }
+
+    // entries the group has yet to read, when that can be known; a deletion
+    // past the group's cursor makes counting what's left unreliable
+    pub fn group_lag(&self, g:&ConsumerGroup) -> Option<u64> {
+        if let Some(read) = g.entries_read {
+            return Some(self.entries_added.saturating_sub(read));
+        }
+        if self.max_deleted_id > g.last_id {
+            return None;
+        }
+        return Some(match g.last_id.next() {
+            Some(start) => self.entries.range(start..).count() as u64,
+            None => 0,
+        });
+    }
+}
+
+// an entry as replied by XRANGE: its id followed by the flattened fields
+pub fn entry_value(id:&StreamId, fields:&Vec<(String, String)>) -> Value {
+    let mut flat = Vec::with_capacity(fields.len() * 2);
+    for &(ref field, ref value) in fields {
+        flat.push(Value::StrValue(field.clone()));
+        flat.push(Value::StrValue(value.clone()));
+    }
+    return Value::ArrayValue(vec![Value::StrValue(id.to_string()), Value::ArrayValue(flat)]);
+}
+
+#[test]
+fn test_stream_ids() {
+    let mut s = Stream::new();
+    assert_eq!(s.next_id("0-0"), Err("ERR The ID specified in XADD must be greater than 0-0".to_string()));
+    assert_eq!(s.next_id("0-*"), Ok(StreamId::new(0, 1)));
+    s.add(StreamId::new(5, 3), vec![]);
+    assert_eq!(s.next_id("5-*"), Ok(StreamId::new(5, 4)));
+    assert!(s.next_id("5-3").is_err());
+    assert!(s.next_id("4-*").is_err());
+    assert_eq!(s.next_id("abc"), Err(INVALID_ID_ERR.to_string()));
+    assert!(s.next_id("*").unwrap() > StreamId::new(5, 3));
+    assert_eq!(parse_range_bound("(5-3", true), Ok(StreamId::new(5, 4)));
+    assert_eq!(parse_range_bound("(5-0", false), Ok(StreamId::new(4, u64::MAX)));
+    assert_eq!(parse_range_bound("5", false), Ok(StreamId::new(5, u64::MAX)));
+}
+
+#[test]
+fn test_stream_trim() {
+    let mut s = Stream::new();
+    for i in 1..251 {
+        s.add(StreamId::new(i, 0), vec![("i".to_string(), i.to_string())]);
+    }
+    assert_eq!(s.trim(TrimTo::MaxLen(100), true, None), 100);
+    assert_eq!(s.len(), 150);
+    assert_eq!(s.trim(TrimTo::MinId(StreamId::new(200, 0)), false, None), 99);
+    assert_eq!(s.first_id(), StreamId::new(200, 0));
+    assert_eq!(s.trim(TrimTo::MaxLen(0), false, Some(10)), 10);
+    assert_eq!(s.len(), 41);
+}
+
+#[test]
+fn test_consumer_group() {
+    let mut s = Stream::new();
+    for i in 1..6 {
+        s.add(StreamId::new(i, 0), vec![]);
+    }
+    s.groups.insert("g".to_string(), ConsumerGroup::new(MIN_ID, Some(0)));
+    assert_eq!(s.read_group("g", "alice", Some(3), false, 1000), vec![StreamId::new(1, 0), StreamId::new(2, 0), StreamId::new(3, 0)]);
+    assert_eq!(s.group_lag(&s.groups["g"]), Some(2));
+    assert_eq!(s.read_history("g", "alice", StreamId::new(1, 0), None, 1500), vec![StreamId::new(2, 0), StreamId::new(3, 0)]);
+    assert_eq!(s.groups["g"].pending[&StreamId::new(2, 0)].delivery_count, 2);
+    s.delete(&StreamId::new(3, 0));
+    let claim = Claim {min_idle: 100, delivery_time: 2000, retrycount: None, force: false, justid: false};
+    let (claimed, deleted, cursor) = s.autoclaim("g", "bob", MIN_ID, 10, &claim, 2000);
+    assert_eq!(claimed, vec![StreamId::new(1, 0), StreamId::new(2, 0)]);
+    assert_eq!(deleted, vec![StreamId::new(3, 0)]);
+    assert_eq!(cursor, MIN_ID);
+    assert_eq!(s.groups["g"].consumers["alice"].pending.len(), 0);
+    assert_eq!(s.groups["g"].pending[&StreamId::new(2, 0)].delivery_count, 3);
+    assert!(s.groups.get_mut("g").unwrap().ack(&StreamId::new(1, 0)));
+    assert_eq!(s.groups.get_mut("g").unwrap().delete_consumer("bob"), Some(1));
+    assert_eq!(s.groups["g"].pending.len(), 0);
+}
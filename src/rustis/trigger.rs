@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+use nom::IResult;
+use rustis::command::{Command, RestorePolicy};
+use rustis::parse::{command_parser, words_parser};
+use rustis::pubsub::glob_match;
+use rustis::rdb;
+use rustis::scripting::allowed_in_script;
+use rustis::value::Value;
+
+// what a trigger's commands say in place of the key that fired it
+pub const KEY_PLACEHOLDER:&'static str = "{key}";
+
+// commands a database runs whenever a keyspace event matching `event`
+// happens to a key matching `pattern`, both glob style
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trigger {
+    pub name:String,
+    pub pattern:String,
+    pub event:String,
+    // each command split into its arguments
+    commands:Vec<Vec<String>>,
+}
+
+impl Trigger {
+    pub fn new(name:String, pattern:String, event:String, commands:&[String]) -> Result<Trigger, String> {
+        let mut parsed = Vec::new();
+        for command in commands {
+            let words = match words_parser(command) {
+                IResult::Done("", ref words) if !words.is_empty() => words.clone(),
+                _ => return Err(format!("ERR Invalid trigger command '{}'", command)),
+            };
+            match trigger_command(&words, "key") {
+                Some(ref cmd) if allowed_in_script(cmd) => {}
+                _ => return Err(format!("ERR Invalid trigger command '{}'", command)),
+            }
+            parsed.push(words);
+        }
+        return Ok(Trigger {name: name, pattern: pattern, event: event, commands: parsed});
+    }
+
+    pub fn matches(&self, event:&str, key:&str) -> bool {
+        return glob_match(&self.event, event) && glob_match(&self.pattern, key);
+    }
+
+    // the trigger's commands as they were given
+    pub fn definitions(&self) -> Vec<String> {
+        return self.commands.iter().map(|words| {
+            words.iter().map(|word| Command::quote(word)).collect::<Vec<String>>().join(" ")
+        }).collect();
+    }
+
+    // the commands to run for a key, which were all checked to parse
+    pub fn commands_for(&self, key:&str) -> Vec<Command> {
+        return self.commands.iter().filter_map(|words| trigger_command(words, key)).collect();
+    }
+}
+
+fn trigger_command(words:&[String], key:&str) -> Option<Command> {
+    let joined = words.iter().map(|word| Command::quote(&word.replace(KEY_PLACEHOLDER, key))).collect::<Vec<String>>().join(" ");
+    return match command_parser(&joined) {
+        IResult::Done("", cmd) => Some(cmd),
+        _ => None,
+    };
+}
+
+pub struct Triggers {
+    triggers:BTreeMap<String, Trigger>,
+}
+
+impl Triggers {
+    pub fn new() -> Triggers {
+        return Triggers {triggers: BTreeMap::new()};
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.triggers.is_empty();
+    }
+
+    pub fn create(&mut self, trigger:Trigger, replace:bool) -> Result<(), String> {
+        if !replace && self.triggers.contains_key(&trigger.name) {
+            return Err(format!("ERR Trigger '{}' already exists", trigger.name));
+        }
+        self.triggers.insert(trigger.name.clone(), trigger);
+        return Ok(());
+    }
+
+    pub fn delete(&mut self, name:&str) -> Result<(), String> {
+        return match self.triggers.remove(name) {
+            Some(_) => Ok(()),
+            None => Err("ERR Trigger not found".to_string()),
+        };
+    }
+
+    pub fn list(&self) -> Value {
+        return Value::ArrayValue(self.triggers.values().map(|trigger| Value::ArrayValue(vec![
+            Value::StrValue("name".to_string()), Value::StrValue(trigger.name.clone()),
+            Value::StrValue("pattern".to_string()), Value::StrValue(trigger.pattern.clone()),
+            Value::StrValue("event".to_string()), Value::StrValue(trigger.event.clone()),
+            Value::StrValue("commands".to_string()),
+            Value::ArrayValue(trigger.definitions().into_iter().map(Value::StrValue).collect()),
+        ])).collect());
+    }
+
+    // the commands of every trigger an event fires, in trigger name order
+    pub fn fired(&self, event:&str, key:&str) -> Vec<Command> {
+        return self.triggers.values().filter(|trigger| trigger.matches(event, key)).flat_map(|trigger| trigger.commands_for(key)).collect();
+    }
+
+    // TRIGGER DUMP's payload, each trigger as its name, pattern, event and
+    // commands
+    pub fn dump(&self) -> Vec<u8> {
+        return rdb::dump_triggers(&self.triggers.values().map(|trigger| {
+            let mut fields = vec![trigger.name.clone(), trigger.pattern.clone(), trigger.event.clone()];
+            fields.extend(trigger.definitions());
+            fields
+        }).collect::<Vec<Vec<String>>>());
+    }
+
+    // restores dumped triggers, leaving the triggers as they were if any of
+    // them can't be
+    pub fn restore(&mut self, payload:&[u8], policy:RestorePolicy) -> Result<(), String> {
+        let mut triggers = if policy == RestorePolicy::Flush {BTreeMap::new()} else {self.triggers.clone()};
+        for mut fields in rdb::restore_triggers(payload)? {
+            if fields.len() < 4 {
+                return Err("ERR Bad data format".to_string());
+            }
+            let commands = fields.split_off(3);
+            let event = fields.pop().unwrap();
+            let pattern = fields.pop().unwrap();
+            let name = fields.pop().unwrap();
+            if policy == RestorePolicy::Append && triggers.contains_key(&name) {
+                return Err(format!("ERR Trigger '{}' already exists", name));
+            }
+            triggers.insert(name.clone(), Trigger::new(name, pattern, event, &commands)?);
+        }
+        self.triggers = triggers;
+        return Ok(());
+    }
+}
+
+#[test]
+fn test_triggers() {
+    let commands = |c:&[&str]| c.iter().map(|s| s.to_string()).collect::<Vec<String>>();
+    let mut triggers = Triggers::new();
+    assert_eq!(Trigger::new("t".to_string(), "*".to_string(), "*".to_string(), &commands(&["NOPE x"])), Err("ERR Invalid trigger command 'NOPE x'".to_string()));
+    assert_eq!(Trigger::new("t".to_string(), "*".to_string(), "*".to_string(), &commands(&["SELECT 1"])), Err("ERR Invalid trigger command 'SELECT 1'".to_string()));
+    let trigger = Trigger::new("count".to_string(), "user:*".to_string(), "s*".to_string(), &commands(&["INCR users:writes", "SADD \"changed users\" {key}"])).unwrap();
+    assert_eq!(trigger.definitions(), commands(&["INCR users:writes", "SADD \"changed users\" {key}"]));
+    triggers.create(trigger.clone(), false).unwrap();
+    assert_eq!(triggers.create(trigger, false), Err("ERR Trigger 'count' already exists".to_string()));
+
+    assert_eq!(triggers.fired("del", "user:1"), vec![]);
+    assert_eq!(triggers.fired("set", "session:1"), vec![]);
+    assert_eq!(triggers.fired("set", "user:1"), vec![
+        Command::Incr {key: "users:writes".to_string()},
+        Command::Sadd {key: "changed users".to_string(), members: vec!["user:1".to_string()]},
+    ]);
+
+    let payload = triggers.dump();
+    let mut restored = Triggers::new();
+    restored.restore(&payload, RestorePolicy::Append).unwrap();
+    assert_eq!(format!("{}", restored.list()), format!("{}", triggers.list()));
+    assert_eq!(restored.restore(&payload, RestorePolicy::Append), Err("ERR Trigger 'count' already exists".to_string()));
+    assert_eq!(restored.restore(&payload, RestorePolicy::Replace), Ok(()));
+    assert_eq!(restored.delete("count"), Ok(()));
+    assert_eq!(restored.delete("count"), Err("ERR Trigger not found".to_string()));
+    assert!(restored.is_empty());
+}
@@ -1,5 +1,9 @@
-use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::{Display, Formatter, Result};
+use rustis::binary::byte_len;
+use rustis::module::ModuleValue;
+use rustis::stream::Stream;
+use rustis::zset::SortedSet;
 
 #[derive(Clone, PartialEq, Debug)]
 pub enum Value {
@@ -9,15 +13,66 @@ pub enum Value {
     ArrayValue(Vec<Value>),
     ListValue(VecDeque<String>),
     SetValue(HashSet<String>),
-    SortedSetValue(BTreeMap<String, f64>),
+    SortedSetValue(SortedSet),
     HashValue(HashMap<String, String>),
+    StreamValue(Stream),
+    ModuleValue(ModuleValue),
+}
+
+// size limits for the compact encodings reported by OBJECT ENCODING
+const MAX_LISTPACK_ENTRIES:usize = 128;
+const MAX_LISTPACK_VALUE:usize = 64;
+const MAX_INTSET_ENTRIES:usize = 512;
+const MAX_EMBSTR_LEN:usize = 44;
+
+impl Value {
+    // a copy for COPY, which module values can refuse
+    pub fn try_clone(&self) -> ::std::result::Result<Value, String> {
+        return match self {
+            &Value::ModuleValue(ref v) => v.try_clone().map(Value::ModuleValue),
+            v => Ok(v.clone()),
+        };
+    }
+
+    // the name redis would give to this value's internal representation
+    pub fn encoding(&self) -> &'static str {
+        fn small<'a, I:Iterator<Item=&'a String>>(len:usize, mut items:I) -> bool {
+            return len <= MAX_LISTPACK_ENTRIES && items.all(|s| byte_len(s) <= MAX_LISTPACK_VALUE);
+        }
+        match self {
+            &Value::IntValue(_) => "int",
+            &Value::StrValue(ref s) => {
+                if s.len() <= 20 && s.parse::<i64>().is_ok() {
+                    "int"
+                } else if byte_len(s) <= MAX_EMBSTR_LEN {
+                    "embstr"
+                } else {
+                    "raw"
+                }
+            }
+            &Value::ListValue(ref l) => if small(l.len(), l.iter()) {"listpack"} else {"quicklist"},
+            &Value::SetValue(ref s) => {
+                if s.len() <= MAX_INTSET_ENTRIES && s.iter().all(|m| m.parse::<i64>().is_ok()) {
+                    "intset"
+                } else if small(s.len(), s.iter()) {
+                    "listpack"
+                } else {
+                    "hashtable"
+                }
+            }
+            &Value::SortedSetValue(ref z) => if small(z.len(), z.keys()) {"listpack"} else {"skiplist"},
+            &Value::HashValue(ref h) => if small(h.len(), h.keys().chain(h.values())) {"listpack"} else {"hashtable"},
+            &Value::StreamValue(_) => "stream",
+            _ => "raw",
+        }
+    }
 }
 
 impl Display for Value {
     fn fmt(&self, f: &mut Formatter) -> Result {
         match self {
             &Value::IntValue(ref i) => write!(f, ":{}\r\n", i),
-            &Value::StrValue(ref s) => write!(f, "${}\r\n{}\r\n", s.len(), s),
+            &Value::StrValue(ref s) => write!(f, "${}\r\n{}\r\n", byte_len(s), s),
             &Value::ArrayValue(ref a) => write!(f, "*{}\r\n{}", a.len(), a.iter().map(|ref x| format!("{}", x)).collect::<Vec<String>>().join("")),
             _ => write!(f, "$-1\r\n"),
         }
@@ -43,6 +98,7 @@ fn test_str() {
     assert_eq!(format!("{}", Value::StrValue("a".to_string())), "$1\r\na\r\n");
     assert_eq!(format!("{}", Value::StrValue("abc def".to_string())), "$7\r\nabc def\r\n");
     assert_eq!(format!("{}", Value::StrValue("abc\ndefg".to_string())), "$8\r\nabc\ndefg\r\n");
+    assert_eq!(format!("{}", Value::StrValue("\u{0}\u{ff}".to_string())), "$2\r\n\u{0}\u{ff}\r\n");
 }
 
 #[test]
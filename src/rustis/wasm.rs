@@ -0,0 +1,488 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::mem;
+use std::path::Path;
+use std::ptr;
+use wasmi::{self, Caller, Engine, Extern, Instance, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder};
+use wasmi::core::TrapCode;
+use rustis::binary::{byte_len, bytes_to_string, string_to_bytes};
+use rustis::command::{Command, Return};
+use rustis::config::Config;
+use rustis::db::RustisDb;
+use rustis::key::{Key, now_ms};
+use rustis::parse::ParseResult;
+use rustis::scripting::allowed_in_script;
+use rustis::value::Value;
+
+// user commands implemented by WebAssembly modules, run sandboxed with a
+// fuel, time and memory budget.
+//
+// A module exports its `memory` and a `rustis_init` function, which
+// registers its commands with the host API it imports from "rustis":
+//
+//   register_command(name_ptr, name_len, handler_ptr, handler_len, flags) -> i32
+//   arg_count() -> i32
+//   arg(index, ptr, cap) -> i32      copies an argument, returning its length
+//   call(ptr, len) -> i32            runs a command written as a RESP array,
+//                                    returning the length of its RESP reply
+//   result(ptr, cap) -> i32          copies the last call's reply
+//   reply_ok(), reply_nil(), reply_int(i64), reply_str(ptr, len),
+//   reply_error(ptr, len), reply_array(len)
+//
+// Handlers are exports taking and returning nothing. Built in commands take
+// precedence over module commands with the same name.
+
+// the command may write to the dataset
+pub const FLAG_WRITE:i32 = 1;
+
+// roughly the fuel a slow machine burns in a millisecond, so code that never
+// calls the host, and can't be stopped by the clock, still runs out of fuel
+// in about the time limit
+const FUEL_PER_MS:u64 = 100000;
+
+struct Host {
+    limits:StoreLimits,
+    // the database of the command being run, only set while one runs
+    db:*mut RustisDb,
+    write:bool,
+    deadline:u64,
+    // commands registered by rustis_init, as (name, handler, flags)
+    registered:Vec<(String, String, i32)>,
+    initializing:bool,
+    args:Vec<String>,
+    result:Vec<u8>,
+    reply:String,
+    // how many more values the reply needs, counting array elements
+    pending:usize,
+    ready_keys:Vec<Key>,
+}
+
+struct WasmModule {
+    path:String,
+    store:Store<Host>,
+    instance:Instance,
+    commands:Vec<String>,
+}
+
+struct WasmCommand {
+    module:String,
+    handler:String,
+    flags:i32,
+}
+
+pub struct Wasm {
+    engine:Engine,
+    linker:Linker<Host>,
+    modules:BTreeMap<String, WasmModule>,
+    // by lowercase command name
+    commands:HashMap<String, WasmCommand>,
+    fuel:u64,
+    time_limit:u64,
+    memory_limit:usize,
+    ready_keys:Vec<Key>,
+}
+
+impl Wasm {
+    pub fn new() -> Wasm {
+        let mut config = wasmi::Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let linker = host_api(&engine);
+        let defaults = Config::new();
+        return Wasm {
+            engine: engine,
+            linker: linker,
+            modules: BTreeMap::new(),
+            commands: HashMap::new(),
+            fuel: defaults.wasm_fuel_limit,
+            time_limit: defaults.wasm_time_limit,
+            memory_limit: defaults.wasm_memory_limit,
+            ready_keys: Vec::new(),
+        };
+    }
+
+    pub fn configure(&mut self, config:&Config) {
+        self.fuel = config.wasm_fuel_limit;
+        self.time_limit = config.wasm_time_limit;
+        self.memory_limit = config.wasm_memory_limit;
+        for module in self.modules.values_mut() {
+            module.store.data_mut().limits = memory_limits(self.memory_limit);
+        }
+    }
+
+    // the fuel of a call, and whether the time limit is what bounds it
+    fn budget(&self) -> (u64, bool) {
+        let timed = self.time_limit.saturating_mul(FUEL_PER_MS);
+        return if timed < self.fuel {(timed, true)} else {(self.fuel, false)};
+    }
+
+    pub fn handles(&self, name:&str) -> bool {
+        return self.commands.contains_key(&name.to_lowercase());
+    }
+
+    // whether a command was registered with the write flag
+    pub fn writes(&self, name:&str) -> bool {
+        return self.commands.get(&name.to_lowercase()).map_or(false, |command| command.flags & FLAG_WRITE != 0);
+    }
+
+    // loads a module, naming it after its file, and runs its rustis_init
+    pub fn load(&mut self, path:&str) -> Result<(), String> {
+        let name = match Path::new(path).file_stem() {
+            Some(stem) => stem.to_string_lossy().into_owned(),
+            None => return Err("ERR Error loading the module: invalid path".to_string()),
+        };
+        let mut bytes = Vec::new();
+        if let Err(e) = File::open(path).and_then(|mut f| f.read_to_end(&mut bytes)) {
+            return Err(format!("ERR Error loading the module: {}", e));
+        }
+        return self.load_module(name, path, &bytes);
+    }
+
+    fn load_module(&mut self, name:String, path:&str, bytes:&[u8]) -> Result<(), String> {
+        if self.modules.contains_key(&name) {
+            return Err(format!("ERR Error loading the module: a module named '{}' is already loaded", name));
+        }
+        let module = Module::new(&self.engine, bytes).map_err(|e| format!("ERR Error loading the module: {}", e))?;
+        let mut store = Store::new(&self.engine, Host {
+            limits: memory_limits(self.memory_limit),
+            db: ptr::null_mut(),
+            write: false,
+            deadline: now_ms() + self.time_limit,
+            registered: Vec::new(),
+            initializing: true,
+            args: Vec::new(),
+            result: Vec::new(),
+            reply: String::new(),
+            pending: 0,
+            ready_keys: Vec::new(),
+        });
+        store.limiter(|host| &mut host.limits);
+        let (fuel, timed) = self.budget();
+        store.set_fuel(fuel).unwrap();
+        let instance = self.linker.instantiate(&mut store, &module)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(|e| format!("ERR Error loading the module: {}", e))?;
+        let init = instance.get_typed_func::<(), ()>(&store, "rustis_init")
+            .map_err(|_| "ERR Error loading the module: it doesn't export rustis_init".to_string())?;
+        if let Err(e) = init.call(&mut store, ()) {
+            return Err(format!("ERR Error loading the module: {}", trap_message(&e, timed)));
+        }
+        let registered = {
+            let host = store.data_mut();
+            host.initializing = false;
+            mem::replace(&mut host.registered, Vec::new())
+        };
+        for &(ref command, ref handler, _) in registered.iter() {
+            if self.commands.contains_key(command) {
+                return Err(format!("ERR Error loading the module: command '{}' is already registered", command));
+            }
+            if instance.get_typed_func::<(), ()>(&store, handler).is_err() {
+                return Err(format!("ERR Error loading the module: '{}' isn't an exported function", handler));
+            }
+        }
+        let mut commands = Vec::new();
+        for (command, handler, flags) in registered {
+            commands.push(command.clone());
+            self.commands.insert(command, WasmCommand {module: name.clone(), handler: handler, flags: flags});
+        }
+        self.modules.insert(name, WasmModule {
+            path: path.to_string(),
+            store: store,
+            instance: instance,
+            commands: commands,
+        });
+        return Ok(());
+    }
+
+    pub fn unload(&mut self, name:&str) -> Result<(), String> {
+        return match self.modules.remove(name) {
+            Some(module) => {
+                for command in module.commands {
+                    self.commands.remove(&command);
+                }
+                Ok(())
+            }
+            None => Err("ERR Error unloading module: no such module with that name".to_string()),
+        };
+    }
+
+    pub fn list(&self) -> Value {
+        return Value::ArrayValue(self.modules.iter().map(|(name, module)| Value::ArrayValue(vec![
+            Value::StrValue("name".to_string()), Value::StrValue(name.clone()),
+            Value::StrValue("path".to_string()), Value::StrValue(module.path.clone()),
+            Value::StrValue("commands".to_string()),
+            Value::ArrayValue(module.commands.iter().map(|c| Value::StrValue(c.clone())).collect()),
+        ])).collect());
+    }
+
+    // runs a module command against a database, returning its RESP reply
+    pub fn call(&mut self, db:&mut RustisDb, args:Vec<String>) -> Result<String, String> {
+        let commands = &self.commands;
+        let command = match args.first().and_then(|name| commands.get(&name.to_lowercase())) {
+            Some(command) => command,
+            None => return Err(Command::unknown_command_error(&args)),
+        };
+        let (fuel, timed) = self.budget();
+        let module = self.modules.get_mut(&command.module).unwrap();
+        {
+            let host = module.store.data_mut();
+            host.db = db as *mut RustisDb;
+            host.write = command.flags & FLAG_WRITE != 0;
+            host.deadline = now_ms() + self.time_limit;
+            host.args = args;
+            host.reply = String::new();
+            host.pending = 1;
+        }
+        module.store.set_fuel(fuel).unwrap();
+        let result = module.instance.get_typed_func::<(), ()>(&module.store, &command.handler)
+            .and_then(|handler| handler.call(&mut module.store, ()));
+        let host = module.store.data_mut();
+        host.db = ptr::null_mut();
+        self.ready_keys.extend(host.ready_keys.drain(..));
+        if let Err(e) = result {
+            return Err(format!("ERR WASM command failed: {}", trap_message(&e, timed)));
+        }
+        // a handler that doesn't finish its reply gets nils for the rest
+        let mut reply = mem::replace(&mut host.reply, String::new());
+        for _ in 0..host.pending {
+            reply.push_str(&format!("{}", Value::Nil));
+        }
+        return Ok(reply);
+    }
+
+    // streams appended to by module commands, which may unblock readers
+    pub fn take_ready_keys(&mut self) -> Vec<Key> {
+        return mem::replace(&mut self.ready_keys, Vec::new());
+    }
+}
+
+fn memory_limits(memory_limit:usize) -> StoreLimits {
+    return StoreLimitsBuilder::new().memory_size(memory_limit).instances(1).memories(1).trap_on_grow_failure(true).build();
+}
+
+fn trap_message(e:&wasmi::Error, timed:bool) -> String {
+    return match e.as_trap_code() {
+        Some(TrapCode::OutOfFuel) if timed => "time limit exceeded".to_string(),
+        Some(TrapCode::OutOfFuel) => "fuel limit exceeded".to_string(),
+        _ => e.to_string(),
+    };
+}
+
+fn memory(caller:&Caller<Host>) -> Result<Memory, wasmi::Error> {
+    return match caller.get_export("memory").and_then(Extern::into_memory) {
+        Some(memory) => Ok(memory),
+        None => Err(wasmi::Error::new("module doesn't export its memory")),
+    };
+}
+
+fn read_memory(caller:&Caller<Host>, ptr:i32, len:i32) -> Result<Vec<u8>, wasmi::Error> {
+    let memory = memory(caller)?;
+    if ptr < 0 || len < 0 || ptr as usize + len as usize > memory.data(caller).len() {
+        return Err(wasmi::Error::new("out of bounds memory access"));
+    }
+    return Ok(memory.data(caller)[ptr as usize..ptr as usize + len as usize].to_vec());
+}
+
+// copies as much of the bytes as fits, returning their full length
+fn write_memory(caller:&mut Caller<Host>, ptr:i32, cap:i32, bytes:&[u8]) -> Result<i32, wasmi::Error> {
+    let memory = memory(caller)?;
+    if ptr < 0 || cap < 0 {
+        return Err(wasmi::Error::new("out of bounds memory access"));
+    }
+    let n = bytes.len().min(cap as usize);
+    memory.write(caller, ptr as usize, &bytes[..n]).map_err(|e| wasmi::Error::new(e.to_string()))?;
+    return Ok(bytes.len() as i32);
+}
+
+// every host call checks the time limit, which catches modules that keep
+// calling into the server; the fuel budget catches the ones that don't
+fn check_deadline(caller:&Caller<Host>) -> Result<(), wasmi::Error> {
+    if now_ms() > caller.data().deadline {
+        return Err(wasmi::Error::new("time limit exceeded"));
+    }
+    return Ok(());
+}
+
+fn add_reply(caller:&mut Caller<Host>, reply:String, elements:usize) -> Result<(), wasmi::Error> {
+    check_deadline(caller)?;
+    let host = caller.data_mut();
+    if host.initializing || host.pending == 0 {
+        return Err(wasmi::Error::new("reply already complete"));
+    }
+    host.pending += elements;
+    host.pending -= 1;
+    host.reply.push_str(&reply);
+    return Ok(());
+}
+
+fn host_api(engine:&Engine) -> Linker<Host> {
+    let mut linker = Linker::new(engine);
+    linker.func_wrap("rustis", "register_command", |mut caller:Caller<Host>, name_ptr:i32, name_len:i32, handler_ptr:i32, handler_len:i32, flags:i32| -> Result<i32, wasmi::Error> {
+        let name = bytes_to_string(&read_memory(&caller, name_ptr, name_len)?).to_lowercase();
+        let handler = bytes_to_string(&read_memory(&caller, handler_ptr, handler_len)?);
+        let host = caller.data_mut();
+        if !host.initializing || name.is_empty() || host.registered.iter().any(|&(ref n, _, _)| *n == name) {
+            return Ok(-1);
+        }
+        host.registered.push((name, handler, flags));
+        return Ok(0);
+    }).unwrap();
+    linker.func_wrap("rustis", "arg_count", |caller:Caller<Host>| -> i32 {
+        return caller.data().args.len() as i32;
+    }).unwrap();
+    linker.func_wrap("rustis", "arg", |mut caller:Caller<Host>, index:i32, ptr:i32, cap:i32| -> Result<i32, wasmi::Error> {
+        let arg = match caller.data().args.get(index as usize) {
+            Some(arg) if index >= 0 => string_to_bytes(arg),
+            _ => return Ok(-1),
+        };
+        return write_memory(&mut caller, ptr, cap, &arg);
+    }).unwrap();
+    linker.func_wrap("rustis", "call", |mut caller:Caller<Host>, ptr:i32, len:i32| -> Result<i32, wasmi::Error> {
+        check_deadline(&caller)?;
+        let request = bytes_to_string(&read_memory(&caller, ptr, len)?);
+        let host = caller.data_mut();
+        if host.db.is_null() {
+            return Err(wasmi::Error::new("commands can only be called while handling one"));
+        }
+        let ParseResult(parsed, mut commands) = Command::parse(&request);
+        let result = match commands.pop() {
+            // each command called has its own result
+            Some(_) if !commands.is_empty() || parsed < request.chars().count() => Return::Error("ERR Protocol error: expected a single command as a RESP array".to_string()),
+            Some(Ok(ref cmd)) if !allowed_in_script(cmd) => Return::Error("ERR This Redis command is not allowed from WASM commands".to_string()),
+            Some(Ok(ref cmd)) if cmd.is_write() && !host.write => Return::Error("ERR Write commands are not allowed from read-only WASM commands".to_string()),
+            Some(Ok(cmd)) => {
+                if let Command::XAdd {ref key, ..} = cmd {
+                    host.ready_keys.push(key.clone());
+                }
+                // the database outlives the call that set this pointer
+                unsafe { (*host.db).run_command(cmd) }
+            }
+            Some(Err(e)) => Return::Error(e),
+            None => Return::Error("ERR Protocol error: expected a command as a RESP array".to_string()),
+        };
+        host.result = string_to_bytes(&format!("{}", result));
+        return Ok(host.result.len() as i32);
+    }).unwrap();
+    linker.func_wrap("rustis", "result", |mut caller:Caller<Host>, ptr:i32, cap:i32| -> Result<i32, wasmi::Error> {
+        let result = caller.data().result.clone();
+        return write_memory(&mut caller, ptr, cap, &result);
+    }).unwrap();
+    linker.func_wrap("rustis", "reply_ok", |mut caller:Caller<Host>| -> Result<(), wasmi::Error> {
+        return add_reply(&mut caller, format!("{}", Return::Ok), 0);
+    }).unwrap();
+    linker.func_wrap("rustis", "reply_nil", |mut caller:Caller<Host>| -> Result<(), wasmi::Error> {
+        return add_reply(&mut caller, format!("{}", Value::Nil), 0);
+    }).unwrap();
+    linker.func_wrap("rustis", "reply_int", |mut caller:Caller<Host>, i:i64| -> Result<(), wasmi::Error> {
+        return add_reply(&mut caller, format!("{}", Value::IntValue(i)), 0);
+    }).unwrap();
+    linker.func_wrap("rustis", "reply_str", |mut caller:Caller<Host>, ptr:i32, len:i32| -> Result<(), wasmi::Error> {
+        let s = bytes_to_string(&read_memory(&caller, ptr, len)?);
+        return add_reply(&mut caller, format!("${}\r\n{}\r\n", byte_len(&s), s), 0);
+    }).unwrap();
+    linker.func_wrap("rustis", "reply_error", |mut caller:Caller<Host>, ptr:i32, len:i32| -> Result<(), wasmi::Error> {
+        // errors are a single line
+        let e = bytes_to_string(&read_memory(&caller, ptr, len)?).replace(|c| c == '\r' || c == '\n', " ");
+        return add_reply(&mut caller, format!("{}", Return::Error(e)), 0);
+    }).unwrap();
+    linker.func_wrap("rustis", "reply_array", |mut caller:Caller<Host>, len:i32| -> Result<(), wasmi::Error> {
+        if len < 0 {
+            return Err(wasmi::Error::new("negative array length"));
+        }
+        return add_reply(&mut caller, format!("*{}\r\n", len), len as usize);
+    }).unwrap();
+    return linker;
+}
+
+#[cfg(test)]
+fn load_wat(wasm:&mut Wasm, name:&str, source:&str) -> Result<(), String> {
+    use wat;
+    return wasm.load_module(name.to_string(), name, &wat::parse_str(source).unwrap());
+}
+
+#[cfg(test)]
+const TEST_MODULE:&'static str = r#"(module
+    (import "rustis" "register_command" (func $register (param i32 i32 i32 i32 i32) (result i32)))
+    (import "rustis" "arg" (func $arg (param i32 i32 i32) (result i32)))
+    (import "rustis" "call" (func $call (param i32 i32) (result i32)))
+    (import "rustis" "result" (func $result (param i32 i32) (result i32)))
+    (import "rustis" "reply_str" (func $reply_str (param i32 i32)))
+    (import "rustis" "reply_int" (func $reply_int (param i64)))
+    (import "rustis" "reply_array" (func $reply_array (param i32)))
+    (memory (export "memory") 1)
+    (data (i32.const 0) "echo")
+    (data (i32.const 16) "spin")
+    (data (i32.const 32) "setk")
+    (data (i32.const 48) "*3\0d\0a$3\0d\0aSET\0d\0a$1\0d\0ak\0d\0a$1\0d\0av\0d\0a")
+    (data (i32.const 96) "pair")
+    (data (i32.const 112) "twice")
+    (data (i32.const 128) "*1\0d\0a$4\0d\0aPING\0d\0a*1\0d\0a$4\0d\0aPING\0d\0a")
+    (func (export "rustis_init")
+        (drop (call $register (i32.const 0) (i32.const 4) (i32.const 0) (i32.const 4) (i32.const 0)))
+        (drop (call $register (i32.const 16) (i32.const 4) (i32.const 16) (i32.const 4) (i32.const 0)))
+        (drop (call $register (i32.const 32) (i32.const 4) (i32.const 32) (i32.const 4) (i32.const 0)))
+        (drop (call $register (i32.const 96) (i32.const 4) (i32.const 96) (i32.const 4) (i32.const 1)))
+        (drop (call $register (i32.const 112) (i32.const 5) (i32.const 112) (i32.const 5) (i32.const 0))))
+    (func (export "echo")
+        (call $reply_str (i32.const 1024) (call $arg (i32.const 1) (i32.const 1024) (i32.const 1024))))
+    (func (export "spin") (loop (br 0)))
+    (func (export "setk")
+        (call $reply_int (i64.extend_i32_s (call $call (i32.const 48) (i32.const 27)))))
+    (func (export "pair")
+        (drop (call $call (i32.const 48) (i32.const 27)))
+        (call $reply_array (i32.const 2))
+        (call $reply_str (i32.const 1024) (call $result (i32.const 1024) (i32.const 64))))
+    (func (export "twice")
+        (drop (call $call (i32.const 128) (i32.const 28)))
+        (call $reply_str (i32.const 1024) (call $result (i32.const 1024) (i32.const 128)))))
+"#;
+
+#[test]
+fn test_wasm_commands() {
+    let mut wasm = Wasm::new();
+    let mut config = Config::new();
+    config.wasm_fuel_limit = 1000000;
+    wasm.configure(&config);
+    let mut db = RustisDb::new();
+    let args = |args:&[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<String>>();
+    assert_eq!(load_wat(&mut wasm, "cmds", TEST_MODULE), Ok(()));
+    assert!(load_wat(&mut wasm, "cmds", TEST_MODULE).is_err());
+    assert!(wasm.handles("ECHO"));
+    assert_eq!(wasm.call(&mut db, args(&["echo", "hello"])), Ok("$5\r\nhello\r\n".to_string()));
+    assert_eq!(wasm.call(&mut db, args(&["spin"])), Err("ERR WASM command failed: fuel limit exceeded".to_string()));
+    // setk isn't flagged as writing, so its SET is refused
+    assert_eq!(wasm.call(&mut db, args(&["setk"])), Ok(":66\r\n".to_string()));
+    assert_eq!(db.run_command(Command::Exists {key: "k".to_string()}), Return::ValueReturn(Value::IntValue(0)));
+    assert_eq!(wasm.call(&mut db, args(&["pair"])), Ok("*2\r\n$5\r\n+OK\r\n\r\n$-1\r\n".to_string()));
+    assert_eq!(db.run_command(Command::Get {key: "k".to_string()}), Return::ValueReturn(Value::StrValue("v".to_string())));
+    // a call runs one command
+    assert_eq!(wasm.call(&mut db, args(&["twice"])), Ok("$64\r\n-ERR Protocol error: expected a single command as a RESP array\r\n\r\n".to_string()));
+    assert_eq!(wasm.unload("cmds"), Ok(()));
+    assert!(!wasm.handles("echo"));
+    assert!(wasm.call(&mut db, args(&["echo", "x"])).is_err());
+}
+
+#[test]
+fn test_wasm_limits() {
+    let mut wasm = Wasm::new();
+    let mut config = Config::new();
+    config.wasm_fuel_limit = 1000000;
+    config.wasm_memory_limit = 65536;
+    wasm.configure(&config);
+    assert!(load_wat(&mut wasm, "big", r#"(module (memory (export "memory") 2) (func (export "rustis_init")))"#).is_err());
+    assert!(load_wat(&mut wasm, "noinit", r#"(module (memory (export "memory") 1))"#).is_err());
+    assert!(load_wat(&mut wasm, "hang", r#"(module (memory (export "memory") 1) (func (export "rustis_init") (loop (br 0))))"#).is_err());
+
+    // a loop that never calls the host is stopped by the time limit too
+    config.wasm_fuel_limit = u64::max_value();
+    config.wasm_time_limit = 10;
+    config.wasm_memory_limit = 64 << 20;
+    wasm.configure(&config);
+    assert_eq!(load_wat(&mut wasm, "cmds", TEST_MODULE), Ok(()));
+    let start = now_ms();
+    let mut db = RustisDb::new();
+    assert_eq!(wasm.call(&mut db, vec!["spin".to_string()]), Err("ERR WASM command failed: time limit exceeded".to_string()));
+    assert!(now_ms() - start < 5000);
+}
+
@@ -0,0 +1,107 @@
+// the sorted set type: scores by member, and members by score for range
+// queries, like the dict and skiplist of redis' t_zset.c
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::collections::btree_map::{Iter, Keys};
+use std::iter::FromIterator;
+
+// scores ordered as the skiplist orders them
+#[derive(Clone, Copy, Debug)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other:&Score) -> bool {
+        return self.cmp(other) == Ordering::Equal;
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other:&Score) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other:&Score) -> Ordering {
+        return self.0.total_cmp(&other.0);
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct SortedSet {
+    scores:BTreeMap<String, f64>,
+    by_score:BTreeSet<(Score, String)>,
+}
+
+impl SortedSet {
+    pub fn new() -> SortedSet {
+        return SortedSet {scores: BTreeMap::new(), by_score: BTreeSet::new()};
+    }
+
+    pub fn len(&self) -> usize {
+        return self.scores.len();
+    }
+
+    pub fn get(&self, member:&str) -> Option<&f64> {
+        return self.scores.get(member);
+    }
+
+    // sets a member's score, returning the one it had
+    pub fn insert(&mut self, member:String, score:f64) -> Option<f64> {
+        let old = self.scores.insert(member.clone(), score);
+        if let Some(old) = old {
+            self.by_score.remove(&(Score(old), member.clone()));
+        }
+        self.by_score.insert((Score(score), member));
+        return old;
+    }
+
+    // members and scores in member order
+    pub fn iter<'a>(&'a self) -> Iter<'a, String, f64> {
+        return self.scores.iter();
+    }
+
+    pub fn keys<'a>(&'a self) -> Keys<'a, String, f64> {
+        return self.scores.keys();
+    }
+
+    // the members with min <= score < max, lowest first
+    pub fn range_by_score<'a>(&'a self, min:f64, max:f64) -> impl Iterator<Item=(&'a String, f64)> + 'a {
+        return self.by_score.range((Score(min), String::new())..)
+            .take_while(move |&&(Score(score), _)| score < max)
+            .map(|&(Score(score), ref member)| (member, score));
+    }
+}
+
+impl<'a> IntoIterator for &'a SortedSet {
+    type Item = (&'a String, &'a f64);
+    type IntoIter = Iter<'a, String, f64>;
+
+    fn into_iter(self) -> Iter<'a, String, f64> {
+        return self.scores.iter();
+    }
+}
+
+impl FromIterator<(String, f64)> for SortedSet {
+    fn from_iter<I:IntoIterator<Item=(String, f64)>>(items:I) -> SortedSet {
+        let mut z = SortedSet::new();
+        for (member, score) in items {
+            z.insert(member, score);
+        }
+        return z;
+    }
+}
+
+#[test]
+fn test_range_by_score() {
+    let mut z = vec![("a", 3.0), ("b", 1.0), ("c", 2.0), ("d", 2.0)].into_iter().map(|(m, s)| (m.to_string(), s)).collect::<SortedSet>();
+    assert_eq!(z.insert("a".to_string(), 0.5), Some(3.0));
+    assert_eq!(z.len(), 4);
+    let range = |z:&SortedSet, min:f64, max:f64| z.range_by_score(min, max).map(|(m, s)| (m.clone(), s)).collect::<Vec<(String, f64)>>();
+    assert_eq!(range(&z, 1.0, 3.0), vec![("b".to_string(), 1.0), ("c".to_string(), 2.0), ("d".to_string(), 2.0)]);
+    assert_eq!(range(&z, 0.0, 1.0), vec![("a".to_string(), 0.5)]);
+    assert_eq!(range(&z, 2.5, 10.0), vec![]);
+    assert_eq!(z.keys().cloned().collect::<Vec<String>>(), vec!["a", "b", "c", "d"]);
+}